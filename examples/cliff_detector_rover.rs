@@ -4,497 +4,954 @@
 use panic_semihosting as _;
 use rtic::app;
 
-#[app(device = hal::pac, peripherals = true)]
+#[app(device = hal::pac, peripherals = true, dispatchers = [SPI1, SPI2, SPI3, SPI4])]
 mod app {
-    use cortex_m_semihosting::hprintln;
+    use core::fmt::Write as _;
+    use embedded_graphics::{
+        mono_font::{ascii::FONT_6X10, MonoTextStyle},
+        pixelcolor::BinaryColor,
+        prelude::*,
+        text::Text,
+    };
+    use embedded_hal::serial::Read as _;
     use hal::prelude::*;
+    use heapless::{Deque, String};
+    use rtic::mutex_prelude::*;
+    use stm32f401_rover_testbed::board::{
+        tof_config, Button, Display, Encoders, Led, Motors, Rover, SerialRx, Store, TofInterrupts,
+        Tofs, Watchdog,
+    };
+    use stm32f401_rover_testbed::button::{self, Gesture, Gestures};
+    use stm32f401_rover_testbed::calibration::{self, Calibration, Phase};
+    use stm32f401_rover_testbed::cliff_monitor::CliffMonitor;
+    use stm32f401_rover_testbed::config::{Loaded, RoverConfig, SaveError};
+    use stm32f401_rover_testbed::console::{
+        self, Command, DriveMode, Line, LineEditor, ParseError, Reply,
+    };
+    use stm32f401_rover_testbed::dashboard::{self, Dashboard, RateMeter};
+    use stm32f401_rover_testbed::diff_drive::DifferentialDrive;
+    use stm32f401_rover_testbed::drive::{Corner, DriveState};
+    use stm32f401_rover_testbed::i2c_dma::I2C1_BUS;
+    use stm32f401_rover_testbed::i2c_queue::Priority;
+    use stm32f401_rover_testbed::motor::{ChannelOutput, Direction};
+    use stm32f401_rover_testbed::odometry::WheelOdometry;
+    use stm32f401_rover_testbed::pid::{PidConfig, WheelSpeedController};
+    use stm32f401_rover_testbed::serial_dma::DmaSerialTx;
+    use stm32f401_rover_testbed::settings::Settings;
+    use stm32f401_rover_testbed::supervisor::{ResetCause, Supervisor, Verdict};
+    use stm32f401_rover_testbed::telemetry::{Packet, MAX_FRAME_LEN};
+    use stm32f401_rover_testbed::tof::{
+        Correction, RangeStatus, TofError, BOOT_MS, RESET_MS, SENSOR_DEADLINE_MS,
+    };
     use stm32f4xx_hal as hal;
-
-    type I2c = hal::i2c::I2c<
-        you_must_enable_the_rt_feature_for_the_pac_in_your_cargo_toml::I2C1,
-        (
-            hal::gpio::gpiob::PB8<hal::gpio::Alternate<4, hal::gpio::OpenDrain>>,
-            hal::gpio::gpiob::PB9<hal::gpio::Alternate<4, hal::gpio::OpenDrain>>,
-        ),
-    >;
-    type I2cProxy = shared_bus::I2cProxy<'static, shared_bus::AtomicCheckMutex<I2c>>;
-
-    type Vl6180xType = vl6180x::VL6180X<vl6180x::RangeContinuousMode, I2cProxy>;
-
-    type TofBRType = vl6180x::VL6180XwPins<
-        vl6180x::RangeContinuousMode,
-        I2cProxy,
-        hal::gpio::gpioc::PC15<hal::gpio::Output>,
-        hal::gpio::gpioc::PC14<hal::gpio::Input>,
-    >;
-
-    type TofFRType = vl6180x::VL6180XwPins<
-        vl6180x::RangeContinuousMode,
-        I2cProxy,
-        hal::gpio::gpioa::PA2<hal::gpio::Output>,
-        hal::gpio::gpioa::PA1<hal::gpio::Input>,
-    >;
-
-    type TofFLType = vl6180x::VL6180XwPins<
-        vl6180x::RangeContinuousMode,
-        I2cProxy,
-        hal::gpio::gpioa::PA5<hal::gpio::Output>,
-        hal::gpio::gpioa::PA4<hal::gpio::Input>,
-    >;
-
-    type TofBLType = vl6180x::VL6180XwPins<
-        vl6180x::RangeContinuousMode,
-        I2cProxy,
-        hal::gpio::gpiob::PB1<hal::gpio::Output>,
-        hal::gpio::gpiob::PB0<hal::gpio::Input>,
-    >;
-
-    type MotorsType = l298n::L298N<
-        hal::gpio::gpiob::PB5<hal::gpio::Output<hal::gpio::PushPull>>,
-        hal::gpio::gpiob::PB4<hal::gpio::Output<hal::gpio::PushPull>>,
-        hal::gpio::gpioa::PA15<hal::gpio::Output<hal::gpio::PushPull>>,
-        hal::gpio::gpioa::PA12<hal::gpio::Output<hal::gpio::PushPull>>,
-        hal::timer::PwmChannel<hal::pac::TIM4, 0>,
-        hal::timer::PwmChannel<hal::pac::TIM1, 3>,
-    >;
-
-    pub struct I2cDevices {
-        tof_br: TofBRType,
-        tof_fr: TofFRType,
-        tof_fl: TofFLType,
-        tof_bl: TofBLType,
-    }
-
-    #[derive(Debug)]
-    pub struct Cliffs {
-        br: bool,
-        fr: bool,
-        fl: bool,
-        bl: bool,
-    }
-    #[derive(Debug, Copy, Clone, PartialEq)]
-    pub enum Heading {
-        Forward,
-        Reverse,
-    }
-    impl Heading {
-        fn toggle(self) -> Heading {
-            match self {
-                Heading::Forward => Heading::Reverse,
-                Heading::Reverse => Heading::Forward,
-            }
-        }
-    }
-    #[derive(Debug, Copy, Clone, PartialEq)]
-    pub enum TurnDirection {
-        Left,
-        Right,
+    use systick_monotonic::{ExtU64, Systick};
+
+    // Starting values of the settings that differ from the defaults, until the console saves
+    // others
+    /// Longest a turn may take, in case the wheels slip.
+    const TURN_MS: u32 = 1500;
+    /// How far to turn away from a cliff, in degrees.
+    const TURN_ANGLE_DEG: u16 = 143;
+    /// Wait before trying again to boot a sensor that failed to come back.
+    const RETRY_MS: u64 = 500;
+
+    /// The watchdog resets the rover if it is not fed for this long.
+    const WATCHDOG_MS: u32 = 250;
+    /// How often `supervise` checks on the tasks.
+    const SUPERVISE_MS: u64 = 50;
+    /// Tasks watched by the supervisor. Sensor processing covers `read_range` and the sensor
+    /// recovery, which retries well within its deadline.
+    const SENSOR_TASK: usize = 0;
+    const DRIVE_TASK: usize = 1;
+    const DEADLINES_MS: [u32; 2] = [SENSOR_DEADLINE_MS, 100];
+
+    /// How often `drive_motors` moves the motors along their ramps and corrects the wheel
+    /// speeds. Long enough for a few encoder counts per period at a crawl.
+    const CONTROL_PERIOD_MS: u64 = 10;
+
+    /// How often a telemetry packet goes out on the serial port, about 40 bytes each.
+    const TELEMETRY_PERIOD_MS: u64 = 50;
+    /// Console replies waiting for the serial port, more are dropped.
+    const REPLY_QUEUE_LEN: usize = 2;
+    /// How long `send_reply` waits for the serial port to free up before trying again.
+    const REPLY_RETRY_MS: u64 = 1;
+    /// Lowest and highest address `i2c scan` probes, the rest are reserved.
+    const SCAN_ADDRESSES: core::ops::RangeInclusive<u8> = 0x08..=0x77;
+
+    /// How often the display is redrawn. Only what changed goes out, and `read_range` preempts
+    /// it to queue its reads ahead of the rest.
+    const SCREEN_PERIOD_MS: u64 = 250;
+    /// How long the notes from start up stay before the dashboard takes over.
+    const NOTE_MS: u64 = 3000;
+    /// Longest text on the display, 6 lines of 21 characters.
+    const SCREEN_LEN: usize = 128;
+
+    /// Calibration of the cliff sensors, see `calibration`. The button starts it and moves it
+    /// along.
+    pub enum CalibrationState {
+        Off,
+        /// The rover stands still until it is over.
+        Running(Calibration),
+        /// How the last calibration ended, shown until the next one.
+        Ended(&'static str),
     }
 
-    #[derive(Debug, Copy, Clone, PartialEq)]
-    pub enum Command {
-        Advance,
-        PreTurn,
-        Turn,
-        Standby,
-    }
-
-    #[derive(Debug, Copy, Clone, PartialEq)]
-    pub struct DriveState {
-        command: Command,
-        heading: Heading,
-        turn_direction: TurnDirection,
-        pre_turn_count: u32,
-        turn_count: u32,
-    }
-
-    const CLIFF_THRESHOLD: u16 = 20;
-    const PRE_TURN_COUNT_THRESHOLD: u32 = 40000;
-    const TURN_COUNT_THRESHOLD: u32 = 60000;
+    /// Millisecond resolution time base for the drive state machine.
+    #[monotonic(binds = SysTick, default = true)]
+    type Mono = Systick<1000>;
 
     #[shared]
     struct Shared {
-        i2c_devices: I2cDevices,
-        motors: MotorsType,
-        cliffs: Cliffs,
-        led: hal::gpio::gpioc::PC13<hal::gpio::Output<hal::gpio::PushPull>>,
+        tofs: Tofs,
+        tof_interrupts: TofInterrupts,
+        /// Velocity the motors are ramping towards, set by the drive state machine.
+        drive: DifferentialDrive,
+        odometry: WheelOdometry,
+        drive_state: DriveState,
+        /// Channel outputs last applied by `drive_motors`.
+        outputs: (ChannelOutput, ChannelOutput),
+        /// Whether the cliff avoidance or the console drives the motors.
+        mode: DriveMode,
+        /// Latest reading and health of every cliff sensor.
+        cliff_monitor: CliffMonitor,
+        led: Led,
+        /// Sensors held in reset after they stopped answering, indexed by `Corner`.
+        faulty: [bool; 4],
+        /// Whether `recover_sensor` is working through `faulty`.
+        recovering: bool,
+        supervisor: Supervisor<2>,
+        serial: DmaSerialTx,
+        /// Console replies in the order they are to go out.
+        replies: Deque<Reply, REPLY_QUEUE_LEN>,
+        /// Off while the console is in use, the frames would garble its replies.
+        telemetry_on: bool,
+        settings: Settings,
+        config_store: Store,
+        calibration: CalibrationState,
+        /// Times round the idle loop, for the dashboard.
+        idle_loops: u32,
+        reset_cause: ResetCause,
     }
 
     #[local]
     struct Local {
-        drive_state: DriveState,
+        motors: Motors,
+        encoders: Encoders,
+        /// Speed control of the right (`a`) and left (`b`) wheels.
+        speed_controllers: [WheelSpeedController; 2],
+        watchdog: Watchdog,
+        serial_rx: SerialRx,
+        line_editor: LineEditor,
+        button: Button,
+        /// Left out if the rover has no display.
+        display: Option<Display>,
     }
 
     #[init]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
-        let dp = ctx.device;
-        let cp = ctx.core;
-        let rcc = dp.RCC.constrain();
-        let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
-        let mut delay = cp.SYST.delay(&clocks);
-        let mut exti = dp.EXTI;
-        let mut syscfg = dp.SYSCFG.constrain();
-
-        let gpioa = dp.GPIOA.split();
-        let gpiob = dp.GPIOB.split();
-        let gpioc = dp.GPIOC.split();
-
-        // Set up led
-        let mut led = gpioc.pc13.into_push_pull_output();
-        led.set_high();
-
-        // Create the shared-bus I2C manager.
-        let bus_manager: &'static _ = {
-            let scl = gpiob
-                .pb8
-                .into_alternate()
-                .internal_pull_up(true)
-                .set_open_drain();
-            let sda = gpiob
-                .pb9
-                .into_alternate()
-                .internal_pull_up(true)
-                .set_open_drain();
-            let i2c = dp.I2C1.i2c((scl, sda), 400.kHz(), &clocks);
-
-            shared_bus::new_atomic_check!(I2c = i2c).unwrap()
-        };
-
-        let mut tof_config = vl6180x::Config::new();
-        tof_config.set_range_interrupt_mode(vl6180x::RangeInterruptMode::NewSampleReady);
-        tof_config.set_range_max_convergence_time(10).expect("rmc");
-        tof_config
-            .set_range_inter_measurement_period(20)
-            .expect("rimp");
-
-        // Set up x_shut pins
-        let mut x_shut_br = gpioc.pc15.into_push_pull_output();
-        let mut x_shut_fr = gpioa.pa2.into_push_pull_output();
-        let mut x_shut_fl = gpioa.pa5.into_push_pull_output();
-        let mut x_shut_bl = gpiob.pb1.into_push_pull_output();
-
-        x_shut_br.set_low();
-        x_shut_fr.set_low();
-        x_shut_fl.set_low();
-        x_shut_bl.set_low();
-
-        // Set up interrupt pins
-        let mut int_br = gpioc.pc14.into_pull_up_input();
-        int_br.make_interrupt_source(&mut syscfg);
-        int_br.trigger_on_edge(&mut exti, hal::gpio::Edge::Rising);
-        int_br.enable_interrupt(&mut exti);
-        let mut int_fr = gpioa.pa1.into_pull_up_input();
-        int_fr.make_interrupt_source(&mut syscfg);
-        int_fr.trigger_on_edge(&mut exti, hal::gpio::Edge::Rising);
-        int_fr.enable_interrupt(&mut exti);
-        let mut int_fl = gpioa.pa4.into_pull_up_input();
-        int_fl.make_interrupt_source(&mut syscfg);
-        int_fl.trigger_on_edge(&mut exti, hal::gpio::Edge::Rising);
-        int_fl.enable_interrupt(&mut exti);
-        let mut int_bl = gpiob.pb0.into_pull_up_input();
-        int_bl.make_interrupt_source(&mut syscfg);
-        int_bl.trigger_on_edge(&mut exti, hal::gpio::Edge::Rising);
-        int_bl.enable_interrupt(&mut exti);
-
-        // Set up vl6180x's
-        x_shut_br.set_high();
-        delay.delay_ms(50_u8);
-        let mut vl6180x_br =
-            vl6180x::VL6180X::with_config(bus_manager.acquire_i2c(), &tof_config).expect("vl1");
-        vl6180x_br.change_i2c_address(10).expect("sa1");
-
-        x_shut_fr.set_high();
-        delay.delay_ms(50_u8);
-        let mut vl6180x_fr =
-            vl6180x::VL6180X::with_config(bus_manager.acquire_i2c(), &tof_config).expect("vl2");
-        vl6180x_fr.change_i2c_address(11).expect("sa2");
-
-        x_shut_fl.set_high();
-        delay.delay_ms(50_u8);
-        let mut vl6180x_fl =
-            vl6180x::VL6180X::with_config(bus_manager.acquire_i2c(), &tof_config).expect("vl3");
-        vl6180x_fl.change_i2c_address(12).expect("sa3");
-
-        x_shut_bl.set_high();
-        delay.delay_ms(50_u8);
-        let mut vl6180x_bl =
-            vl6180x::VL6180X::with_config(bus_manager.acquire_i2c(), &tof_config).expect("vl4");
-        vl6180x_bl.change_i2c_address(13).expect("sa4");
-
-        // Start continuous range measurement
-        let vl6180x_br: Vl6180xType = vl6180x_br.start_range_continuous_mode().expect("ct1");
-        let vl6180x_fr: Vl6180xType = vl6180x_fr.start_range_continuous_mode().expect("ct2");
-        let vl6180x_fl: Vl6180xType = vl6180x_fl.start_range_continuous_mode().expect("ct3");
-        let vl6180x_bl: Vl6180xType = vl6180x_bl.start_range_continuous_mode().expect("ct4");
-
-        // Compose them into objects
-        let tof_br: TofBRType = vl6180x::VL6180XwPins {
-            vl6180x: vl6180x_br,
-            x_shutdown_pin: x_shut_br,
-            interrupt_pin: int_br,
-        };
-        let tof_fr: TofFRType = vl6180x::VL6180XwPins {
-            vl6180x: vl6180x_fr,
-            x_shutdown_pin: x_shut_fr,
-            interrupt_pin: int_fr,
-        };
-        let tof_fl: TofFLType = vl6180x::VL6180XwPins {
-            vl6180x: vl6180x_fl,
-            x_shutdown_pin: x_shut_fl,
-            interrupt_pin: int_fl,
+        let mut rover = Rover::take(ctx.device, ctx.core);
+        let mono = Systick::new(rover.syst, rover.clocks.sysclk().raw());
+
+        let watchdog_reset = rover.reset_cause.is_watchdog();
+        let config_note = match rover.config_source {
+            Loaded::Version(_) => Some("Saved config is for\nother firmware"),
+            Loaded::Corrupt => Some("Saved config is\ncorrupt"),
+            Loaded::Stored | Loaded::Empty => None,
         };
-        let tof_bl: TofBLType = vl6180x::VL6180XwPins {
-            vl6180x: vl6180x_bl,
-            x_shutdown_pin: x_shut_bl,
-            interrupt_pin: int_bl,
-        };
-
-        // Set up motor driver
-        let m1l1 = gpiob.pb5.into_push_pull_output();
-        let m1l2 = gpiob.pb4.into_push_pull_output();
-        let m2l1 = gpioa.pa15.into_push_pull_output();
-        let m2l2 = gpioa.pa12.into_push_pull_output();
-
-        let tim4_channels = gpiob.pb6.into_alternate();
-        let m1pwm = dp.TIM4.pwm_hz(tim4_channels, 20.kHz(), &clocks).split();
-        let max_duty = m1pwm.get_max_duty();
-
-        let tim1_channels = gpioa.pa11.into_alternate();
-        let m2pwm = dp.TIM1.pwm_hz(tim1_channels, 20.kHz(), &clocks).split();
-
-        let mut motors = l298n::L298N::new(m1l1, m1l2, m1pwm, m2l1, m2l2, m2pwm);
-        motors.a.set_duty(max_duty);
-        motors.b.set_duty(max_duty);
+        if let Some(display) = rover.display.as_mut() {
+            let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+            if watchdog_reset {
+                Text::new("Recovered from\nwatchdog reset", Point::new(0, 10), style)
+                    .draw(display)
+                    .ok();
+            }
+            if let Some(note) = config_note {
+                Text::new(note, Point::new(0, 34), style).draw(display).ok();
+            }
+            display.flush().ok();
+        }
 
-        let i2c_devices = I2cDevices {
-            tof_br,
-            tof_fr,
-            tof_fl,
-            tof_bl,
-        };
+        // Sensors that failed to come up keep their cliff set, so the rover stays in standby
+        // until they are recovered. Light the LED to show why.
+        let faulty = rover.tof_report.results.map(|result| result.is_err());
+        let recovering = !rover.tof_report.is_ok();
+        if recovering {
+            rover.led.set_low();
+            recover_sensor::spawn_after(RETRY_MS.millis()).ok();
+        }
 
-        let cliffs = Cliffs {
-            br: true,
-            fr: true,
-            fl: true,
-            bl: true,
+        let settings = match rover.config_source {
+            Loaded::Stored => rover.config.settings,
+            _ => Settings {
+                turn_ms: TURN_MS,
+                turn_angle_deg: TURN_ANGLE_DEG,
+                ..Settings::default()
+            },
         };
 
-        let drive_state = DriveState {
-            command: Command::Standby,
-            heading: Heading::Forward,
-            turn_direction: TurnDirection::Left,
-            pre_turn_count: 0,
-            turn_count: 0,
+        // Every position counts as a cliff until its sensor reports
+        let mut cliff_monitor = CliffMonitor::default();
+        settings.configure_monitor(&mut cliff_monitor);
+
+        let drive_state = DriveState::new(settings.drive_config());
+
+        let watchdog = Watchdog::start(rover.spare.iwdg, WATCHDOG_MS);
+        let supervisor = Supervisor::new(DEADLINES_MS, 0);
+        supervise::spawn().ok();
+        let mut drive = DifferentialDrive::default();
+        drive.set_config(settings.diff_drive_config(drive.config()));
+        let max_wheel_speed = drive.config().max_wheel_speed;
+        let speed_controllers =
+            [WheelSpeedController::new(PidConfig::WHEEL_SPEED, max_wheel_speed); 2];
+        drive_motors::spawn().ok();
+        send_telemetry::spawn().ok();
+        poll_button::spawn().ok();
+        // Leave the notes up for a while
+        let note_ms = if watchdog_reset || config_note.is_some() {
+            NOTE_MS
+        } else {
+            0
         };
+        if rover.display.is_some() {
+            refresh_display::spawn_after(note_ms.millis()).ok();
+        }
 
         (
             Shared {
-                i2c_devices,
-                motors,
-                cliffs,
-                led,
+                tofs: rover.tofs,
+                tof_interrupts: rover.tof_interrupts,
+                drive,
+                odometry: WheelOdometry::default(),
+                drive_state,
+                outputs: (ChannelOutput::from_signed(0), ChannelOutput::from_signed(0)),
+                mode: DriveMode::Auto,
+                cliff_monitor,
+                led: rover.led,
+                faulty,
+                recovering,
+                supervisor,
+                serial: rover.serial,
+                replies: Deque::new(),
+                telemetry_on: true,
+                settings,
+                config_store: rover.config_store,
+                calibration: CalibrationState::Off,
+                idle_loops: 0,
+                reset_cause: rover.reset_cause,
             },
-            Local { drive_state },
-            init::Monotonics(),
+            Local {
+                motors: rover.motors,
+                encoders: rover.encoders,
+                speed_controllers,
+                watchdog,
+                serial_rx: rover.serial_rx,
+                line_editor: LineEditor::new(),
+                button: rover.button,
+                display: rover.display,
+            },
+            init::Monotonics(mono),
         )
     }
 
-    #[task(binds=EXTI15_10, shared = [cliffs, i2c_devices])]
-    fn exti15_10event(ctx: exti15_10event::Context) {
-        let cliffs = ctx.shared.cliffs;
-        let i2c_devices = ctx.shared.i2c_devices;
-
-        // hprintln!("-------- Interrupt! -------- (tof_br)").unwrap();
-        (cliffs, i2c_devices).lock(|cliffs, i2c_devices| {
-            match i2c_devices.tof_br.vl6180x.read_range_mm() {
-                Ok(range) => {
-                    cliffs.br = range > CLIFF_THRESHOLD;
-                    // hprintln!("Range Read: {}mm", range).unwrap();
+    // Every sensor interrupt goes through `tof_interrupt`, add a binding here for sensors on
+    // other EXTI lines.
+    #[task(binds = EXTI0, priority = 3, shared = [tof_interrupts])]
+    fn exti0(ctx: exti0::Context) {
+        tof_interrupt(ctx.shared.tof_interrupts);
+    }
+
+    #[task(binds = EXTI1, priority = 3, shared = [tof_interrupts])]
+    fn exti1(ctx: exti1::Context) {
+        tof_interrupt(ctx.shared.tof_interrupts);
+    }
+
+    #[task(binds = EXTI4, priority = 3, shared = [tof_interrupts])]
+    fn exti4(ctx: exti4::Context) {
+        tof_interrupt(ctx.shared.tof_interrupts);
+    }
+
+    #[task(binds = EXTI15_10, priority = 3, shared = [tof_interrupts])]
+    fn exti15_10(ctx: exti15_10::Context) {
+        tof_interrupt(ctx.shared.tof_interrupts);
+    }
+
+    /// Records which sensors have a new sample. The I2C read is left to `read_range`, so the
+    /// interrupt never waits on the bus.
+    fn tof_interrupt(mut tof_interrupts: impl Mutex<T = TofInterrupts>) {
+        tof_interrupts.lock(|pins| {
+            for (corner, pin) in Corner::ALL.iter().zip(pins.iter_mut()) {
+                if pin.check_interrupt() {
+                    pin.clear_interrupt_pending_bit();
+                    // Already queued if this fails, the read will pick up the latest sample
+                    read_range::spawn(*corner).ok();
                 }
-                Err(_e) => (), //hprintln!("Error {:?}", e).unwrap(),
-            };
-            i2c_devices
-                .tof_br
-                .interrupt_pin
-                .clear_interrupt_pending_bit();
-            i2c_devices
-                .tof_br
-                .vl6180x
-                .clear_all_interrupts()
-                .expect("clrall");
+            }
         });
     }
 
-    #[task(binds=EXTI1, shared = [cliffs, i2c_devices])]
-    fn exti1_event(ctx: exti1_event::Context) {
-        let cliffs = ctx.shared.cliffs;
-        let i2c_devices = ctx.shared.i2c_devices;
-
-        // hprintln!("-------- Interrupt! -------- (tof_fr)").unwrap();
-        (cliffs, i2c_devices).lock(|cliffs, i2c_devices| {
-            match i2c_devices.tof_fr.vl6180x.read_range_mm() {
-                Ok(range) => {
-                    cliffs.fr = range > CLIFF_THRESHOLD;
-                    // hprintln!("Range Read: {}mm", range).unwrap();
-                }
-                Err(_e) => (), //hprintln!("Error {:?}", e).unwrap(),
+    // The I2C bus is moved along from these, above every task that uses it.
+    #[task(binds = I2C1_EV, priority = 4)]
+    fn i2c1_ev(_: i2c1_ev::Context) {
+        I2C1_BUS.on_interrupt();
+    }
+
+    #[task(binds = I2C1_ER, priority = 4)]
+    fn i2c1_er(_: i2c1_er::Context) {
+        I2C1_BUS.on_interrupt();
+    }
+
+    #[task(binds = DMA1_STREAM0, priority = 4)]
+    fn dma1_stream0(_: dma1_stream0::Context) {
+        I2C1_BUS.on_interrupt();
+    }
+
+    /// Reads the new sample of the sensor at `corner`. Above the display, so the read queues
+    /// ahead of the rest of a frame, but below the motors and the serial port, which would
+    /// otherwise wait on the bus with it.
+    #[task(
+        priority = 2,
+        capacity = 4,
+        shared = [cliff_monitor, tofs, faulty, recovering, led, supervisor, calibration]
+    )]
+    fn read_range(ctx: read_range::Context, corner: Corner) {
+        let mut cliff_monitor = ctx.shared.cliff_monitor;
+        let mut tofs = ctx.shared.tofs;
+        let mut faulty = ctx.shared.faulty;
+        let mut recovering = ctx.shared.recovering;
+        let mut led = ctx.shared.led;
+        let mut calibration = ctx.shared.calibration;
+        check_in(ctx.shared.supervisor, SENSOR_TASK);
+
+        // None if the sensor is held in reset
+        let sample = tofs.lock(|tofs| {
+            let index = corner.index();
+            let range = {
+                let tof = tofs.sensor_mut(index)?;
+                tof.read_range_mm()
+                    .and_then(|range| tof.clear_all_interrupts().map(|_| range))
+                    .map_err(|_| TofError::Read)
             };
-            i2c_devices
-                .tof_fr
-                .interrupt_pin
-                .clear_interrupt_pending_bit();
-            i2c_devices
-                .tof_fr
-                .vl6180x
-                .clear_all_interrupts()
-                .expect("clrall");
+            // The status of a range stays until the next one
+            let sample = range.and_then(|range| Ok((range, tofs.range_status(index)?)));
+            // Stop a misbehaving sensor from holding up the bus until it is reset
+            if sample.is_err() {
+                tofs.shut_down(index);
+            }
+            Some(sample)
         });
-    }
 
-    #[task(binds=EXTI4, shared = [cliffs, i2c_devices])]
-    fn exti4_event(ctx: exti4_event::Context) {
-        let cliffs = ctx.shared.cliffs;
-        let i2c_devices = ctx.shared.i2c_devices;
-
-        // hprintln!("-------- Interrupt! -------- (tof_fl)").unwrap();
-        (cliffs, i2c_devices).lock(|cliffs, i2c_devices| {
-            match i2c_devices.tof_fl.vl6180x.read_range_mm() {
-                Ok(range) => {
-                    cliffs.fl = range > CLIFF_THRESHOLD;
-                    // hprintln!("Range Read: {}mm", range).unwrap();
+        match sample {
+            Some(Ok((range, status))) => {
+                let now_ms = monotonics::now().ticks() as u32;
+                cliff_monitor.lock(|monitor| monitor.record_sample(corner, range, status, now_ms));
+
+                // The calibration also wants the return rate of every range it can trust
+                let measuring = calibration.lock(|calibration| {
+                    matches!(calibration, CalibrationState::Running(c) if c.is_measuring())
+                });
+                if measuring && status != RangeStatus::Unreliable {
+                    let rate = tofs.lock(|tofs| tofs.return_rate(corner.index()));
+                    let next = calibration.lock(|calibration| match calibration {
+                        CalibrationState::Running(c) => {
+                            let changed = c.record(corner, range, rate.ok()?);
+                            changed.then(|| c.corrections())
+                        }
+                        _ => None,
+                    });
+                    // On to the next step, with the sensors corrected for it
+                    if let Some(corrections) = next {
+                        restart_sensors(
+                            &mut tofs,
+                            &mut faulty,
+                            &mut recovering,
+                            &mut led,
+                            |tofs| correct(tofs, corrections),
+                        );
+                    }
                 }
-                Err(_e) => (), //hprintln!("Error {:?}", e).unwrap(),
-            };
-            i2c_devices
-                .tof_fl
-                .interrupt_pin
-                .clear_interrupt_pending_bit();
-            i2c_devices
-                .tof_fl
-                .vl6180x
-                .clear_all_interrupts()
-                .expect("clrall");
+            }
+            Some(Err(_)) => {
+                cliff_monitor.lock(|monitor| monitor.record_error(corner));
+                let start = (&mut faulty, &mut recovering).lock(|faulty, recovering| {
+                    faulty[corner.index()] = true;
+                    !core::mem::replace(recovering, true)
+                });
+                if start {
+                    led.lock(|led| led.set_low());
+                    recover_sensor::spawn_after(u64::from(RESET_MS).millis()).ok();
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Boots the faulty sensors again, one at a time as they all come out of reset at the
+    /// default address. A sensor's cliff stays set until its first sample after recovering.
+    #[task(priority = 2, shared = [tofs, faulty, recovering, led], local = [from: usize = 0])]
+    fn recover_sensor(ctx: recover_sensor::Context) {
+        let mut tofs = ctx.shared.tofs;
+        let mut led = ctx.shared.led;
+        let from = ctx.local.from;
+
+        // Take turns, so a sensor that never comes back does not hold up the others
+        let next = (ctx.shared.faulty, ctx.shared.recovering).lock(|faulty, recovering| {
+            let next = (0..faulty.len())
+                .map(|i| (*from + i) % faulty.len())
+                .find(|i| faulty[*i]);
+            *recovering = next.is_some();
+            next
         });
+        match next {
+            Some(index) => {
+                *from = index + 1;
+                tofs.lock(|tofs| tofs.release(index));
+                boot_sensor::spawn_after(u64::from(BOOT_MS).millis(), index).ok();
+            }
+            None => led.lock(|led| led.set_high()),
+        }
     }
 
-    #[task(binds=EXTI0, shared = [cliffs, i2c_devices])]
-    fn exti0_event(ctx: exti0_event::Context) {
-        let cliffs = ctx.shared.cliffs;
-        let i2c_devices = ctx.shared.i2c_devices;
-
-        // hprintln!("-------- Interrupt! -------- (tof_bl)").unwrap();
-        (cliffs, i2c_devices).lock(|cliffs, i2c_devices| {
-            match i2c_devices.tof_bl.vl6180x.read_range_mm() {
-                Ok(range) => {
-                    cliffs.bl = range > CLIFF_THRESHOLD;
-                    // hprintln!("Range Read: {}mm", range).unwrap();
-                }
-                Err(_e) => (), //hprintln!("Error {:?}", e).unwrap(),
-            };
-            i2c_devices
-                .tof_bl
-                .interrupt_pin
-                .clear_interrupt_pending_bit();
-            i2c_devices
-                .tof_bl
-                .vl6180x
-                .clear_all_interrupts()
-                .expect("clrall");
+    #[task(priority = 2, shared = [tofs, faulty, supervisor])]
+    fn boot_sensor(ctx: boot_sensor::Context, index: usize) {
+        let mut tofs = ctx.shared.tofs;
+        let mut faulty = ctx.shared.faulty;
+        check_in(ctx.shared.supervisor, SENSOR_TASK);
+
+        match tofs.lock(|tofs| tofs.start(index, I2C1_BUS.acquire(Priority::High))) {
+            Ok(()) => {
+                faulty.lock(|faulty| faulty[index] = false);
+                recover_sensor::spawn().ok();
+            }
+            Err(_) => {
+                recover_sensor::spawn_after(RETRY_MS.millis()).ok();
+            }
+        }
+    }
+
+    /// Feeds the watchdog while every task checks in on time. Otherwise cuts the motors and
+    /// lets the watchdog reset the rover, even if the task holding them is hung. Also times out
+    /// a stuck I2C bus, which raises no interrupts of its own.
+    #[task(priority = 5, shared = [supervisor], local = [watchdog])]
+    fn supervise(ctx: supervise::Context) {
+        let mut supervisor = ctx.shared.supervisor;
+        let watchdog = ctx.local.watchdog;
+        I2C1_BUS.check_timeout();
+
+        match supervisor.lock(|supervisor| supervisor.poll(monotonics::now().ticks() as u32)) {
+            Verdict::Feed => {
+                watchdog.feed();
+                supervise::spawn_after(SUPERVISE_MS.millis()).ok();
+            }
+            Verdict::Starve { .. } => watchdog.trip(),
+        }
+    }
+
+    fn check_in(mut supervisor: impl Mutex<T = Supervisor<2>>, task: usize) {
+        supervisor.lock(|supervisor| supervisor.check_in(task, monotonics::now().ticks() as u32));
+    }
+
+    /// Moves the wheel speeds along their ramps towards the latest motor command, tracks how far
+    /// the wheels actually moved, and holds each wheel at its ramped speed. Under manual control
+    /// from the console the ramps head for its duties instead, which are applied open loop.
+    #[task(
+        priority = 3,
+        shared = [drive, odometry, outputs, mode],
+        local = [motors, encoders, speed_controllers]
+    )]
+    fn drive_motors(ctx: drive_motors::Context) {
+        let mut drive = ctx.shared.drive;
+        let mut odometry = ctx.shared.odometry;
+        let mut mode = ctx.shared.mode;
+        let mut last_outputs = ctx.shared.outputs;
+        let motors = ctx.local.motors;
+        let [right_control, left_control] = ctx.local.speed_controllers;
+        let now_ms = monotonics::now().ticks() as u32;
+
+        let (right, left) = ctx.local.encoders.counts();
+        let (right_speed, left_speed) = odometry.lock(|odometry| {
+            odometry.update(right, left, now_ms);
+            odometry.wheel_speeds()
         });
+
+        let drive_mode = mode.lock(|mode| *mode);
+        let (ramped, (target_a, target_b)) = drive.lock(|drive| {
+            if let DriveMode::Manual([a, b]) = drive_mode {
+                drive.ramp_mut().set_targets(a, b);
+            }
+            let ramped = drive.update(now_ms);
+            right_control.set_trim(drive.config().trim_a);
+            left_control.set_trim(drive.config().trim_b);
+            (ramped, drive.ramp().speeds())
+        });
+        let dt = CONTROL_PERIOD_MS as f32 / 1000.0;
+        let outputs = match drive_mode {
+            DriveMode::Manual(_) => {
+                right_control.pid_mut().reset();
+                left_control.pid_mut().reset();
+                ramped
+            }
+            DriveMode::Auto | DriveMode::Stopped => (
+                right_control.update(target_a, right_speed, dt),
+                left_control.update(target_b, left_speed, dt),
+            ),
+        };
+        apply_outputs(outputs, motors);
+        last_outputs.lock(|last| *last = outputs);
+        drive_motors::spawn_after(CONTROL_PERIOD_MS.millis()).ok();
     }
 
-    #[idle(shared = [cliffs, motors], local=[drive_state])]
-    fn idle(ctx: idle::Context) -> ! {
-        let mut cliffs = ctx.shared.cliffs;
-        let mut motors = ctx.shared.motors;
-        let drive_state = ctx.local.drive_state;
+    /// Streams a snapshot of the sensors and drive, see `telemetry`. A packet that finds the
+    /// previous one still going out is dropped, the sequence number shows the gap. Nothing is
+    /// sent while the console has the serial port.
+    #[task(
+        shared = [cliff_monitor, drive_state, outputs, serial, telemetry_on, reset_cause],
+        local = [sequence: u16 = 0]
+    )]
+    fn send_telemetry(ctx: send_telemetry::Context) {
+        let mut cause = ctx.shared.reset_cause;
+        let reset_cause = cause.lock(|cause| *cause);
+        let sequence = ctx.local.sequence;
+        send_telemetry::spawn_after(TELEMETRY_PERIOD_MS.millis()).ok();
+        let mut telemetry_on = ctx.shared.telemetry_on;
+        if !telemetry_on.lock(|on| *on) {
+            return;
+        }
+
+        let packet = (
+            ctx.shared.cliff_monitor,
+            ctx.shared.drive_state,
+            ctx.shared.outputs,
+        )
+            .lock(|monitor, drive_state, outputs| {
+                let now_ms = monotonics::now().ticks() as u32;
+                Packet::snapshot(
+                    *sequence,
+                    now_ms,
+                    monitor,
+                    drive_state,
+                    *outputs,
+                    reset_cause,
+                )
+            });
+        *sequence = sequence.wrapping_add(1);
 
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = packet.encode(&mut frame);
+        let mut serial = ctx.shared.serial;
+        serial.lock(|serial| serial.send(&frame[..len]).ok());
+    }
+
+    /// Collects the bytes received into lines for `run_command`.
+    #[task(binds = USART1, priority = 3, local = [serial_rx, line_editor])]
+    fn usart1(ctx: usart1::Context) {
+        let rx = ctx.local.serial_rx;
         loop {
-            cliffs.lock(|cliffs| {
-                // hprintln!("{:?}", cliffs).unwrap();
-                if !cliffs.br && !cliffs.fr && !cliffs.fl && !cliffs.bl {
-                    if drive_state.command == Command::Standby {
-                        drive_state.command = Command::Advance;
-                        motors.lock(|motors| continue_current_heading(drive_state.heading, motors));
-                    };
-                    return;
+            match rx.read() {
+                Ok(byte) => {
+                    if let Some(line) = ctx.local.line_editor.push(byte) {
+                        // Dropped if the commands before it are still being carried out
+                        run_command::spawn(line).ok();
+                    }
                 }
+                Err(nb::Error::WouldBlock) => break,
+                // Overrun or noise, the byte is lost
+                Err(nb::Error::Other(_)) => {}
+            }
+        }
+    }
 
-                if cliffs.br && cliffs.fr && cliffs.fl && cliffs.bl {
-                    drive_state.command = Command::Standby;
-                    motors.lock(|motors| stop(motors));
+    /// Carries out a console command, see `console`, and queues the reply. Telemetry stops
+    /// until `telemetry on`, so the frames do not garble the replies.
+    #[task(
+        capacity = 2,
+        shared = [
+            replies, telemetry_on, mode, cliff_monitor, drive_state, drive, tofs, faulty,
+            recovering, led, settings, config_store,
+        ]
+    )]
+    fn run_command(ctx: run_command::Context, line: Result<Line, ParseError>) {
+        let mut settings = ctx.shared.settings;
+        let mut mode = ctx.shared.mode;
+        let mut cliff_monitor = ctx.shared.cliff_monitor;
+        let mut tofs = ctx.shared.tofs;
+        let mut drive_state = ctx.shared.drive_state;
+        let mut drive = ctx.shared.drive;
+        let mut led = ctx.shared.led;
+        let mut replies = ctx.shared.replies;
+        let mut telemetry_on = ctx.shared.telemetry_on;
+        let mut reply = Reply::new();
+
+        let command = line.and_then(|line| console::parse(&line));
+        telemetry_on.lock(|on| *on = command == Ok(Command::Telemetry(true)));
+        let current = settings.lock(|settings| *settings);
+
+        // A reply too long for the buffer is cut short
+        match command {
+            Err(e) => write!(reply, "error: {}\r\n", e),
+            Ok(Command::Help) => console::write_help(&mut reply),
+            Ok(Command::Get(None)) => console::write_settings(&mut reply, &current),
+            Ok(Command::Get(Some(param))) => console::write_setting(&mut reply, &current, param),
+            // The parser has only checked the parameter's own range
+            Ok(Command::Set(param, value)) => match settings
+                .lock(|settings| settings.set(param, value).map(|_| *settings))
+            {
+                Err(e) => write!(reply, "error: {}\r\n", e),
+                Ok(settings) => {
+                    cliff_monitor.lock(|monitor| settings.configure_monitor(monitor));
+                    drive_state.lock(|drive_state| drive_state.set_config(settings.drive_config()));
+                    drive
+                        .lock(|drive| drive.set_config(settings.diff_drive_config(drive.config())));
+                    if param.restarts_sensors() {
+                        let config =
+                            tof_config(settings.max_convergence_ms, settings.inter_measurement_ms);
+                        restart_sensors(
+                            &mut tofs,
+                            ctx.shared.faulty,
+                            ctx.shared.recovering,
+                            &mut led,
+                            |tofs| tofs.set_config(config),
+                        );
+                    }
+                    console::write_setting(&mut reply, &settings, param)
                 }
+            },
+            Ok(Command::Save) => match save_config(&current, &mut tofs, ctx.shared.config_store) {
+                Ok(()) => write!(reply, "saved\r\n"),
+                Err(SaveError::Full) => write!(reply, "error: config full, reset to clear\r\n"),
+                Err(SaveError::Flash(e)) => write!(reply, "error: flash {:?}\r\n", e),
+            },
+            Ok(Command::TofStatus) => {
+                let active = tofs.lock(|tofs| core::array::from_fn(|index| tofs.is_active(index)));
+                cliff_monitor.lock(|monitor| {
+                    let now_ms = monotonics::now().ticks() as u32;
+                    console::write_tof_status(&mut reply, monitor, active, now_ms)
+                })
+            }
+            Ok(Command::Motor(channel, percent)) => {
+                mode.lock(|mode| *mode = mode.with_motor(channel, percent));
+                write!(reply, "cliffs ignored until drive start\r\n")
+            }
+            Ok(Command::DriveStop) => {
+                mode.lock(|mode| *mode = DriveMode::Stopped);
+                write!(reply, "stopped\r\n")
+            }
+            Ok(Command::DriveStart) => {
+                mode.lock(|mode| *mode = DriveMode::Auto);
+                write!(reply, "driving\r\n")
+            }
+            // The probes hold up the button, the display and telemetry, so not while driving
+            Ok(Command::I2cScan) if mode.lock(|mode| *mode) != DriveMode::Stopped => {
+                write!(reply, "error: drive stop first\r\n")
+            }
+            Ok(Command::I2cScan) => {
+                let found = SCAN_ADDRESSES.filter(|&address| {
+                    I2C1_BUS
+                        .transfer(Priority::Low, address, &[], &mut [])
+                        .is_ok()
+                });
+                console::write_scan(&mut reply, found)
+            }
+            Ok(Command::Telemetry(on)) => {
+                write!(reply, "telemetry {}\r\n", if on { "on" } else { "off" })
+            }
+        }
+        .ok();
 
-                if drive_state.command == Command::Advance {
-                    // Reaching this point means one or more cliffs has been detected
-                    drive_state.heading = drive_state.heading.toggle();
-                    drive_state.command = Command::PreTurn;
-                    drive_state.turn_direction = if cliffs.fr || cliffs.bl {
-                        TurnDirection::Right
-                    } else {
-                        TurnDirection::Left
-                    };
-                    drive_state.pre_turn_count = 0;
-                    drive_state.turn_count = 0;
-                    motors.lock(|motors| continue_current_heading(drive_state.heading, motors));
-                }
-            });
+        // Dropped if the replies before it have not gone out yet
+        replies.lock(|replies| replies.push_back(reply).ok());
+        // Already queued or waiting to try again if this fails, and it sends every reply
+        send_reply::spawn().ok();
+    }
 
-            // hprintln!("drive_state {:?}", drive_state).unwrap();
-            match drive_state.command {
-                Command::Advance => {}
-                Command::PreTurn => {
-                    drive_state.pre_turn_count += 1;
-                }
-                Command::Turn => {
-                    drive_state.turn_count += 1;
+    /// Sends the queued console replies in turn, trying again shortly while a telemetry frame
+    /// or the previous reply is still going out.
+    #[task(shared = [serial, replies])]
+    fn send_reply(ctx: send_reply::Context) {
+        let mut serial = ctx.shared.serial;
+        let mut replies = ctx.shared.replies;
+        let busy = replies.lock(|replies| {
+            while let Some(reply) = replies.front() {
+                if serial.lock(|serial| serial.send(reply.as_bytes())).is_err() {
+                    return true;
                 }
-                Command::Standby => (),
+                replies.pop_front();
             }
+            false
+        });
+        if busy {
+            send_reply::spawn_after(REPLY_RETRY_MS.millis()).ok();
+        }
+    }
 
-            if drive_state.pre_turn_count == PRE_TURN_COUNT_THRESHOLD {
-                drive_state.command = Command::Turn;
-                drive_state.pre_turn_count = 0;
-                motors.lock(|motors| continue_current_turn(drive_state.turn_direction, motors));
+    /// Boots every sensor again, like any sensor that stopped answering, after `configure` has
+    /// changed how they are set up.
+    fn restart_sensors(
+        mut tofs: impl Mutex<T = Tofs>,
+        mut faulty: impl Mutex<T = [bool; 4]>,
+        mut recovering: impl Mutex<T = bool>,
+        mut led: impl Mutex<T = Led>,
+        configure: impl FnOnce(&mut Tofs),
+    ) {
+        tofs.lock(|tofs| {
+            configure(tofs);
+            for index in 0..tofs.len() {
+                tofs.shut_down(index);
             }
+        });
+        let start = (&mut faulty, &mut recovering).lock(|faulty, recovering| {
+            *faulty = [true; 4];
+            !core::mem::replace(recovering, true)
+        });
+        if start {
+            led.lock(|led| led.set_low());
+            recover_sensor::spawn_after(u64::from(RESET_MS).millis()).ok();
+        }
+    }
 
-            if drive_state.turn_count == TURN_COUNT_THRESHOLD {
-                drive_state.command = Command::Advance;
-                drive_state.turn_count = 0;
-                motors.lock(|motors| continue_current_heading(drive_state.heading, motors));
-            }
+    fn correct(tofs: &mut Tofs, corrections: [Correction; 4]) {
+        for (index, correction) in corrections.iter().enumerate() {
+            tofs.set_correction(index, *correction);
         }
     }
 
-    fn continue_current_heading(heading: Heading, motors: &mut MotorsType) {
-        match heading {
-            Heading::Forward => {
-                motors.a.forward();
-                motors.b.forward();
-            }
-            Heading::Reverse => {
-                motors.a.reverse();
-                motors.b.reverse();
-            }
+    /// Keeps the settings and the corrections of the sensors for the next boot.
+    fn save_config(
+        settings: &Settings,
+        mut tofs: impl Mutex<T = Tofs>,
+        mut store: impl Mutex<T = Store>,
+    ) -> Result<(), SaveError<hal::flash::Error>> {
+        let config = RoverConfig {
+            settings: *settings,
+            corrections: tofs.lock(|tofs| core::array::from_fn(|index| tofs.correction(index))),
+        };
+        store.lock(|store| store.save(&config))
+    }
+
+    /// Reads the button every `button::SAMPLE_MS` and queues its gestures for `button_gesture`,
+    /// above the sensor tasks so the timing holds.
+    #[task(priority = 3, local = [button, gestures: Gestures = Gestures::new()])]
+    fn poll_button(ctx: poll_button::Context) {
+        poll_button::spawn_after(u64::from(button::SAMPLE_MS).millis()).ok();
+        let now_ms = monotonics::now().ticks() as u32;
+        // Active low
+        let pressed = ctx.local.button.is_low();
+        if let Some(gesture) = ctx.local.gestures.update(pressed, now_ms) {
+            // A full queue drops the gesture, as if the button had been missed
+            button_gesture::spawn(gesture).ok();
         }
     }
 
-    fn continue_current_turn(turn_direction: TurnDirection, motors: &mut MotorsType) {
-        match turn_direction {
-            TurnDirection::Left => {
-                motors.a.forward();
-                motors.b.reverse();
+    /// Moves the calibration along on every click of the button: from placing the rover, to
+    /// measuring the offsets, to holding it over the target, to measuring the crosstalk, to
+    /// saving the result. A click while measuring cancels it. The drive mode is
+    /// put back as it was once the calibration ends.
+    #[task(
+        capacity = 4,
+        shared = [calibration, mode, tofs, faulty, recovering, led, settings, config_store],
+        local = [resume: DriveMode = DriveMode::Auto]
+    )]
+    fn button_gesture(ctx: button_gesture::Context, gesture: Gesture) {
+        if gesture != Gesture::Click {
+            return;
+        }
+
+        let mut calibration = ctx.shared.calibration;
+        let mut mode = ctx.shared.mode;
+        let mut tofs = ctx.shared.tofs;
+        let mut settings = ctx.shared.settings;
+        let state = calibration.lock(|state| core::mem::replace(state, CalibrationState::Off));
+        let (next, corrections) = match state {
+            CalibrationState::Off | CalibrationState::Ended(_) => {
+                let previous = tofs.lock(|tofs| core::array::from_fn(|i| tofs.correction(i)));
+                *ctx.local.resume = mode.lock(|mode| core::mem::replace(mode, DriveMode::Stopped));
+                (CalibrationState::Running(Calibration::new(previous)), None)
             }
-            TurnDirection::Right => {
-                motors.a.reverse();
-                motors.b.forward();
+            CalibrationState::Running(mut running) => match running.phase() {
+                Phase::Place => {
+                    running.start();
+                    let corrections = running.corrections();
+                    (CalibrationState::Running(running), Some(corrections))
+                }
+                // The sensors already run with the offsets
+                Phase::Target => {
+                    running.start();
+                    (CalibrationState::Running(running), None)
+                }
+                Phase::Offsets | Phase::Crosstalk => (
+                    CalibrationState::Ended("Calibration\ncancelled"),
+                    Some(running.previous()),
+                ),
+                // The sensors already run with the new corrections
+                Phase::Done => {
+                    let current = settings.lock(|settings| *settings);
+                    let message = match save_config(&current, &mut tofs, ctx.shared.config_store) {
+                        Ok(()) => "Calibration saved",
+                        Err(SaveError::Full) => "Not saved, config\nfull, reset to clear",
+                        Err(SaveError::Flash(_)) => "Not saved, flash\nerror",
+                    };
+                    (CalibrationState::Ended(message), None)
+                }
+                Phase::Failed(_) => (
+                    CalibrationState::Ended("Calibration failed\nKept the old one"),
+                    None,
+                ),
+            },
+        };
+        // Back to however the rover was driving before
+        if let CalibrationState::Ended(_) = next {
+            let resume = *ctx.local.resume;
+            mode.lock(|mode| *mode = resume);
+        }
+        calibration.lock(|state| *state = next);
+
+        if let Some(corrections) = corrections {
+            restart_sensors(
+                &mut tofs,
+                ctx.shared.faulty,
+                ctx.shared.recovering,
+                ctx.shared.led,
+                |tofs| correct(tofs, corrections),
+            );
+        }
+    }
+
+    /// Shows the calibration on the display while there is one, redrawing only when the text
+    /// changes, and the dashboard otherwise.
+    #[task(
+        shared = [
+            calibration,
+            cliff_monitor,
+            drive_state,
+            outputs,
+            idle_loops,
+            reset_cause,
+        ],
+        local = [
+            display,
+            shown: String<SCREEN_LEN> = String::new(),
+            loop_rate: RateMeter = RateMeter::new(),
+        ]
+    )]
+    fn refresh_display(ctx: refresh_display::Context) {
+        refresh_display::spawn_after(SCREEN_PERIOD_MS.millis()).ok();
+        let mut state = ctx.shared.calibration;
+        let mut idle_loops = ctx.shared.idle_loops;
+        let mut cause = ctx.shared.reset_cause;
+        let shown = ctx.local.shown;
+        // Only spawned with a display
+        let display = match ctx.local.display {
+            Some(display) => display,
+            None => return,
+        };
+
+        let mut screen = String::<SCREEN_LEN>::new();
+        let calibrating = state
+            .lock(|state| match state {
+                CalibrationState::Off => Ok(false),
+                CalibrationState::Running(running) => {
+                    calibration::write_screen(&mut screen, running).map(|_| true)
+                }
+                CalibrationState::Ended(message) => screen
+                    .push_str(message)
+                    .map(|_| true)
+                    .map_err(|_| core::fmt::Error),
+            })
+            .unwrap_or(true);
+
+        if !calibrating {
+            let now_ms = monotonics::now().ticks() as u32;
+            let loops = idle_loops.lock(|loops| *loops);
+            let loops_per_s = ctx.local.loop_rate.update(loops, now_ms);
+            let reset_cause = cause.lock(|cause| *cause);
+            let packet = (
+                ctx.shared.cliff_monitor,
+                ctx.shared.drive_state,
+                ctx.shared.outputs,
+            )
+                .lock(|monitor, drive_state, outputs| {
+                    Packet::snapshot(0, now_ms, monitor, drive_state, *outputs, reset_cause)
+                });
+            dashboard::draw(display, &Dashboard::new(packet, loops_per_s)).ok();
+            display.flush().ok();
+            // The calibration is drawn afresh next time
+            shown.clear();
+            return;
+        }
+        if screen == *shown {
+            return;
+        }
+
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        display.clear(BinaryColor::Off).ok();
+        Text::new(&screen, Point::new(0, 10), style)
+            .draw(display)
+            .ok();
+        display.flush().ok();
+        *shown = screen;
+    }
+
+    #[idle(shared = [cliff_monitor, drive, drive_state, odometry, supervisor, mode, idle_loops])]
+    fn idle(ctx: idle::Context) -> ! {
+        let mut idle_loops = ctx.shared.idle_loops;
+        let mut cliff_monitor = ctx.shared.cliff_monitor;
+        let mut mode = ctx.shared.mode;
+        let mut drive = ctx.shared.drive;
+        let mut odometry = ctx.shared.odometry;
+        let mut supervisor = ctx.shared.supervisor;
+        let mut drive_state = ctx.shared.drive_state;
+
+        let mut motor_command = drive_state.lock(|drive_state| drive_state.motor_command());
+        let mut manual = false;
+        loop {
+            check_in(&mut supervisor, DRIVE_TASK);
+            idle_loops.lock(|loops| *loops = loops.wrapping_add(1));
+
+            // Read the time under the lock, so no reading can be newer than `now_ms`. Truncating
+            // the 64 bit tick count wraps cleanly, which the monitor and state machine expect.
+            let (now_ms, current_cliffs, healthy) = cliff_monitor.lock(|monitor| {
+                let now_ms = monotonics::now().ticks() as u32;
+                (now_ms, monitor.cliffs(now_ms), monitor.healthy(now_ms))
+            });
+
+            // Stop outright rather than manoeuvre on readings that cannot be trusted, and stay
+            // out of the way while the console has the motors
+            let yaw = odometry.lock(|odometry| odometry.pose().theta);
+            let drive_mode = mode.lock(|mode| *mode);
+            let auto = drive_mode == DriveMode::Auto;
+            let next_motor_command = drive_state.lock(|drive_state| {
+                if healthy && auto {
+                    drive_state.step_with_yaw(&current_cliffs, now_ms, Some(yaw))
+                } else {
+                    drive_state.halt()
+                }
+            });
+
+            // The console's duties replaced the ramp targets, so they are set afresh once it lets
+            // go of the motors
+            let released = manual && !matches!(drive_mode, DriveMode::Manual(_));
+            manual = matches!(drive_mode, DriveMode::Manual(_));
+            if next_motor_command != motor_command || released {
+                motor_command = next_motor_command;
+                drive.lock(|drive| drive.command(motor_command));
             }
         }
     }
 
-    fn stop(motors: &mut MotorsType) {
-        motors.a.stop();
-        motors.b.stop();
+    fn apply_outputs((a, b): (ChannelOutput, ChannelOutput), motors: &mut Motors) {
+        let max_duty = motors.a.get_max_duty();
+        motors.a.set_duty(a.scaled_duty(max_duty));
+        match a.direction {
+            Direction::Forward => motors.a.forward(),
+            Direction::Reverse => motors.a.reverse(),
+            Direction::Stop => motors.a.stop(),
+        };
+
+        let max_duty = motors.b.get_max_duty();
+        motors.b.set_duty(b.scaled_duty(max_duty));
+        match b.direction {
+            Direction::Forward => motors.b.forward(),
+            Direction::Reverse => motors.b.reverse(),
+            Direction::Stop => motors.b.stop(),
+        };
     }
 }
@@ -4,10 +4,12 @@
 use panic_semihosting as _;
 use rtic::app;
 
-#[app(device = hal::pac, peripherals = true)]
+#[app(device = hal::pac, peripherals = true, dispatchers = [USART1])]
 mod app {
     use cortex_m_semihosting::hprintln;
     use hal::prelude::*;
+    use hal::timer::{CounterHz, Event};
+    use stm32f401_rover_testbed::button::{self, Gesture, Gestures};
     use stm32f4xx_hal as hal;
 
     #[shared]
@@ -19,6 +21,7 @@ mod app {
     #[local]
     struct Local {
         btn: hal::gpio::gpioa::PA0<hal::gpio::Input>,
+        timer: CounterHz<hal::pac::TIM2>,
     }
 
     #[init]
@@ -30,33 +33,56 @@ mod app {
         let clocks = rcc.cfgr.sysclk(48.MHz()).freeze();
         let delay = cp.SYST.delay(&clocks);
 
-        let mut exti = dp.EXTI;
-        let mut syscfg = dp.SYSCFG.constrain();
-
         let gpioc = dp.GPIOC.split();
         let mut led = gpioc.pc13.into_push_pull_output();
         led.set_high();
 
         let gpioa = dp.GPIOA.split();
-        let mut btn = gpioa.pa0.into_pull_up_input();
-        btn.make_interrupt_source(&mut syscfg);
-        btn.trigger_on_edge(&mut exti, hal::gpio::Edge::Falling);
-        btn.enable_interrupt(&mut exti);
-        (Shared { led, delay }, Local { btn }, init::Monotonics())
+        let btn = gpioa.pa0.into_pull_up_input();
+
+        // Sample the button on a timer, an edge interrupt would fire on every bounce
+        let mut timer = dp.TIM2.counter_hz(&clocks);
+        timer.start((1000 / button::SAMPLE_MS).Hz()).unwrap();
+        timer.listen(Event::Update);
+        (
+            Shared { led, delay },
+            Local { btn, timer },
+            init::Monotonics(),
+        )
+    }
+
+    /// Reads the button every `button::SAMPLE_MS` and queues its gestures.
+    #[task(
+        binds = TIM2,
+        priority = 2,
+        local = [btn, timer, gestures: Gestures = Gestures::new(), now_ms: u32 = 0]
+    )]
+    fn tim2(ctx: tim2::Context) {
+        ctx.local.timer.clear_interrupt(Event::Update);
+        *ctx.local.now_ms = ctx.local.now_ms.wrapping_add(button::SAMPLE_MS);
+        // Active low
+        let pressed = ctx.local.btn.is_low();
+        if let Some(gesture) = ctx.local.gestures.update(pressed, *ctx.local.now_ms) {
+            gesture::spawn(gesture).ok();
+        }
     }
 
-    #[task(binds=EXTI0, shared = [led, delay], local = [btn])]
-    fn exti0_event(ctx: exti0_event::Context) {
+    #[task(capacity = 4, shared = [led, delay])]
+    fn gesture(ctx: gesture::Context, gesture: Gesture) {
         let delay = ctx.shared.delay;
         let led = ctx.shared.led;
-        let btn = ctx.local.btn;
 
-        hprintln!("Button!").unwrap();
+        hprintln!("{:?}!", gesture).unwrap();
+        let ms: u16 = match gesture {
+            Gesture::Click => 200,
+            Gesture::DoubleClick => 500,
+            Gesture::LongPress => 1000,
+            Gesture::Hold(_) => 50,
+        };
         (led, delay).lock(|led, delay| {
             led.toggle();
-            delay.delay_ms(3000_u16);
+            delay.delay_ms(ms);
             led.toggle();
-            btn.clear_interrupt_pending_bit();
         });
     }
 
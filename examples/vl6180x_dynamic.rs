@@ -1,36 +1,35 @@
-//! Draw Ferris the Rust mascot on an SSD1306 display
+//! Try out the VL6180X's ranging and ambient light modes, picked from a menu on the SSD1306
+//!
+//! A click of the button moves down the menu, a double click up, and a long press runs the test.
+//! Another long press goes back to the menu.
 
 #![allow(clippy::empty_loop)]
 #![no_std]
 #![no_main]
 
 use core::cell::RefCell;
-use core::fmt::Write;
 
 use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::{DWT, NVIC};
 use cortex_m_rt::ExceptionFrame;
 use cortex_m_rt::{entry, exception};
 use cortex_m_semihosting::hprintln;
-use embedded_graphics::{
-    image::Image,
-    image::ImageRaw,
-    mono_font::{ascii::FONT_6X10, MonoTextStyle},
-    pixelcolor::BinaryColor,
-    prelude::*,
-    text::{Alignment, Text},
-};
+use embedded_graphics::{image::Image, image::ImageRaw, pixelcolor::BinaryColor, prelude::*};
 use hal::gpio::{Alternate, OpenDrain, Pin};
 use hal::i2c::I2c;
 use hal::pac::I2C1;
 use heapless::String;
 use panic_semihosting as _;
 use shared_bus::{self, I2cProxy};
-use ssd1306::mode::BufferedGraphicsMode;
-use ssd1306::{prelude::*, I2CDisplayInterface, Ssd1306};
+use stm32f401_rover_testbed::board::{init_display, ButtonSampler, Oled};
+use stm32f401_rover_testbed::button::Gesture;
+use stm32f401_rover_testbed::frame_buffer::{BufferedDisplay, FrameLimiter};
+use stm32f401_rover_testbed::texts::{self, write_ambient, write_range, write_test_reading};
+use stm32f401_rover_testbed::ui::{self, CycleClock, Menu, Message, Screen, StatusBar};
 use stm32f4xx_hal as hal;
 use vl6180x::{DynamicMode, VL6180X};
 
-use crate::hal::{pac, prelude::*};
+use crate::hal::{pac, pac::interrupt, prelude::*};
 
 type I2cType = I2c<
     I2C1,
@@ -40,17 +39,13 @@ type I2cType = I2c<
     ),
 >;
 
-type DispType<'a> = Ssd1306<
-    I2CInterface<I2cProxy<'a, Mutex<RefCell<I2cType>>>>,
-    DisplaySize128x64,
-    BufferedGraphicsMode<DisplaySize128x64>,
->;
+type DispType<'a> = BufferedDisplay<Oled<I2cProxy<'a, Mutex<RefCell<I2cType>>>>>;
 
 type TofDynamicModeType<'a> = VL6180X<DynamicMode, I2cProxy<'a, Mutex<RefCell<I2cType>>>>;
 
 #[entry]
 fn main() -> ! {
-    if let (Some(dp), Some(cp)) = (
+    if let (Some(dp), Some(mut cp)) = (
         pac::Peripherals::take(),
         cortex_m::peripheral::Peripherals::take(),
     ) {
@@ -110,11 +105,7 @@ fn main() -> ! {
             .into_dynamic_mode();
 
         // Set up the display
-        let interface = I2CDisplayInterface::new(bus.acquire_i2c());
-        let mut disp = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
-            .into_buffered_graphics_mode();
-        disp.init().unwrap();
-        disp.flush().unwrap();
+        let mut disp = init_display(bus.acquire_i2c()).unwrap();
 
         // Create image rustacean
         let raw_image: ImageRaw<BinaryColor> =
@@ -123,226 +114,160 @@ fn main() -> ! {
         image.draw(&mut disp).unwrap();
         disp.flush().unwrap();
 
-        // Set up state for the loop
-        let mut state = State::Image;
-        let mut was_pressed = btn.is_low();
+        // Time the button gestures and the frames with the cycle counter
+        cp.DCB.enable_trace();
+        cp.DWT.enable_cycle_counter();
+        let mut clock = CycleClock::new(CYCLES_PER_MS, DWT::cycle_count());
+        let button = ButtonSampler::start(btn, dp.TIM2, &clocks);
+        cortex_m::interrupt::free(|cs| BUTTON.borrow(cs).replace(Some(button)));
+        // Safe, the handler only touches `BUTTON`
+        unsafe { NVIC::unmask(pac::Interrupt::TIM2) };
+        let mut frames = FrameLimiter::new(FRAME_MS);
+
+        // Ferris until the first click, then the tests to pick from
+        let mut menu = Menu::new(&Test::NAMES);
+        let mut test = Some(Test::Ferris);
 
         // This runs continuously, as fast as possible
         loop {
-            let is_pressed = btn.is_low();
-            if !was_pressed && is_pressed {
-                use State::*;
-                // On exiting state
-                match state {
-                    RangeContinuousPoll => tof_1
-                        .try_stop_range_continuous_mode()
-                        .expect("stop range continuous"),
-                    AmbientContinuousPoll => tof_1
-                        .try_stop_ambient_continuous_mode()
-                        .expect("stop ambeint continuous"),
-                    _ => (),
-                };
-
-                state.cycle();
-                was_pressed = true;
-
-                // On first entering state
-                match state {
-                    Image => show_drawable(&image, &mut disp),
-                    WelcomeText => show_text(&WELCOME_TEXT, &mut disp),
-                    RangeContinuousPoll => tof_1
-                        .try_start_range_continuous_mode()
-                        .expect("start range cont"),
-                    AmbientContinuousPoll => tof_1
-                        .try_start_ambient_continuous_mode()
-                        .expect("start ambient cont"),
-                    // Single => (),
-                    // AddressCycle => {
-                    //     for i in 0_u8..255 {
-                    //         if i == 60 {
-                    //             continue; // Skip the I2C address of the OLED
-                    //         }
-                    //         match gyul53l0x.set_address(i) {
-                    //             Ok(()) => {
-                    //                 if !(i > 0x07 && i < 0x78) {
-                    //                     hprintln!(
-                    //                         "Failed cycle test! Invalid address was accepted"
-                    //                     )
-                    //                     .unwrap();
-                    //                 }
-                    //             }
-                    //             Err(_e) => {
-                    //                 if i > 0x07 && i < 0x78 {
-                    //                     hprintln!(
-                    //                         "Failed cycle test! Valid address {} did not work",
-                    //                         i
-                    //                     )
-                    //                     .unwrap();
-                    //                 } else {
-                    //                     hprintln!(
-                    //                         "correctly received error for invalid adddress {}",
-                    //                         i
-                    //                     )
-                    //                     .unwrap();
-                    //                     let mut text: String<50> = String::from(
-                    //                         "Correctly\nreceived error:\nInvalid address ",
-                    //                     );
-                    //                     text.push_str(&String::<3>::from(i)).unwrap();
-                    //                     show_text(&text, &mut disp);
-                    //                     continue;
-                    //                 }
-                    //             }
-                    //         }
-                    //         match gyul53l0x.read_range_single_millimeters_blocking() {
-                    //             Ok(range) => {
-                    //                 let mut text: String<20> = String::from("Address: ");
-                    //                 text.push_str(&String::<3>::from(i)).unwrap();
-                    //                 text.push_str("\n").unwrap();
-                    //                 text.push_str(&String::<4>::from(range)).unwrap();
-                    //                 text.push_str("mm").unwrap();
-
-                    //                 show_text(&text, &mut disp);
-                    //             }
-                    //             Err(_e) => {
-                    //                 hprintln!("Error reading TOF sensor at address {}!", i).unwrap()
-                    //             }
-                    //         };
-                    //         delay.delay_ms(50_u32);
-                    //     }
-                    // }
-                    // WhoAmI => {
-                    //     let addr = gyul53l0x.who_am_i().expect("who am i");
-                    //     let mut text: String<16> = String::from("Who Am I?\n");
-                    //     text.push_str(&String::<4>::from(addr)).expect("addr conv");
-
-                    //     show_text(&text, &mut disp);
-                    // }
-                    EndText => show_text(&END_TEXT, &mut disp),
-                    _ => (),
-                };
-            } else if !is_pressed {
-                was_pressed = false;
+            let now_ms = clock.update(DWT::cycle_count());
+            match (test, next_gesture()) {
+                (None, Some(gesture)) => {
+                    // On first entering the test
+                    test = menu.press(gesture).map(|index| Test::ALL[index]);
+                    match test {
+                        None => show(&mut disp, &StatusBar::new(TITLE, "hold: run"), &menu),
+                        Some(Test::Ferris) => show_drawable(&image, &mut disp),
+                        Some(Test::RangeContinuousPoll) => tof_1
+                            .try_start_range_continuous_mode()
+                            .expect("start range cont"),
+                        Some(Test::AmbientContinuousPoll) => tof_1
+                            .try_start_ambient_continuous_mode()
+                            .expect("start ambient cont"),
+                        _ => (),
+                    };
+                }
+                (Some(running), Some(Gesture::LongPress))
+                | (Some(running @ Test::Ferris), Some(Gesture::Click)) => {
+                    // On leaving the test
+                    match running {
+                        Test::RangeContinuousPoll => tof_1
+                            .try_stop_range_continuous_mode()
+                            .expect("stop range continuous"),
+                        Test::AmbientContinuousPoll => tof_1
+                            .try_stop_ambient_continuous_mode()
+                            .expect("stop ambeint continuous"),
+                        _ => (),
+                    };
+                    test = None;
+                    show(&mut disp, &StatusBar::new(TITLE, "hold: run"), &menu);
+                }
+                _ => (),
             }
-            // While in state
-            use State::*;
-            match state {
-                RangeContinuousPoll => match tof_1.try_read_range_mm_blocking() {
-                    Ok(range) => {
-                        let mut text: String<50> = String::from("Range Continuous\nPoll ");
-                        text.push_str(&String::<4>::from(range)).unwrap();
-                        text.push_str("mm").unwrap();
-
-                        show_text(&text, &mut disp);
-                    }
+
+            // While in the test
+            let mut reading: String<32> = String::new();
+            match test {
+                None | Some(Test::Ferris) => (),
+                Some(Test::RangeContinuousPoll) => match tof_1.try_read_range_mm_blocking() {
+                    Ok(range) => write_range(&mut reading, range.into()).unwrap(),
                     Err(e) => hprintln!("Error reading TOF sensor Continuous! {:?}", e).unwrap(),
                 },
-                RangeSinglePoll => match tof_1.try_poll_range_single_blocking_mm() {
-                    Ok(range) => {
-                        let mut text: String<50> = String::from("Range Single\nPoll ");
-                        text.push_str(&String::<4>::from(range)).unwrap();
-                        text.push_str("mm").unwrap();
-
-                        show_text(&text, &mut disp);
-                    }
+                Some(Test::RangeSinglePoll) => match tof_1.try_poll_range_single_blocking_mm() {
+                    Ok(range) => write_range(&mut reading, range.into()).unwrap(),
                     Err(e) => hprintln!("Error reading TOF sensor Single Poll! {:?}", e).unwrap(),
                 },
-                AmbientContinuousPoll => {
+                Some(Test::AmbientContinuousPoll) => {
                     match tof_1.try_read_ambient_lux_blocking() {
-                        Ok(ambient) => {
-                            let mut text: String<50> = String::from("Ambient Continuous\nPoll ");
-                            let mut val_buff: heapless::String<8> = heapless::String::new();
-                            write!(&mut val_buff, "{:08.4}", ambient).expect("f32 to str");
-                            text.push_str(&val_buff).unwrap();
-                            text.push_str(" lux").unwrap();
-
-                            show_text(&text, &mut disp);
-                        }
+                        Ok(ambient) => write_ambient(&mut reading, ambient).unwrap(),
                         Err(e) => {
                             hprintln!("Error reading TOF sensor Continuous! {:?}", e).unwrap()
                         }
                     };
                     delay.delay_ms(500_u32);
                 }
-                AmbientSinglePoll => {
-                    match tof_1.try_poll_ambient_single_blocking() {
-                        Ok(ambient) => {
-                            let mut text: String<50> = String::from("Ambient Single\nPoll ");
-                            let mut val_buff: heapless::String<8> = heapless::String::new();
-                            write!(&mut val_buff, "{:08.4}", ambient).expect("f32 to str");
-                            text.push_str(&val_buff).unwrap();
-                            text.push_str(" lux").unwrap();
-
-                            show_text(&text, &mut disp);
-                        }
-                        Err(e) => {
-                            hprintln!("Error reading TOF sensor Single Poll! {:?}", e).unwrap()
-                        }
-                    };
-                }
-                // AddressCycle => show_text("Done Cycling\nAddresses", &mut disp),
-                _ => (),
+                Some(Test::AmbientSinglePoll) => match tof_1.try_poll_ambient_single_blocking() {
+                    Ok(ambient) => write_ambient(&mut reading, ambient).unwrap(),
+                    Err(e) => hprintln!("Error reading TOF sensor Single Poll! {:?}", e).unwrap(),
+                },
             };
+            if let Some(running) = test.filter(|_| !reading.is_empty() && frames.ready(now_ms)) {
+                let mut text: String<64> = String::new();
+                write_test_reading(&mut text, running.name(), &reading).unwrap();
+                let status = StatusBar::new(TITLE, "hold: back");
+                show(&mut disp, &status, &Message::new(&text));
+            }
         }
     }
 
     loop {}
 }
 
-const WELCOME_TEXT: &str = "VL6180X\nSingle Test Suite";
-const END_TEXT: &str = "Goodbye\nSee you soon!";
-
-fn show_text(text: &str, disp: &mut DispType) {
-    let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+const TITLE: &str = texts::VL6180X_TITLE;
+/// The system clock.
+const CYCLES_PER_MS: u32 = 48_000;
+/// Shortest time between two readings on the display, they come much faster.
+const FRAME_MS: u32 = 100;
 
-    let disp_text: Text<MonoTextStyle<BinaryColor>> =
-        Text::with_alignment(text, Point::new(64, 32), style, Alignment::Center);
-    show_drawable(&disp_text, disp);
+fn show(disp: &mut DispType, status: &StatusBar, screen: &impl Screen) {
+    ui::draw(disp, status, screen).unwrap();
+    disp.flush().unwrap();
 }
 
 fn show_drawable(item: &impl Drawable<Color = BinaryColor>, disp: &mut DispType) {
-    disp.clear();
+    disp.clear(BinaryColor::Off).unwrap();
     item.draw(disp).unwrap();
     disp.flush().unwrap();
 }
 
-enum State {
-    Image,
-    WelcomeText,
+#[derive(Copy, Clone, PartialEq)]
+enum Test {
+    Ferris,
     RangeContinuousPoll,
-    RangeContinuousHostPoll,
-    RangeContinuousInterrupt,
     RangeSinglePoll,
-    RangeSingleHostPoll,
-    RangeSingleInterrupt,
     AmbientContinuousPoll,
     AmbientSinglePoll,
-    AddressCycle,
-    WhoAmI,
-    EndText,
 }
 
-impl State {
-    fn cycle(&mut self) {
-        use State::*;
-        *self = match *self {
-            Image => WelcomeText,
-            WelcomeText => RangeContinuousPoll,
-            RangeContinuousPoll => RangeSinglePoll,
-            // RangeContinuousHostPoll,
-            // RangeContinuousInterrupt,
-            RangeSinglePoll => AmbientContinuousPoll,
-            AmbientContinuousPoll => AmbientSinglePoll,
-            AmbientSinglePoll => EndText,
-            // RangeSingleHostPoll,
-            // RangeSingleInterrupt,
-            // AddressCycle => WhoAmI,
-            // WhoAmI => EndText,
-            EndText => Image,
-            _ => Image,
-        }
+impl Test {
+    const ALL: [Test; 5] = [
+        Test::Ferris,
+        Test::RangeContinuousPoll,
+        Test::RangeSinglePoll,
+        Test::AmbientContinuousPoll,
+        Test::AmbientSinglePoll,
+    ];
+    /// Menu items, in the order of `ALL`.
+    const NAMES: [&'static str; 5] = texts::VL6180X_MENU;
+
+    fn name(self) -> &'static str {
+        Test::NAMES[self as usize]
     }
 }
 
+/// The button, sampled from `TIM2` while the main loop waits on the sensor.
+static BUTTON: Mutex<RefCell<Option<ButtonSampler>>> = Mutex::new(RefCell::new(None));
+
+#[interrupt]
+fn TIM2() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(button) = BUTTON.borrow(cs).borrow_mut().as_mut() {
+            button.on_interrupt();
+        }
+    });
+}
+
+/// The oldest gesture the main loop has not handled yet.
+fn next_gesture() -> Option<Gesture> {
+    cortex_m::interrupt::free(|cs| {
+        BUTTON
+            .borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .and_then(ButtonSampler::next)
+    })
+}
+
 #[exception]
 unsafe fn HardFault(ef: &ExceptionFrame) -> ! {
     panic!("{:#?}", ef);
@@ -20,6 +20,11 @@ MEMORY
    after the vector table */
 /* _stext = ORIGIN(FLASH) + 0x400; */
 
+/* The 16K sectors 1 and 2, 0x08004000 to 0x0800C000, are left to the config store, see
+   src/flash.rs. Flash is erased a sector at a time, so the program cannot share them: the
+   vector table stays in sector 0 and everything else starts at sector 3. */
+_stext = ORIGIN(FLASH) + 0xC000;
+
 /* Example of putting non-initialized variables into custom RAM locations. */
 /* This assumes you have defined a region RAM2 above, and in the Rust
    sources added the attribute `#[link_section = ".ram2bss"]` to the data
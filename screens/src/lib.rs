@@ -0,0 +1,480 @@
+//! The rover's SSD1306 screens on the host.
+//!
+//! Every screen draws to any `DrawTarget<Color = BinaryColor>`, so here they are drawn into a
+//! [`FrameBuffer`], the same 128x64 frame the firmware sends the display, and exported as PBM or
+//! PNG images to look at without a rover. The [`catalogue`] holds each screen in a telling state,
+//! and the tests compare every one with its golden image in `screens/golden`:
+//!
+//! cargo test -p rover-screens --target x86_64-unknown-linux-gnu
+//!
+//! A screen that no longer looks the same fails, with images of what it looks like now written to
+//! `target/screens`. After a change on purpose, run the tests with `UPDATE_GOLDEN=1` to write the
+//! golden images afresh, and check them in with the change.
+//!
+//! The golden images are plain PBM, a character per pixel, so a diff shows what moved. Lit pixels
+//! are white, like on the display.
+
+use std::convert::Infallible;
+use std::fmt;
+use std::io::{self, Write};
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+use stm32f401_rover_testbed::cliff_monitor::SensorStatus;
+use stm32f401_rover_testbed::dashboard::{self, Dashboard};
+use stm32f401_rover_testbed::drive::{Cliffs, Command, Heading, MotorCommand, TurnDirection};
+use stm32f401_rover_testbed::frame_buffer::FrameBuffer;
+use stm32f401_rover_testbed::settings::{Param, Settings};
+use stm32f401_rover_testbed::supervisor::ResetCause;
+use stm32f401_rover_testbed::telemetry::{Packet, SensorTelemetry};
+use stm32f401_rover_testbed::texts;
+use stm32f401_rover_testbed::ui::{self, Menu, Message, Screen, StatusBar, ValueEditor};
+
+pub const WIDTH: usize = ui::WIDTH as usize;
+pub const HEIGHT: usize = ui::HEIGHT as usize;
+
+/// A screen as drawn.
+pub struct Snapshot {
+    /// Also the name of its images.
+    pub name: &'static str,
+    pub frame: FrameBuffer,
+    /// Pixels the screen drew outside the area it is given, cut off on the display.
+    pub overflow: usize,
+    /// Characters of its text that do not fit, left out of the drawing.
+    pub cut_off: usize,
+}
+
+impl Snapshot {
+    /// `screen` under `status`.
+    pub fn screen<S: Screen>(name: &'static str, status: &StatusBar, screen: &S) -> Self {
+        let mut frame = FrameBuffer::new();
+        unwrap(ui::draw(&mut frame, status, screen));
+        Snapshot {
+            name,
+            frame,
+            overflow: overflow(screen),
+            cut_off: status.cut_off() + screen.cut_off(),
+        }
+    }
+
+    pub fn dashboard(name: &'static str, dashboard: &Dashboard) -> Self {
+        let mut frame = FrameBuffer::new();
+        unwrap(dashboard::draw(&mut frame, dashboard));
+        Snapshot {
+            name,
+            frame,
+            overflow: overflow(dashboard),
+            cut_off: dashboard.cut_off(),
+        }
+    }
+
+    /// Whether each pixel is lit, row by row.
+    pub fn pixels(&self) -> Vec<bool> {
+        (0..HEIGHT)
+            .flat_map(|y| (0..WIDTH).map(move |x| (x, y)))
+            .map(|(x, y)| self.frame.pixel(x as u32, y as u32))
+            .collect()
+    }
+}
+
+fn unwrap(result: Result<(), Infallible>) {
+    match result {
+        Ok(()) => (),
+        Err(never) => match never {},
+    }
+}
+
+/// Counts the pixels drawn off it, the size of the body of a screen.
+struct Probe {
+    outside: usize,
+}
+
+impl OriginDimensions for Probe {
+    fn size(&self) -> Size {
+        ui::BODY.size
+    }
+}
+
+impl DrawTarget for Probe {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+    where
+        I: IntoIterator<Item = Pixel<BinaryColor>>,
+    {
+        let area = self.bounding_box();
+        self.outside += pixels
+            .into_iter()
+            .filter(|Pixel(point, _)| !area.contains(*point))
+            .count();
+        Ok(())
+    }
+}
+
+/// Pixels `screen` draws outside the body, which `ui::draw` crops away.
+pub fn overflow<S: Screen>(screen: &S) -> usize {
+    let mut probe = Probe { outside: 0 };
+    unwrap(screen.draw(&mut probe));
+    probe.outside
+}
+
+/// Every screen of the test programs and the rover, in a state that shows most of it.
+pub fn catalogue() -> Vec<Snapshot> {
+    // `vl6180x_dynamic`'s, scrolled to the end
+    let tests = texts::VL6180X_MENU;
+    let mut menu = Menu::new(&tests);
+    menu.select(tests.len() - 1);
+
+    // The longest readings `vl6180x_dynamic` shows
+    let mut reading = String::new();
+    texts::write_range(&mut reading, u8::MAX.into()).unwrap();
+    let mut range = String::new();
+    texts::write_test_reading(&mut range, tests[1], &reading).unwrap();
+    reading.clear();
+    texts::write_ambient(&mut reading, 65535.0 * 0.32).unwrap();
+    let mut ambient = String::new();
+    texts::write_test_reading(&mut ambient, tests[3], &reading).unwrap();
+
+    let param = Param::CliffThreshold;
+    let editor = ValueEditor::new(
+        param.name(),
+        param.unit(),
+        Settings::default().get(param),
+        param.range(),
+        param.step(),
+    );
+
+    vec![
+        Snapshot::screen(
+            "vl6180x_menu",
+            &StatusBar::new(texts::VL6180X_TITLE, "hold: run"),
+            &menu,
+        ),
+        Snapshot::screen(
+            "range_reading",
+            &StatusBar::new(texts::VL6180X_TITLE, "hold: back"),
+            &Message::new(&range),
+        ),
+        Snapshot::screen(
+            "ambient_reading",
+            &StatusBar::new(texts::VL6180X_TITLE, "hold: back"),
+            &Message::new(&ambient),
+        ),
+        Snapshot::screen(
+            "welcome",
+            &StatusBar::new(texts::TESTBED_TITLE, "hold: back"),
+            &Message::new(texts::WELCOME_TEXT),
+        ),
+        Snapshot::screen(
+            "settings_editor",
+            &StatusBar::new("Settings", "hold: done"),
+            &editor,
+        ),
+        Snapshot::dashboard("dashboard_driving", &Dashboard::new(driving(), 48_213)),
+        Snapshot::dashboard("dashboard_start_up", &Dashboard::new(start_up(), 0)),
+    ]
+}
+
+/// Turning right away from a cliff at the front left, an hour in.
+fn driving() -> Packet {
+    let sensor = |range_mm| SensorTelemetry {
+        status: SensorStatus::Ok,
+        range_mm,
+        age_ms: 12,
+    };
+    Packet {
+        sequence: 0,
+        at_ms: 3_723_000,
+        sensors: [sensor(10), sensor(11), sensor(255), sensor(9)],
+        cliffs: Cliffs {
+            fl: true,
+            ..Cliffs::NONE
+        },
+        command: Command::Turn,
+        heading: Heading::Reverse,
+        turn_direction: TurnDirection::Right,
+        motor_command: MotorCommand::SpinRight,
+        duties: [-640, 655],
+        reset_cause: ResetCause::PowerOn,
+    }
+}
+
+/// Before any sensor has reported, one of them failed to boot.
+fn start_up() -> Packet {
+    let sensor = |status| SensorTelemetry {
+        status,
+        range_mm: 0,
+        age_ms: u16::MAX,
+    };
+    Packet {
+        sequence: 0,
+        at_ms: 0,
+        sensors: [
+            sensor(SensorStatus::NoData),
+            sensor(SensorStatus::Error),
+            sensor(SensorStatus::NoData),
+            sensor(SensorStatus::NoData),
+        ],
+        cliffs: Cliffs::ALL,
+        command: Command::Standby,
+        heading: Heading::Forward,
+        turn_direction: TurnDirection::Left,
+        motor_command: MotorCommand::Stop,
+        duties: [0, 0],
+        reset_cause: ResetCause::PowerOn,
+    }
+}
+
+/// Writes `pixels`, row by row, as a plain PBM image with a line per half row.
+pub fn write_pbm(w: &mut impl Write, pixels: &[bool]) -> io::Result<()> {
+    writeln!(w, "P1\n{} {}", WIDTH, HEIGHT)?;
+    for line in pixels.chunks(WIDTH / 2) {
+        // 1 is black
+        let line: String = line
+            .iter()
+            .map(|lit| if *lit { '0' } else { '1' })
+            .collect();
+        writeln!(w, "{}", line)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PbmError {
+    /// Not a plain PBM image.
+    Format,
+    /// Not the size of the display.
+    Size(usize, usize),
+}
+
+impl fmt::Display for PbmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PbmError::Format => write!(f, "not a plain PBM image"),
+            PbmError::Size(width, height) => {
+                write!(f, "{}x{}, not {}x{}", width, height, WIDTH, HEIGHT)
+            }
+        }
+    }
+}
+
+/// The pixels of a plain PBM image of the display, row by row.
+pub fn read_pbm(text: &str) -> Result<Vec<bool>, PbmError> {
+    // Comments run to the end of the line
+    let mut tokens = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(str::split_whitespace);
+    if tokens.next() != Some("P1") {
+        return Err(PbmError::Format);
+    }
+    let mut size = || -> Result<usize, PbmError> {
+        let token = tokens.next().ok_or(PbmError::Format)?;
+        token.parse().map_err(|_| PbmError::Format)
+    };
+    let (width, height) = (size()?, size()?);
+    if (width, height) != (WIDTH, HEIGHT) {
+        return Err(PbmError::Size(width, height));
+    }
+    let pixels = tokens
+        .flat_map(str::chars)
+        .map(|c| match c {
+            '0' => Ok(true),
+            '1' => Ok(false),
+            _ => Err(PbmError::Format),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if pixels.len() != WIDTH * HEIGHT {
+        return Err(PbmError::Format);
+    }
+    Ok(pixels)
+}
+
+/// Writes `pixels`, row by row, as a 1 bit greyscale PNG image, uncompressed.
+pub fn write_png(w: &mut impl Write, pixels: &[bool]) -> io::Result<()> {
+    w.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(WIDTH as u32).to_be_bytes());
+    header.extend_from_slice(&(HEIGHT as u32).to_be_bytes());
+    // Bit depth, greyscale, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[1, 0, 0, 0, 0]);
+    write_chunk(w, b"IHDR", &header)?;
+
+    // Each row starts with its filter type, none
+    let mut raw = Vec::new();
+    for row in pixels.chunks(WIDTH) {
+        raw.push(0);
+        raw.extend(row.chunks(8).map(|byte| {
+            byte.iter()
+                .enumerate()
+                .fold(0_u8, |bits, (i, lit)| bits | (*lit as u8) << (7 - i))
+        }));
+    }
+    // A zlib stream of a single stored block, the frame is well under its 64 KiB
+    let mut data = vec![0x78, 0x01, 1];
+    let len = raw.len() as u16;
+    data.extend_from_slice(&len.to_le_bytes());
+    data.extend_from_slice(&(!len).to_le_bytes());
+    data.extend_from_slice(&raw);
+    data.extend_from_slice(&adler32(&raw).to_be_bytes());
+    write_chunk(w, b"IDAT", &data)?;
+    write_chunk(w, b"IEND", &[])
+}
+
+fn write_chunk(w: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data));
+    w.write_all(&crc.to_be_bytes())
+}
+
+/// CRC-32 of the PNG chunks, the reflected 0x04c11db7 polynomial.
+fn crc32<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u32 {
+    !bytes.into_iter().fold(!0, |crc, byte| {
+        (0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (a, b) = bytes.iter().fold((1_u32, 0_u32), |(a, b), byte| {
+        let a = (a + u32::from(*byte)) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn pbm_round_trip() {
+        let snapshot = &catalogue()[0];
+        let mut pbm = Vec::new();
+        write_pbm(&mut pbm, &snapshot.pixels()).unwrap();
+        let text = String::from_utf8(pbm).unwrap();
+        assert!(text.lines().all(|line| line.len() <= 70));
+        assert_eq!(read_pbm(&text), Ok(snapshot.pixels()));
+
+        let commented = text.replacen("\n", " # made on the host\n", 1);
+        assert_eq!(read_pbm(&commented), Ok(snapshot.pixels()));
+        assert_eq!(read_pbm("P4 128 64"), Err(PbmError::Format));
+        assert_eq!(read_pbm("P1 96 16 0"), Err(PbmError::Size(96, 16)));
+        assert_eq!(read_pbm("P1 128 64 0 1 0"), Err(PbmError::Format));
+    }
+
+    #[test]
+    fn png_holds_the_pixels() {
+        let mut pixels = vec![false; WIDTH * HEIGHT];
+        pixels[0] = true;
+        pixels[WIDTH + 9] = true;
+        let mut png = Vec::new();
+        write_png(&mut png, &pixels).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        // Every chunk's CRC checks out
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = &rest[8 + len..12 + len];
+            assert_eq!(crc, crc32(kind.iter().chain(data)).to_be_bytes());
+            chunks.push((kind.to_vec(), data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        let kinds: Vec<_> = chunks.iter().map(|(kind, _)| kind.as_slice()).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+
+        // Rows of a filter byte and 16 bytes of pixels, the leftmost the top bit
+        let idat = &chunks[1].1;
+        let raw = &idat[7..idat.len() - 4];
+        assert_eq!(raw.len(), HEIGHT * (1 + WIDTH / 8));
+        assert_eq!(raw[..3], [0, 0x80, 0]);
+        assert_eq!(raw[17..20], [0, 0, 0x40]);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    /// Draws a line past the bottom of the body.
+    struct Spill;
+
+    impl Screen for Spill {
+        type Output = ();
+
+        fn press(&mut self, _gesture: stm32f401_rover_testbed::button::Gesture) -> Option<()> {
+            None
+        }
+
+        fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+        where
+            D: DrawTarget<Color = BinaryColor>,
+        {
+            let bottom = ui::BODY.size.height as i32;
+            let pixels = (0..4).map(|x| Pixel(Point::new(x, bottom), BinaryColor::On));
+            target.draw_iter(pixels)
+        }
+    }
+
+    #[test]
+    fn counts_what_is_cut_off() {
+        assert_eq!(overflow(&Spill), 4);
+
+        // Text too long is left out rather than drawn off the body
+        let status = StatusBar::new("Test", "hold: back");
+        let long = Message::new("A line\nmuch too long to fit on the display");
+        let snapshot = Snapshot::screen("long", &status, &long);
+        assert_eq!((snapshot.overflow, snapshot.cut_off), (0, 14));
+    }
+
+    #[test]
+    fn screens_look_like_their_golden_images() {
+        let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("golden");
+        let actual = Path::new(env!("CARGO_MANIFEST_DIR")).join("../target/screens");
+        let update = env::var_os("UPDATE_GOLDEN").is_some();
+        let mut failures = Vec::new();
+        for snapshot in catalogue() {
+            assert_eq!(snapshot.overflow, 0, "{} is cut off", snapshot.name);
+            assert_eq!(snapshot.cut_off, 0, "{} leaves text out", snapshot.name);
+            let pixels = snapshot.pixels();
+            let path = golden.join(format!("{}.pbm", snapshot.name));
+            if update {
+                write_pbm(&mut fs::File::create(&path).unwrap(), &pixels).unwrap();
+                continue;
+            }
+
+            let expected = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| read_pbm(&text).map_err(|e| e.to_string()));
+            let differ = match expected {
+                Ok(expected) => expected.iter().zip(&pixels).filter(|(a, b)| a != b).count(),
+                Err(e) => {
+                    failures.push(format!("{}: {}", path.display(), e));
+                    continue;
+                }
+            };
+            if differ > 0 {
+                fs::create_dir_all(&actual).unwrap();
+                let name = actual.join(snapshot.name);
+                let pbm = name.with_extension("pbm");
+                write_pbm(&mut fs::File::create(&pbm).unwrap(), &pixels).unwrap();
+                let png = name.with_extension("png");
+                write_png(&mut fs::File::create(&png).unwrap(), &pixels).unwrap();
+                failures.push(format!(
+                    "{}: {} pixels differ, now {}",
+                    snapshot.name,
+                    differ,
+                    png.display()
+                ));
+            }
+        }
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    }
+}
@@ -0,0 +1,42 @@
+//! Writes every screen of the catalogue as images, to look at without a rover.
+//!
+//! cargo run -p rover-screens --target x86_64-unknown-linux-gnu -- [DIR]
+//!
+//! Writes DIR/NAME.png and DIR/NAME.pbm for each screen, DIR is `target/screens` by default.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::process;
+
+use rover_screens::{catalogue, write_pbm, write_png};
+
+fn main() {
+    let dir = PathBuf::from(
+        env::args()
+            .nth(1)
+            .unwrap_or_else(|| "target/screens".into()),
+    );
+    if let Err(e) = fs::create_dir_all(&dir) {
+        eprintln!("{}: {}", dir.display(), e);
+        process::exit(1);
+    }
+
+    for snapshot in catalogue() {
+        let pixels = snapshot.pixels();
+        let path = dir.join(snapshot.name);
+        let written = File::create(path.with_extension("png"))
+            .and_then(|file| write_png(&mut BufWriter::new(file), &pixels))
+            .and_then(|_| File::create(path.with_extension("pbm")))
+            .and_then(|file| write_pbm(&mut BufWriter::new(file), &pixels));
+        if let Err(e) = written {
+            eprintln!("{}: {}", path.display(), e);
+            process::exit(1);
+        }
+        if snapshot.overflow > 0 {
+            eprintln!("{}: {} pixels cut off", snapshot.name, snapshot.overflow);
+        }
+        println!("{}", path.display());
+    }
+}
@@ -0,0 +1,419 @@
+//! Table top simulator for the cliff detector rover.
+//!
+//! Models the rover as a differential drive robot with the L298N channel `a` driving the right
+//! wheel and channel `b` the left wheel, and a VL6180X looking down from each corner. Over the
+//! table a sensor reads the height it is mounted at, past the edge it reads out of range. The
+//! readings go through the same [`CliffFilter`]s to the same [`DriveState`] the firmware runs, at
+//! the rate the sensors produce samples, and the wheels follow the same [`DifferentialDrive`].
+//!
+//! A run can also be recorded as the rover's serial telemetry, see [`Sim::telemetry`], to try
+//! out the host tools without a rover.
+
+use std::f64::consts::PI;
+use std::io::{self, Write};
+
+use stm32f401_rover_testbed::cliff_filter::{CliffFilter, FilterConfig};
+use stm32f401_rover_testbed::cliff_monitor::CliffMonitor;
+use stm32f401_rover_testbed::diff_drive::{DiffDriveConfig, DifferentialDrive};
+use stm32f401_rover_testbed::drive::{Cliffs, Corner, DriveConfig, DriveState, MotorCommand};
+use stm32f401_rover_testbed::motor::{ChannelOutput, RampConfig, FULL_SPEED};
+use stm32f401_rover_testbed::supervisor::ResetCause;
+use stm32f401_rover_testbed::telemetry::Packet;
+use stm32f401_rover_testbed::tof::RangeStatus;
+
+/// What a VL6180X reports when there is nothing within range.
+pub const OUT_OF_RANGE_MM: u16 = 255;
+
+/// A rectangular table top with a corner at the origin.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Table {
+    /// Extent along x, in metres.
+    pub width: f64,
+    /// Extent along y, in metres.
+    pub length: f64,
+}
+
+impl Table {
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        x >= 0.0 && x <= self.width && y >= 0.0 && y <= self.length
+    }
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Table {
+            width: 1.2,
+            length: 0.8,
+        }
+    }
+}
+
+/// Physical layout of the rover. Positions are in metres in the rover frame, x pointing forward
+/// and y to the left, with the origin midway between the wheels.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Geometry {
+    /// Sensor positions in [`Corner`] order, named by [`Corner::name`].
+    pub sensors: [(f64, f64); 4],
+    /// Distance between the wheels.
+    pub track: f64,
+    /// Wheel speed at full duty, in metres per second.
+    pub max_speed: f64,
+    /// What a sensor reads when it is over the table, in millimetres.
+    pub mount_height_mm: u16,
+    /// Peak to peak noise added to each reading, in millimetres.
+    pub noise_mm: u16,
+    /// Time between samples from each sensor, in milliseconds.
+    pub sample_period_ms: u32,
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Geometry {
+            sensors: [(-0.08, -0.06), (0.08, -0.06), (0.08, 0.06), (-0.08, 0.06)],
+            track: 0.1,
+            max_speed: 0.25,
+            mount_height_mm: 10,
+            noise_mm: 4,
+            // Inter measurement period (20 ms) plus max convergence time (10 ms)
+            sample_period_ms: 30,
+        }
+    }
+}
+
+impl Geometry {
+    /// Radius of the circle around the rover origin that contains every sensor.
+    pub fn radius(&self) -> f64 {
+        self.sensors
+            .iter()
+            .map(|(x, y)| x.hypot(*y))
+            .fold(0.0, f64::max)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Pose {
+    pub x: f64,
+    pub y: f64,
+    /// Heading of the rover's forward axis, in radians from the x axis.
+    pub theta: f64,
+}
+
+impl Pose {
+    /// Transforms a point from the rover frame into the table frame.
+    pub fn transform(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let (sin, cos) = self.theta.sin_cos();
+        (self.x + x * cos - y * sin, self.y + x * sin + y * cos)
+    }
+}
+
+/// Small xorshift generator, so runs are reproducible from a seed without extra dependencies.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Zero is a fixed point of xorshift
+        Rng(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in `[low, high)`.
+    pub fn range(&mut self, low: f64, high: f64) -> f64 {
+        let unit = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        low + unit * (high - low)
+    }
+}
+
+/// One simulation step, as written to the trajectory log.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sample {
+    pub t_ms: u32,
+    pub pose: Pose,
+    pub drive_state: DriveState,
+    pub motor_command: MotorCommand,
+    pub ranges: [u16; 4],
+    pub cliffs: Cliffs,
+}
+
+impl Sample {
+    pub const CSV_HEADER: &'static str = "t_ms,x,y,theta,command,heading,motor,\
+        range_br,range_fr,range_fl,range_bl,cliff_br,cliff_fr,cliff_fl,cliff_bl";
+
+    pub fn write_csv(&self, mut w: impl Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "{},{:.4},{:.4},{:.4},{:?},{:?},{:?},{},{},{},{},{},{},{},{}",
+            self.t_ms,
+            self.pose.x,
+            self.pose.y,
+            self.pose.theta,
+            self.drive_state.command(),
+            self.drive_state.heading(),
+            self.motor_command,
+            self.ranges[0],
+            self.ranges[1],
+            self.ranges[2],
+            self.ranges[3],
+            self.cliffs.br as u8,
+            self.cliffs.fr as u8,
+            self.cliffs.fl as u8,
+            self.cliffs.bl as u8,
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Outcome {
+    /// Still on the table when the run ended.
+    Survived,
+    /// A wheel left the table at this time.
+    FellOff { t_ms: u32 },
+}
+
+pub struct Sim {
+    pub table: Table,
+    pub geometry: Geometry,
+    pose: Pose,
+    t_ms: u32,
+    drive_state: DriveState,
+    motor_command: MotorCommand,
+    drive: DifferentialDrive,
+    ranges: [u16; 4],
+    filters: [CliffFilter; 4],
+    filter_config: FilterConfig,
+    cliffs: Cliffs,
+    /// When each sensor produces its next sample. Staggered, as the sensors are not synchronised.
+    next_sample_ms: [u32; 4],
+    /// When each sensor produced its latest sample.
+    sampled_ms: [Option<u32>; 4],
+    rng: Rng,
+}
+
+impl Sim {
+    pub fn new(
+        table: Table,
+        geometry: Geometry,
+        config: DriveConfig,
+        pose: Pose,
+        seed: u64,
+    ) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut next_sample_ms = [0; 4];
+        for next in next_sample_ms.iter_mut() {
+            *next = rng.range(0.0, geometry.sample_period_ms as f64) as u32;
+        }
+        Sim {
+            table,
+            geometry,
+            pose,
+            t_ms: 0,
+            drive_state: DriveState::new(config),
+            motor_command: MotorCommand::Stop,
+            drive: DifferentialDrive::new(
+                DiffDriveConfig {
+                    track: geometry.track as f32,
+                    max_wheel_speed: geometry.max_speed as f32,
+                    ..DiffDriveConfig::default()
+                },
+                RampConfig::default(),
+            ),
+            ranges: [OUT_OF_RANGE_MM; 4],
+            filters: [CliffFilter::new(); 4],
+            filter_config: FilterConfig::default(),
+            // Like the firmware, assume the worst until the sensors report
+            cliffs: Cliffs::ALL,
+            next_sample_ms,
+            sampled_ms: [None; 4],
+            rng,
+        }
+    }
+
+    /// A simulation with the rover placed somewhere on the table facing a random direction.
+    pub fn random_start(table: Table, geometry: Geometry, config: DriveConfig, seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let margin = geometry.radius();
+        let pose = Pose {
+            x: rng.range(margin, table.width - margin),
+            y: rng.range(margin, table.length - margin),
+            theta: rng.range(-PI, PI),
+        };
+        Sim::new(table, geometry, config, pose, rng.next_u64())
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    pub fn t_ms(&self) -> u32 {
+        self.t_ms
+    }
+
+    pub fn on_table(&self) -> bool {
+        let half_track = self.geometry.track / 2.0;
+        [(0.0, half_track), (0.0, -half_track)].iter().all(|wheel| {
+            let (x, y) = self.pose.transform(*wheel);
+            self.table.contains(x, y)
+        })
+    }
+
+    /// Advances the simulation by one millisecond.
+    pub fn step(&mut self) -> Sample {
+        for (i, next) in self.next_sample_ms.iter_mut().enumerate() {
+            if self.t_ms < *next {
+                continue;
+            }
+            *next += self.geometry.sample_period_ms;
+            self.sampled_ms[i] = Some(self.t_ms);
+
+            let (x, y) = self.pose.transform(self.geometry.sensors[i]);
+            let (range, status) = if self.table.contains(x, y) {
+                let noise = self.rng.range(0.0, self.geometry.noise_mm as f64 + 1.0) as u16;
+                let range = self.geometry.mount_height_mm + noise - self.geometry.noise_mm / 2;
+                (range, RangeStatus::Valid)
+            } else {
+                (OUT_OF_RANGE_MM, RangeStatus::NoTarget)
+            };
+            self.ranges[i] = range;
+
+            let filter = &mut self.filters[i];
+            filter.push(range, status, &self.filter_config);
+            self.cliffs.set(Corner::ALL[i], filter.is_cliff());
+        }
+
+        self.motor_command = self.drive_state.step(&self.cliffs, self.t_ms);
+
+        // Wheel speeds taken as proportional to duty
+        self.drive.command(self.motor_command);
+        self.drive.update(self.t_ms);
+        let (a, b) = self.drive.ramp().speeds();
+        let scale = self.geometry.max_speed / f64::from(FULL_SPEED);
+        let (right, left) = (f64::from(a) * scale, f64::from(b) * scale);
+        let linear = (right + left) / 2.0;
+        let angular = (right - left) / self.geometry.track;
+
+        let dt = 0.001;
+        let (sin, cos) = self.pose.theta.sin_cos();
+        self.pose.x += linear * cos * dt;
+        self.pose.y += linear * sin * dt;
+        self.pose.theta = (self.pose.theta + angular * dt + PI).rem_euclid(2.0 * PI) - PI;
+
+        let sample = Sample {
+            t_ms: self.t_ms,
+            pose: self.pose,
+            drive_state: self.drive_state,
+            motor_command: self.motor_command,
+            ranges: self.ranges,
+            cliffs: self.cliffs,
+        };
+        self.t_ms += 1;
+        sample
+    }
+
+    /// The telemetry packet the rover would send now.
+    pub fn telemetry(&self, sequence: u16) -> Packet {
+        let mut monitor = CliffMonitor::default();
+        for corner in Corner::ALL {
+            if let Some(at_ms) = self.sampled_ms[corner.index()] {
+                monitor.record(corner, self.ranges[corner.index()], at_ms);
+            }
+        }
+        let (a, b) = self.drive.ramp().speeds();
+        Packet::snapshot(
+            sequence,
+            self.t_ms,
+            &monitor,
+            &self.drive_state,
+            (ChannelOutput::from_signed(a), ChannelOutput::from_signed(b)),
+            ResetCause::PowerOn,
+        )
+    }
+
+    /// Runs for `duration_ms` or until the rover falls off, logging every step as a CSV row if a
+    /// writer is given. The header is left to the caller, so several runs can share one log.
+    pub fn run(
+        &mut self,
+        duration_ms: u32,
+        mut log: Option<&mut dyn Write>,
+    ) -> io::Result<Outcome> {
+        self.run_with(duration_ms, |_, sample| match log.as_mut() {
+            Some(w) => sample.write_csv(w),
+            None => Ok(()),
+        })
+    }
+
+    /// Like [`run`](Sim::run), handing the simulation and every step to `observe` instead.
+    pub fn run_with(
+        &mut self,
+        duration_ms: u32,
+        mut observe: impl FnMut(&Sim, &Sample) -> io::Result<()>,
+    ) -> io::Result<Outcome> {
+        while self.t_ms < duration_ms {
+            let sample = self.step();
+            observe(self, &sample)?;
+            if !self.on_table() {
+                return Ok(Outcome::FellOff { t_ms: sample.t_ms });
+            }
+        }
+        Ok(Outcome::Survived)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pose(x: f64, y: f64, theta: f64) -> Pose {
+        Pose { x, y, theta }
+    }
+
+    #[test]
+    fn transform_rotates_into_table_frame() {
+        let (x, y) = pose(1.0, 1.0, PI / 2.0).transform((0.1, 0.0));
+        assert!((x - 1.0).abs() < 1e-9 && (y - 1.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn drives_straight_until_the_edge() {
+        let table = Table::default();
+        let mut sim = Sim::new(
+            table,
+            Geometry::default(),
+            DriveConfig::default(),
+            pose(0.3, 0.4, 0.0),
+            1,
+        );
+        while sim.drive_state.command() != stm32f401_rover_testbed::drive::Command::PreTurn {
+            sim.step();
+            assert!(sim.t_ms() < 10_000, "never reached the edge");
+        }
+        // Stopped advancing with the front sensors just past the edge
+        let (front_x, _) = sim.pose().transform(Geometry::default().sensors[1]);
+        assert!(
+            front_x > table.width && front_x < table.width + 0.02,
+            "{}",
+            front_x
+        );
+        assert!(sim.on_table());
+    }
+
+    #[test]
+    fn never_drives_off_the_table() {
+        for seed in 0..500 {
+            let mut sim = Sim::random_start(
+                Table::default(),
+                Geometry::default(),
+                DriveConfig::default(),
+                seed,
+            );
+            let start = sim.pose();
+            let outcome = sim.run(30_000, None).unwrap();
+            assert_eq!(outcome, Outcome::Survived, "seed {} from {:?}", seed, start);
+        }
+    }
+}
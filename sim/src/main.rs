@@ -0,0 +1,117 @@
+//! Runs the cliff detector rover simulation from the command line.
+//!
+//! cargo run -p rover-sim --target x86_64-unknown-linux-gnu -- [OPTIONS]
+//!
+//! --runs N         number of randomised starts (default 1)
+//! --seed S         seed of the first run, later runs use S + 1, S + 2, ... (default 0)
+//! --duration MS    simulated time per run in milliseconds (default 60000)
+//! --log PATH       write the trajectory of every run to PATH as CSV, `t_ms` restarts from 0
+//!                  at the start of each run
+//! --telemetry PATH write the serial telemetry the rover would send to PATH, for replaying
+//!                  in the viewer; each run restarts the rover's clock and sequence numbers
+//!
+//! Exits with an error if the rover drives off the table in any run.
+
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process;
+
+use rover_sim::{Geometry, Outcome, Sample, Sim, Table};
+use stm32f401_rover_testbed::drive::DriveConfig;
+use stm32f401_rover_testbed::telemetry::MAX_FRAME_LEN;
+
+/// How often the rover sends a telemetry packet, in milliseconds.
+const TELEMETRY_PERIOD_MS: u32 = 50;
+
+struct Args {
+    runs: u64,
+    seed: u64,
+    duration_ms: u32,
+    log: Option<String>,
+    telemetry: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        runs: 1,
+        seed: 0,
+        duration_ms: 60_000,
+        log: None,
+        telemetry: None,
+    };
+    let mut iter = env::args().skip(1);
+    while let Some(flag) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("missing value for {}", flag));
+        match flag.as_str() {
+            "--runs" => args.runs = value()?.parse().map_err(|e| format!("--runs: {}", e))?,
+            "--seed" => args.seed = value()?.parse().map_err(|e| format!("--seed: {}", e))?,
+            "--duration" => {
+                args.duration_ms = value()?.parse().map_err(|e| format!("--duration: {}", e))?
+            }
+            "--log" => args.log = Some(value()?),
+            "--telemetry" => args.telemetry = Some(value()?),
+            _ => return Err(format!("unknown argument {}", flag)),
+        }
+    }
+    Ok(args)
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let create = |path: &String| {
+        BufWriter::new(File::create(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(2);
+        }))
+    };
+    let mut log = args.log.as_ref().map(create);
+    let mut telemetry = args.telemetry.as_ref().map(create);
+    if let Some(w) = log.as_mut() {
+        writeln!(w, "{}", Sample::CSV_HEADER).expect("write trajectory log");
+    }
+
+    let mut fell_off = 0;
+    for seed in args.seed..args.seed + args.runs {
+        let mut sim = Sim::random_start(
+            Table::default(),
+            Geometry::default(),
+            DriveConfig::default(),
+            seed,
+        );
+        let start = sim.pose();
+        let mut sequence: u16 = 0;
+        let outcome = sim
+            .run_with(args.duration_ms, |sim, sample| {
+                if let Some(w) = log.as_mut() {
+                    sample.write_csv(w)?;
+                }
+                if let Some(w) = telemetry.as_mut() {
+                    if sample.t_ms % TELEMETRY_PERIOD_MS == 0 {
+                        let mut frame = [0; MAX_FRAME_LEN];
+                        let len = sim.telemetry(sequence).encode(&mut frame);
+                        w.write_all(&frame[..len])?;
+                        sequence = sequence.wrapping_add(1);
+                    }
+                }
+                Ok(())
+            })
+            .expect("write output");
+        if let Outcome::FellOff { t_ms } = outcome {
+            fell_off += 1;
+            println!(
+                "seed {}: fell off after {} ms, started at {:?}",
+                seed, t_ms, start
+            );
+        }
+    }
+
+    println!("{} runs, {} fell off the table", args.runs, fell_off);
+    if fell_off > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,472 @@
+//! Pinout of the Black Pill (STM32F401) rover.
+//!
+//! [`Rover::take`] sets up every peripheral the rover uses, so an experiment only has to pick
+//! the parts it needs:
+//!
+//! | Peripheral           | Pins                                               |
+//! |----------------------|----------------------------------------------------|
+//! | I2C1 (400 kHz)       | PB8 SCL, PB9 SDA, DMA1 streams 0 and 7             |
+//! | LED                  | PC13, active low                                   |
+//! | User button          | PA0, active low                                    |
+//! | VL6180X x_shut / int | BR PC15/PC14, FR PA2/PA1, FL PB12/PA4, BL PB1/PB0  |
+//! | L298N motor a        | PB5, PB4 direction, PB6 PWM (TIM4 ch1)             |
+//! | L298N motor b        | PA15, PA12 direction, PA11 PWM (TIM1 ch4)          |
+//! | Right wheel encoder  | PA5 A, PB3 B (TIM2 encoder mode)                   |
+//! | Left wheel encoder   | PA6 A, PA7 B (TIM3 encoder mode)                   |
+//! | SSD1306 display      | I2C1, optional                                     |
+//! | USART1 (115200 baud) | PA9 TX, DMA2 stream 7, PA10 RX                     |
+//! | Config store         | flash sectors 1 and 2                              |
+//!
+//! # Rewiring for the wheel encoders
+//!
+//! The FL x_shut line moved from PA5 to PB12 when the wheel encoders were added. Encoder mode
+//! needs channels 1 and 2 of one timer, and only TIM3 has both on free pins. TIM2's channel 1 is
+//! on PA0 (the button), PA5 or PA15 (motor b), TIM5's are on PA0 and PA1, and TIM1 and TIM4
+//! drive the motors. So the right encoder takes PA5, and PB3 (SWO, unused with SWD) for channel
+//! 2.
+//!
+//! On a rover still wired to PA5 nothing drives the FL x_shut line, so that sensor is never held
+//! in reset. It answers at the default address along with the first sensor to boot, and the two
+//! end up sharing an address.
+
+use cortex_m::peripheral::{DWT, SYST};
+use embedded_hal::blocking::i2c;
+use heapless::Deque;
+use ssd1306::command::AddrMode;
+use ssd1306::mode::BasicMode;
+use ssd1306::{prelude::*, I2CDisplayInterface, Ssd1306};
+use stm32f4xx_hal as hal;
+
+use hal::gpio::{gpioa::*, gpiob::*, gpioc::*};
+use hal::gpio::{Alternate, Edge, ErasedPin, Input, OpenDrain, Output, PushPull};
+use hal::pac;
+use hal::prelude::*;
+use hal::qei::Qei;
+use hal::rcc::Clocks;
+use hal::syscfg::SysCfg;
+use hal::timer::{CounterHz, Event, PwmChannel};
+
+use crate::button::{self, Gesture, Gestures};
+use crate::config::{ConfigStore, Loaded, RoverConfig};
+use crate::console;
+use crate::flash::ConfigFlash;
+use crate::frame_buffer::{BufferedDisplay, Panel, Span};
+use crate::i2c_dma::{DmaI2cBus, DmaI2cProxy, I2C1_BUS};
+use crate::i2c_queue::Priority;
+use crate::serial_dma::DmaSerialTx;
+use crate::supervisor::ResetCause;
+use crate::telemetry;
+use crate::tof::{InitReport, TofArray};
+use crate::ui::CycleClock;
+
+pub type I2c =
+    hal::i2c::I2c<pac::I2C1, (PB8<Alternate<4, OpenDrain>>, PB9<Alternate<4, OpenDrain>>)>;
+/// Cliff sensors acquire it at `Priority::High`, the display at `Priority::Low`. Bind
+/// `I2C1_EV`, `I2C1_ER` and `DMA1_STREAM0` to `on_interrupt`, and call `check_timeout` from a
+/// periodic task.
+pub type I2cBus = DmaI2cBus;
+pub type I2cProxy = DmaI2cProxy;
+
+pub type Motors = l298n::L298N<
+    PB5<Output<PushPull>>,
+    PB4<Output<PushPull>>,
+    PA15<Output<PushPull>>,
+    PA12<Output<PushPull>>,
+    PwmChannel<pac::TIM4, 0>,
+    PwmChannel<pac::TIM1, 3>,
+>;
+
+/// A 128x64 SSD1306 on `I2C`, see [`init_display`].
+pub type Oled<I2C> = Ssd1306<I2CInterface<I2C>, DisplaySize128x64, BasicMode>;
+pub type Display = BufferedDisplay<Oled<I2cProxy>>;
+
+/// Takes the spans in horizontal addressing mode, which moves on to the next column and wraps
+/// round within the area set.
+impl<I2C: i2c::Write> Panel for Oled<I2C> {
+    type Error = <Self as DisplayConfig>::Error;
+
+    fn send(&mut self, span: &Span, data: &[u8]) -> Result<(), Self::Error> {
+        let (start, end) = span.draw_area();
+        self.set_draw_area(start, end)?;
+        self.draw(data)
+    }
+}
+
+/// Initialises the SSD1306 on `i2c` and clears it, to be drawn on and flushed like the driver's
+/// `BufferedGraphicsMode`, but sending only what changed. Fails if the display does not answer,
+/// e.g. when none is fitted.
+pub fn init_display<I2C: i2c::Write>(
+    i2c: I2C,
+) -> Result<BufferedDisplay<Oled<I2C>>, <Oled<I2C> as Panel>::Error> {
+    let interface = I2CDisplayInterface::new(i2c);
+    let mut panel = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0);
+    panel.init_with_addr_mode(AddrMode::Horizontal)?;
+    let mut display = BufferedDisplay::new(panel);
+    display.flush()?;
+    Ok(display)
+}
+
+/// Channel `a`'s wheel, on pins freed by moving the FL x_shut line, see the module docs.
+pub type RightEncoder = Qei<pac::TIM2, (PA5<Alternate<1>>, PB3<Alternate<1>>)>;
+/// Channel `b`'s wheel.
+pub type LeftEncoder = Qei<pac::TIM3, (PA6<Alternate<2>>, PA7<Alternate<2>>)>;
+
+pub struct Encoders {
+    pub right: RightEncoder,
+    pub left: LeftEncoder,
+}
+
+impl Encoders {
+    /// Counts of the right and left encoders, truncated to 16 bits for `WheelOdometry`.
+    pub fn counts(&self) -> (u16, u16) {
+        use embedded_hal::Qei as _;
+        (self.right.count() as u16, self.left.count())
+    }
+}
+
+/// Receive side of USART1, e.g. for the `console`.
+pub type SerialRx = hal::serial::Rx<pac::USART1>;
+
+pub type Led = PC13<Output<PushPull>>;
+pub type Button = PA0<Input>;
+
+/// The VL6180Xs are moved to consecutive addresses from here, in `Corner` order.
+pub const TOF_BASE_ADDRESS: u8 = 10;
+
+/// Settings for the cliff sensors: continuous ranging with an interrupt on every new sample,
+/// e.g. with `tof::MAX_CONVERGENCE_MS` and `tof::INTER_MEASUREMENT_MS`.
+///
+/// # Panics
+///
+/// If the VL6180X cannot take the times. `settings::Settings::set` only lets through times it
+/// can take.
+pub fn tof_config(max_convergence_ms: u8, inter_measurement_ms: u16) -> vl6180x::Config {
+    let mut config = vl6180x::Config::new();
+    config.set_range_interrupt_mode(vl6180x::RangeInterruptMode::NewSampleReady);
+    config
+        .set_range_max_convergence_time(max_convergence_ms)
+        .expect("rmc");
+    config
+        .set_range_inter_measurement_period(inter_measurement_ms)
+        .expect("rimp");
+    config
+}
+
+/// Saves the `RoverConfig` without erasing, so it can be used while the watchdog runs.
+pub type Store = ConfigStore<ConfigFlash>;
+
+/// The four cliff sensors, indexed by `Corner`.
+pub type Tofs = TofArray<I2cProxy, ErasedPin<Output>, (), 4>;
+/// Interrupt pins of the cliff sensors, indexed by `Corner`. They trigger EXTI0 (BL), EXTI1 (FR),
+/// EXTI4 (FL) and EXTI15_10 (BR) on a new sample.
+pub type TofInterrupts = [ErasedPin<Input>; 4];
+
+/// Drives every L298N direction pin low, which lets both motors coast.
+///
+/// Writes the GPIO set/reset registers directly, so it works from any context without the
+/// `Motors`, e.g. while a hung task holds them.
+pub fn cut_motors() {
+    // Safe, writes to BSRR are atomic and only touch the motor direction pins
+    unsafe {
+        (*pac::GPIOB::ptr())
+            .bsrr
+            .write(|w| w.br5().set_bit().br4().set_bit());
+        (*pac::GPIOA::ptr())
+            .bsrr
+            .write(|w| w.br15().set_bit().br12().set_bit());
+    }
+}
+
+/// The independent watchdog. Once started it cannot be stopped, so it is left to the
+/// experiments that are supervised, see `supervisor::Supervisor`.
+pub struct Watchdog {
+    iwdg: hal::watchdog::IndependentWatchdog,
+}
+
+impl Watchdog {
+    /// Starts the watchdog, it resets the rover unless fed every `timeout_ms`.
+    pub fn start(iwdg: pac::IWDG, timeout_ms: u32) -> Self {
+        let mut iwdg = hal::watchdog::IndependentWatchdog::new(iwdg);
+        iwdg.start(timeout_ms.millis());
+        Watchdog { iwdg }
+    }
+
+    pub fn feed(&mut self) {
+        self.iwdg.feed();
+    }
+
+    /// Cuts the motors and waits for the watchdog to reset the rover.
+    pub fn trip(&mut self) -> ! {
+        cut_motors();
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+}
+
+/// Gestures that can wait for the main loop of a `ButtonSampler`.
+const GESTURE_QUEUE_LEN: usize = 4;
+
+/// The button read every `button::SAMPLE_MS` from the TIM2 interrupt, for the test programs
+/// without RTIC, whose main loops block on the sensors for much longer than that. The gestures
+/// wait in a queue for the main loop to take them.
+///
+/// Time is taken from the DWT cycle counter, which has to be running, so a sample held up
+/// behind an I2C transaction with interrupts off still sees how long the button was down.
+pub struct ButtonSampler {
+    pin: Button,
+    timer: CounterHz<pac::TIM2>,
+    clock: CycleClock,
+    gestures: Gestures,
+    queue: Deque<Gesture, GESTURE_QUEUE_LEN>,
+}
+
+impl ButtonSampler {
+    /// Starts TIM2. Unmask its interrupt once the sampler is where the handler can reach it.
+    pub fn start(pin: Button, tim2: pac::TIM2, clocks: &Clocks) -> Self {
+        let mut timer = tim2.counter_hz(clocks);
+        timer.start((1000 / button::SAMPLE_MS).Hz()).unwrap();
+        timer.listen(Event::Update);
+        let cycles_per_ms = clocks.sysclk().raw() / 1000;
+        ButtonSampler {
+            pin,
+            timer,
+            clock: CycleClock::new(cycles_per_ms, DWT::cycle_count()),
+            gestures: Gestures::new(),
+            queue: Deque::new(),
+        }
+    }
+
+    /// Call from the TIM2 interrupt.
+    pub fn on_interrupt(&mut self) {
+        self.timer.clear_interrupt(Event::Update);
+        let now_ms = self.clock.update(DWT::cycle_count());
+        // Active low
+        if let Some(gesture) = self.gestures.update(self.pin.is_low(), now_ms) {
+            // Dropped if the main loop has fallen that far behind
+            self.queue.push_back(gesture).ok();
+        }
+    }
+
+    /// The oldest gesture the main loop has not taken yet.
+    pub fn next(&mut self) -> Option<Gesture> {
+        self.queue.pop_front()
+    }
+}
+
+/// Peripherals the rover does not use, for experiments to claim.
+pub struct Spare {
+    pub iwdg: pac::IWDG,
+    pub tim5: pac::TIM5,
+}
+
+pub struct Rover {
+    /// Why the rover last reset, the flags are cleared for the next boot.
+    pub reset_cause: ResetCause,
+    /// Config saved before the reset, or the defaults, see `config_source`. The sensors are
+    /// already set up with it.
+    pub config: RoverConfig,
+    pub config_source: Loaded,
+    pub config_store: Store,
+    pub clocks: Clocks,
+    /// Released after the start up delays, e.g. to drive an RTIC monotonic.
+    pub syst: SYST,
+    pub exti: pac::EXTI,
+    pub syscfg: SysCfg,
+    pub i2c_bus: &'static I2cBus,
+    /// Ranging continuously, apart from the ones listed as failed in `tof_report`.
+    pub tofs: Tofs,
+    pub tof_report: InitReport<4>,
+    pub tof_interrupts: TofInterrupts,
+    /// Both channels stopped, with the duty set to the maximum.
+    pub motors: Motors,
+    /// Counting from wherever the wheels were at start up.
+    pub encoders: Encoders,
+    /// Initialised and cleared, `None` if no display answered. The rover runs without one.
+    pub display: Option<Display>,
+    /// Idle, e.g. for `telemetry` frames and `console` replies.
+    pub serial: DmaSerialTx,
+    /// Interrupting on every byte received, bind `USART1`.
+    pub serial_rx: SerialRx,
+    /// Off.
+    pub led: Led,
+    pub button: Button,
+    pub spare: Spare,
+}
+
+impl Rover {
+    /// Sysclk the rover runs at.
+    pub const SYSCLK_MHZ: u32 = 48;
+
+    /// Sets up the rover. Can only be called once, as it takes over the static I2C bus.
+    ///
+    /// # Panics
+    ///
+    /// If the config sector not in use cannot be erased.
+    pub fn take(dp: pac::Peripherals, cp: cortex_m::Peripherals) -> Self {
+        let reset_cause = ResetCause::from_csr(dp.RCC.csr.read().bits());
+        dp.RCC.csr.modify(|_, w| w.rmvf().set_bit());
+
+        // Load the config while the watchdog is still off, opening may erase a sector
+        let (config_store, config, config_source) =
+            ConfigStore::open(ConfigFlash::new(dp.FLASH)).expect("config erase");
+
+        let rcc = dp.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(Self::SYSCLK_MHZ.MHz()).freeze();
+        let mut delay = cp.SYST.delay(&clocks);
+        let mut exti = dp.EXTI;
+        let mut syscfg = dp.SYSCFG.constrain();
+
+        let gpioa = dp.GPIOA.split();
+        let gpiob = dp.GPIOB.split();
+        let gpioc = dp.GPIOC.split();
+
+        // Set up led, it is active low
+        let mut led = gpioc.pc13.into_push_pull_output();
+        led.set_high();
+
+        let button = gpioa.pa0.into_pull_up_input();
+
+        // Hand I2C1 to the DMA driven bus once the HAL has set up its pins and timing.
+        let i2c_bus: &'static I2cBus = {
+            let scl = gpiob
+                .pb8
+                .into_alternate()
+                .internal_pull_up(true)
+                .set_open_drain();
+            let sda = gpiob
+                .pb9
+                .into_alternate()
+                .internal_pull_up(true)
+                .set_open_drain();
+            let i2c: I2c = dp.I2C1.i2c((scl, sda), 400.kHz(), &clocks);
+            let (i2c, _pins) = i2c.release();
+
+            I2C1_BUS.init(i2c, dp.DMA1, clocks.sysclk().raw());
+            &I2C1_BUS
+        };
+
+        // Set up x_shut pins, FL moved off PA5 for the right encoder
+        let x_shut_br = gpioc.pc15.into_push_pull_output().erase();
+        let x_shut_fr = gpioa.pa2.into_push_pull_output().erase();
+        let x_shut_fl = gpiob.pb12.into_push_pull_output().erase();
+        let x_shut_bl = gpiob.pb1.into_push_pull_output().erase();
+
+        // Set up interrupt pins
+        let mut int_br = gpioc.pc14.into_pull_up_input();
+        int_br.make_interrupt_source(&mut syscfg);
+        int_br.trigger_on_edge(&mut exti, Edge::Rising);
+        int_br.enable_interrupt(&mut exti);
+        let mut int_fr = gpioa.pa1.into_pull_up_input();
+        int_fr.make_interrupt_source(&mut syscfg);
+        int_fr.trigger_on_edge(&mut exti, Edge::Rising);
+        int_fr.enable_interrupt(&mut exti);
+        let mut int_fl = gpioa.pa4.into_pull_up_input();
+        int_fl.make_interrupt_source(&mut syscfg);
+        int_fl.trigger_on_edge(&mut exti, Edge::Rising);
+        int_fl.enable_interrupt(&mut exti);
+        let mut int_bl = gpiob.pb0.into_pull_up_input();
+        int_bl.make_interrupt_source(&mut syscfg);
+        int_bl.trigger_on_edge(&mut exti, Edge::Rising);
+        int_bl.enable_interrupt(&mut exti);
+
+        // Set up vl6180x's, in `Corner` order
+        let mut tofs = TofArray::new(
+            i2c_bus.acquire(Priority::High),
+            [
+                (x_shut_br, int_br.erase()),
+                (x_shut_fr, int_fr.erase()),
+                (x_shut_fl, int_fl.erase()),
+                (x_shut_bl, int_bl.erase()),
+            ],
+            tof_config(
+                config.settings.max_convergence_ms,
+                config.settings.inter_measurement_ms,
+            ),
+            TOF_BASE_ADDRESS,
+        );
+        for (index, correction) in config.corrections.iter().enumerate() {
+            tofs.set_correction(index, *correction);
+        }
+        let tof_report = tofs.init(|| i2c_bus.acquire(Priority::High), &mut delay);
+        let (tofs, tof_interrupts) = tofs.split_interrupt_pins();
+
+        // Set up motor driver
+        let m1l1 = gpiob.pb5.into_push_pull_output();
+        let m1l2 = gpiob.pb4.into_push_pull_output();
+        let m2l1 = gpioa.pa15.into_push_pull_output();
+        let m2l2 = gpioa.pa12.into_push_pull_output();
+
+        let tim4_channels = gpiob.pb6.into_alternate();
+        let m1pwm = dp.TIM4.pwm_hz(tim4_channels, 20.kHz(), &clocks).split();
+        let max_duty = m1pwm.get_max_duty();
+
+        let tim1_channels = gpioa.pa11.into_alternate();
+        let m2pwm = dp.TIM1.pwm_hz(tim1_channels, 20.kHz(), &clocks).split();
+
+        let mut motors = l298n::L298N::new(m1l1, m1l2, m1pwm, m2l1, m2l2, m2pwm);
+        motors.a.set_duty(max_duty);
+        motors.b.set_duty(max_duty);
+        motors.a.stop();
+        motors.b.stop();
+
+        // Set up the wheel encoders
+        let encoders = Encoders {
+            right: Qei::new(
+                dp.TIM2,
+                (gpioa.pa5.into_alternate(), gpiob.pb3.into_alternate()),
+            ),
+            left: Qei::new(
+                dp.TIM3,
+                (gpioa.pa6.into_alternate(), gpioa.pa7.into_alternate()),
+            ),
+        };
+
+        // Set up the serial port, sending from a buffer long enough for any console reply
+        let (serial, serial_rx) = {
+            let config =
+                hal::serial::config::Config::default().baudrate(telemetry::BAUD_RATE.bps());
+            let mut serial = dp
+                .USART1
+                .serial(
+                    (gpioa.pa9.into_alternate(), gpioa.pa10.into_alternate()),
+                    config,
+                    &clocks,
+                )
+                .unwrap();
+            serial.listen(hal::serial::Event::Rxne);
+            let (tx, rx) = serial.split();
+            let buffer =
+                cortex_m::singleton!(: [u8; console::REPLY_LEN] = [0; console::REPLY_LEN]).unwrap();
+            (DmaSerialTx::new(tx, dp.DMA2, buffer), rx)
+        };
+
+        let display = init_display(i2c_bus.acquire(Priority::Low)).ok();
+
+        Rover {
+            reset_cause,
+            config,
+            config_source,
+            config_store,
+            clocks,
+            syst: delay.release().release(),
+            exti,
+            syscfg,
+            i2c_bus,
+            tofs,
+            tof_report,
+            tof_interrupts,
+            motors,
+            encoders,
+            display,
+            serial,
+            serial_rx,
+            led,
+            button,
+            spare: Spare {
+                iwdg: dp.IWDG,
+                tim5: dp.TIM5,
+            },
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Gestures of the one button.
+//!
+//! [`Gestures`] is fed the raw level of the button every [`SAMPLE_MS`] or so, e.g. from a timer
+//! task, debounces it and tells the gestures apart by how long the button is held and how soon it
+//! is pressed again:
+//!
+//! | Gesture       | When                                                                     |
+//! |---------------|--------------------------------------------------------------------------|
+//! | `Click`       | let go before [`LONG_PRESS_MS`], not pressed again in [`DOUBLE_CLICK_MS`] |
+//! | `DoubleClick` | let go of a second press that started within [`DOUBLE_CLICK_MS`]         |
+//! | `LongPress`   | held for [`LONG_PRESS_MS`]                                               |
+//! | `Hold(n)`     | every [`HOLD_REPEAT_MS`] after that, while still held                    |
+//!
+//! So a click is only reported once no second press can follow it. The level is debounced here
+//! rather than with `debounced-pin`, so the timing can be tested on the host.
+
+/// How often the button should be read.
+pub const SAMPLE_MS: u32 = 5;
+/// How long the level has to keep still for a change to count, longer than the contacts bounce.
+pub const DEBOUNCE_MS: u32 = 20;
+/// Held this long, a press is a long one.
+pub const LONG_PRESS_MS: u32 = 600;
+/// Longest from letting go to pressing again for a double click.
+pub const DOUBLE_CLICK_MS: u32 = 250;
+/// Time between `Hold`s.
+pub const HOLD_REPEAT_MS: u32 = 200;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Gesture {
+    Click,
+    DoubleClick,
+    LongPress,
+    /// Still held after a long press, counting from 1.
+    Hold(u16),
+}
+
+/// A level that only changes once the raw level has kept still for [`DEBOUNCE_MS`].
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Debouncer {
+    level: bool,
+    raw: bool,
+    /// When the raw level last changed.
+    changed_at: u32,
+}
+
+impl Debouncer {
+    pub const fn new() -> Self {
+        Debouncer {
+            level: false,
+            raw: false,
+            changed_at: 0,
+        }
+    }
+
+    /// Takes the raw level at `now_ms`, returns the debounced one.
+    pub fn update(&mut self, raw: bool, now_ms: u32) -> bool {
+        if raw != self.raw {
+            self.raw = raw;
+            self.changed_at = now_ms;
+        } else if raw != self.level && now_ms.wrapping_sub(self.changed_at) >= DEBOUNCE_MS {
+            self.level = raw;
+        }
+        self.level
+    }
+
+    pub fn level(&self) -> bool {
+        self.level
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Up,
+    /// Pressed at `at`, the second press of a double click if `second`.
+    Down {
+        at: u32,
+        second: bool,
+    },
+    /// Let go of a click at `at`, a second press would make it a double click.
+    Released {
+        at: u32,
+    },
+    /// Held past a long press, the latest `Hold` due at `at`.
+    Held {
+        at: u32,
+        repeats: u16,
+    },
+}
+
+/// Tells the gestures from the raw level of the button.
+#[derive(Debug, Copy, Clone)]
+pub struct Gestures {
+    debouncer: Debouncer,
+    state: State,
+}
+
+impl Default for Gestures {
+    fn default() -> Self {
+        Gestures::new()
+    }
+}
+
+impl Gestures {
+    pub const fn new() -> Self {
+        Gestures {
+            debouncer: Debouncer::new(),
+            state: State::Up,
+        }
+    }
+
+    /// Takes whether the button is down at `now_ms`, from the same free running clock every
+    /// time. A second press held for a long press is a long press, the click before it is
+    /// dropped.
+    pub fn update(&mut self, pressed: bool, now_ms: u32) -> Option<Gesture> {
+        let down = self.debouncer.update(pressed, now_ms);
+        let since = |at: u32| now_ms.wrapping_sub(at);
+        let (state, gesture) = match (self.state, down) {
+            (State::Up, true) => (
+                State::Down {
+                    at: now_ms,
+                    second: false,
+                },
+                None,
+            ),
+            (State::Down { at, .. }, true) if since(at) >= LONG_PRESS_MS => (
+                State::Held {
+                    at: at.wrapping_add(LONG_PRESS_MS),
+                    repeats: 0,
+                },
+                Some(Gesture::LongPress),
+            ),
+            (State::Down { second: false, .. }, false) => (State::Released { at: now_ms }, None),
+            (State::Down { second: true, .. }, false) => (State::Up, Some(Gesture::DoubleClick)),
+            (State::Released { .. }, true) => (
+                State::Down {
+                    at: now_ms,
+                    second: true,
+                },
+                None,
+            ),
+            (State::Released { at }, false) if since(at) >= DOUBLE_CLICK_MS => {
+                (State::Up, Some(Gesture::Click))
+            }
+            (State::Held { at, repeats }, true) if since(at) >= HOLD_REPEAT_MS => {
+                let repeats = repeats.saturating_add(1);
+                (
+                    State::Held {
+                        at: at.wrapping_add(HOLD_REPEAT_MS),
+                        repeats,
+                    },
+                    Some(Gesture::Hold(repeats)),
+                )
+            }
+            (State::Held { .. }, false) => (State::Up, None),
+            (state, _) => (state, None),
+        };
+        self.state = state;
+        gesture
+    }
+
+    /// Whether the button is down, debounced.
+    pub fn is_pressed(&self) -> bool {
+        self.debouncer.level()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How long the contacts bounce after each edge, toggling every sample.
+    const BOUNCE_MS: u32 = 10;
+
+    /// Samples the button every [`SAMPLE_MS`] from `start_ms` to `end_ms`, pressed over each of
+    /// `presses`, and returns the gestures and when they came.
+    fn replay(start_ms: u32, presses: &[(u32, u32)], end_ms: u32) -> Vec<(u32, Gesture)> {
+        let mut gestures = Gestures::new();
+        let mut seen = Vec::new();
+        let mut t = start_ms;
+        while t != end_ms {
+            let bouncing = presses.iter().any(|(down, up)| {
+                t.wrapping_sub(*down) < BOUNCE_MS || t.wrapping_sub(*up) < BOUNCE_MS
+            });
+            let pressed = presses
+                .iter()
+                .any(|(down, up)| t.wrapping_sub(*down) < up.wrapping_sub(*down));
+            let pressed = if bouncing {
+                t % (2 * SAMPLE_MS) < SAMPLE_MS
+            } else {
+                pressed
+            };
+            if let Some(gesture) = gestures.update(pressed, t) {
+                seen.push((t, gesture));
+            }
+            t = t.wrapping_add(SAMPLE_MS);
+        }
+        seen
+    }
+
+    #[test]
+    fn debounces() {
+        let mut debouncer = Debouncer::new();
+        assert!(!debouncer.update(true, 0));
+        assert!(!debouncer.update(false, 5));
+        assert!(!debouncer.update(true, 10));
+        assert!(!debouncer.update(true, 25));
+        assert!(debouncer.update(true, 30));
+
+        // A blip shorter than the debounce time never shows
+        assert_eq!(replay(0, &[(100, 115)], 1000), []);
+    }
+
+    #[test]
+    fn click_once_no_second_press_can_follow() {
+        // Down from 130 ms and up from 275 ms, 20 ms after the contacts last moved
+        assert_eq!(
+            replay(0, &[(100, 250)], 1000),
+            [(275 + DOUBLE_CLICK_MS, Gesture::Click)]
+        );
+        assert_eq!(
+            replay(0, &[(100, 250), (700, 800)], 1500),
+            [
+                (525, Gesture::Click),
+                (825 + DOUBLE_CLICK_MS, Gesture::Click)
+            ]
+        );
+    }
+
+    #[test]
+    fn double_click() {
+        assert_eq!(
+            replay(0, &[(100, 200), (350, 450)], 1500),
+            [(475, Gesture::DoubleClick)]
+        );
+        // A third press starts over
+        assert_eq!(
+            replay(0, &[(100, 200), (350, 450), (550, 650)], 1500),
+            [
+                (475, Gesture::DoubleClick),
+                (675 + DOUBLE_CLICK_MS, Gesture::Click)
+            ]
+        );
+    }
+
+    #[test]
+    fn long_press_then_holds() {
+        let down = 130;
+        let long = down + LONG_PRESS_MS;
+        assert_eq!(
+            replay(0, &[(100, 1400)], 2000),
+            [
+                (long, Gesture::LongPress),
+                (long + HOLD_REPEAT_MS, Gesture::Hold(1)),
+                (long + 2 * HOLD_REPEAT_MS, Gesture::Hold(2)),
+                (long + 3 * HOLD_REPEAT_MS, Gesture::Hold(3)),
+            ]
+        );
+
+        // The second press of a double click held long
+        assert_eq!(
+            replay(0, &[(100, 200), (350, 1100)], 1500),
+            [(380 + LONG_PRESS_MS, Gesture::LongPress)]
+        );
+    }
+
+    #[test]
+    fn across_the_clock_wrapping_around() {
+        let start = u32::MAX - 99;
+        let down = start.wrapping_add(100);
+        assert_eq!(
+            replay(start, &[(down, down.wrapping_add(900))], 2000),
+            [
+                (down.wrapping_add(30 + LONG_PRESS_MS), Gesture::LongPress),
+                (
+                    down.wrapping_add(30 + LONG_PRESS_MS + HOLD_REPEAT_MS),
+                    Gesture::Hold(1)
+                ),
+            ]
+        );
+    }
+}
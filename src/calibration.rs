@@ -0,0 +1,383 @@
+//! Guided calibration of the cliff sensors on a flat floor and over a dark target.
+//!
+//! The sensors sit at slightly different heights and behind different cover windows, so on
+//! the same floor they read different ranges. A [`Calibration`] works out a [`Correction`] for
+//! each of them, so they all read [`FLOOR_MM`] and one cliff threshold fits all four:
+//!
+//! 1. The rover is put on a flat floor, [`Phase::Place`], and the calibration
+//!    [`start`](Calibration::start)ed.
+//! 2. With no correction, the range offset is the floor height less the average range.
+//! 3. The rover is held with the sensors [`TARGET_MM`] over a dark surface,
+//!    [`Phase::Target`], and the calibration started again.
+//! 4. With the offsets applied, the range falls short of the target by the light the cover
+//!    window reflects back. The crosstalk is the share of the return rate that makes up for it,
+//!    as in ST's AN4545.
+//!
+//! The crosstalk needs a second distance: on the floor the offsets were just fitted to, the
+//! ranges are already right and nothing is left to measure. Between the steps the firmware
+//! boots the sensors again with the [`corrections`](Calibration::corrections), and feeds every
+//! range and its return rate to [`record`](Calibration::record).
+
+use core::fmt::{self, Write};
+
+use crate::drive::Corner;
+use crate::tof::Correction;
+
+/// Height of the sensors above the floor, what they read once calibrated.
+pub const FLOOR_MM: u16 = 10;
+/// How far the dark target is from the sensors while measuring the crosstalk.
+pub const TARGET_MM: u16 = 100;
+/// Ranges averaged in each step.
+pub const SAMPLES: u16 = 32;
+/// A larger offset means the sensor does not see the floor, or the floor is not flat.
+pub const MAX_OFFSET_MM: i32 = 20;
+/// What a VL6180X reports when nothing is within range.
+const OUT_OF_RANGE_MM: u16 = 255;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for the rover to be put on a flat floor.
+    Place,
+    /// Measuring the range offsets, with no correction.
+    Offsets,
+    /// Waiting for the rover to be held over the dark target.
+    Target,
+    /// Measuring the crosstalk, with the offsets applied.
+    Crosstalk,
+    /// Every sensor is corrected.
+    Done,
+    /// The sensor at this corner did not see the floor or the target, the corrections are back
+    /// to what they were.
+    Failed(Corner),
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct Sum {
+    count: u16,
+    range_mm: u32,
+    return_rate: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    phase: Phase,
+    sums: [Sum; 4],
+    /// What the sensors were corrected with before.
+    previous: [Correction; 4],
+    corrections: [Correction; 4],
+}
+
+impl Calibration {
+    /// Waits for the rover to be placed, with the sensors corrected by `previous` until then.
+    pub fn new(previous: [Correction; 4]) -> Self {
+        Calibration {
+            phase: Phase::Place,
+            sums: [Sum::default(); 4],
+            previous,
+            corrections: [Correction::default(); 4],
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Whether ranges are wanted.
+    pub fn is_measuring(&self) -> bool {
+        matches!(self.phase, Phase::Offsets | Phase::Crosstalk)
+    }
+
+    /// What the sensors are to be corrected with in the current phase.
+    pub fn corrections(&self) -> [Correction; 4] {
+        match self.phase {
+            Phase::Place | Phase::Failed(_) => self.previous,
+            Phase::Offsets => [Correction::default(); 4],
+            Phase::Target | Phase::Crosstalk => self.corrections.map(|correction| Correction {
+                crosstalk: 0,
+                ..correction
+            }),
+            Phase::Done => self.corrections,
+        }
+    }
+
+    /// The corrections from before the calibration, e.g. to go back to when it is cut short.
+    pub fn previous(&self) -> [Correction; 4] {
+        self.previous
+    }
+
+    /// Ranges taken of each sensor in the current step, out of [`SAMPLES`].
+    pub fn progress(&self) -> [u16; 4] {
+        self.sums.map(|sum| sum.count)
+    }
+
+    /// Starts measuring the offsets once the rover is on the floor, or the crosstalk once it is
+    /// over the target.
+    pub fn start(&mut self) {
+        let next = match self.phase {
+            Phase::Place => Phase::Offsets,
+            Phase::Target => Phase::Crosstalk,
+            _ => return,
+        };
+        self.phase = next;
+        self.sums = [Sum::default(); 4];
+    }
+
+    /// Takes a range of the sensor at `corner` and its return rate, see
+    /// `TofArray::return_rate`. Returns whether the phase changed, and with it the
+    /// [`corrections`](Self::corrections) the sensors need to be booted with.
+    pub fn record(&mut self, corner: Corner, range_mm: u16, return_rate: u16) -> bool {
+        if !self.is_measuring() {
+            return false;
+        }
+        if range_mm >= OUT_OF_RANGE_MM {
+            self.phase = Phase::Failed(corner);
+            return true;
+        }
+        let sum = &mut self.sums[corner.index()];
+        if sum.count == SAMPLES {
+            return false;
+        }
+        sum.count += 1;
+        sum.range_mm += u32::from(range_mm);
+        sum.return_rate += u32::from(return_rate);
+        if self.sums.iter().any(|sum| sum.count < SAMPLES) {
+            return false;
+        }
+
+        let count = u32::from(SAMPLES);
+        // Total of the ranges had every sensor read the floor height, or the target distance
+        let floor = u32::from(FLOOR_MM) * count;
+        let target = u32::from(TARGET_MM) * count;
+        for corner in Corner::ALL {
+            let sum = self.sums[corner.index()];
+            let correction = &mut self.corrections[corner.index()];
+            match self.phase {
+                Phase::Offsets => {
+                    // Rounded to the nearest millimetre
+                    let short = floor as i32 - sum.range_mm as i32;
+                    let offset = (short + short.signum() * count as i32 / 2) / count as i32;
+                    if offset.abs() > MAX_OFFSET_MM {
+                        self.phase = Phase::Failed(corner);
+                        return true;
+                    }
+                    correction.offset_mm = offset as i8;
+                }
+                _ => {
+                    // Still on the floor, or much too far off, the crosstalk would be made up
+                    let range = (sum.range_mm / count) as i32;
+                    let target_mm = i32::from(TARGET_MM);
+                    if range < target_mm / 2 || range > target_mm + MAX_OFFSET_MM {
+                        self.phase = Phase::Failed(corner);
+                        return true;
+                    }
+                    // rate * (1 - range / target), with the offsets applied a sensor only falls
+                    // short of the target by what the cover window adds
+                    let short = target.saturating_sub(sum.range_mm);
+                    let crosstalk = u64::from(sum.return_rate) * u64::from(short)
+                        / (u64::from(target) * u64::from(count));
+                    correction.crosstalk = crosstalk.min(u64::from(u16::MAX)) as u16;
+                }
+            }
+        }
+        self.phase = match self.phase {
+            Phase::Offsets => Phase::Target,
+            _ => Phase::Done,
+        };
+        self.sums = [Sum::default(); 4];
+        true
+    }
+}
+
+/// What the display shows in each phase, lines of at most 21 characters.
+pub fn write_screen(w: &mut impl Write, calibration: &Calibration) -> fmt::Result {
+    match calibration.phase() {
+        Phase::Place => write!(
+            w,
+            "Calibration\n\nPut the rover on a\nflat floor and press\nthe button"
+        ),
+        Phase::Target => write!(
+            w,
+            "Crosstalk\n\nHold the sensors\n{} mm over a dark\nsurface and press the\nbutton",
+            TARGET_MM
+        ),
+        Phase::Offsets | Phase::Crosstalk => {
+            let what = match calibration.phase() {
+                Phase::Offsets => "offsets",
+                _ => "crosstalk",
+            };
+            write!(w, "Measuring {}\nKeep still\n\n", what)?;
+            let progress = calibration.progress();
+            for corner in Corner::ALL {
+                let index = corner.index();
+                let separator = if index % 2 == 0 { "" } else { "  " };
+                write!(
+                    w,
+                    "{}{} {:2}/{}",
+                    separator,
+                    corner.name(),
+                    progress[index],
+                    SAMPLES
+                )?;
+                if index % 2 == 1 {
+                    writeln!(w)?;
+                }
+            }
+            write!(w, "Press to cancel")
+        }
+        Phase::Done => {
+            write!(w, "Done, press to save\n\n")?;
+            for (corner, correction) in Corner::ALL.iter().zip(calibration.corrections().iter()) {
+                writeln!(
+                    w,
+                    "{} {:+3} mm xt {:3}",
+                    corner.name(),
+                    correction.offset_mm,
+                    correction.crosstalk
+                )?;
+            }
+            Ok(())
+        }
+        Phase::Failed(corner) => write!(
+            w,
+            "{} does not see a\nflat surface\n\nPress to go back",
+            corner.name()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(calibration: &mut Calibration, ranges: [u16; 4], rates: [u16; 4]) -> bool {
+        let mut changed = false;
+        for _ in 0..SAMPLES {
+            for corner in Corner::ALL {
+                let index = corner.index();
+                assert!(!changed, "changed before the last sample");
+                changed = calibration.record(corner, ranges[index], rates[index]);
+            }
+        }
+        changed
+    }
+
+    #[test]
+    fn corrects_every_sensor_to_the_floor_height() {
+        let previous = [Correction {
+            offset_mm: 1,
+            crosstalk: 2,
+        }; 4];
+        let mut calibration = Calibration::new(previous);
+        assert_eq!(calibration.corrections(), previous);
+        // Nothing counts until the rover is in place
+        assert!(!calibration.record(Corner::BackLeft, 10, 100));
+
+        calibration.start();
+        assert_eq!(calibration.phase(), Phase::Offsets);
+        assert_eq!(calibration.corrections(), [Correction::default(); 4]);
+        assert!(measure(&mut calibration, [13, 10, 6, 22], [0; 4]));
+        assert_eq!(calibration.phase(), Phase::Target);
+        let offsets = calibration
+            .corrections()
+            .map(|correction| correction.offset_mm);
+        assert_eq!(offsets, [-3, 0, 4, -12]);
+        // Nothing counts until the rover is over the target
+        assert!(!calibration.record(Corner::BackLeft, 100, 100));
+        calibration.start();
+        assert_eq!(calibration.phase(), Phase::Crosstalk);
+        assert_eq!(calibration.corrections().map(|c| c.offset_mm), offsets);
+
+        // 20 mm short of the target at 640 (5 Mcps), 640 * 20 / 100 is the crosstalk
+        assert!(measure(&mut calibration, [80, 100, 100, 120], [640; 4]));
+        assert_eq!(calibration.phase(), Phase::Done);
+        let crosstalk = calibration
+            .corrections()
+            .map(|correction| correction.crosstalk);
+        assert_eq!(crosstalk, [128, 0, 0, 0]);
+        assert_eq!(calibration.corrections()[3].offset_mm, -12);
+        assert_eq!(calibration.previous(), previous);
+    }
+
+    #[test]
+    fn averages_the_ranges() {
+        let mut calibration = Calibration::new([Correction::default(); 4]);
+        calibration.start();
+        for i in 0..SAMPLES {
+            for corner in Corner::ALL {
+                // Half the samples read 11, half 12, the offset rounds to -2
+                calibration.record(corner, 11 + i % 2, 0);
+            }
+        }
+        // On to the crosstalk, from scratch
+        assert_eq!(calibration.phase(), Phase::Target);
+        assert_eq!(calibration.progress(), [0; 4]);
+        let offsets = calibration
+            .corrections()
+            .map(|correction| correction.offset_mm);
+        assert_eq!(offsets, [-2; 4]);
+    }
+
+    #[test]
+    fn fails_without_a_floor() {
+        let previous = [Correction {
+            offset_mm: 5,
+            crosstalk: 0,
+        }; 4];
+        let mut calibration = Calibration::new(previous);
+        calibration.start();
+        assert!(!calibration.record(Corner::FrontLeft, 10, 0));
+        assert_eq!(calibration.progress(), [0, 0, 1, 0]);
+        assert!(calibration.record(Corner::FrontRight, 255, 0));
+        assert_eq!(calibration.phase(), Phase::Failed(Corner::FrontRight));
+        assert_eq!(calibration.corrections(), previous);
+        assert!(!calibration.record(Corner::FrontRight, 10, 0));
+
+        // Seeing the floor, but much too far off
+        let mut calibration = Calibration::new(previous);
+        calibration.start();
+        assert!(measure(&mut calibration, [10, 10, 10, 40], [0; 4]));
+        assert_eq!(calibration.phase(), Phase::Failed(Corner::BackLeft));
+
+        // Left on the floor for the crosstalk
+        let mut calibration = Calibration::new(previous);
+        calibration.start();
+        measure(&mut calibration, [10; 4], [0; 4]);
+        calibration.start();
+        assert!(measure(&mut calibration, [100, 12, 100, 100], [640; 4]));
+        assert_eq!(calibration.phase(), Phase::Failed(Corner::FrontRight));
+        assert_eq!(calibration.corrections(), previous);
+    }
+
+    #[test]
+    fn screens() {
+        let mut calibration = Calibration::new([Correction::default(); 4]);
+        let mut screen = String::new();
+        write_screen(&mut screen, &calibration).unwrap();
+        assert!(screen.starts_with("Calibration\n"));
+
+        calibration.start();
+        calibration.record(Corner::BackRight, 10, 0);
+        let mut screen = String::new();
+        write_screen(&mut screen, &calibration).unwrap();
+        assert_eq!(
+            screen,
+            "Measuring offsets\nKeep still\n\n\
+             BR  1/32  FR  0/32\n\
+             FL  0/32  BL  0/32\n\
+             Press to cancel"
+        );
+
+        measure(&mut calibration, [12; 4], [0; 4]);
+        let mut screen = String::new();
+        write_screen(&mut screen, &calibration).unwrap();
+        assert!(screen.starts_with("Crosstalk\n"));
+        assert!(screen.lines().all(|line| line.len() <= 21), "{}", screen);
+
+        calibration.start();
+        measure(&mut calibration, [90; 4], [128; 4]);
+        let mut screen = String::new();
+        write_screen(&mut screen, &calibration).unwrap();
+        assert!(screen.starts_with("Done, press to save\n\nBR  -2 mm xt  12\n"));
+        assert!(screen.lines().all(|line| line.len() <= 21), "{}", screen);
+    }
+}
@@ -0,0 +1,359 @@
+//! Cliff decisions that ride out noisy ranges.
+//!
+//! A single range beyond the threshold used to be a cliff, so one stray sample sent the rover
+//! reversing and turning. A [`CliffFilter`] takes the median of the latest ranges instead, and
+//! uses two thresholds: a cliff starts past the threshold and only ends once the range is back
+//! below it by the hysteresis. Both thresholds are for a floor at [`FLOOR_MM`], the height the
+//! calibration corrects the sensors to, and move with the floor the sensor actually sees, e.g.
+//! on a thick carpet, by up to [`MAX_BASELINE_SHIFT_MM`].
+//!
+//! The range status says how far a range can be trusted. Ranges with no target count as out of
+//! range, ones the sensor could not vouch for are left out, and the share of ranges kept is the
+//! sensor's [`confidence`](CliffFilter::confidence).
+//!
+//! The tests replay range traces from `traces/`, one sample per line: the time in milliseconds,
+//! the range and the VL6180X error code, see `RangeStatus::from_code`.
+
+use crate::calibration::FLOOR_MM;
+use crate::drive::CLIFF_THRESHOLD;
+use crate::tof::RangeStatus;
+
+/// Most ranges the median can be taken of.
+pub const MAX_WINDOW: usize = 7;
+/// Ranges the median is taken of by default, one stray range is ignored.
+pub const WINDOW: u8 = 3;
+/// How far below the threshold a cliff ends by default.
+pub const HYSTERESIS_MM: u16 = 4;
+/// Furthest from [`FLOOR_MM`] a floor can be for the baseline to follow it.
+pub const MAX_BASELINE_SHIFT_MM: u16 = 5;
+/// Below this confidence, in percent, a sensor is not trusted.
+pub const MIN_CONFIDENCE: u8 = 50;
+/// What a VL6180X reports when nothing is within range.
+const OUT_OF_RANGE_MM: u16 = 255;
+/// Ranges the confidence is worked out over, one bit each.
+const HISTORY: u8 = 8;
+/// The baseline is kept in 1/256 mm, and moves 1/32 of the way to each floor range.
+const BASELINE_SCALE: i32 = 256;
+const BASELINE_RATE: i32 = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FilterConfig {
+    /// Ranges the median is taken of, from 1 to [`MAX_WINDOW`]. Of an even number, the larger
+    /// of the middle two is taken.
+    pub window: u8,
+    /// Ranges beyond this are cliffs, over a floor at [`FLOOR_MM`].
+    pub threshold_mm: u16,
+    /// A cliff ends once the range is this much below the threshold.
+    pub hysteresis_mm: u16,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            window: WINDOW,
+            threshold_mm: CLIFF_THRESHOLD,
+            hysteresis_mm: HYSTERESIS_MM,
+        }
+    }
+}
+
+/// Decides whether one sensor sees a cliff.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CliffFilter {
+    /// Latest ranges, `next` is the oldest once all `len` are filled.
+    ranges: [u16; MAX_WINDOW],
+    len: usize,
+    next: usize,
+    filtered: Option<u16>,
+    cliff: bool,
+    /// Floor height the sensor sees, in 1/256 mm.
+    baseline: i32,
+    /// Whether each of the latest `seen` ranges was reliable, newest in bit 0.
+    history: u8,
+    seen: u8,
+}
+
+impl Default for CliffFilter {
+    fn default() -> Self {
+        CliffFilter::new()
+    }
+}
+
+impl CliffFilter {
+    /// A cliff until the first range says otherwise.
+    pub const fn new() -> Self {
+        CliffFilter {
+            ranges: [0; MAX_WINDOW],
+            len: 0,
+            next: 0,
+            filtered: None,
+            cliff: true,
+            baseline: FLOOR_MM as i32 * BASELINE_SCALE,
+            history: 0,
+            seen: 0,
+        }
+    }
+
+    /// Takes the next range and its status.
+    pub fn push(&mut self, range_mm: u16, status: RangeStatus, config: &FilterConfig) {
+        let reliable = status != RangeStatus::Unreliable;
+        self.history = self.history << 1 | reliable as u8;
+        self.seen = (self.seen + 1).min(HISTORY);
+        let range_mm = match status {
+            RangeStatus::Valid => range_mm,
+            RangeStatus::NoTarget => OUT_OF_RANGE_MM,
+            RangeStatus::Underflow => 0,
+            RangeStatus::Unreliable => return,
+        };
+
+        self.ranges[self.next] = range_mm;
+        self.next = (self.next + 1) % MAX_WINDOW;
+        self.len = (self.len + 1).min(MAX_WINDOW);
+        let filtered = self.median(config.window);
+        self.filtered = Some(filtered);
+        self.decide(config);
+
+        // Follow the floor slowly, and only ever a floor close to the calibrated one, not the
+        // ranges of a sensor hovering over an edge
+        let near_floor = i32::from(filtered) - i32::from(FLOOR_MM);
+        if !self.cliff
+            && status == RangeStatus::Valid
+            && near_floor.abs() <= i32::from(MAX_BASELINE_SHIFT_MM)
+        {
+            let target = i32::from(filtered) * BASELINE_SCALE;
+            self.baseline += (target - self.baseline) / BASELINE_RATE;
+        }
+    }
+
+    /// Forgets the ranges after a failed read, a cliff until the sensor ranges again.
+    pub fn fail(&mut self) {
+        self.len = 0;
+        self.filtered = None;
+        self.cliff = true;
+    }
+
+    /// Decides again with a new config, without waiting for the next range.
+    pub fn reconfigure(&mut self, config: &FilterConfig) {
+        if self.len > 0 {
+            self.filtered = Some(self.median(config.window));
+            self.decide(config);
+        }
+    }
+
+    pub fn is_cliff(&self) -> bool {
+        self.cliff
+    }
+
+    /// Median of the latest ranges, `None` before the first one.
+    pub fn filtered_mm(&self) -> Option<u16> {
+        self.filtered
+    }
+
+    /// The floor height the thresholds are moved to, in millimetres.
+    pub fn baseline_mm(&self) -> u16 {
+        ((self.baseline + BASELINE_SCALE / 2) / BASELINE_SCALE) as u16
+    }
+
+    /// Where a cliff starts and where it ends again, over this sensor's floor.
+    pub fn thresholds(&self, config: &FilterConfig) -> (u16, u16) {
+        let shift = i32::from(self.baseline_mm()) - i32::from(FLOOR_MM);
+        let enter = (i32::from(config.threshold_mm) + shift).max(0);
+        let exit = (enter - i32::from(config.hysteresis_mm)).max(0);
+        (enter as u16, exit as u16)
+    }
+
+    /// Share of the latest ranges that could be trusted, in percent. 0 before the first one.
+    pub fn confidence(&self) -> u8 {
+        if self.seen == 0 {
+            return 0;
+        }
+        let mask = (1u16 << self.seen) - 1;
+        let reliable = (u16::from(self.history) & mask).count_ones() as u16;
+        (reliable * 100 / u16::from(self.seen)) as u8
+    }
+
+    fn median(&self, window: u8) -> u16 {
+        let n = usize::from(window).clamp(1, MAX_WINDOW).min(self.len);
+        let mut latest = [0; MAX_WINDOW];
+        for (i, range) in latest[..n].iter_mut().enumerate() {
+            *range = self.ranges[(self.next + MAX_WINDOW - 1 - i) % MAX_WINDOW];
+        }
+        let latest = &mut latest[..n];
+        latest.sort_unstable();
+        latest[n / 2]
+    }
+
+    fn decide(&mut self, config: &FilterConfig) {
+        if let Some(filtered) = self.filtered {
+            let (enter, exit) = self.thresholds(config);
+            if self.cliff {
+                self.cliff = filtered > exit;
+            } else {
+                self.cliff = filtered > enter;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replays a trace, returning the time of every sample and whether it left a cliff.
+    fn replay(filter: &mut CliffFilter, config: &FilterConfig, trace: &str) -> Vec<(u32, bool)> {
+        trace
+            .lines()
+            .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+            .map(|line| {
+                let fields: Vec<u32> = line.split(',').map(|f| f.trim().parse().unwrap()).collect();
+                let status = RangeStatus::from_code(fields[2] as u8);
+                filter.push(fields[1] as u16, status, config);
+                (fields[0], filter.is_cliff())
+            })
+            .collect()
+    }
+
+    /// Times at which the decision changed.
+    fn changes(decisions: &[(u32, bool)]) -> Vec<(u32, bool)> {
+        decisions
+            .windows(2)
+            .filter(|pair| pair[0].1 != pair[1].1)
+            .map(|pair| pair[1])
+            .collect()
+    }
+
+    #[test]
+    fn stray_ranges_are_ignored() {
+        let mut filter = CliffFilter::new();
+        let decisions = replay(
+            &mut filter,
+            &FilterConfig::default(),
+            include_str!("../traces/floor_spikes.csv"),
+        );
+        assert!(!decisions[0].1, "floor at the first range");
+        assert_eq!(changes(&decisions), []);
+
+        // Without the filter every spike would have been a cliff
+        let config = FilterConfig {
+            window: 1,
+            hysteresis_mm: 0,
+            ..FilterConfig::default()
+        };
+        let mut filter = CliffFilter::new();
+        let decisions = replay(
+            &mut filter,
+            &config,
+            include_str!("../traces/floor_spikes.csv"),
+        );
+        assert_eq!(changes(&decisions).len(), 6);
+    }
+
+    #[test]
+    fn finds_a_table_edge_one_range_later() {
+        let mut filter = CliffFilter::new();
+        let decisions = replay(
+            &mut filter,
+            &FilterConfig::default(),
+            include_str!("../traces/table_edge.csv"),
+        );
+        // Past 20 mm from 1060 ms on, and back under the 16 mm a cliff ends at from 2040 ms
+        assert_eq!(changes(&decisions), [(1080, true), (2060, false)]);
+    }
+
+    #[test]
+    fn hysteresis_holds_at_the_edge() {
+        let mut filter = CliffFilter::new();
+        let decisions = replay(
+            &mut filter,
+            &FilterConfig::default(),
+            include_str!("../traces/edge_dither.csv"),
+        );
+        // The range wanders around the threshold, the cliff is found once and kept
+        assert_eq!(changes(&decisions), [(220, true)]);
+
+        let config = FilterConfig {
+            hysteresis_mm: 0,
+            ..FilterConfig::default()
+        };
+        let mut filter = CliffFilter::new();
+        let decisions = replay(
+            &mut filter,
+            &config,
+            include_str!("../traces/edge_dither.csv"),
+        );
+        assert!(changes(&decisions).len() > 4);
+    }
+
+    #[test]
+    fn follows_a_thicker_floor() {
+        let mut filter = CliffFilter::new();
+        let config = FilterConfig::default();
+        let decisions = replay(&mut filter, &config, include_str!("../traces/carpet.csv"));
+        // Carpet pile reads 14 mm, a little under the 16 mm a cliff ends at
+        assert_eq!(changes(&decisions), []);
+        assert_eq!(filter.baseline_mm(), 14);
+        assert_eq!(filter.thresholds(&config), (24, 20));
+
+        // A floor further off than the baseline may shift is not followed
+        for _ in 0..200 {
+            filter.push(4, RangeStatus::Valid, &config);
+        }
+        assert_eq!(filter.baseline_mm(), 14);
+        for _ in 0..200 {
+            filter.push(
+                FLOOR_MM - MAX_BASELINE_SHIFT_MM,
+                RangeStatus::Valid,
+                &config,
+            );
+        }
+        assert_eq!(filter.baseline_mm(), FLOOR_MM - MAX_BASELINE_SHIFT_MM);
+    }
+
+    #[test]
+    fn confidence_from_the_range_status() {
+        let mut filter = CliffFilter::new();
+        let config = FilterConfig::default();
+        assert_eq!(filter.confidence(), 0);
+        let decisions = replay(&mut filter, &config, include_str!("../traces/sunlight.csv"));
+        // Ambient light drowns out five of the last eight ranges, the last good ones were floor
+        assert!(!decisions.last().unwrap().1);
+        assert_eq!(filter.confidence(), 37);
+
+        for _ in 0..8 {
+            filter.push(10, RangeStatus::Valid, &config);
+        }
+        assert_eq!(filter.confidence(), 100);
+
+        // No target is as good as a range, and a cliff
+        filter.push(0, RangeStatus::NoTarget, &config);
+        filter.push(0, RangeStatus::NoTarget, &config);
+        assert!(filter.is_cliff());
+        assert_eq!(filter.filtered_mm(), Some(OUT_OF_RANGE_MM));
+        assert_eq!(filter.confidence(), 100);
+    }
+
+    #[test]
+    fn medians() {
+        let config = FilterConfig {
+            window: 5,
+            ..FilterConfig::default()
+        };
+        let mut filter = CliffFilter::new();
+        for (range, median) in [(10, 10), (30, 30), (12, 12), (11, 12), (9, 11), (40, 12)] {
+            filter.push(range, RangeStatus::Valid, &config);
+            assert_eq!(filter.filtered_mm(), Some(median));
+        }
+
+        // A shorter window takes effect straight away
+        filter.reconfigure(&FilterConfig {
+            window: 1,
+            ..config
+        });
+        assert_eq!(filter.filtered_mm(), Some(40));
+        assert!(filter.is_cliff());
+
+        filter.fail();
+        assert_eq!(filter.filtered_mm(), None);
+        assert!(filter.is_cliff());
+    }
+}
@@ -0,0 +1,305 @@
+//! Health of the cliff sensors.
+//!
+//! Every reading is kept with the time it arrived, so a sensor that stops producing samples is
+//! noticed instead of its last "no cliff" being trusted forever. A position is only clear of
+//! cliffs while its sensor is healthy, confident in its ranges and sees the floor, as the sensor's
+//! [`CliffFilter`] decides.
+
+use crate::cliff_filter::{CliffFilter, FilterConfig, MIN_CONFIDENCE};
+use crate::drive::{Cliffs, Corner};
+use crate::tof::RangeStatus;
+
+/// How long a reading stays fresh by default, a little over three samples at the rover's sensor
+/// settings.
+pub const STALE_MS: u32 = 100;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SensorStatus {
+    /// Nothing read since start up.
+    NoData,
+    /// The latest reading is fresh.
+    Ok,
+    /// No reading for longer than the staleness timeout.
+    Stale,
+    /// The latest read failed.
+    Error,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Reading {
+    pub range_mm: u16,
+    /// When the reading arrived, in milliseconds.
+    pub at_ms: u32,
+}
+
+/// What the display and telemetry show for a sensor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SensorHealth {
+    pub status: SensorStatus,
+    pub last: Option<Reading>,
+    /// Failed reads since start up.
+    pub errors: u32,
+    /// The range the cliff is decided on, see `CliffFilter::filtered_mm`.
+    pub filtered_mm: Option<u16>,
+    /// Share of the latest ranges the sensor could vouch for, in percent.
+    pub confidence: u8,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+struct Sensor {
+    last: Option<Reading>,
+    failed: bool,
+    errors: u32,
+    filter: CliffFilter,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CliffMonitor {
+    sensors: [Sensor; 4],
+    stale_ms: u32,
+    filter_config: FilterConfig,
+}
+
+impl CliffMonitor {
+    /// Readings older than `stale_ms` are treated as cliffs. The ranges are filtered as
+    /// `FilterConfig::default` says until [`set_filter_config`](Self::set_filter_config).
+    pub fn new(stale_ms: u32) -> Self {
+        CliffMonitor {
+            sensors: [Sensor::default(); 4],
+            stale_ms,
+            filter_config: FilterConfig::default(),
+        }
+    }
+
+    pub fn stale_ms(&self) -> u32 {
+        self.stale_ms
+    }
+
+    pub fn set_stale_ms(&mut self, stale_ms: u32) {
+        self.stale_ms = stale_ms;
+    }
+
+    pub fn filter_config(&self) -> FilterConfig {
+        self.filter_config
+    }
+
+    /// Decides every position again with `config`, from the next [`cliffs`](Self::cliffs) on.
+    pub fn set_filter_config(&mut self, config: FilterConfig) {
+        self.filter_config = config;
+        for sensor in &mut self.sensors {
+            sensor.filter.reconfigure(&config);
+        }
+    }
+
+    /// Records a range read at `now_ms`, from the same free running clock as `DriveState::step`.
+    pub fn record(&mut self, corner: Corner, range_mm: u16, now_ms: u32) {
+        self.record_sample(corner, range_mm, RangeStatus::Valid, now_ms);
+    }
+
+    /// Records a range and the status the sensor read it with.
+    pub fn record_sample(
+        &mut self,
+        corner: Corner,
+        range_mm: u16,
+        status: RangeStatus,
+        now_ms: u32,
+    ) {
+        let sensor = &mut self.sensors[corner.index()];
+        sensor.last = Some(Reading {
+            range_mm,
+            at_ms: now_ms,
+        });
+        sensor.failed = false;
+        sensor.filter.push(range_mm, status, &self.filter_config);
+    }
+
+    /// Records a failed read. The position counts as a cliff until the next good reading.
+    pub fn record_error(&mut self, corner: Corner) {
+        let sensor = &mut self.sensors[corner.index()];
+        sensor.failed = true;
+        sensor.errors = sensor.errors.saturating_add(1);
+        sensor.filter.fail();
+    }
+
+    pub fn health(&self, corner: Corner, now_ms: u32) -> SensorHealth {
+        let sensor = &self.sensors[corner.index()];
+        let status = match sensor.last {
+            _ if sensor.failed => SensorStatus::Error,
+            None => SensorStatus::NoData,
+            Some(reading) if now_ms.wrapping_sub(reading.at_ms) > self.stale_ms => {
+                SensorStatus::Stale
+            }
+            Some(_) => SensorStatus::Ok,
+        };
+        SensorHealth {
+            status,
+            last: sensor.last,
+            errors: sensor.errors,
+            filtered_mm: sensor.filter.filtered_mm(),
+            confidence: sensor.filter.confidence(),
+        }
+    }
+
+    /// Whether every sensor has a fresh reading and is confident in its ranges.
+    pub fn healthy(&self, now_ms: u32) -> bool {
+        Corner::ALL.iter().all(|corner| {
+            let health = self.health(*corner, now_ms);
+            health.status == SensorStatus::Ok && health.confidence >= MIN_CONFIDENCE
+        })
+    }
+
+    /// Cliff flags, set for every position whose sensor is not healthy.
+    pub fn cliffs(&self, now_ms: u32) -> Cliffs {
+        let mut cliffs = Cliffs::NONE;
+        for corner in Corner::ALL {
+            let health = self.health(corner, now_ms);
+            let trusted = health.status == SensorStatus::Ok && health.confidence >= MIN_CONFIDENCE;
+            let cliff = !trusted || self.sensors[corner.index()].filter.is_cliff();
+            cliffs.set(corner, cliff);
+        }
+        cliffs
+    }
+}
+
+impl Default for CliffMonitor {
+    fn default() -> Self {
+        CliffMonitor::new(STALE_MS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::CLIFF_THRESHOLD;
+
+    /// A monitor with every sensor seeing the floor at `t = 0`.
+    fn on_floor() -> CliffMonitor {
+        let mut monitor = CliffMonitor::new(50);
+        for corner in Corner::ALL {
+            monitor.record(corner, 10, 0);
+        }
+        monitor
+    }
+
+    #[test]
+    fn no_data_is_a_cliff() {
+        let monitor = CliffMonitor::default();
+        assert_eq!(monitor.cliffs(0), Cliffs::ALL);
+        assert!(!monitor.healthy(0));
+        assert_eq!(
+            monitor.health(Corner::FrontLeft, 0).status,
+            SensorStatus::NoData
+        );
+    }
+
+    #[test]
+    fn fresh_readings() {
+        let mut monitor = on_floor();
+        assert_eq!(monitor.cliffs(50), Cliffs::NONE);
+        assert!(monitor.healthy(50));
+
+        // One range past the threshold is not enough, two of the last three are
+        monitor.record(Corner::FrontRight, 10, 20);
+        monitor.record(Corner::FrontRight, CLIFF_THRESHOLD + 1, 30);
+        assert_eq!(monitor.cliffs(50), Cliffs::NONE);
+        monitor.record(Corner::FrontRight, CLIFF_THRESHOLD + 1, 40);
+        let mut expected = Cliffs::NONE;
+        expected.fr = true;
+        assert_eq!(monitor.cliffs(50), expected);
+        assert_eq!(
+            monitor.health(Corner::FrontRight, 50).filtered_mm,
+            Some(CLIFF_THRESHOLD + 1)
+        );
+    }
+
+    #[test]
+    fn stale_reading_is_a_cliff() {
+        let mut monitor = on_floor();
+        for corner in [Corner::BackRight, Corner::FrontRight, Corner::BackLeft] {
+            monitor.record(corner, 10, 40);
+        }
+        assert_eq!(
+            monitor.health(Corner::FrontLeft, 51).status,
+            SensorStatus::Stale
+        );
+        assert!(!monitor.healthy(51));
+        let mut expected = Cliffs::NONE;
+        expected.fl = true;
+        assert_eq!(monitor.cliffs(51), expected);
+
+        // Fresh again with the next reading
+        monitor.record(Corner::FrontLeft, 10, 52);
+        assert!(monitor.healthy(52));
+    }
+
+    #[test]
+    fn errors_until_next_reading() {
+        let mut monitor = on_floor();
+        monitor.record_error(Corner::BackLeft);
+        monitor.record_error(Corner::BackLeft);
+        let health = monitor.health(Corner::BackLeft, 1);
+        assert_eq!(health.status, SensorStatus::Error);
+        assert_eq!(health.errors, 2);
+        assert!(monitor.cliffs(1).bl);
+
+        monitor.record(Corner::BackLeft, 10, 2);
+        let health = monitor.health(Corner::BackLeft, 2);
+        assert_eq!(health.status, SensorStatus::Ok);
+        assert_eq!(health.errors, 2);
+        assert!(!monitor.cliffs(2).bl);
+    }
+
+    #[test]
+    fn threshold_can_be_tuned() {
+        let mut monitor = on_floor();
+        let config = FilterConfig {
+            threshold_mm: 8,
+            ..FilterConfig::default()
+        };
+        monitor.set_filter_config(config);
+        assert_eq!(monitor.cliffs(0), Cliffs::ALL);
+        // The cliffs end once the floor is the hysteresis below the threshold
+        monitor.set_filter_config(FilterConfig {
+            threshold_mm: 13,
+            ..config
+        });
+        assert_eq!(monitor.cliffs(0), Cliffs::ALL);
+        monitor.set_filter_config(FilterConfig {
+            threshold_mm: 14,
+            ..config
+        });
+        assert_eq!(monitor.cliffs(0), Cliffs::NONE);
+        assert_eq!(monitor.filter_config().threshold_mm, 14);
+    }
+
+    #[test]
+    fn unreliable_ranges_are_not_trusted() {
+        let mut monitor = on_floor();
+        for t in 1..4 {
+            monitor.record_sample(Corner::FrontLeft, 10, RangeStatus::Unreliable, t);
+        }
+        // One in four ranges could be trusted
+        let health = monitor.health(Corner::FrontLeft, 3);
+        assert_eq!((health.status, health.confidence), (SensorStatus::Ok, 25));
+        assert!(!monitor.healthy(3));
+        let mut expected = Cliffs::NONE;
+        expected.fl = true;
+        assert_eq!(monitor.cliffs(3), expected);
+
+        for t in 4..8 {
+            monitor.record_sample(Corner::FrontLeft, 10, RangeStatus::Valid, t);
+        }
+        assert_eq!(monitor.health(Corner::FrontLeft, 7).confidence, 62);
+        assert_eq!(monitor.cliffs(7), Cliffs::NONE);
+    }
+
+    #[test]
+    fn staleness_across_clock_wrap_around() {
+        let mut monitor = CliffMonitor::new(50);
+        for corner in Corner::ALL {
+            monitor.record(corner, 10, u32::MAX - 10);
+        }
+        assert!(monitor.healthy(20));
+        assert!(!monitor.healthy(40));
+    }
+}
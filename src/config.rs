@@ -0,0 +1,495 @@
+//! Configuration that survives a reset, kept in flash.
+//!
+//! A [`RoverConfig`] is saved as a record: a header with the layout [`VERSION`] and payload
+//! length, a sequence number, the payload and a CRC-16 over all of it. Records are appended one
+//! after the other to one of two flash sectors, so most saves only program a few words, and a
+//! sector is only erased once the other one has taken over. The newest record with a good CRC
+//! wins, a record cut short by a reset falls back to the one before it.
+//!
+//! Erasing a sector stalls the CPU for up to half a second, longer than the watchdog allows, so
+//! [`ConfigStore::open`] does it at start up: a save while running only ever programs words,
+//! moving on to the sector erased at start up once the current one is full.
+//!
+//! The flash itself is behind the [`Flash`] trait, the rover's is in `flash`.
+
+use core::convert::TryFrom;
+
+use crate::settings::{Param, Settings};
+use crate::telemetry::crc16;
+use crate::tof::Correction;
+
+/// Bumped whenever the payload layout changes, e.g. with a new `Param`. Records of another
+/// version are ignored in favour of the defaults.
+pub const VERSION: u8 = 3;
+/// Bytes in the payload: every `Param` in `Param::ALL` order, then the sensor corrections.
+pub const PAYLOAD_LEN: usize = SETTINGS_LEN + 4 * CORRECTION_LEN;
+const SETTINGS_LEN: usize = Param::ALL.len() * 4;
+/// Range offset, then crosstalk.
+const CORRECTION_LEN: usize = 3;
+
+const MAGIC: [u8; 2] = *b"RC";
+/// Magic, version and payload length, then the sequence number.
+const HEADER_LEN: usize = 8;
+/// Records start on word boundaries, as flash is programmed a word at a time.
+const ALIGN: usize = 4;
+const RECORD_LEN: usize = record_len(PAYLOAD_LEN);
+/// Value of erased flash.
+const ERASED: u8 = 0xFF;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RoverConfig {
+    pub settings: Settings,
+    /// Of each cliff sensor, indexed by `Corner`, see `calibration`.
+    pub corrections: [Correction; 4],
+}
+
+impl RoverConfig {
+    pub fn to_payload(&self) -> [u8; PAYLOAD_LEN] {
+        let mut payload = [0; PAYLOAD_LEN];
+        for (chunk, param) in payload.chunks_exact_mut(4).zip(Param::ALL.iter()) {
+            chunk.copy_from_slice(&self.settings.get(*param).to_le_bytes());
+        }
+        for (chunk, correction) in payload[SETTINGS_LEN..]
+            .chunks_exact_mut(CORRECTION_LEN)
+            .zip(self.corrections.iter())
+        {
+            chunk[0] = correction.offset_mm as u8;
+            chunk[1..].copy_from_slice(&correction.crosstalk.to_le_bytes());
+        }
+        payload
+    }
+
+    /// `None` if a setting is out of range, or does not go with the others.
+    pub fn from_payload(payload: &[u8; PAYLOAD_LEN]) -> Option<Self> {
+        let mut settings = Settings::default();
+        for (chunk, param) in payload.chunks_exact(4).zip(Param::ALL.iter()) {
+            let value = i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            settings.put(*param, param.check(value).ok()?);
+        }
+        // Checked once all are in, as the defaults may not go with the saved ones
+        if !settings.is_valid() {
+            return None;
+        }
+        let mut corrections = [Correction::default(); 4];
+        for (correction, chunk) in corrections
+            .iter_mut()
+            .zip(payload[SETTINGS_LEN..].chunks_exact(CORRECTION_LEN))
+        {
+            *correction = Correction {
+                offset_mm: chunk[0] as i8,
+                crosstalk: u16::from_le_bytes([chunk[1], chunk[2]]),
+            };
+        }
+        Some(RoverConfig {
+            settings,
+            corrections,
+        })
+    }
+}
+
+/// Two equally sized flash sectors, indexed 0 and 1, that only the store uses.
+pub trait Flash {
+    type Error;
+
+    /// Bytes in each sector.
+    fn sector_len(&self) -> usize;
+
+    fn read(&self, sector: usize, offset: usize, buffer: &mut [u8]);
+
+    /// Programs erased bytes, from an `offset` on a word boundary.
+    fn program(&mut self, sector: usize, offset: usize, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Sets every byte of the sector to 0xFF, slowly.
+    fn erase(&mut self, sector: usize) -> Result<(), Self::Error>;
+}
+
+/// Where the config [`ConfigStore::open`] returned came from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Loaded {
+    Stored,
+    /// The defaults, nothing has been saved yet.
+    Empty,
+    /// The defaults, the newest record is from this other layout version.
+    Version(u8),
+    /// The defaults, no record passed its CRC and range checks.
+    Corrupt,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SaveError<E> {
+    /// Both sectors are full, the next start up makes room again.
+    Full,
+    Flash(E),
+}
+
+pub struct ConfigStore<F> {
+    flash: F,
+    /// Sector the next record goes to, at `next_offset`.
+    sector: usize,
+    next_offset: usize,
+    /// Whether the other sector is erased and ready to take over.
+    spare_erased: bool,
+    sequence: u32,
+    /// What the newest record holds.
+    saved: Option<RoverConfig>,
+}
+
+/// A record found while scanning a sector.
+struct Record {
+    version: u8,
+    sequence: u32,
+    /// `None` for a record of another version, or one with settings out of range.
+    config: Option<RoverConfig>,
+}
+
+impl<F: Flash> ConfigStore<F> {
+    /// Reads the newest config, falling back to the defaults, and erases the sector not in use
+    /// if needed, so the store is ready for saves that do not erase.
+    pub fn open(mut flash: F) -> Result<(Self, RoverConfig, Loaded), F::Error> {
+        // Newest record that passes its checks in either sector, and where each sector's
+        // records end
+        let mut newest: Option<(usize, Record)> = None;
+        let mut ends = [0; 2];
+        for (sector, end) in ends.iter_mut().enumerate() {
+            *end = scan(&flash, sector, |record| {
+                let usable = record.version != VERSION || record.config.is_some();
+                let newer = match &newest {
+                    None => true,
+                    Some((_, best)) => record.sequence > best.sequence,
+                };
+                if usable && newer {
+                    newest = Some((sector, record));
+                }
+            });
+        }
+
+        let (config, loaded) = match &newest {
+            Some((_, record)) if record.version != VERSION => {
+                (RoverConfig::default(), Loaded::Version(record.version))
+            }
+            Some((
+                _,
+                Record {
+                    config: Some(config),
+                    ..
+                },
+            )) => (*config, Loaded::Stored),
+            _ if ends != [0, 0] => (RoverConfig::default(), Loaded::Corrupt),
+            _ => (RoverConfig::default(), Loaded::Empty),
+        };
+
+        // Carry on after the newest record, or start afresh in the emptier sector
+        let sector = match &newest {
+            Some((sector, _)) => *sector,
+            None if ends[1] < ends[0] => 1,
+            None => 0,
+        };
+        let spare = 1 - sector;
+        if ends[spare] > 0 || !is_erased(&flash, spare, ends[spare]) {
+            flash.erase(spare)?;
+        }
+
+        let store = ConfigStore {
+            flash,
+            sector,
+            next_offset: ends[sector],
+            spare_erased: true,
+            sequence: newest.as_ref().map_or(0, |(_, record)| record.sequence),
+            saved: match loaded {
+                Loaded::Stored => Some(config),
+                _ => None,
+            },
+        };
+        Ok((store, config, loaded))
+    }
+
+    /// Saves `config`, unless it is what was saved last.
+    pub fn save(&mut self, config: &RoverConfig) -> Result<(), SaveError<F::Error>> {
+        if self.saved.as_ref() == Some(config) {
+            return Ok(());
+        }
+        if self.next_offset + RECORD_LEN > self.flash.sector_len() {
+            if !self.spare_erased {
+                return Err(SaveError::Full);
+            }
+            // The full sector is erased at the next start up
+            self.sector = 1 - self.sector;
+            self.next_offset = 0;
+            self.spare_erased = false;
+        }
+
+        let sequence = self.sequence.wrapping_add(1);
+        let mut record = [ERASED; RECORD_LEN];
+        record[..2].copy_from_slice(&MAGIC);
+        record[2] = VERSION;
+        record[3] = PAYLOAD_LEN as u8;
+        record[4..HEADER_LEN].copy_from_slice(&sequence.to_le_bytes());
+        record[HEADER_LEN..HEADER_LEN + PAYLOAD_LEN].copy_from_slice(&config.to_payload());
+        let crc_at = HEADER_LEN + PAYLOAD_LEN;
+        let crc = crc16(&record[..crc_at]);
+        record[crc_at..crc_at + 2].copy_from_slice(&crc.to_le_bytes());
+
+        // Whatever happens the space is used up, a partly programmed record is skipped later
+        let offset = self.next_offset;
+        self.next_offset += RECORD_LEN;
+        self.flash
+            .program(self.sector, offset, &record)
+            .map_err(SaveError::Flash)?;
+        self.sequence = sequence;
+        self.saved = Some(*config);
+        Ok(())
+    }
+
+    /// Gives the flash back.
+    pub fn release(self) -> F {
+        self.flash
+    }
+}
+
+/// Bytes a record with a payload of `payload_len` takes up, CRC and padding included.
+const fn record_len(payload_len: usize) -> usize {
+    (HEADER_LEN + payload_len + 2 + ALIGN - 1) / ALIGN * ALIGN
+}
+
+/// Hands every record in `sector` to `found`, and returns where the records end.
+fn scan<F: Flash>(flash: &F, sector: usize, mut found: impl FnMut(Record)) -> usize {
+    let sector_len = flash.sector_len();
+    let mut offset = 0;
+    while offset + HEADER_LEN <= sector_len {
+        let mut header = [0; HEADER_LEN];
+        flash.read(sector, offset, &mut header);
+        if header.iter().all(|&byte| byte == ERASED) {
+            return offset;
+        }
+        let payload_len = usize::from(header[3]);
+        let len = record_len(payload_len);
+        if header[..2] != MAGIC || offset + len > sector_len {
+            // Cut short while programming the header, nothing after it can be trusted
+            return sector_len;
+        }
+
+        let mut record = [0; 256 + HEADER_LEN + 2];
+        let record = &mut record[..HEADER_LEN + payload_len + 2];
+        flash.read(sector, offset, record);
+        let crc_at = HEADER_LEN + payload_len;
+        if crc16(&record[..crc_at]) == u16::from_le_bytes([record[crc_at], record[crc_at + 1]]) {
+            let version = header[2];
+            let config = match <&[u8; PAYLOAD_LEN]>::try_from(&record[HEADER_LEN..crc_at]) {
+                Ok(payload) if version == VERSION => RoverConfig::from_payload(payload),
+                _ => None,
+            };
+            found(Record {
+                version,
+                sequence: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+                config,
+            });
+        }
+        offset += len;
+    }
+    sector_len
+}
+
+/// Whether `sector` is erased from `offset` on.
+fn is_erased<F: Flash>(flash: &F, sector: usize, mut offset: usize) -> bool {
+    let mut buffer = [0; 64];
+    while offset < flash.sector_len() {
+        let len = buffer.len().min(flash.sector_len() - offset);
+        flash.read(sector, offset, &mut buffer[..len]);
+        if buffer[..len].iter().any(|&byte| byte != ERASED) {
+            return false;
+        }
+        offset += len;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flash in RAM that, like the real thing, can only clear bits until erased.
+    #[derive(Debug, Clone)]
+    struct RamFlash {
+        sectors: [Vec<u8>; 2],
+        erases: [u32; 2],
+        /// Bytes left to program before "losing power".
+        power: Option<usize>,
+    }
+
+    impl RamFlash {
+        fn new(sector_len: usize) -> Self {
+            RamFlash {
+                sectors: [vec![ERASED; sector_len], vec![ERASED; sector_len]],
+                erases: [0; 2],
+                power: None,
+            }
+        }
+    }
+
+    impl Flash for RamFlash {
+        type Error = ();
+
+        fn sector_len(&self) -> usize {
+            self.sectors[0].len()
+        }
+
+        fn read(&self, sector: usize, offset: usize, buffer: &mut [u8]) {
+            buffer.copy_from_slice(&self.sectors[sector][offset..offset + buffer.len()]);
+        }
+
+        fn program(&mut self, sector: usize, offset: usize, bytes: &[u8]) -> Result<(), ()> {
+            assert_eq!(offset % ALIGN, 0);
+            for (i, &byte) in bytes.iter().enumerate() {
+                if let Some(power) = &mut self.power {
+                    if *power == 0 {
+                        return Err(());
+                    }
+                    *power -= 1;
+                }
+                self.sectors[sector][offset + i] &= byte;
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self, sector: usize) -> Result<(), ()> {
+            self.sectors[sector].fill(ERASED);
+            self.erases[sector] += 1;
+            Ok(())
+        }
+    }
+
+    fn config(cliff_threshold_mm: u16) -> RoverConfig {
+        let mut config = RoverConfig::default();
+        config.settings.cliff_threshold_mm = cliff_threshold_mm;
+        config.corrections[0].offset_mm = -3;
+        config.corrections[2] = Correction {
+            offset_mm: 127,
+            crosstalk: 0x1234,
+        };
+        config
+    }
+
+    #[test]
+    fn payload_round_trip() {
+        let config = config(33);
+        assert_eq!(
+            RoverConfig::from_payload(&config.to_payload()),
+            Some(config)
+        );
+
+        // Out of range settings are refused
+        let mut payload = config.to_payload();
+        payload[..4].copy_from_slice(&1000i32.to_le_bytes());
+        assert_eq!(RoverConfig::from_payload(&payload), None);
+
+        // And so are settings that do not go together, a period longer than `stale_ms`
+        let mut config = RoverConfig::default();
+        config.settings.stale_ms = 300;
+        config.settings.inter_measurement_ms = 250;
+        assert_eq!(
+            RoverConfig::from_payload(&config.to_payload()),
+            Some(config)
+        );
+        config.settings.stale_ms = 200;
+        assert_eq!(RoverConfig::from_payload(&config.to_payload()), None);
+    }
+
+    #[test]
+    fn starts_with_the_defaults() {
+        let (_, loaded_config, loaded) = ConfigStore::open(RamFlash::new(256)).unwrap();
+        assert_eq!(loaded_config, RoverConfig::default());
+        assert_eq!(loaded, Loaded::Empty);
+    }
+
+    #[test]
+    fn loads_the_newest_save() {
+        let (mut store, _, _) = ConfigStore::open(RamFlash::new(256)).unwrap();
+        store.save(&config(30)).unwrap();
+        store.save(&config(31)).unwrap();
+
+        let (_, loaded_config, loaded) = ConfigStore::open(store.release()).unwrap();
+        assert_eq!((loaded_config, loaded), (config(31), Loaded::Stored));
+    }
+
+    #[test]
+    fn unchanged_config_is_not_written_again() {
+        let (mut store, _, _) = ConfigStore::open(RamFlash::new(256)).unwrap();
+        store.save(&config(30)).unwrap();
+        store.save(&config(30)).unwrap();
+        assert_eq!(store.next_offset, RECORD_LEN);
+    }
+
+    #[test]
+    fn takes_turns_between_sectors() {
+        // Room for two records per sector
+        let mut flash = RamFlash::new(2 * RECORD_LEN + 8);
+        let mut last = None;
+        let mut threshold = 20;
+        for _ in 0..4 {
+            let (mut store, loaded_config, _) = ConfigStore::open(flash).unwrap();
+            if let Some(last) = last {
+                assert_eq!(loaded_config, last);
+            }
+            // Saves fill up the current sector and then the erased one, without erasing
+            let mut saved = 0;
+            while store.save(&config(threshold)).is_ok() {
+                last = Some(config(threshold));
+                threshold += 1;
+                saved += 1;
+            }
+            assert!(saved >= 2);
+            assert_eq!(store.save(&config(threshold)), Err(SaveError::Full));
+            flash = store.release();
+        }
+        // Only every start up after the first erases, one sector each time
+        assert_eq!(flash.erases[0] + flash.erases[1], 3);
+    }
+
+    #[test]
+    fn falls_back_to_the_record_before_a_torn_one() {
+        let (mut store, _, _) = ConfigStore::open(RamFlash::new(256)).unwrap();
+        store.save(&config(30)).unwrap();
+        let mut flash = store.release();
+
+        // Power lost halfway through the next save
+        flash.power = Some(RECORD_LEN / 2);
+        let (mut store, _, _) = ConfigStore::open(flash).unwrap();
+        assert_eq!(store.save(&config(31)), Err(SaveError::Flash(())));
+        let mut flash = store.release();
+        flash.power = None;
+
+        let (mut store, loaded_config, loaded) = ConfigStore::open(flash).unwrap();
+        assert_eq!((loaded_config, loaded), (config(30), Loaded::Stored));
+        // The next save goes after the torn record
+        store.save(&config(32)).unwrap();
+        let (_, loaded_config, _) = ConfigStore::open(store.release()).unwrap();
+        assert_eq!(loaded_config, config(32));
+    }
+
+    #[test]
+    fn corrupt_or_other_version_loads_the_defaults() {
+        let (mut store, _, _) = ConfigStore::open(RamFlash::new(256)).unwrap();
+        store.save(&config(30)).unwrap();
+        let mut flash = store.release();
+        flash.sectors[0][HEADER_LEN] ^= 0x01;
+        let (_, loaded_config, loaded) = ConfigStore::open(flash.clone()).unwrap();
+        assert_eq!(
+            (loaded_config, loaded),
+            (RoverConfig::default(), Loaded::Corrupt)
+        );
+
+        // A record of a later layout, with a good CRC
+        let mut flash = RamFlash::new(256);
+        let mut record = [ERASED; 12];
+        record[..4].copy_from_slice(&[b'R', b'C', VERSION + 1, 2]);
+        record[4..8].copy_from_slice(&7u32.to_le_bytes());
+        let crc = crc16(&record[..10]);
+        record[10..12].copy_from_slice(&crc.to_le_bytes());
+        flash.program(1, 0, &record).unwrap();
+        let (_, loaded_config, loaded) = ConfigStore::open(flash).unwrap();
+        assert_eq!(
+            (loaded_config, loaded),
+            (RoverConfig::default(), Loaded::Version(VERSION + 1))
+        );
+    }
+}
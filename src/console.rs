@@ -0,0 +1,423 @@
+//! Line based command console on the serial port, for tuning the rover without reflashing.
+//!
+//! Received bytes are collected into lines by a [`LineEditor`] and [`parse`]d into a
+//! [`Command`]. The firmware carries the command out and answers with the `write_*` helpers:
+//!
+//! | Command                | Does                                                       |
+//! |------------------------|------------------------------------------------------------|
+//! | `help`                 | lists the commands                                         |
+//! | `get [PARAM]`          | shows a setting, or all of them, see `settings::Param`      |
+//! | `set PARAM VALUE`      | changes a setting                                          |
+//! | `save`                 | keeps the settings over a reset, see `config`              |
+//! | `tof status`           | health, latest and filtered range of every cliff sensor    |
+//! | `motor a\|b PERCENT`   | runs a motor at a duty from -100 to 100, see [`DriveMode`] |
+//! | `drive stop`           | stops the rover until `drive start`                        |
+//! | `drive start`          | hands the motors back to the cliff avoidance               |
+//! | `i2c scan`             | lists the addresses that answer on the bus, after a stop   |
+//! | `telemetry on\|off`    | streams telemetry frames between the replies               |
+//!
+//! Replies share the serial port with the binary telemetry, so the firmware stops the telemetry
+//! whenever a command comes in. Nothing is echoed, use a terminal with local echo, e.g.
+//! `picocom -b 115200 --echo /dev/ttyUSB0`.
+
+use core::fmt::{self, Write};
+
+use heapless::String;
+
+use crate::cliff_monitor::CliffMonitor;
+use crate::drive::Corner;
+use crate::motor::FULL_SPEED;
+use crate::settings::{OutOfRange, Param, Settings};
+
+/// Longest command line.
+pub const LINE_LEN: usize = 48;
+/// Longest reply, the help text being the longest.
+pub const REPLY_LEN: usize = 512;
+
+pub type Line = String<LINE_LEN>;
+pub type Reply = String<REPLY_LEN>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MotorChannel {
+    /// The right wheel.
+    A,
+    /// The left wheel.
+    B,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    /// One setting, or all of them.
+    Get(Option<Param>),
+    /// A setting and its new value, in range.
+    Set(Param, i32),
+    Save,
+    TofStatus,
+    /// A motor and its duty in percent, negative to reverse.
+    Motor(MotorChannel, i8),
+    DriveStop,
+    DriveStart,
+    I2cScan,
+    Telemetry(bool),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnknownCommand,
+    UnknownParam,
+    MissingArgument,
+    ExtraArgument,
+    BadNumber,
+    OutOfRange(OutOfRange),
+    /// The line did not fit in [`LINE_LEN`].
+    TooLong,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnknownCommand => write!(f, "unknown command, try help"),
+            ParseError::UnknownParam => write!(f, "unknown setting, try get"),
+            ParseError::MissingArgument => write!(f, "missing argument, try help"),
+            ParseError::ExtraArgument => write!(f, "too many arguments, try help"),
+            ParseError::BadNumber => write!(f, "not a whole number"),
+            ParseError::OutOfRange(range) => write!(f, "{}", range),
+            ParseError::TooLong => write!(f, "line longer than {} characters", LINE_LEN),
+        }
+    }
+}
+
+/// Parses a command line, words separated by spaces.
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    let mut words = line.split_ascii_whitespace();
+    let words = &mut words;
+
+    let command = match words.next().ok_or(ParseError::UnknownCommand)? {
+        "help" | "?" => Command::Help,
+        "get" => match words.next() {
+            Some(name) => Command::Get(Some(param(name)?)),
+            None => Command::Get(None),
+        },
+        "set" => {
+            let param = param(arg(words)?)?;
+            let value = number(arg(words)?)?;
+            Command::Set(param, param.check(value).map_err(ParseError::OutOfRange)?)
+        }
+        "save" => Command::Save,
+        "tof" => match arg(words)? {
+            "status" => Command::TofStatus,
+            _ => return Err(ParseError::UnknownCommand),
+        },
+        "motor" => {
+            let channel = match arg(words)? {
+                "a" => MotorChannel::A,
+                "b" => MotorChannel::B,
+                _ => return Err(ParseError::UnknownCommand),
+            };
+            let percent = number(arg(words)?)?;
+            if !(-100..=100).contains(&percent) {
+                return Err(ParseError::OutOfRange(OutOfRange {
+                    min: -100,
+                    max: 100,
+                    step: 1,
+                }));
+            }
+            Command::Motor(channel, percent as i8)
+        }
+        "drive" => match arg(words)? {
+            "stop" => Command::DriveStop,
+            "start" => Command::DriveStart,
+            _ => return Err(ParseError::UnknownCommand),
+        },
+        "i2c" => match arg(words)? {
+            "scan" => Command::I2cScan,
+            _ => return Err(ParseError::UnknownCommand),
+        },
+        "telemetry" => match arg(words)? {
+            "on" => Command::Telemetry(true),
+            "off" => Command::Telemetry(false),
+            _ => return Err(ParseError::UnknownCommand),
+        },
+        _ => return Err(ParseError::UnknownCommand),
+    };
+    match words.next() {
+        Some(_) => Err(ParseError::ExtraArgument),
+        None => Ok(command),
+    }
+}
+
+fn arg<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<&'a str, ParseError> {
+    words.next().ok_or(ParseError::MissingArgument)
+}
+
+fn param(name: &str) -> Result<Param, ParseError> {
+    Param::from_name(name).ok_or(ParseError::UnknownParam)
+}
+
+fn number(word: &str) -> Result<i32, ParseError> {
+    word.parse().map_err(|_| ParseError::BadNumber)
+}
+
+/// Collects received bytes into lines.
+#[derive(Debug, Default, Clone)]
+pub struct LineEditor {
+    line: Line,
+    too_long: bool,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor::default()
+    }
+
+    /// Takes the next byte received. Returns the line once it ends in `\r` or `\n`, or
+    /// [`ParseError::TooLong`] if it did not fit. Backspace and delete remove the last
+    /// character, other control characters and blank lines are ignored.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Line, ParseError>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = core::mem::take(&mut self.line);
+                if core::mem::replace(&mut self.too_long, false) {
+                    Some(Err(ParseError::TooLong))
+                } else if line.trim().is_empty() {
+                    None
+                } else {
+                    Some(Ok(line))
+                }
+            }
+            0x08 | 0x7f => {
+                self.line.pop();
+                None
+            }
+            b' '..=b'~' => {
+                if self.line.push(byte as char).is_err() {
+                    self.too_long = true;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Who drives the motors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DriveMode {
+    /// The cliff avoidance.
+    Auto,
+    /// Nobody, the rover stands still.
+    Stopped,
+    /// The console, at these signed speeds of channels `a` and `b` out of `FULL_SPEED`, ramped
+    /// to like any other. Cliffs are not avoided, this is for trying the motors with the wheels
+    /// off the ground.
+    Manual([i16; 2]),
+}
+
+impl DriveMode {
+    /// Runs `channel` at `percent` of full duty. The other channel keeps its duty if already
+    /// under manual control, and stands still otherwise.
+    pub fn with_motor(self, channel: MotorChannel, percent: i8) -> DriveMode {
+        let mut speeds = match self {
+            DriveMode::Manual(speeds) => speeds,
+            _ => [0; 2],
+        };
+        speeds[channel as usize] = i16::from(percent) * (FULL_SPEED / 100);
+        DriveMode::Manual(speeds)
+    }
+}
+
+pub fn write_help(w: &mut impl Write) -> fmt::Result {
+    write!(
+        w,
+        "help                  this list\r\n\
+         get [PARAM]           show settings\r\n\
+         set PARAM VALUE       change a setting\r\n\
+         save                  keep the settings over a reset\r\n\
+         tof status            cliff sensor health\r\n\
+         motor a|b PERCENT     run a motor, -100 to 100\r\n\
+         drive stop|start      stop or resume driving\r\n\
+         i2c scan              list devices on the bus, once stopped\r\n\
+         telemetry on|off      stream telemetry\r\n"
+    )
+}
+
+pub fn write_setting(w: &mut impl Write, settings: &Settings, param: Param) -> fmt::Result {
+    write!(
+        w,
+        "{} = {} {}\r\n",
+        param.name(),
+        settings.get(param),
+        param.unit()
+    )
+}
+
+pub fn write_settings(w: &mut impl Write, settings: &Settings) -> fmt::Result {
+    for param in Param::ALL {
+        write_setting(w, settings, param)?;
+    }
+    Ok(())
+}
+
+/// One line per sensor, with `active` telling which ones are booted rather than held in reset.
+pub fn write_tof_status(
+    w: &mut impl Write,
+    monitor: &CliffMonitor,
+    active: [bool; 4],
+    now_ms: u32,
+) -> fmt::Result {
+    for corner in Corner::ALL {
+        let index = corner.index();
+        let health = monitor.health(corner, now_ms);
+        write!(w, "{} {:?}", corner.name(), health.status)?;
+        if let Some(reading) = health.last {
+            write!(
+                w,
+                ", {} mm {} ms ago",
+                reading.range_mm,
+                now_ms.wrapping_sub(reading.at_ms)
+            )?;
+            if let Some(filtered) = health.filtered_mm {
+                write!(w, ", filtered {} mm", filtered)?;
+            }
+            write!(w, ", confidence {}%", health.confidence)?;
+        }
+        write!(w, ", errors {}", health.errors)?;
+        if !active[index] {
+            write!(w, ", in reset")?;
+        }
+        write!(w, "\r\n")?;
+    }
+    Ok(())
+}
+
+pub fn write_scan(w: &mut impl Write, addresses: impl Iterator<Item = u8>) -> fmt::Result {
+    let mut found = false;
+    for address in addresses {
+        write!(w, "{}0x{:02x}", if found { " " } else { "" }, address)?;
+        found = true;
+    }
+    if !found {
+        write!(w, "nothing answered")?;
+    }
+    write!(w, "\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(editor: &mut LineEditor, bytes: &[u8]) -> Vec<Result<Line, ParseError>> {
+        bytes.iter().filter_map(|&byte| editor.push(byte)).collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("help"), Ok(Command::Help));
+        assert_eq!(parse("get"), Ok(Command::Get(None)));
+        assert_eq!(
+            parse("get cliff_threshold"),
+            Ok(Command::Get(Some(Param::CliffThreshold)))
+        );
+        assert_eq!(
+            parse("  set   turn_ms 1200 "),
+            Ok(Command::Set(Param::TurnMs, 1200))
+        );
+        assert_eq!(parse("save"), Ok(Command::Save));
+        assert_eq!(parse("tof status"), Ok(Command::TofStatus));
+        assert_eq!(parse("motor a 50"), Ok(Command::Motor(MotorChannel::A, 50)));
+        assert_eq!(
+            parse("motor b -100"),
+            Ok(Command::Motor(MotorChannel::B, -100))
+        );
+        assert_eq!(parse("drive stop"), Ok(Command::DriveStop));
+        assert_eq!(parse("drive start"), Ok(Command::DriveStart));
+        assert_eq!(parse("i2c scan"), Ok(Command::I2cScan));
+        assert_eq!(parse("telemetry off"), Ok(Command::Telemetry(false)));
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert_eq!(parse("fly"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("drive"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("drive fast"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("get speed"), Err(ParseError::UnknownParam));
+        assert_eq!(parse("set stale_ms"), Err(ParseError::MissingArgument));
+        assert_eq!(parse("set stale_ms 1.5"), Err(ParseError::BadNumber));
+        assert_eq!(
+            parse("set stale_ms 5"),
+            Err(ParseError::OutOfRange(OutOfRange {
+                min: 10,
+                max: 1000,
+                step: 1
+            }))
+        );
+        assert_eq!(
+            parse("motor a 101"),
+            Err(ParseError::OutOfRange(OutOfRange {
+                min: -100,
+                max: 100,
+                step: 1
+            }))
+        );
+        assert_eq!(parse("motor c 10"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("tof status now"), Err(ParseError::ExtraArgument));
+    }
+
+    #[test]
+    fn edits_lines() {
+        let mut editor = LineEditor::new();
+        let got = lines(&mut editor, b"\r\n  \rhelq\x7fp\r\nget\n");
+        assert_eq!(got, [Ok("help".into()), Ok("get".into())]);
+
+        // Control characters are dropped, an overlong line is reported once it ends
+        let mut long = [b'x'; LINE_LEN + 1].to_vec();
+        long.extend_from_slice(b"\r\x1btof status\r");
+        assert_eq!(
+            lines(&mut editor, &long),
+            [Err(ParseError::TooLong), Ok("tof status".into())]
+        );
+    }
+
+    #[test]
+    fn motors_under_manual_control() {
+        let mode = DriveMode::Auto.with_motor(MotorChannel::B, -30);
+        assert_eq!(mode, DriveMode::Manual([0, -300]));
+        let mode = mode.with_motor(MotorChannel::A, 100);
+        assert_eq!(mode, DriveMode::Manual([FULL_SPEED, -300]));
+        assert_eq!(
+            DriveMode::Stopped.with_motor(MotorChannel::A, 5),
+            DriveMode::Manual([50, 0])
+        );
+    }
+
+    #[test]
+    fn replies() {
+        let mut reply = Reply::new();
+        write_help(&mut reply).unwrap();
+        assert!(reply.starts_with("help "));
+
+        let mut reply = Reply::new();
+        write_settings(&mut reply, &Settings::default()).unwrap();
+        assert!(reply.starts_with("cliff_threshold = 20 mm\r\ncliff_hysteresis = 4 mm\r\n"));
+        assert_eq!(reply.lines().count(), Param::ALL.len());
+
+        let mut monitor = CliffMonitor::default();
+        monitor.record(Corner::FrontRight, 12, 90);
+        monitor.record_error(Corner::BackLeft);
+        let mut reply = Reply::new();
+        write_tof_status(&mut reply, &monitor, [true, true, true, false], 100).unwrap();
+        assert_eq!(
+            reply,
+            "BR NoData, errors 0\r\n\
+             FR Ok, 12 mm 10 ms ago, filtered 12 mm, confidence 100%, errors 0\r\n\
+             FL NoData, errors 0\r\n\
+             BL Error, errors 1, in reset\r\n"
+        );
+
+        let mut reply = Reply::new();
+        write_scan(&mut reply, [0x0a, 0x3c].iter().copied()).unwrap();
+        write_scan(&mut reply, core::iter::empty()).unwrap();
+        assert_eq!(reply, "0x0a 0x3c\r\nnothing answered\r\n");
+    }
+}
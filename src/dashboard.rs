@@ -0,0 +1,393 @@
+//! Live view of the rover on the display while it drives itself.
+//!
+//! The [`Dashboard`] screen draws the same [`Packet`] the telemetry streams: a top-down rover with
+//! a box at each corner, filled while that sensor sees a cliff, the range each sensor read beside
+//! it, the drive command and heading, and a bar for the duty of each wheel. [`draw`] puts the
+//! uptime and how often the idle loop goes round, see [`RateMeter`], in the status bar above it.
+//!
+//! Most of it stays the same from one redraw to the next, so on a `frame_buffer::BufferedDisplay`
+//! a redraw only sends the ranges, bars and counters that moved.
+
+use core::fmt::{self, Write};
+
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, Triangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use heapless::String;
+
+use crate::button::Gesture;
+use crate::cliff_monitor::SensorStatus;
+use crate::drive::{Command, Corner, Heading, TurnDirection};
+use crate::motor::FULL_SPEED;
+use crate::telemetry::{Packet, SensorTelemetry};
+use crate::ui::{self, Screen, StatusBar};
+
+/// Shortest time a rate is counted over, so it does not jump about between redraws.
+pub const RATE_WINDOW_MS: u32 = 1000;
+
+/// The rover's outline, front up, within the corner boxes.
+const CHASSIS: Rectangle = Rectangle::new(Point::new(26, 4), Size::new(20, 42));
+/// Side of the box at each corner.
+const CORNER_SIZE: u32 = 8;
+/// Where the ranges and bars start, right of the rover and its ranges.
+const INFO_X: i32 = 74;
+/// Where the duty bars are centred, half of [`BAR_WIDTH`] either side.
+const BAR_CENTRE_X: i32 = 104;
+const BAR_WIDTH: u32 = 44;
+const BAR_HEIGHT: u32 = 7;
+
+/// Counts how many times a second something happens, from a running count of it.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RateMeter {
+    count: u32,
+    at_ms: u32,
+    per_s: u32,
+}
+
+impl RateMeter {
+    /// Counts from 0 at 0 ms.
+    pub const fn new() -> Self {
+        RateMeter {
+            count: 0,
+            at_ms: 0,
+            per_s: 0,
+        }
+    }
+
+    /// Takes the running `count` at `now_ms`, returns the rate over the last
+    /// [`RATE_WINDOW_MS`] or more. Both may wrap around.
+    pub fn update(&mut self, count: u32, now_ms: u32) -> u32 {
+        let elapsed_ms = now_ms.wrapping_sub(self.at_ms);
+        if elapsed_ms >= RATE_WINDOW_MS {
+            let counted = u64::from(count.wrapping_sub(self.count));
+            self.per_s = (counted * 1000 / u64::from(elapsed_ms)) as u32;
+            self.count = count;
+            self.at_ms = now_ms;
+        }
+        self.per_s
+    }
+}
+
+/// The rover at a glance, see the module documentation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Dashboard {
+    packet: Packet,
+    loops_per_s: u32,
+}
+
+impl Dashboard {
+    /// Shows `packet`, with the idle loop going round `loops_per_s` times a second.
+    pub fn new(packet: Packet, loops_per_s: u32) -> Self {
+        Dashboard {
+            packet,
+            loops_per_s,
+        }
+    }
+
+    /// Where the box of `corner` goes, and the range beside it.
+    fn corner_box(corner: Corner) -> Rectangle {
+        let left = CHASSIS.top_left.x - CORNER_SIZE as i32 / 2;
+        let right = left + CHASSIS.size.width as i32;
+        let top = CHASSIS.top_left.y - CORNER_SIZE as i32 / 2;
+        let bottom = top + CHASSIS.size.height as i32;
+        let top_left = match corner {
+            Corner::FrontLeft => Point::new(left, top),
+            Corner::FrontRight => Point::new(right, top),
+            Corner::BackLeft => Point::new(left, bottom),
+            Corner::BackRight => Point::new(right, bottom),
+        };
+        Rectangle::new(top_left, Size::new(CORNER_SIZE, CORNER_SIZE))
+    }
+
+    fn draw_rover<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        CHASSIS
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(target)?;
+
+        // Which way it drives, an arrow at the front or the back
+        let centre = CHASSIS.center();
+        let (tip, base) = match self.packet.heading {
+            Heading::Forward => (-12, -4),
+            Heading::Reverse => (12, 4),
+        };
+        Triangle::new(
+            centre + Point::new(0, tip),
+            centre + Point::new(-5, base),
+            centre + Point::new(5, base),
+        )
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(target)?;
+
+        let open = PrimitiveStyleBuilder::new()
+            .stroke_color(BinaryColor::On)
+            .stroke_width(1)
+            .fill_color(BinaryColor::Off)
+            .build();
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        for corner in Corner::ALL {
+            let corner_box = Self::corner_box(corner);
+            let box_style = if self.packet.cliffs.get(corner) {
+                PrimitiveStyle::with_fill(BinaryColor::On)
+            } else {
+                open
+            };
+            corner_box.into_styled(box_style).draw(target)?;
+
+            // The range on the outside of the box, level with it
+            let (x, alignment) = match corner {
+                Corner::FrontLeft | Corner::BackLeft => {
+                    (corner_box.top_left.x - 2, Alignment::Right)
+                }
+                Corner::FrontRight | Corner::BackRight => (
+                    corner_box.top_left.x + CORNER_SIZE as i32 + 2,
+                    Alignment::Left,
+                ),
+            };
+            let y = match corner {
+                Corner::FrontLeft | Corner::FrontRight => 0,
+                Corner::BackLeft | Corner::BackRight => {
+                    corner_box.top_left.y + CORNER_SIZE as i32 - 10
+                }
+            };
+            let mut range: String<8> = String::new();
+            write_range(&mut range, &self.packet.sensors[corner.index()]).ok();
+            Text::with_text_style(
+                &range,
+                Point::new(x, y),
+                style,
+                TextStyleBuilder::new()
+                    .alignment(alignment)
+                    .baseline(Baseline::Top)
+                    .build(),
+            )
+            .draw(target)?;
+        }
+        Ok(())
+    }
+
+    /// A bar from the middle, right for forward and left for reverse, labelled `label`.
+    fn draw_duty<D>(target: &mut D, label: &str, duty: i16, y: i32) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        Text::with_baseline(label, Point::new(INFO_X, y - 1), style, Baseline::Top).draw(target)?;
+
+        let half = BAR_WIDTH / 2;
+        let len = u32::from(duty.unsigned_abs().min(FULL_SPEED as u16)) * half / FULL_SPEED as u32;
+        let x = if duty < 0 {
+            BAR_CENTRE_X - len as i32
+        } else {
+            BAR_CENTRE_X
+        };
+        Rectangle::new(Point::new(x, y), Size::new(len, BAR_HEIGHT))
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(target)?;
+
+        // Ends and the middle of the scale
+        let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+        let bottom = y + BAR_HEIGHT as i32;
+        for (x, top) in [
+            (BAR_CENTRE_X - half as i32, y + 2),
+            (BAR_CENTRE_X, y - 1),
+            (BAR_CENTRE_X + half as i32, y + 2),
+        ] {
+            Line::new(Point::new(x, top), Point::new(x, bottom))
+                .into_styled(stroke)
+                .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+impl Screen for Dashboard {
+    /// Only ever looked at.
+    type Output = ();
+
+    fn press(&mut self, _gesture: Gesture) -> Option<()> {
+        None
+    }
+
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        self.draw_rover(target)?;
+
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        let command = match (self.packet.command, self.packet.turn_direction) {
+            (Command::Advance, _) => "Advance",
+            (Command::PreTurn, _) => "Back off",
+            (Command::Turn, TurnDirection::Left) => "Turn L",
+            (Command::Turn, TurnDirection::Right) => "Turn R",
+            (Command::Standby, _) => "Standby",
+        };
+        let heading = match self.packet.heading {
+            Heading::Forward => "Forward",
+            Heading::Reverse => "Reverse",
+        };
+        Text::with_baseline(command, Point::new(INFO_X, 0), style, Baseline::Top).draw(target)?;
+        Text::with_baseline(heading, Point::new(INFO_X, 10), style, Baseline::Top).draw(target)?;
+
+        // Channel `a` drives the right wheel, `b` the left
+        let [a, b] = self.packet.duties;
+        Self::draw_duty(target, "R", a, 26)?;
+        Self::draw_duty(target, "L", b, 38)
+    }
+}
+
+/// Clears `target` and draws the dashboard, under the uptime and the loop rate.
+pub fn draw<D>(target: &mut D, dashboard: &Dashboard) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let mut uptime: String<16> = String::new();
+    write!(uptime, "Up ").ok();
+    write_uptime(&mut uptime, dashboard.packet.at_ms).ok();
+    let mut rate: String<16> = String::new();
+    write!(rate, "{}/s", dashboard.loops_per_s).ok();
+    ui::draw(target, &StatusBar::new(&uptime, &rate), dashboard)
+}
+
+/// `ms` as hours, minutes and seconds, e.g. `1:02:03`.
+pub fn write_uptime(w: &mut impl Write, ms: u32) -> fmt::Result {
+    let s = ms / 1000;
+    write!(w, "{}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+}
+
+/// The range of a sensor in at most 3 characters, or what is wrong with it.
+fn write_range(w: &mut impl Write, sensor: &SensorTelemetry) -> fmt::Result {
+    match sensor.status {
+        SensorStatus::NoData => write!(w, "--"),
+        SensorStatus::Error => write!(w, "err"),
+        SensorStatus::Ok | SensorStatus::Stale => write!(w, "{}", sensor.range_mm.min(999)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cliff_monitor::CliffMonitor;
+    use crate::drive::{DriveConfig, DriveState};
+    use crate::motor::ChannelOutput;
+    use crate::supervisor::ResetCause;
+    use crate::ui::Frame;
+
+    /// First row of the body, under the status bar.
+    const TOP: usize = ui::BODY.top_left.y as usize;
+
+    fn packet(now_ms: u32, monitor: &CliffMonitor, duties: (i16, i16)) -> Packet {
+        let outputs = (
+            ChannelOutput::from_signed(duties.0),
+            ChannelOutput::from_signed(duties.1),
+        );
+        let drive_state = DriveState::new(DriveConfig::default());
+        Packet::snapshot(
+            0,
+            now_ms,
+            monitor,
+            &drive_state,
+            outputs,
+            ResetCause::PowerOn,
+        )
+    }
+
+    #[test]
+    fn rate_over_a_second() {
+        let mut meter = RateMeter::new();
+        assert_eq!(meter.update(400, 500), 0);
+        assert_eq!(meter.update(1000, 1000), 1000);
+        // Held until the next second is up
+        assert_eq!(meter.update(5000, 1250), 1000);
+        assert_eq!(meter.update(6000, 2500), 3333);
+
+        // Across both wrapping around
+        let mut meter = RateMeter {
+            count: u32::MAX - 99,
+            at_ms: u32::MAX - 999,
+            per_s: 0,
+        };
+        assert_eq!(meter.update(100, 1000), 100);
+    }
+
+    #[test]
+    fn uptime() {
+        let mut text = std::string::String::new();
+        write_uptime(&mut text, 3_723_999).unwrap();
+        assert_eq!(text, "1:02:03");
+        let mut text = std::string::String::new();
+        write_uptime(&mut text, 59_000).unwrap();
+        assert_eq!(text, "0:00:59");
+    }
+
+    #[test]
+    fn ranges() {
+        let mut text = std::string::String::new();
+        let sensor = |status, range_mm| SensorTelemetry {
+            status,
+            range_mm,
+            age_ms: 0,
+        };
+        for (status, range_mm) in [
+            (SensorStatus::NoData, 0),
+            (SensorStatus::Ok, 12),
+            (SensorStatus::Stale, 1200),
+            (SensorStatus::Error, 10),
+        ] {
+            write_range(&mut text, &sensor(status, range_mm)).unwrap();
+            text.push(' ');
+        }
+        assert_eq!(text, "-- 12 999 err ");
+    }
+
+    #[test]
+    fn draws_the_cliffs_and_duties() {
+        let mut monitor = CliffMonitor::default();
+        for corner in Corner::ALL {
+            monitor.record(corner, 10, 0);
+        }
+        monitor.record(Corner::FrontLeft, 255, 0);
+        let dashboard = Dashboard::new(packet(0, &monitor, (1000, -500)), 123_456);
+        let mut frame = Frame::new();
+        draw(&mut frame, &dashboard).unwrap();
+        assert_eq!(frame.outside, 0);
+
+        // Only the front left box is filled
+        let inside = |corner| {
+            let centre = Dashboard::corner_box(corner).center();
+            frame.lit(centre.x as usize, TOP + centre.y as usize)
+        };
+        assert!(inside(Corner::FrontLeft));
+        assert!(!inside(Corner::FrontRight));
+        assert!(!inside(Corner::BackLeft));
+        assert!(!inside(Corner::BackRight));
+
+        // The right wheel full ahead, the left half back
+        let middle = BAR_CENTRE_X as usize;
+        let half = BAR_WIDTH as usize / 2;
+        let row = TOP + 26 + 1;
+        assert!(frame.lit(middle + half - 1, row) && !frame.lit(middle - 2, row));
+        let row = TOP + 38 + 1;
+        assert!(frame.lit(middle - half / 2, row) && !frame.lit(middle - half / 2 - 2, row));
+        assert!(!frame.lit(middle + 2, row));
+    }
+
+    #[test]
+    fn draws_inside_the_display() {
+        // Nothing read, every corner a cliff, at the longest uptime
+        let monitor = CliffMonitor::default();
+        let dashboard = Dashboard::new(packet(u32::MAX, &monitor, (-1000, 1000)), u32::MAX);
+        let mut frame = Frame::new();
+        draw(&mut frame, &dashboard).unwrap();
+        assert_eq!(frame.outside, 0);
+        // Nothing under the body
+        assert_eq!(frame.lit_in_row(0, ui::HEIGHT as usize - 1), 0);
+    }
+}
@@ -0,0 +1,244 @@
+//! Velocity commands for the two wheeled rover.
+//!
+//! [`DifferentialDrive`] turns a linear and angular velocity into a signed speed for each wheel,
+//! L298N channel `a` driving the right wheel and channel `b` the left, and hands them to a
+//! [`MotorRamp`]. Behaviours command velocities, including arcs, and never switch the channels
+//! themselves.
+
+use crate::drive::MotorCommand;
+use crate::motor::{ChannelOutput, MotorRamp, RampConfig, FULL_SPEED};
+
+/// Body velocity of the rover.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Velocity {
+    /// Forward speed, in metres per second.
+    pub linear: f32,
+    /// Anticlockwise turn rate seen from above, in radians per second.
+    pub angular: f32,
+}
+
+impl Velocity {
+    pub const STOP: Velocity = Velocity {
+        linear: 0.0,
+        angular: 0.0,
+    };
+
+    pub fn straight(linear: f32) -> Self {
+        Velocity {
+            linear,
+            angular: 0.0,
+        }
+    }
+
+    /// Spins on the spot.
+    pub fn spin(angular: f32) -> Self {
+        Velocity {
+            linear: 0.0,
+            angular,
+        }
+    }
+
+    /// Drives along a circle of `radius` metres, curving left for a positive radius and right
+    /// for a negative one.
+    pub fn arc(linear: f32, radius: f32) -> Self {
+        Velocity {
+            linear,
+            angular: linear / radius,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DiffDriveConfig {
+    /// Distance between the wheels, in metres.
+    pub track: f32,
+    /// Wheel speed at full duty, in metres per second.
+    pub max_wheel_speed: f32,
+    /// Factor applied to channel `a`'s feedforward, see `pid::WheelSpeedController::set_trim`,
+    /// e.g. below 1 for the stronger motor. The wheel speeds asked for are not trimmed.
+    pub trim_a: f32,
+    /// Factor applied to channel `b`'s feedforward.
+    pub trim_b: f32,
+}
+
+impl Default for DiffDriveConfig {
+    fn default() -> Self {
+        DiffDriveConfig {
+            track: 0.1,
+            max_wheel_speed: 0.25,
+            trim_a: 1.0,
+            trim_b: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DifferentialDrive {
+    config: DiffDriveConfig,
+    ramp: MotorRamp,
+    velocity: Velocity,
+}
+
+impl DifferentialDrive {
+    /// Stopped.
+    pub fn new(config: DiffDriveConfig, ramp_config: RampConfig) -> Self {
+        DifferentialDrive {
+            config,
+            ramp: MotorRamp::new(ramp_config),
+            velocity: Velocity::STOP,
+        }
+    }
+
+    pub fn config(&self) -> DiffDriveConfig {
+        self.config
+    }
+
+    /// Takes effect with the next velocity.
+    pub fn set_config(&mut self, config: DiffDriveConfig) {
+        self.config = config;
+    }
+
+    pub fn ramp(&self) -> &MotorRamp {
+        &self.ramp
+    }
+
+    pub fn ramp_mut(&mut self) -> &mut MotorRamp {
+        &mut self.ramp
+    }
+
+    /// The velocity last asked for, before saturation.
+    pub fn velocity(&self) -> Velocity {
+        self.velocity
+    }
+
+    /// Fastest straight line speed, in metres per second.
+    pub fn max_linear(&self) -> f32 {
+        self.config.max_wheel_speed
+    }
+
+    /// Fastest spin on the spot, in radians per second.
+    pub fn max_angular(&self) -> f32 {
+        2.0 * self.config.max_wheel_speed / self.config.track
+    }
+
+    /// Sets the velocity to ramp towards. If a wheel would have to go faster than it can, both
+    /// wheels are slowed down by the same factor, so the rover still follows the same curve.
+    pub fn set_velocity(&mut self, velocity: Velocity) {
+        self.velocity = velocity;
+        let (a, b) = self.wheel_duties(velocity);
+        self.ramp.set_targets(to_speed(a), to_speed(b));
+    }
+
+    /// The velocities behind the drive state machine's motor commands, at full speed.
+    pub fn command(&mut self, command: MotorCommand) {
+        let velocity = match command {
+            MotorCommand::Stop => Velocity::STOP,
+            MotorCommand::Forward => Velocity::straight(self.max_linear()),
+            MotorCommand::Reverse => Velocity::straight(-self.max_linear()),
+            MotorCommand::SpinLeft => Velocity::spin(self.max_angular()),
+            MotorCommand::SpinRight => Velocity::spin(-self.max_angular()),
+        };
+        self.set_velocity(velocity);
+    }
+
+    /// Moves the wheels along their ramps, see [`MotorRamp::update`].
+    pub fn update(&mut self, now_ms: u32) -> (ChannelOutput, ChannelOutput) {
+        self.ramp.update(now_ms)
+    }
+
+    /// Signed speed of the right (`a`) and left (`b`) wheels as fractions of full speed,
+    /// saturated to 1.
+    fn wheel_duties(&self, velocity: Velocity) -> (f32, f32) {
+        let turn = velocity.angular * self.config.track / 2.0;
+        let right = (velocity.linear + turn) / self.config.max_wheel_speed;
+        let left = (velocity.linear - turn) / self.config.max_wheel_speed;
+        let largest = magnitude(right).max(magnitude(left));
+        if largest > 1.0 {
+            (right / largest, left / largest)
+        } else {
+            (right, left)
+        }
+    }
+}
+
+impl Default for DifferentialDrive {
+    fn default() -> Self {
+        DifferentialDrive::new(DiffDriveConfig::default(), RampConfig::default())
+    }
+}
+
+fn magnitude(x: f32) -> f32 {
+    if x < 0.0 {
+        -x
+    } else {
+        x
+    }
+}
+
+/// Rounds to the nearest thousandth of full speed.
+fn to_speed(duty: f32) -> i16 {
+    let speed = duty.clamp(-1.0, 1.0) * FULL_SPEED as f32;
+    if speed < 0.0 {
+        (speed - 0.5) as i16
+    } else {
+        (speed + 0.5) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(drive: &DifferentialDrive) -> (i16, i16) {
+        drive.ramp().targets()
+    }
+
+    #[test]
+    fn motor_commands() {
+        let mut drive = DifferentialDrive::default();
+        let cases = [
+            (MotorCommand::Stop, (0, 0)),
+            (MotorCommand::Forward, (1000, 1000)),
+            (MotorCommand::Reverse, (-1000, -1000)),
+            (MotorCommand::SpinLeft, (1000, -1000)),
+            (MotorCommand::SpinRight, (-1000, 1000)),
+        ];
+        for (command, expected) in cases {
+            drive.command(command);
+            assert_eq!(targets(&drive), expected, "{:?}", command);
+        }
+    }
+
+    #[test]
+    fn arc_turns() {
+        let mut drive = DifferentialDrive::default();
+        // Left wheel on a 0.05 m radius, right wheel on 0.15 m
+        drive.set_velocity(Velocity::arc(0.1, 0.1));
+        assert_eq!(targets(&drive), (600, 200));
+        drive.set_velocity(Velocity::arc(0.1, -0.1));
+        assert_eq!(targets(&drive), (200, 600));
+    }
+
+    #[test]
+    fn saturation_keeps_the_curve() {
+        let mut drive = DifferentialDrive::default();
+        drive.set_velocity(Velocity::arc(0.5, 0.1));
+        let (right, left) = targets(&drive);
+        assert_eq!(right, 1000);
+        assert_eq!(left, 333);
+        assert_eq!(drive.velocity(), Velocity::arc(0.5, 0.1));
+    }
+
+    #[test]
+    fn trim_leaves_the_speeds_alone() {
+        let mut drive = DifferentialDrive::new(
+            DiffDriveConfig {
+                trim_b: 0.9,
+                ..DiffDriveConfig::default()
+            },
+            RampConfig::default(),
+        );
+        drive.set_velocity(Velocity::straight(-0.125));
+        assert_eq!(targets(&drive), (-500, -500));
+    }
+}
@@ -0,0 +1,606 @@
+//! Cliff avoidance drive logic.
+//!
+//! The rover drives straight until one of its corner sensors sees a cliff, then backs away by
+//! reversing its heading for a while, spins on the spot and carries on in the new heading. If
+//! every sensor reports a cliff (e.g. the rover has been picked up) it stops and waits.
+//!
+//! Turns last a fixed time, or with wheel odometry until the rover has turned far enough.
+//!
+//! [`DriveState::step`] only decides what the motors should be doing, it never touches them, so
+//! the same state machine runs on the rover and in host unit tests.
+
+use crate::odometry::wrap_angle;
+
+/// Ranges above this many millimetres mean there is no floor under a sensor.
+pub const CLIFF_THRESHOLD: u16 = 20;
+
+/// Where a cliff sensor is mounted, in the order the sensors are indexed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Corner {
+    BackRight,
+    FrontRight,
+    FrontLeft,
+    BackLeft,
+}
+
+impl Corner {
+    pub const ALL: [Corner; 4] = [
+        Corner::BackRight,
+        Corner::FrontRight,
+        Corner::FrontLeft,
+        Corner::BackLeft,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Short name, e.g. `FL` for the front left.
+    pub fn name(self) -> &'static str {
+        match self {
+            Corner::BackRight => "BR",
+            Corner::FrontRight => "FR",
+            Corner::FrontLeft => "FL",
+            Corner::BackLeft => "BL",
+        }
+    }
+}
+
+/// Cliff flags for each corner of the rover, `true` meaning there is no floor under that sensor.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Cliffs {
+    /// Back right
+    pub br: bool,
+    /// Front right
+    pub fr: bool,
+    /// Front left
+    pub fl: bool,
+    /// Back left
+    pub bl: bool,
+}
+
+impl Cliffs {
+    /// No cliffs anywhere, the rover is safely on the table.
+    pub const NONE: Cliffs = Cliffs {
+        br: false,
+        fr: false,
+        fl: false,
+        bl: false,
+    };
+
+    /// Cliffs everywhere, the rover has been picked up (or has not seen any readings yet).
+    pub const ALL: Cliffs = Cliffs {
+        br: true,
+        fr: true,
+        fl: true,
+        bl: true,
+    };
+
+    pub fn get(&self, corner: Corner) -> bool {
+        match corner {
+            Corner::BackRight => self.br,
+            Corner::FrontRight => self.fr,
+            Corner::FrontLeft => self.fl,
+            Corner::BackLeft => self.bl,
+        }
+    }
+
+    pub fn set(&mut self, corner: Corner, cliff: bool) {
+        match corner {
+            Corner::BackRight => self.br = cliff,
+            Corner::FrontRight => self.fr = cliff,
+            Corner::FrontLeft => self.fl = cliff,
+            Corner::BackLeft => self.bl = cliff,
+        }
+    }
+
+    /// Whether at least one corner sees a cliff.
+    pub fn any(&self) -> bool {
+        self.br || self.fr || self.fl || self.bl
+    }
+
+    /// Whether every corner sees a cliff.
+    pub fn all(&self) -> bool {
+        self.br && self.fr && self.fl && self.bl
+    }
+
+    /// Whether either corner at the leading end of the rover sees a cliff when driving in
+    /// `heading`.
+    pub fn ahead(&self, heading: Heading) -> bool {
+        match heading {
+            Heading::Forward => self.fr || self.fl,
+            Heading::Reverse => self.br || self.bl,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Heading {
+    Forward,
+    Reverse,
+}
+
+impl Heading {
+    pub fn toggle(self) -> Heading {
+        match self {
+            Heading::Forward => Heading::Reverse,
+            Heading::Reverse => Heading::Forward,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TurnDirection {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Command {
+    /// Driving in the current heading.
+    Advance,
+    /// Backing away from a cliff in the (already toggled) heading before turning.
+    PreTurn,
+    /// Spinning on the spot.
+    Turn,
+    /// Stopped until all cliffs clear.
+    Standby,
+}
+
+/// What the two L298N channels should be doing. Spinning left drives channel `a` forward and
+/// channel `b` in reverse, spinning right the other way around.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MotorCommand {
+    Stop,
+    Forward,
+    Reverse,
+    SpinLeft,
+    SpinRight,
+}
+
+impl From<Heading> for MotorCommand {
+    fn from(heading: Heading) -> Self {
+        match heading {
+            Heading::Forward => MotorCommand::Forward,
+            Heading::Reverse => MotorCommand::Reverse,
+        }
+    }
+}
+
+impl From<TurnDirection> for MotorCommand {
+    fn from(turn_direction: TurnDirection) -> Self {
+        match turn_direction {
+            TurnDirection::Left => MotorCommand::SpinLeft,
+            TurnDirection::Right => MotorCommand::SpinRight,
+        }
+    }
+}
+
+/// How long the rover backs away from a cliff before turning, in milliseconds.
+pub const PRE_TURN_MS: u32 = 600;
+/// How long the rover spins before advancing again, in milliseconds.
+pub const TURN_MS: u32 = 900;
+
+/// Durations of the avoidance manoeuvre.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DriveConfig {
+    /// How long to back away from a cliff before turning, in milliseconds.
+    pub pre_turn_ms: u32,
+    /// How long to spin before advancing again, in milliseconds. With `turn_angle` the turn
+    /// gives up after this long, e.g. if the wheels slip.
+    pub turn_ms: u32,
+    /// How far to spin before advancing again, in radians less than `PI`, as the angle turned is
+    /// wrapped into `(-PI, PI]`. Only used with [`DriveState::step_with_yaw`].
+    pub turn_angle: Option<f32>,
+}
+
+impl Default for DriveConfig {
+    fn default() -> Self {
+        DriveConfig {
+            pre_turn_ms: PRE_TURN_MS,
+            turn_ms: TURN_MS,
+            turn_angle: None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DriveState {
+    config: DriveConfig,
+    command: Command,
+    heading: Heading,
+    turn_direction: TurnDirection,
+    motor_command: MotorCommand,
+    /// When the current `PreTurn` or `Turn` started, in milliseconds.
+    since_ms: u32,
+    /// Yaw when the current `Turn` started, in radians.
+    turn_start_yaw: Option<f32>,
+}
+
+impl DriveState {
+    /// Creates a drive state in `Standby`, heading forward.
+    pub fn new(config: DriveConfig) -> Self {
+        DriveState {
+            config,
+            command: Command::Standby,
+            heading: Heading::Forward,
+            turn_direction: TurnDirection::Left,
+            motor_command: MotorCommand::Stop,
+            since_ms: 0,
+            turn_start_yaw: None,
+        }
+    }
+
+    pub fn config(&self) -> DriveConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: DriveConfig) {
+        self.config = config;
+    }
+
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    pub fn heading(&self) -> Heading {
+        self.heading
+    }
+
+    pub fn turn_direction(&self) -> TurnDirection {
+        self.turn_direction
+    }
+
+    /// The motor command returned by the last [`step`](DriveState::step).
+    pub fn motor_command(&self) -> MotorCommand {
+        self.motor_command
+    }
+
+    /// Advances the state machine with the latest cliff readings.
+    ///
+    /// `now_ms` is the time in milliseconds from a free running clock, it may wrap around.
+    /// Returns what the motors should be doing from now on.
+    pub fn step(&mut self, cliffs: &Cliffs, now_ms: u32) -> MotorCommand {
+        self.step_with_yaw(cliffs, now_ms, None)
+    }
+
+    /// Like [`step`](DriveState::step), with the rover's yaw in radians from odometry, so turns
+    /// can end by angle when the config has a `turn_angle`.
+    pub fn step_with_yaw(
+        &mut self,
+        cliffs: &Cliffs,
+        now_ms: u32,
+        yaw: Option<f32>,
+    ) -> MotorCommand {
+        if !cliffs.any() {
+            if self.command == Command::Standby {
+                self.command = Command::Advance;
+                self.motor_command = self.heading.into();
+            }
+        } else {
+            if cliffs.all() {
+                self.command = Command::Standby;
+                self.motor_command = MotorCommand::Stop;
+            }
+
+            // A cliff behind the rover is one it is already driving away from, backing away from
+            // it would drive straight over it
+            if self.command == Command::Advance && cliffs.ahead(self.heading) {
+                self.heading = self.heading.toggle();
+                self.command = Command::PreTurn;
+                self.turn_direction = if cliffs.fr || cliffs.bl {
+                    TurnDirection::Right
+                } else {
+                    TurnDirection::Left
+                };
+                self.since_ms = now_ms;
+                self.motor_command = self.heading.into();
+            }
+        }
+
+        let elapsed_ms = now_ms.wrapping_sub(self.since_ms);
+        match self.command {
+            Command::PreTurn if elapsed_ms >= self.config.pre_turn_ms => {
+                self.command = Command::Turn;
+                self.since_ms = now_ms;
+                self.turn_start_yaw = yaw;
+                self.motor_command = self.turn_direction.into();
+            }
+            Command::Turn if elapsed_ms >= self.config.turn_ms || self.turned_far_enough(yaw) => {
+                self.command = Command::Advance;
+                self.motor_command = self.heading.into();
+            }
+            _ => (),
+        }
+
+        self.motor_command
+    }
+
+    fn turned_far_enough(&self, yaw: Option<f32>) -> bool {
+        match (self.config.turn_angle, self.turn_start_yaw, yaw) {
+            (Some(angle), Some(start), Some(yaw)) => {
+                let turned = wrap_angle(yaw - start);
+                turned >= angle || -turned >= angle
+            }
+            _ => false,
+        }
+    }
+
+    /// Stops the rover in `Standby`, e.g. while a sensor cannot be trusted. It sets off again
+    /// once a [`step`](DriveState::step) sees every cliff clear.
+    pub fn halt(&mut self) -> MotorCommand {
+        self.command = Command::Standby;
+        self.motor_command = MotorCommand::Stop;
+        self.motor_command
+    }
+}
+
+impl Default for DriveState {
+    fn default() -> Self {
+        DriveState::new(DriveConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: DriveConfig = DriveConfig {
+        pre_turn_ms: 10,
+        turn_ms: 20,
+        turn_angle: None,
+    };
+
+    /// A drive state that has just started advancing forward at `t = 0`.
+    fn advancing() -> DriveState {
+        let mut state = DriveState::new(CONFIG);
+        assert_eq!(state.step(&Cliffs::NONE, 0), MotorCommand::Forward);
+        assert_eq!(state.command(), Command::Advance);
+        state
+    }
+
+    /// A drive state that has just finished avoiding a cliff and is advancing in reverse at
+    /// `t = 30`.
+    fn reversing() -> DriveState {
+        let mut state = advancing();
+        state.step(&cliff(false, true, true, false), 0);
+        state.step(&Cliffs::NONE, CONFIG.pre_turn_ms);
+        assert_eq!(
+            state.step(&Cliffs::NONE, CONFIG.pre_turn_ms + CONFIG.turn_ms),
+            MotorCommand::Reverse
+        );
+        assert_eq!(state.command(), Command::Advance);
+        state
+    }
+
+    fn cliff(br: bool, fr: bool, fl: bool, bl: bool) -> Cliffs {
+        Cliffs { br, fr, fl, bl }
+    }
+
+    #[test]
+    fn waits_in_standby_until_all_cliffs_clear() {
+        let mut state = DriveState::new(CONFIG);
+        assert_eq!(state.step(&Cliffs::ALL, 0), MotorCommand::Stop);
+        assert_eq!(
+            state.step(&cliff(false, true, false, false), 1),
+            MotorCommand::Stop
+        );
+        assert_eq!(state.command(), Command::Standby);
+        assert_eq!(state.step(&Cliffs::NONE, 2), MotorCommand::Forward);
+        assert_eq!(state.command(), Command::Advance);
+    }
+
+    #[test]
+    fn halt_mid_manoeuvre() {
+        let mut state = advancing();
+        assert_eq!(
+            state.step(&cliff(false, true, false, false), 5),
+            MotorCommand::Reverse
+        );
+        assert_eq!(state.halt(), MotorCommand::Stop);
+        assert_eq!(state.command(), Command::Standby);
+        assert_eq!(state.step(&Cliffs::NONE, 100), MotorCommand::Reverse);
+        assert_eq!(state.command(), Command::Advance);
+    }
+
+    #[test]
+    fn turns_by_angle() {
+        let mut state = DriveState::new(DriveConfig {
+            turn_angle: Some(1.5),
+            ..CONFIG
+        });
+        let front = cliff(false, false, true, false);
+        state.step_with_yaw(&Cliffs::NONE, 0, Some(3.0));
+        assert_eq!(
+            state.step_with_yaw(&front, 100, Some(3.0)),
+            MotorCommand::Reverse
+        );
+        assert_eq!(
+            state.step_with_yaw(&Cliffs::NONE, 110, Some(3.0)),
+            MotorCommand::SpinLeft
+        );
+        // Turning left across the +-PI boundary
+        assert_eq!(
+            state.step_with_yaw(&Cliffs::NONE, 115, Some(-2.0)),
+            MotorCommand::SpinLeft
+        );
+        assert_eq!(
+            state.step_with_yaw(&Cliffs::NONE, 116, Some(-1.75)),
+            MotorCommand::Reverse
+        );
+        assert_eq!(state.command(), Command::Advance);
+    }
+
+    #[test]
+    fn angle_turn_times_out() {
+        let mut state = DriveState::new(DriveConfig {
+            turn_angle: Some(1.5),
+            ..CONFIG
+        });
+        state.step_with_yaw(&Cliffs::NONE, 0, Some(0.0));
+        state.step_with_yaw(&cliff(false, true, false, false), 100, Some(0.0));
+        assert_eq!(
+            state.step_with_yaw(&Cliffs::NONE, 110, Some(0.0)),
+            MotorCommand::SpinRight
+        );
+        // The wheels are slipping
+        assert_eq!(
+            state.step_with_yaw(&Cliffs::NONE, 129, Some(-0.1)),
+            MotorCommand::SpinRight
+        );
+        assert_eq!(
+            state.step_with_yaw(&Cliffs::NONE, 130, Some(-0.1)),
+            MotorCommand::Reverse
+        );
+    }
+
+    #[test]
+    fn keeps_advancing_without_cliffs() {
+        let mut state = advancing();
+        for now in 1..1000 {
+            assert_eq!(state.step(&Cliffs::NONE, now), MotorCommand::Forward);
+        }
+    }
+
+    #[test]
+    fn single_cliff_turn_directions() {
+        let cases = [
+            (cliff(true, false, false, false), TurnDirection::Left),
+            (cliff(false, true, false, false), TurnDirection::Right),
+            (cliff(false, false, true, false), TurnDirection::Left),
+            (cliff(false, false, false, true), TurnDirection::Right),
+        ];
+        for (cliffs, turn_direction) in cases {
+            // Only cliffs at the leading end count
+            let (mut state, heading) = if cliffs.ahead(Heading::Forward) {
+                (advancing(), Heading::Reverse)
+            } else {
+                (reversing(), Heading::Forward)
+            };
+            assert_eq!(state.step(&cliffs, 50), heading.into());
+            assert_eq!(state.command(), Command::PreTurn);
+            assert_eq!(state.heading(), heading);
+            assert_eq!(state.turn_direction(), turn_direction, "{:?}", cliffs);
+        }
+    }
+
+    #[test]
+    fn cliffs_ahead() {
+        assert!(cliff(false, true, false, false).ahead(Heading::Forward));
+        assert!(cliff(false, false, true, false).ahead(Heading::Forward));
+        assert!(!cliff(true, false, false, true).ahead(Heading::Forward));
+        assert!(cliff(true, false, false, false).ahead(Heading::Reverse));
+        assert!(cliff(false, false, false, true).ahead(Heading::Reverse));
+        assert!(!cliff(false, true, true, false).ahead(Heading::Reverse));
+    }
+
+    #[test]
+    fn ignores_cliffs_behind() {
+        for cliffs in [
+            cliff(true, false, false, false),
+            cliff(false, false, false, true),
+            cliff(true, false, false, true),
+        ] {
+            let mut state = advancing();
+            assert_eq!(state.step(&cliffs, 5), MotorCommand::Forward);
+            assert_eq!(state.command(), Command::Advance);
+            assert_eq!(state.heading(), Heading::Forward);
+        }
+
+        let mut state = reversing();
+        assert_eq!(
+            state.step(&cliff(false, true, true, false), 50),
+            MotorCommand::Reverse
+        );
+        assert_eq!(state.command(), Command::Advance);
+    }
+
+    #[test]
+    fn diagonal_cliffs() {
+        for cliffs in [
+            cliff(false, true, false, true),
+            cliff(true, false, true, false),
+        ] {
+            let mut state = advancing();
+            assert_eq!(state.step(&cliffs, 5), MotorCommand::Reverse);
+            let expected = if cliffs.fr {
+                TurnDirection::Right
+            } else {
+                TurnDirection::Left
+            };
+            assert_eq!(state.turn_direction(), expected, "{:?}", cliffs);
+        }
+    }
+
+    #[test]
+    fn full_manoeuvre() {
+        let mut state = advancing();
+        let front = cliff(false, true, true, false);
+        assert_eq!(state.step(&front, 100), MotorCommand::Reverse);
+
+        // Further cliffs while backing away do not restart the manoeuvre
+        assert_eq!(state.step(&front, 105), MotorCommand::Reverse);
+        assert_eq!(state.step(&Cliffs::NONE, 109), MotorCommand::Reverse);
+        assert_eq!(state.command(), Command::PreTurn);
+
+        assert_eq!(state.step(&Cliffs::NONE, 110), MotorCommand::SpinRight);
+        assert_eq!(state.command(), Command::Turn);
+        assert_eq!(state.step(&Cliffs::NONE, 129), MotorCommand::SpinRight);
+
+        // Carries on in the toggled heading
+        assert_eq!(state.step(&Cliffs::NONE, 130), MotorCommand::Reverse);
+        assert_eq!(state.command(), Command::Advance);
+        assert_eq!(state.heading(), Heading::Reverse);
+
+        // The next cliff flips the heading back
+        assert_eq!(
+            state.step(&cliff(true, false, false, false), 200),
+            MotorCommand::Forward
+        );
+        assert_eq!(state.heading(), Heading::Forward);
+    }
+
+    #[test]
+    fn picked_up_stops_from_any_command() {
+        let some = cliff(false, true, false, false);
+
+        let mut advance = advancing();
+        let mut pre_turn = advancing();
+        pre_turn.step(&some, 1);
+        let mut turn = advancing();
+        turn.step(&some, 1);
+        turn.step(&some, 1 + CONFIG.pre_turn_ms);
+        assert_eq!(turn.command(), Command::Turn);
+
+        for state in [&mut advance, &mut pre_turn, &mut turn] {
+            assert_eq!(state.step(&Cliffs::ALL, 50), MotorCommand::Stop);
+            assert_eq!(state.command(), Command::Standby);
+            assert_eq!(state.step(&some, 51), MotorCommand::Stop);
+        }
+    }
+
+    #[test]
+    fn picked_up_and_put_down_resumes_in_current_heading() {
+        let mut state = advancing();
+        state.step(&cliff(false, false, true, false), 1);
+        state.step(&Cliffs::ALL, 2);
+        assert_eq!(state.step(&Cliffs::NONE, 3), MotorCommand::Reverse);
+        assert_eq!(state.command(), Command::Advance);
+    }
+
+    #[test]
+    fn durations_survive_clock_wrap_around() {
+        let mut state = DriveState::new(CONFIG);
+        let start = u32::MAX - 4;
+        state.step(&Cliffs::NONE, start);
+        state.step(&cliff(false, false, true, false), start);
+        assert_eq!(
+            state.step(&Cliffs::NONE, start.wrapping_add(9)),
+            MotorCommand::Reverse
+        );
+        assert_eq!(
+            state.step(&Cliffs::NONE, start.wrapping_add(10)),
+            MotorCommand::SpinLeft
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! The flash sectors `memory.x` sets aside for the `config` store.
+
+use stm32f4xx_hal as hal;
+
+use hal::flash::{Error, FlashExt};
+use hal::pac::FLASH;
+
+use crate::config::Flash;
+
+/// Sector 1, the first one past the vector table.
+const FIRST_SECTOR: u8 = 1;
+/// Offset of `FIRST_SECTOR` from the start of flash.
+const OFFSET: usize = 0x4000;
+/// Sectors 1 and 2 are both 16K.
+const SECTOR_LEN: usize = 0x4000;
+
+/// Sectors 1 and 2 of the STM32F401's flash, the program starts at sector 3.
+pub struct ConfigFlash {
+    flash: FLASH,
+}
+
+impl ConfigFlash {
+    pub fn new(flash: FLASH) -> Self {
+        ConfigFlash { flash }
+    }
+
+    fn start(sector: usize, offset: usize) -> usize {
+        OFFSET + sector * SECTOR_LEN + offset
+    }
+}
+
+impl Flash for ConfigFlash {
+    type Error = Error;
+
+    fn sector_len(&self) -> usize {
+        SECTOR_LEN
+    }
+
+    fn read(&self, sector: usize, offset: usize, buffer: &mut [u8]) {
+        let start = Self::start(sector, offset);
+        buffer.copy_from_slice(&self.flash.read()[start..start + buffer.len()]);
+    }
+
+    /// The CPU stalls on every instruction fetch until the bytes are programmed, around 16 us
+    /// each.
+    fn program(&mut self, sector: usize, offset: usize, bytes: &[u8]) -> Result<(), Error> {
+        self.flash
+            .unlocked()
+            .program(Self::start(sector, offset), bytes.iter())
+    }
+
+    /// Takes a quarter to half a second, longer than the watchdog allows.
+    fn erase(&mut self, sector: usize) -> Result<(), Error> {
+        self.flash.unlocked().erase(FIRST_SECTOR + sector as u8)
+    }
+}
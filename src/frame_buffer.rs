@@ -0,0 +1,442 @@
+//! Frame buffer for the SSD1306 that only sends what changed.
+//!
+//! The `BufferedGraphicsMode` of the `ssd1306` driver sends the bounding box of every pixel
+//! drawn since the last flush, and as every screen starts by clearing the display, that is the
+//! whole 1 KiB frame on the shared I2C bus every time. A [`BufferedDisplay`] keeps a copy of what
+//! the panel shows instead, compares the frame with it on [`flush`](BufferedDisplay::flush) and
+//! sends only the [`Span`]s of columns that changed on each page, the rows of 8 pixels the
+//! controller stores a byte per column of. A new reading on an otherwise unchanged screen is a
+//! few tens of bytes.
+//!
+//! The [`FrameLimiter`] keeps screens that follow a fast stream of readings down to a few frames
+//! a second.
+
+use core::convert::Infallible;
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+use crate::ui::{HEIGHT, WIDTH};
+
+/// Rows of 8 pixels, a byte per column each.
+pub const PAGES: usize = HEIGHT as usize / 8;
+/// Bytes in a frame.
+pub const FRAME_LEN: usize = WIDTH as usize * PAGES;
+/// Unchanged columns between two changes that are sent anyway, rather than start another span.
+/// Each span takes two more commands to address, about as many bytes on the bus.
+pub const MERGE_GAP: usize = 8;
+
+/// Columns `start..end` of a page.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub page: u8,
+    pub start: u8,
+    pub end: u8,
+}
+
+impl Span {
+    pub fn len(&self) -> usize {
+        usize::from(self.end - self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Top left and bottom right corners in pixels, the bottom right one past the span, as
+    /// `Ssd1306::set_draw_area` takes them.
+    pub fn draw_area(&self) -> ((u8, u8), (u8, u8)) {
+        let top = self.page * 8;
+        ((self.start, top), (self.end, top + 8))
+    }
+}
+
+/// Where the changes go, e.g. an SSD1306 in horizontal addressing mode.
+pub trait Panel {
+    type Error;
+
+    /// Shows `data`, a byte per column of `span`, bit 0 the top row.
+    fn send(&mut self, span: &Span, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// A [`WIDTH`] by [`HEIGHT`] frame laid out like the SSD1306's memory, with what was last sent.
+#[derive(Clone)]
+pub struct FrameBuffer {
+    pixels: [u8; FRAME_LEN],
+    sent: [u8; FRAME_LEN],
+    /// Whether `sent` is what the panel shows, and not just what it was last sent.
+    synced: bool,
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        FrameBuffer::new()
+    }
+}
+
+impl FrameBuffer {
+    /// Blank, with every byte to be sent on the first flush.
+    pub const fn new() -> Self {
+        FrameBuffer {
+            pixels: [0; FRAME_LEN],
+            sent: [0; FRAME_LEN],
+            synced: false,
+        }
+    }
+
+    /// Whether the pixel at `x`, `y` is on, off for pixels off the frame.
+    pub fn pixel(&self, x: u32, y: u32) -> bool {
+        x < WIDTH && y < HEIGHT && self.pixels[index(x, y)] & 1 << (y % 8) != 0
+    }
+
+    /// Sends everything on the next flush, e.g. after the panel was reset.
+    pub fn invalidate(&mut self) {
+        self.synced = false;
+    }
+
+    /// Sends the spans that changed since the last flush to `panel`, returns how many bytes of
+    /// pixels that was. After an error everything is sent again.
+    pub fn flush<P: Panel>(&mut self, panel: &mut P) -> Result<usize, P::Error> {
+        let mut len = 0;
+        for page in 0..PAGES as u8 {
+            let mut from = 0;
+            while let Some(span) = self.changed(page, from) {
+                let bytes = usize::from(page) * WIDTH as usize;
+                let bytes = bytes + usize::from(span.start)..bytes + usize::from(span.end);
+                if let Err(error) = panel.send(&span, &self.pixels[bytes.clone()]) {
+                    self.synced = false;
+                    return Err(error);
+                }
+                self.sent[bytes.clone()].copy_from_slice(&self.pixels[bytes]);
+                len += span.len();
+                from = span.end;
+            }
+        }
+        self.synced = true;
+        Ok(len)
+    }
+
+    /// The next span of `page` from column `from` that has changed, with the unchanged gaps of
+    /// up to [`MERGE_GAP`] columns in it.
+    fn changed(&self, page: u8, from: u8) -> Option<Span> {
+        let row = usize::from(page) * WIDTH as usize;
+        let changed =
+            |column: usize| !self.synced || self.pixels[row + column] != self.sent[row + column];
+        let start = (usize::from(from)..WIDTH as usize).find(|&column| changed(column))?;
+        let mut end = start + 1;
+        for column in start + 1..WIDTH as usize {
+            if changed(column) {
+                end = column + 1;
+            } else if column - end >= MERGE_GAP {
+                break;
+            }
+        }
+        Some(Span {
+            page,
+            start: start as u8,
+            end: end as u8,
+        })
+    }
+}
+
+impl OriginDimensions for FrameBuffer {
+    fn size(&self) -> Size {
+        Size::new(WIDTH, HEIGHT)
+    }
+}
+
+impl DrawTarget for FrameBuffer {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+    where
+        I: IntoIterator<Item = Pixel<BinaryColor>>,
+    {
+        for Pixel(point, color) in pixels {
+            if point.x < 0 || point.y < 0 || point.x as u32 >= WIDTH || point.y as u32 >= HEIGHT {
+                continue;
+            }
+            let (x, y) = (point.x as u32, point.y as u32);
+            let bit = 1 << (y % 8);
+            let byte = &mut self.pixels[index(x, y)];
+            if color.is_on() {
+                *byte |= bit;
+            } else {
+                *byte &= !bit;
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: BinaryColor) -> Result<(), Infallible> {
+        let byte = if color.is_on() { 0xff } else { 0 };
+        self.pixels = [byte; FRAME_LEN];
+        Ok(())
+    }
+}
+
+/// Byte of the pixel at `x`, `y`.
+fn index(x: u32, y: u32) -> usize {
+    (y / 8 * WIDTH + x) as usize
+}
+
+/// A [`Panel`] drawn on through a [`FrameBuffer`], in place of the driver's
+/// `BufferedGraphicsMode`.
+pub struct BufferedDisplay<P> {
+    panel: P,
+    frame: FrameBuffer,
+}
+
+impl<P: Panel> BufferedDisplay<P> {
+    /// Takes an initialised `panel`, whatever it shows is replaced on the first flush.
+    pub fn new(panel: P) -> Self {
+        BufferedDisplay {
+            panel,
+            frame: FrameBuffer::new(),
+        }
+    }
+
+    /// Sends what changed since the last flush, returns how many bytes of pixels that was.
+    pub fn flush(&mut self) -> Result<usize, P::Error> {
+        self.frame.flush(&mut self.panel)
+    }
+
+    pub fn panel(&self) -> &P {
+        &self.panel
+    }
+
+    pub fn frame(&self) -> &FrameBuffer {
+        &self.frame
+    }
+
+    /// The panel, e.g. to change its brightness. Call [`FrameBuffer::invalidate`] through
+    /// [`frame_mut`](Self::frame_mut) after drawing on it directly.
+    pub fn panel_mut(&mut self) -> &mut P {
+        &mut self.panel
+    }
+
+    pub fn frame_mut(&mut self) -> &mut FrameBuffer {
+        &mut self.frame
+    }
+}
+
+impl<P> OriginDimensions for BufferedDisplay<P> {
+    fn size(&self) -> Size {
+        self.frame.size()
+    }
+}
+
+impl<P> DrawTarget for BufferedDisplay<P> {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+    where
+        I: IntoIterator<Item = Pixel<BinaryColor>>,
+    {
+        self.frame.draw_iter(pixels)
+    }
+
+    fn clear(&mut self, color: BinaryColor) -> Result<(), Infallible> {
+        self.frame.clear(color)
+    }
+}
+
+/// Lets frames through no more often than once every `period_ms`.
+#[derive(Debug, Copy, Clone)]
+pub struct FrameLimiter {
+    period_ms: u32,
+    last_ms: Option<u32>,
+}
+
+impl FrameLimiter {
+    pub const fn new(period_ms: u32) -> Self {
+        FrameLimiter {
+            period_ms,
+            last_ms: None,
+        }
+    }
+
+    /// Whether a frame can go out at `now_ms`, counting it if so. The first always can.
+    pub fn ready(&mut self, now_ms: u32) -> bool {
+        match self.last_ms {
+            Some(last_ms) if now_ms.wrapping_sub(last_ms) < self.period_ms => false,
+            _ => {
+                self.last_ms = Some(now_ms);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ui::{self, Message, StatusBar};
+    use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+
+    /// Keeps what it was sent, like the panel's memory.
+    struct Recorder {
+        memory: [u8; FRAME_LEN],
+        spans: Vec<Span>,
+        fail: bool,
+    }
+
+    impl Recorder {
+        fn new() -> Self {
+            Recorder {
+                memory: [0xaa; FRAME_LEN],
+                spans: Vec::new(),
+                fail: false,
+            }
+        }
+    }
+
+    impl Panel for Recorder {
+        type Error = ();
+
+        fn send(&mut self, span: &Span, data: &[u8]) -> Result<(), ()> {
+            if self.fail {
+                return Err(());
+            }
+            assert_eq!(data.len(), span.len());
+            let start = usize::from(span.page) * WIDTH as usize + usize::from(span.start);
+            self.memory[start..start + data.len()].copy_from_slice(data);
+            self.spans.push(*span);
+            Ok(())
+        }
+    }
+
+    fn fill(display: &mut BufferedDisplay<Recorder>, top_left: (i32, i32), size: (u32, u32)) {
+        Rectangle::new(
+            Point::new(top_left.0, top_left.1),
+            Size::new(size.0, size.1),
+        )
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(display)
+        .unwrap();
+    }
+
+    #[test]
+    fn lays_out_pixels_like_the_panel() {
+        let mut frame = FrameBuffer::new();
+        Pixel(Point::new(3, 9), BinaryColor::On)
+            .draw(&mut frame)
+            .unwrap();
+        // Off the frame
+        Pixel(Point::new(-1, 0), BinaryColor::On)
+            .draw(&mut frame)
+            .unwrap();
+        Pixel(Point::new(0, HEIGHT as i32), BinaryColor::On)
+            .draw(&mut frame)
+            .unwrap();
+        assert!(frame.pixel(3, 9) && !frame.pixel(3, 8) && !frame.pixel(WIDTH, 0));
+        assert_eq!(frame.pixels[WIDTH as usize + 3], 0b10);
+        assert_eq!(frame.pixels.iter().filter(|byte| **byte != 0).count(), 1);
+    }
+
+    #[test]
+    fn sends_only_what_changed() {
+        let mut display = BufferedDisplay::new(Recorder::new());
+        // All of it the first time
+        assert_eq!(display.flush(), Ok(FRAME_LEN));
+        assert_eq!(display.panel().memory, [0; FRAME_LEN]);
+        assert_eq!(display.panel().spans.len(), PAGES);
+
+        // Nothing, cleared and drawn the same
+        display.panel_mut().spans.clear();
+        display.clear(BinaryColor::Off).unwrap();
+        assert_eq!(display.flush(), Ok(0));
+        fill(&mut display, (10, 10), (4, 8));
+        display.flush().unwrap();
+        display.clear(BinaryColor::Off).unwrap();
+        fill(&mut display, (10, 10), (4, 8));
+        assert_eq!(display.flush(), Ok(0));
+
+        // Rows 10 to 17 cover pages 1 and 2
+        assert_eq!(
+            display.panel().spans,
+            [
+                Span {
+                    page: 1,
+                    start: 10,
+                    end: 14
+                },
+                Span {
+                    page: 2,
+                    start: 10,
+                    end: 14
+                },
+            ]
+        );
+        assert_eq!(display.panel().memory, display.frame().pixels);
+    }
+
+    #[test]
+    fn merges_nearby_changes() {
+        let mut display = BufferedDisplay::new(Recorder::new());
+        display.flush().unwrap();
+        display.panel_mut().spans.clear();
+        fill(&mut display, (0, 0), (1, 1));
+        fill(&mut display, (1 + MERGE_GAP as i32, 0), (1, 1));
+        fill(&mut display, (100, 0), (1, 1));
+        assert_eq!(display.flush(), Ok(MERGE_GAP + 2 + 1));
+        assert_eq!(
+            display.panel().spans,
+            [
+                Span {
+                    page: 0,
+                    start: 0,
+                    end: MERGE_GAP as u8 + 2
+                },
+                Span {
+                    page: 0,
+                    start: 100,
+                    end: 101
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn sends_everything_after_an_error() {
+        let mut display = BufferedDisplay::new(Recorder::new());
+        display.flush().unwrap();
+        fill(&mut display, (0, 0), (1, 1));
+        display.panel_mut().fail = true;
+        assert_eq!(display.flush(), Err(()));
+        display.panel_mut().fail = false;
+        assert_eq!(display.flush(), Ok(FRAME_LEN));
+
+        display.frame_mut().invalidate();
+        assert_eq!(display.flush(), Ok(FRAME_LEN));
+    }
+
+    #[test]
+    fn a_new_reading_is_a_few_bytes() {
+        let mut display = BufferedDisplay::new(Recorder::new());
+        let status = StatusBar::new("VL6180X tests", "hold: back");
+        ui::draw(&mut display, &status, &Message::new("123 mm")).unwrap();
+        display.flush().unwrap();
+        ui::draw(&mut display, &status, &Message::new("124 mm")).unwrap();
+        // One character of the 6x10 font, over two pages
+        let sent = display.flush().unwrap();
+        assert!(sent > 0 && sent <= 2 * 6, "{}", sent);
+        assert_eq!(display.panel().memory, display.frame().pixels);
+    }
+
+    #[test]
+    fn limits_the_frame_rate() {
+        let mut limiter = FrameLimiter::new(100);
+        assert!(limiter.ready(50));
+        assert!(!limiter.ready(149));
+        assert!(limiter.ready(150));
+        assert!(!limiter.ready(200));
+
+        // Across the clock wrapping around
+        let mut limiter = FrameLimiter::new(100);
+        assert!(limiter.ready(u32::MAX - 49));
+        assert!(!limiter.ready(49));
+        assert!(limiter.ready(50));
+    }
+}
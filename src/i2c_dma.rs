@@ -0,0 +1,600 @@
+//! I2C1 driven by DMA1 and interrupts, shared between tasks of any priority.
+//!
+//! Every transaction goes through a [`PriorityQueue`], so a cliff sensor read issued while the
+//! display is being flushed goes on the bus as soon as the display transaction in flight is done,
+//! instead of waiting for the whole frame. Data is moved by DMA1 stream 7 (transmit) and stream 0
+//! (receive), both on channel 1. The serial port has DMA2 to itself, see `serial_dma`.
+//!
+//! [`DmaI2cProxy`] implements the blocking embedded-hal I2C traits, so drivers like `vl6180x` and
+//! `ssd1306` work unchanged: a call queues the transaction and sleeps until it has finished. The
+//! bus is moved along by [`DmaI2cBus::on_interrupt`], which has to be called from the `I2C1_EV`,
+//! `I2C1_ER` and `DMA1_STREAM0` handlers. Those handlers have to run at a higher priority than
+//! any task using the bus. Only with interrupts disabled, e.g. in RTIC `init`, does a call
+//! service the bus itself while it waits.
+//!
+//! The bus supervises itself: a transaction not done [`TIMEOUT_US`] after it started, however
+//! far it got, is aborted with [`Error::Timeout`], and whenever the bus is found stuck, e.g. a
+//! device holding SDA low after being interrupted mid byte, it is recovered by clocking SCL (PB8)
+//! by hand until SDA is released and sending a stop condition, before I2C1 is reset and set up
+//! again. A stuck bus raises no interrupts, so [`DmaI2cBus::check_timeout`] has to be called
+//! every so often as well.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::DWT;
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use stm32f4xx_hal::pac;
+
+use crate::i2c_queue::{Priority, PriorityQueue};
+
+/// Transactions that can wait at each priority, one per task using the bus is enough.
+const QUEUE_LEN: usize = 8;
+/// DMA channel of the I2C1 requests on both streams.
+const DMA_CHANNEL: u8 = 1;
+/// Longest a transaction may take once it is on the bus. A 16 byte display chunk takes about
+/// 0.5 ms at 400 kHz.
+pub const TIMEOUT_US: u32 = 5_000;
+/// Clock rate used to recover a stuck bus.
+const RECOVERY_HZ: u32 = 100_000;
+
+/// The bus on I2C1, set up by [`DmaI2cBus::init`].
+pub static I2C1_BUS: DmaI2cBus = DmaI2cBus::new();
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The device did not acknowledge its address or a byte.
+    Nack,
+    /// Misplaced start or stop condition.
+    Bus,
+    ArbitrationLoss,
+    Overrun,
+    /// Too many transactions waiting at this priority.
+    QueueFull,
+    /// The transaction made no progress, the bus has been recovered.
+    Timeout,
+}
+
+const PENDING: u8 = 0;
+const DONE: u8 = 1;
+
+impl Error {
+    fn status(self) -> u8 {
+        DONE + 1 + self as u8
+    }
+
+    fn from_status(status: u8) -> Result<(), Error> {
+        match status - DONE {
+            0 => Ok(()),
+            1 => Err(Error::Nack),
+            2 => Err(Error::Bus),
+            3 => Err(Error::ArbitrationLoss),
+            4 => Err(Error::Overrun),
+            5 => Err(Error::QueueFull),
+            _ => Err(Error::Timeout),
+        }
+    }
+}
+
+/// A queued transaction. The buffers and status live on the stack of the task that queued it,
+/// which waits for the status to leave `PENDING` before returning, so they outlive the transfer.
+struct Transfer {
+    address: u8,
+    write: *const u8,
+    write_len: usize,
+    read: *mut u8,
+    read_len: usize,
+    status: *const AtomicU8,
+}
+
+// Only touched inside critical sections, see above for why the pointers stay valid
+unsafe impl Send for Transfer {}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for the (repeated) start condition.
+    Start {
+        read: bool,
+    },
+    /// Waiting for the address to be acknowledged.
+    Address {
+        read: bool,
+    },
+    /// DMA is feeding the write buffer.
+    Writing,
+    /// DMA is filling the read buffer.
+    Reading,
+    /// Single byte read, which DMA cannot do.
+    ReadingByte,
+}
+
+struct Inner {
+    i2c: pac::I2C1,
+    dma: pac::DMA1,
+    queue: PriorityQueue<Transfer, QUEUE_LEN>,
+    current: Option<Transfer>,
+    state: State,
+    /// Cycle count when the current transaction went on the bus.
+    started: u32,
+    timeout_cycles: u32,
+    /// Half an SCL period at `RECOVERY_HZ`.
+    recovery_cycles: u32,
+    /// Timing set up by the HAL, restored after a reset.
+    cr2: u32,
+    ccr: u32,
+    trise: u32,
+    recoveries: u32,
+}
+
+pub struct DmaI2cBus {
+    inner: Mutex<RefCell<Option<Inner>>>,
+}
+
+impl DmaI2cBus {
+    const fn new() -> Self {
+        DmaI2cBus {
+            inner: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Takes over an I2C1 that is already set up, e.g. by the HAL, with SCL on PB8 and SDA on
+    /// PB9, and DMA1. Starts the DWT cycle counter to time transactions.
+    ///
+    /// # Panics
+    ///
+    /// If called more than once.
+    pub fn init(&self, i2c: pac::I2C1, dma: pac::DMA1, sysclk_hz: u32) {
+        // Safe, enabling a clock or the cycle counter does not touch anything another driver
+        // relies on
+        unsafe {
+            (*pac::RCC::ptr())
+                .ahb1enr
+                .modify(|_, w| w.dma1en().enabled());
+            let mut cp = cortex_m::Peripherals::steal();
+            cp.DCB.enable_trace();
+            cp.DWT.enable_cycle_counter();
+        }
+
+        let data_register = &i2c.dr as *const _ as u32;
+        for stream in [&dma.st[0], &dma.st[7]] {
+            stream.cr.write(|w| w.en().disabled());
+            stream.par.write(|w| unsafe { w.pa().bits(data_register) });
+        }
+        i2c.cr2
+            .modify(|_, w| w.itevten().set_bit().iterren().set_bit());
+        let (cr2, ccr, trise) = (
+            i2c.cr2.read().bits(),
+            i2c.ccr.read().bits(),
+            i2c.trise.read().bits(),
+        );
+
+        interrupt::free(|cs| {
+            let mut inner = self.inner.borrow(cs).borrow_mut();
+            assert!(inner.is_none(), "I2C bus already initialised");
+            *inner = Some(Inner {
+                i2c,
+                dma,
+                queue: PriorityQueue::new(),
+                current: None,
+                state: State::Idle,
+                started: 0,
+                timeout_cycles: sysclk_hz / 1_000_000 * TIMEOUT_US,
+                recovery_cycles: sysclk_hz / RECOVERY_HZ / 2,
+                cr2,
+                ccr,
+                trise,
+                recoveries: 0,
+            });
+        });
+    }
+
+    /// A handle whose transactions are queued at `priority`.
+    pub fn acquire(&'static self, priority: Priority) -> DmaI2cProxy {
+        DmaI2cProxy {
+            bus: self,
+            priority,
+        }
+    }
+
+    /// How many times the bus had to be recovered.
+    pub fn recoveries(&self) -> u32 {
+        interrupt::free(|cs| {
+            self.inner
+                .borrow(cs)
+                .borrow()
+                .as_ref()
+                .map_or(0, |inner| inner.recoveries)
+        })
+    }
+
+    /// Moves the transaction in flight along. Call from the `I2C1_EV`, `I2C1_ER` and
+    /// `DMA1_STREAM0` handlers.
+    pub fn on_interrupt(&self) {
+        interrupt::free(|cs| {
+            if let Some(inner) = self.inner.borrow(cs).borrow_mut().as_mut() {
+                inner.service();
+            }
+        });
+    }
+
+    /// Aborts the transaction in flight if it has taken longer than [`TIMEOUT_US`], e.g. from a
+    /// periodic task. It then times out within `TIMEOUT_US` plus the period.
+    pub fn check_timeout(&self) {
+        interrupt::free(|cs| {
+            if let Some(inner) = self.inner.borrow(cs).borrow_mut().as_mut() {
+                inner.check_timeout();
+            }
+        });
+    }
+
+    /// Writes `write`, then reads into `read` after a repeated start. Either may be empty.
+    ///
+    /// Sleeps until the interrupt handlers have finished the transaction, so it must not be
+    /// called at or above their priority.
+    pub fn transfer(
+        &self,
+        priority: Priority,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Error> {
+        let status = AtomicU8::new(PENDING);
+        let transfer = Transfer {
+            address,
+            write: write.as_ptr(),
+            write_len: write.len(),
+            read: read.as_mut_ptr(),
+            read_len: read.len(),
+            status: &status,
+        };
+        interrupt::free(|cs| {
+            let mut inner = self.inner.borrow(cs).borrow_mut();
+            let inner = inner.as_mut().expect("I2C bus not initialised");
+            inner
+                .queue
+                .push(priority, transfer)
+                .map_err(|_| Error::QueueFull)?;
+            if inner.state == State::Idle {
+                inner.start_next();
+            }
+            Ok(())
+        })?;
+
+        // Nothing else moves the bus along while interrupts are disabled
+        let masked = cortex_m::register::primask::read().is_active();
+        loop {
+            let status = status.load(Ordering::Acquire);
+            if status != PENDING {
+                return Error::from_status(status);
+            }
+            if masked {
+                self.on_interrupt();
+            } else {
+                // Woken by any interrupt, and by the event `finish` sends in case the
+                // transaction finished since the status was read
+                cortex_m::asm::wfe();
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn start_next(&mut self) {
+        self.current = self.queue.pop();
+        let transfer = match &self.current {
+            Some(transfer) => transfer,
+            None => {
+                self.state = State::Idle;
+                return;
+            }
+        };
+        // A stop condition from the previous transaction may still be going out
+        let start = DWT::cycle_count();
+        while self.i2c.cr1.read().stop().bit_is_set() || self.i2c.sr2.read().busy().bit_is_set() {
+            if DWT::cycle_count().wrapping_sub(start) > self.recovery_cycles * 4 {
+                self.recover();
+                break;
+            }
+        }
+        self.started = DWT::cycle_count();
+        let read = transfer.write_len == 0 && transfer.read_len > 0;
+        self.i2c
+            .cr1
+            .modify(|_, w| w.ack().set_bit().start().set_bit());
+        self.state = State::Start { read };
+    }
+
+    fn finish(&mut self, result: Result<(), Error>) {
+        if let Some(transfer) = self.current.take() {
+            let status = match result {
+                Ok(()) => DONE,
+                Err(e) => e.status(),
+            };
+            unsafe { (*transfer.status).store(status, Ordering::Release) };
+            cortex_m::asm::sev();
+        }
+        self.start_next();
+    }
+
+    fn service(&mut self) {
+        self.clear_dma_flags();
+
+        let sr1 = self.i2c.sr1.read();
+        let error = if sr1.af().bit_is_set() {
+            Some(Error::Nack)
+        } else if sr1.berr().bit_is_set() {
+            Some(Error::Bus)
+        } else if sr1.arlo().bit_is_set() {
+            Some(Error::ArbitrationLoss)
+        } else if sr1.ovr().bit_is_set() {
+            Some(Error::Overrun)
+        } else {
+            None
+        };
+        if error.is_none() && self.check_timeout() {
+            return;
+        }
+
+        if let Some(error) = error {
+            self.i2c.sr1.modify(|_, w| {
+                w.af()
+                    .clear_bit()
+                    .berr()
+                    .clear_bit()
+                    .arlo()
+                    .clear_bit()
+                    .ovr()
+                    .clear_bit()
+            });
+            self.stop_dma();
+            // After losing arbitration the bus belongs to another master
+            if error != Error::ArbitrationLoss {
+                self.i2c.cr1.modify(|_, w| w.stop().set_bit());
+            }
+            self.finish(Err(error));
+            return;
+        }
+
+        let (address, write_len, read, read_len) = match &self.current {
+            Some(t) => (t.address, t.write_len, t.read, t.read_len),
+            None => return,
+        };
+
+        match self.state {
+            State::Idle => {}
+            State::Start { read } => {
+                if sr1.sb().bit_is_set() {
+                    self.i2c
+                        .dr
+                        .write(|w| unsafe { w.bits(u32::from(address << 1 | read as u8)) });
+                    self.state = State::Address { read };
+                }
+            }
+            State::Address { read: false } => {
+                if sr1.addr().bit_is_set() {
+                    if write_len > 0 {
+                        let write = self.current.as_ref().unwrap().write;
+                        self.start_dma(7, write as u32, write_len, false);
+                        self.i2c.cr2.modify(|_, w| w.dmaen().set_bit());
+                        self.state = State::Writing;
+                    }
+                    // Clears ADDR
+                    self.i2c.sr2.read();
+                    if write_len == 0 {
+                        // Address probe
+                        self.i2c.cr1.modify(|_, w| w.stop().set_bit());
+                        self.finish(Ok(()));
+                    }
+                }
+            }
+            State::Address { read: true } => {
+                if sr1.addr().bit_is_set() {
+                    if read_len == 1 {
+                        // NACK the only byte, then stop as soon as ADDR is cleared
+                        self.i2c.cr1.modify(|_, w| w.ack().clear_bit());
+                        self.i2c.sr2.read();
+                        self.i2c.cr1.modify(|_, w| w.stop().set_bit());
+                        self.i2c.cr2.modify(|_, w| w.itbufen().set_bit());
+                        self.state = State::ReadingByte;
+                    } else {
+                        self.start_dma(0, read as u32, read_len, true);
+                        self.i2c
+                            .cr2
+                            .modify(|_, w| w.dmaen().set_bit().last().set_bit());
+                        self.i2c.sr2.read();
+                        self.state = State::Reading;
+                    }
+                }
+            }
+            State::Writing => {
+                if self.dma.st[7].ndtr.read().ndt().bits() == 0 && sr1.btf().bit_is_set() {
+                    self.stop_dma();
+                    if read_len > 0 {
+                        self.i2c.cr1.modify(|_, w| w.start().set_bit());
+                        self.state = State::Start { read: true };
+                    } else {
+                        self.i2c.cr1.modify(|_, w| w.stop().set_bit());
+                        self.finish(Ok(()));
+                    }
+                }
+            }
+            State::Reading => {
+                if self.dma.st[0].ndtr.read().ndt().bits() == 0 {
+                    self.stop_dma();
+                    self.i2c.cr1.modify(|_, w| w.stop().set_bit());
+                    self.finish(Ok(()));
+                }
+            }
+            State::ReadingByte => {
+                if sr1.rx_ne().bit_is_set() {
+                    let byte = self.i2c.dr.read().bits() as u8;
+                    unsafe { read.write(byte) };
+                    self.i2c.cr2.modify(|_, w| w.itbufen().clear_bit());
+                    self.finish(Ok(()));
+                }
+            }
+        }
+    }
+
+    /// Aborts the transaction in flight and recovers the bus if it has taken longer than
+    /// `TIMEOUT_US`. Returns whether it did.
+    fn check_timeout(&mut self) -> bool {
+        if self.state == State::Idle
+            || DWT::cycle_count().wrapping_sub(self.started) <= self.timeout_cycles
+        {
+            return false;
+        }
+        self.stop_dma();
+        self.i2c.cr2.modify(|_, w| w.itbufen().clear_bit());
+        self.recover();
+        self.finish(Err(Error::Timeout));
+        true
+    }
+
+    /// Frees a bus held by a device that was cut off mid byte, then resets I2C1.
+    fn recover(&mut self) {
+        self.recoveries = self.recoveries.wrapping_add(1);
+        // Safe, PB8 and PB9 belong to I2C1, which this driver owns
+        let gpiob = unsafe { &*pac::GPIOB::ptr() };
+        let half_period = self.recovery_cycles;
+
+        self.i2c.cr1.modify(|_, w| w.pe().clear_bit());
+        // Both lines are already open drain, start with them released
+        gpiob.bsrr.write(|w| w.bs8().set_bit().bs9().set_bit());
+        gpiob
+            .moder
+            .modify(|_, w| w.moder8().output().moder9().output());
+
+        // Clock out whatever byte the device is in the middle of
+        for _ in 0..9 {
+            if gpiob.idr.read().idr9().bit_is_set() {
+                break;
+            }
+            gpiob.bsrr.write(|w| w.br8().set_bit());
+            cortex_m::asm::delay(half_period);
+            gpiob.bsrr.write(|w| w.bs8().set_bit());
+            cortex_m::asm::delay(half_period);
+        }
+
+        // Stop condition: SDA rises while SCL is high
+        gpiob.bsrr.write(|w| w.br8().set_bit());
+        cortex_m::asm::delay(half_period);
+        gpiob.bsrr.write(|w| w.br9().set_bit());
+        cortex_m::asm::delay(half_period);
+        gpiob.bsrr.write(|w| w.bs8().set_bit());
+        cortex_m::asm::delay(half_period);
+        gpiob.bsrr.write(|w| w.bs9().set_bit());
+        cortex_m::asm::delay(half_period);
+
+        gpiob
+            .moder
+            .modify(|_, w| w.moder8().alternate().moder9().alternate());
+
+        self.i2c.cr1.write(|w| w.swrst().set_bit());
+        self.i2c.cr1.write(|w| w.swrst().clear_bit());
+        self.i2c.cr2.write(|w| unsafe { w.bits(self.cr2) });
+        self.i2c.ccr.write(|w| unsafe { w.bits(self.ccr) });
+        self.i2c.trise.write(|w| unsafe { w.bits(self.trise) });
+        self.i2c.cr1.write(|w| w.pe().set_bit());
+    }
+
+    /// Points `stream` at `len` bytes of memory and enables it. Only the receive stream raises
+    /// an interrupt, the end of a write is signalled by the I2C byte transfer finished event.
+    fn start_dma(&mut self, stream: usize, memory: u32, len: usize, receive: bool) {
+        let st = &self.dma.st[stream];
+        st.m0ar.write(|w| unsafe { w.m0a().bits(memory) });
+        st.ndtr.write(|w| w.ndt().bits(len as u16));
+        st.cr.write(|w| {
+            let w = unsafe { w.chsel().bits(DMA_CHANNEL) }
+                .minc()
+                .incremented()
+                .psize()
+                .bits8()
+                .msize()
+                .bits8();
+            if receive {
+                w.dir().peripheral_to_memory().tcie().enabled()
+            } else {
+                w.dir().memory_to_peripheral()
+            }
+        });
+        st.cr.modify(|_, w| w.en().enabled());
+    }
+
+    fn stop_dma(&mut self) {
+        for stream in [0, 7] {
+            let st = &self.dma.st[stream];
+            st.cr.modify(|_, w| w.en().disabled());
+            while st.cr.read().en().is_enabled() {}
+        }
+        self.i2c
+            .cr2
+            .modify(|_, w| w.dmaen().clear_bit().last().clear_bit());
+        self.clear_dma_flags();
+    }
+
+    fn clear_dma_flags(&mut self) {
+        self.dma.lifcr.write(|w| {
+            w.ctcif0()
+                .set_bit()
+                .chtif0()
+                .set_bit()
+                .cteif0()
+                .set_bit()
+                .cdmeif0()
+                .set_bit()
+                .cfeif0()
+                .set_bit()
+        });
+        self.dma.hifcr.write(|w| {
+            w.ctcif7()
+                .set_bit()
+                .chtif7()
+                .set_bit()
+                .cteif7()
+                .set_bit()
+                .cdmeif7()
+                .set_bit()
+                .cfeif7()
+                .set_bit()
+        });
+    }
+}
+
+/// Handle to the bus for a driver, see the module documentation.
+pub struct DmaI2cProxy {
+    bus: &'static DmaI2cBus,
+    priority: Priority,
+}
+
+impl DmaI2cProxy {
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+}
+
+impl Write for DmaI2cProxy {
+    type Error = Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.bus.transfer(self.priority, address, bytes, &mut [])
+    }
+}
+
+impl Read for DmaI2cProxy {
+    type Error = Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.bus.transfer(self.priority, address, &[], buffer)
+    }
+}
+
+impl WriteRead for DmaI2cProxy {
+    type Error = Error;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        self.bus.transfer(self.priority, address, bytes, buffer)
+    }
+}
@@ -0,0 +1,86 @@
+//! Queue of pending transactions on a shared I2C bus.
+//!
+//! Transactions are served in order within a priority, and every high priority transaction is
+//! served before any low priority one, so a cliff sensor read only ever waits for the
+//! transaction already on the bus, not for a whole display refresh.
+
+use heapless::Deque;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Priority {
+    /// E.g. display updates.
+    Low,
+    /// E.g. cliff sensor reads.
+    High,
+}
+
+pub struct PriorityQueue<T, const N: usize> {
+    high: Deque<T, N>,
+    low: Deque<T, N>,
+}
+
+impl<T, const N: usize> PriorityQueue<T, N> {
+    pub const fn new() -> Self {
+        PriorityQueue {
+            high: Deque::new(),
+            low: Deque::new(),
+        }
+    }
+
+    /// Queues `item`, handing it back if there are already `N` items at that priority.
+    pub fn push(&mut self, priority: Priority, item: T) -> Result<(), T> {
+        match priority {
+            Priority::High => self.high.push_back(item),
+            Priority::Low => self.low.push_back(item),
+        }
+    }
+
+    /// The oldest high priority item, or failing that the oldest low priority item.
+    pub fn pop(&mut self) -> Option<T> {
+        self.high.pop_front().or_else(|| self.low.pop_front())
+    }
+
+    pub fn len(&self) -> usize {
+        self.high.len() + self.low.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.low.is_empty()
+    }
+}
+
+impl<T, const N: usize> Default for PriorityQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_priority_first() {
+        let mut queue: PriorityQueue<u8, 4> = PriorityQueue::new();
+        queue.push(Priority::Low, 1).unwrap();
+        queue.push(Priority::Low, 2).unwrap();
+        queue.push(Priority::High, 3).unwrap();
+        assert_eq!(queue.pop(), Some(3));
+        queue.push(Priority::High, 4).unwrap();
+        assert_eq!(queue.pop(), Some(4));
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn capacity_per_priority() {
+        let mut queue: PriorityQueue<u8, 2> = PriorityQueue::new();
+        queue.push(Priority::Low, 1).unwrap();
+        queue.push(Priority::Low, 2).unwrap();
+        assert_eq!(queue.push(Priority::Low, 3), Err(3));
+        queue.push(Priority::High, 4).unwrap();
+        assert_eq!(queue.len(), 3);
+    }
+}
@@ -0,0 +1,39 @@
+//! Building blocks shared by the rover examples.
+//!
+//! The logic in here does not depend on the STM32 HAL, so it also compiles for the host and can
+//! be unit tested there:
+//!
+//! cargo test --lib --target x86_64-unknown-linux-gnu
+//!
+//! Modules that need the HAL are only built for the microcontroller.
+
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+pub mod board;
+pub mod button;
+pub mod calibration;
+pub mod cliff_filter;
+pub mod cliff_monitor;
+pub mod config;
+pub mod console;
+pub mod dashboard;
+pub mod diff_drive;
+pub mod drive;
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+pub mod flash;
+pub mod frame_buffer;
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+pub mod i2c_dma;
+pub mod i2c_queue;
+pub mod motor;
+pub mod odometry;
+pub mod pid;
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+pub mod serial_dma;
+pub mod settings;
+pub mod supervisor;
+pub mod telemetry;
+pub mod texts;
+pub mod tof;
+pub mod ui;
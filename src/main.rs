@@ -1,4 +1,7 @@
-//! Draw Ferris the Rust mascot on an SSD1306 display
+//! Draw Ferris the Rust mascot on an SSD1306 display, and a few more screens picked from a menu
+//!
+//! A click of the button moves down the menu, a double click up, and a long press opens the
+//! screen. Another long press goes back to the menu.
 
 #![allow(clippy::empty_loop)]
 #![no_std]
@@ -7,29 +10,26 @@
 use core::cell::RefCell;
 
 use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::{DWT, NVIC};
 use cortex_m_rt::ExceptionFrame;
 use cortex_m_rt::{entry, exception};
 use cortex_m_semihosting::hprintln;
-use embedded_graphics::{
-    image::Image,
-    image::ImageRaw,
-    mono_font::{ascii::FONT_10X20, MonoTextStyle},
-    pixelcolor::BinaryColor,
-    prelude::*,
-    text::{Alignment, Text},
-};
+use embedded_graphics::{image::Image, image::ImageRaw, pixelcolor::BinaryColor, prelude::*};
 use hal::gpio::{Alternate, OpenDrain, Pin};
 use hal::i2c::I2c;
 use hal::pac::I2C1;
 use heapless::String;
 use panic_semihosting as _;
 use shared_bus::{self, I2cProxy};
-use ssd1306::mode::BufferedGraphicsMode;
-use ssd1306::{prelude::*, I2CDisplayInterface, Ssd1306};
+use stm32f401_rover_testbed::board::{init_display, ButtonSampler, Oled};
+use stm32f401_rover_testbed::button::Gesture;
+use stm32f401_rover_testbed::frame_buffer::{BufferedDisplay, FrameLimiter};
+use stm32f401_rover_testbed::texts::{self, GOODBYE_TEXT, WELCOME_TEXT};
+use stm32f401_rover_testbed::ui::{self, CycleClock, Menu, Message, Screen, StatusBar};
 use stm32f4xx_hal as hal;
 use vl53l0x;
 
-use crate::hal::{pac, prelude::*};
+use crate::hal::{pac, pac::interrupt, prelude::*};
 
 type I2cType = I2c<
     I2C1,
@@ -39,9 +39,11 @@ type I2cType = I2c<
     ),
 >;
 
+type DispType<'a> = BufferedDisplay<Oled<I2cProxy<'a, Mutex<RefCell<I2cType>>>>>;
+
 #[entry]
 fn main() -> ! {
-    if let (Some(dp), Some(_cp)) = (
+    if let (Some(dp), Some(mut cp)) = (
         pac::Peripherals::take(),
         cortex_m::peripheral::Peripherals::take(),
     ) {
@@ -83,29 +85,7 @@ fn main() -> ! {
         gyul53l0x.start_continuous(0).expect("start cont");
 
         // Set up the display
-        let interface = I2CDisplayInterface::new(bus.acquire_i2c());
-        let mut disp = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
-            .into_buffered_graphics_mode();
-        disp.init().unwrap();
-        disp.flush().unwrap();
-
-        // Create a new character style
-        let style: MonoTextStyle<BinaryColor> = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
-
-        // Create a text at position (64, 32) and draw it using the previously defined style
-        let welcome_text: Text<MonoTextStyle<BinaryColor>> = Text::with_alignment(
-            "Hello\nShao Yuan",
-            Point::new(64, 32),
-            style,
-            Alignment::Center,
-        );
-
-        let goodbye_text = Text::with_alignment(
-            "Goodbye\nSee you soon!",
-            Point::new(64, 32),
-            style,
-            Alignment::Center,
-        );
+        let mut disp = init_display(bus.acquire_i2c()).unwrap();
 
         // Create image rustacean
         let raw_image: ImageRaw<BinaryColor> =
@@ -114,73 +94,78 @@ fn main() -> ! {
         image.draw(&mut disp).unwrap();
         disp.flush().unwrap();
 
-        // Set up state for the loop
-        let mut state = State::Image;
-        let mut was_pressed = btn.is_low();
+        // Time the button gestures and the frames with the cycle counter
+        cp.DCB.enable_trace();
+        cp.DWT.enable_cycle_counter();
+        let mut clock = CycleClock::new(CYCLES_PER_MS, DWT::cycle_count());
+        let button = ButtonSampler::start(btn, dp.TIM2, &clocks);
+        cortex_m::interrupt::free(|cs| BUTTON.borrow(cs).replace(Some(button)));
+        // Safe, the handler only touches `BUTTON`
+        unsafe { NVIC::unmask(pac::Interrupt::TIM2) };
+        let mut frames = FrameLimiter::new(FRAME_MS);
+
+        // Ferris until the first click, then the screens to pick from
+        let mut menu = Menu::new(&State::NAMES);
+        let mut state = Some(State::Image);
 
         // This runs continuously, as fast as possible
         loop {
-            let is_pressed = btn.is_low();
-            if !was_pressed && is_pressed {
-                state.cycle();
-                was_pressed = true;
-
-                use State::*;
-                match state {
-                    Image => {
-                        show_drawable(&image, &mut disp);
-                    }
-                    Text1 => {
-                        show_drawable(&welcome_text, &mut disp);
-                    }
-                    GYUL53L0X => (),
-                    Text2 => {
-                        show_drawable(&goodbye_text, &mut disp);
-                    }
-                };
-            } else if !is_pressed {
-                was_pressed = false;
+            let now_ms = clock.update(DWT::cycle_count());
+            match (state, next_gesture()) {
+                (None, Some(gesture)) => {
+                    state = menu.press(gesture).map(|index| State::ALL[index]);
+                    let back = StatusBar::new(TITLE, "hold: back");
+                    match state {
+                        None => show(&mut disp, &StatusBar::new(TITLE, "hold: open"), &menu),
+                        Some(State::Image) => show_drawable(&image, &mut disp),
+                        Some(State::Text1) => show(&mut disp, &back, &Message::new(WELCOME_TEXT)),
+                        Some(State::Text2) => show(&mut disp, &back, &Message::new(GOODBYE_TEXT)),
+                        Some(State::GYUL53L0X) => (),
+                    };
+                }
+                (Some(_), Some(Gesture::LongPress))
+                | (Some(State::Image), Some(Gesture::Click)) => {
+                    state = None;
+                    show(&mut disp, &StatusBar::new(TITLE, "hold: open"), &menu);
+                }
+                _ => (),
             }
 
-            use State::*;
-            match state {
-                GYUL53L0X => match gyul53l0x.read_range_continuous_millimeters_blocking() {
+            if state == Some(State::GYUL53L0X) && frames.ready(now_ms) {
+                match gyul53l0x.read_range_continuous_millimeters_blocking() {
                     Ok(range) => {
-                        let mut reading: String<16> = String::from("GYUL53L0X\n");
-                        reading
-                            .push_str(&String::<4>::from(range))
-                            .expect("string composition - range");
-                        reading.push_str("mm").expect("string composition - unit");
-                        let reading_text: Text<MonoTextStyle<BinaryColor>> = Text::with_alignment(
-                            &reading,
-                            Point::new(64, 32),
-                            style,
-                            Alignment::Center,
-                        );
-                        show_drawable(&reading_text, &mut disp);
+                        let mut reading: String<16> = String::new();
+                        texts::write_range(&mut reading, range).unwrap();
+                        let status = StatusBar::new("GYUL53L0X", "hold: back");
+                        show(&mut disp, &status, &Message::new(&reading));
                     }
                     Err(_e) => hprintln!("gyul53l0x read range continuous error").unwrap(),
-                },
-                _ => (),
-            };
+                }
+            }
         }
     }
 
     loop {}
 }
 
-fn show_drawable(
-    item: &impl Drawable<Color = BinaryColor>,
-    disp: &mut Ssd1306<
-        I2CInterface<I2cProxy<'_, Mutex<RefCell<I2cType>>>>,
-        DisplaySize128x64,
-        BufferedGraphicsMode<DisplaySize128x64>,
-    >,
-) {
-    disp.clear();
+const TITLE: &str = texts::TESTBED_TITLE;
+/// The system clock.
+const CYCLES_PER_MS: u32 = 48_000;
+/// Shortest time between two readings on the display, they come much faster.
+const FRAME_MS: u32 = 100;
+
+fn show(disp: &mut DispType, status: &StatusBar, screen: &impl Screen) {
+    ui::draw(disp, status, screen).unwrap();
+    disp.flush().unwrap();
+}
+
+fn show_drawable(item: &impl Drawable<Color = BinaryColor>, disp: &mut DispType) {
+    disp.clear(BinaryColor::Off).unwrap();
     item.draw(disp).unwrap();
     disp.flush().unwrap();
 }
+
+#[derive(Copy, Clone, PartialEq)]
 enum State {
     Image,
     Text1,
@@ -189,15 +174,32 @@ enum State {
 }
 
 impl State {
-    fn cycle(&mut self) {
-        use State::*;
-        *self = match *self {
-            Image => Text1,
-            Text1 => GYUL53L0X,
-            GYUL53L0X => Text2,
-            Text2 => Image,
+    const ALL: [State; 4] = [State::Image, State::Text1, State::GYUL53L0X, State::Text2];
+    /// Menu items, in the order of `ALL`.
+    const NAMES: [&'static str; 4] = texts::TESTBED_MENU;
+}
+
+/// The button, sampled from `TIM2` while the main loop waits on the sensor.
+static BUTTON: Mutex<RefCell<Option<ButtonSampler>>> = Mutex::new(RefCell::new(None));
+
+#[interrupt]
+fn TIM2() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(button) = BUTTON.borrow(cs).borrow_mut().as_mut() {
+            button.on_interrupt();
         }
-    }
+    });
+}
+
+/// The oldest gesture the main loop has not handled yet.
+fn next_gesture() -> Option<Gesture> {
+    cortex_m::interrupt::free(|cs| {
+        BUTTON
+            .borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .and_then(ButtonSampler::next)
+    })
 }
 
 #[exception]
@@ -0,0 +1,285 @@
+//! Soft start for the L298N channels.
+//!
+//! Instead of switching a channel straight to full duty, or from full forward to full reverse,
+//! [`MotorRamp`] moves each channel's speed towards its target at a limited rate, and lets a
+//! channel coast for a moment at standstill before it changes direction. That keeps wheel slip
+//! and the current spikes that brown out the sensors down.
+//!
+//! [`MotorRamp::update`] is meant to be called from a periodic timer task, and only says what the
+//! channels should be doing, so it runs on the host too. The targets usually come from a
+//! [`DifferentialDrive`](crate::diff_drive::DifferentialDrive).
+
+/// Full speed, speeds are in thousandths of the maximum duty.
+pub const FULL_SPEED: i16 = 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RampConfig {
+    /// How fast a channel may speed up, in thousandths of full speed per millisecond.
+    pub accel_per_ms: u16,
+    /// How fast a channel may slow down, in thousandths of full speed per millisecond.
+    pub decel_per_ms: u16,
+    /// How long a channel coasts at standstill before changing direction, in milliseconds.
+    pub coast_ms: u32,
+}
+
+impl Default for RampConfig {
+    fn default() -> Self {
+        RampConfig {
+            // Full speed in 100 ms
+            accel_per_ms: 10,
+            // Standstill from full speed in 50 ms, stopping short of a cliff matters more
+            decel_per_ms: 20,
+            coast_ms: 20,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Reverse,
+    /// Both inputs low.
+    Stop,
+}
+
+/// What to set a channel to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChannelOutput {
+    pub direction: Direction,
+    /// Thousandths of the maximum duty.
+    pub duty: u16,
+}
+
+impl ChannelOutput {
+    /// Drives the channel at a signed speed in thousandths of full speed, positive forward.
+    pub fn from_signed(speed: i16) -> Self {
+        ChannelOutput {
+            direction: match speed.signum() {
+                1 => Direction::Forward,
+                -1 => Direction::Reverse,
+                _ => Direction::Stop,
+            },
+            duty: speed.unsigned_abs().min(FULL_SPEED as u16),
+        }
+    }
+
+    /// The duty signed by direction, in thousandths of full speed. Zero when stopped.
+    pub fn signed(&self) -> i16 {
+        match self.direction {
+            Direction::Forward => self.duty as i16,
+            Direction::Reverse => -(self.duty as i16),
+            Direction::Stop => 0,
+        }
+    }
+
+    /// The duty in timer counts, for a timer with `max_duty` counts at full duty.
+    pub fn scaled_duty(&self, max_duty: u16) -> u16 {
+        (u32::from(self.duty) * u32::from(max_duty) / FULL_SPEED as u32) as u16
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+struct Channel {
+    /// Signed, positive is forward.
+    speed: i16,
+    target: i16,
+    /// When the channel came to a standstill, while it waits to change direction.
+    stopped_since_ms: Option<u32>,
+}
+
+impl Channel {
+    fn update(&mut self, config: &RampConfig, elapsed_ms: u32, now_ms: u32) -> ChannelOutput {
+        let reversing = self.speed != 0 && self.target.signum() == -self.speed.signum();
+        if self.speed == 0 {
+            let since = *self.stopped_since_ms.get_or_insert(now_ms);
+            if self.target != 0 && now_ms.wrapping_sub(since) >= config.coast_ms {
+                self.stopped_since_ms = None;
+            }
+        }
+
+        if self.stopped_since_ms.is_none() {
+            // Slow down towards standstill first when reversing
+            let goal = if reversing { 0 } else { self.target };
+            let slowing = goal.abs() < self.speed.abs() || reversing;
+            let rate = if slowing {
+                config.decel_per_ms
+            } else {
+                config.accel_per_ms
+            };
+            let step = (u32::from(rate) * elapsed_ms).min(2 * FULL_SPEED as u32) as i16;
+            let delta = (goal - self.speed).clamp(-step, step);
+            self.speed += delta;
+        }
+
+        ChannelOutput::from_signed(self.speed)
+    }
+}
+
+/// Ramps the two L298N channels, `a` the right wheel and `b` the left, towards their targets.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MotorRamp {
+    config: RampConfig,
+    a: Channel,
+    b: Channel,
+    last_update_ms: Option<u32>,
+}
+
+impl MotorRamp {
+    /// Both channels at a standstill.
+    pub fn new(config: RampConfig) -> Self {
+        MotorRamp {
+            config,
+            a: Channel::default(),
+            b: Channel::default(),
+            last_update_ms: None,
+        }
+    }
+
+    pub fn config(&self) -> RampConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: RampConfig) {
+        self.config = config;
+    }
+
+    /// Signed speeds of channels `a` and `b` to ramp towards, in thousandths of full speed.
+    pub fn targets(&self) -> (i16, i16) {
+        (self.a.target, self.b.target)
+    }
+
+    /// Sets the speeds to ramp towards, clamped to full speed either way.
+    pub fn set_targets(&mut self, a: i16, b: i16) {
+        self.a.target = a.clamp(-FULL_SPEED, FULL_SPEED);
+        self.b.target = b.clamp(-FULL_SPEED, FULL_SPEED);
+    }
+
+    /// Signed speeds of channels `a` and `b`, in thousandths of full speed.
+    pub fn speeds(&self) -> (i16, i16) {
+        (self.a.speed, self.b.speed)
+    }
+
+    /// Moves both channels along their ramps and returns what to set channels `a` and `b` to.
+    ///
+    /// `now_ms` is the time in milliseconds from a free running clock, it may wrap around.
+    pub fn update(&mut self, now_ms: u32) -> (ChannelOutput, ChannelOutput) {
+        let elapsed_ms = self
+            .last_update_ms
+            .map_or(0, |last| now_ms.wrapping_sub(last));
+        self.last_update_ms = Some(now_ms);
+        (
+            self.a.update(&self.config, elapsed_ms, now_ms),
+            self.b.update(&self.config, elapsed_ms, now_ms),
+        )
+    }
+}
+
+impl Default for MotorRamp {
+    fn default() -> Self {
+        MotorRamp::new(RampConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: RampConfig = RampConfig {
+        accel_per_ms: 10,
+        decel_per_ms: 20,
+        coast_ms: 5,
+    };
+
+    /// Runs `ramp` from `from_ms` up to and including `to_ms`, one millisecond at a time.
+    fn run(ramp: &mut MotorRamp, from_ms: u32, to_ms: u32) -> (ChannelOutput, ChannelOutput) {
+        let mut outputs = ramp.update(from_ms);
+        for now in from_ms + 1..=to_ms {
+            outputs = ramp.update(now);
+        }
+        outputs
+    }
+
+    #[test]
+    fn soft_start() {
+        let mut ramp = MotorRamp::new(CONFIG);
+        ramp.set_targets(FULL_SPEED, FULL_SPEED);
+        // Coasts at standstill first, as for any change of direction
+        let (a, _) = run(&mut ramp, 0, 4);
+        assert_eq!(a.direction, Direction::Stop);
+        let (a, b) = run(&mut ramp, 5, 10);
+        assert_eq!(a.direction, Direction::Forward);
+        assert_eq!(a.duty, 60);
+        assert_eq!(a, b);
+        let (a, _) = run(&mut ramp, 11, 200);
+        assert_eq!(a.duty, 1000);
+    }
+
+    #[test]
+    fn reversal_slows_down_and_coasts() {
+        let mut ramp = MotorRamp::new(CONFIG);
+        ramp.set_targets(FULL_SPEED, FULL_SPEED);
+        run(&mut ramp, 0, 200);
+        assert_eq!(ramp.speeds(), (1000, 1000));
+
+        ramp.set_targets(FULL_SPEED, -FULL_SPEED);
+        // Channel a keeps going, channel b slows down at the deceleration limit
+        let (a, b) = run(&mut ramp, 201, 210);
+        assert_eq!(a.duty, 1000);
+        assert_eq!(b.direction, Direction::Forward);
+        assert_eq!(b.duty, 800);
+
+        let (_, b) = run(&mut ramp, 211, 250);
+        assert_eq!(b.direction, Direction::Stop);
+        let (_, b) = run(&mut ramp, 251, 254);
+        assert_eq!(b.direction, Direction::Stop);
+        let (_, b) = run(&mut ramp, 255, 260);
+        assert_eq!(b.direction, Direction::Reverse);
+        assert!(b.duty > 0 && b.duty <= 60, "{:?}", b);
+    }
+
+    #[test]
+    fn stop_ramps_down() {
+        let mut ramp = MotorRamp::new(CONFIG);
+        ramp.set_targets(-FULL_SPEED, -FULL_SPEED);
+        run(&mut ramp, 0, 200);
+        ramp.set_targets(0, 0);
+        let (a, _) = run(&mut ramp, 201, 225);
+        assert_eq!(a.direction, Direction::Reverse);
+        assert_eq!(a.duty, 500);
+        let (a, b) = run(&mut ramp, 226, 260);
+        assert_eq!(a.direction, Direction::Stop);
+        assert_eq!(b.duty, 0);
+    }
+
+    #[test]
+    fn long_gaps_between_updates() {
+        let mut ramp = MotorRamp::new(CONFIG);
+        ramp.set_targets(FULL_SPEED, FULL_SPEED);
+        ramp.update(u32::MAX - 10);
+        ramp.update(u32::MAX);
+        assert_eq!(ramp.update(1000).0.duty, 1000);
+    }
+
+    #[test]
+    fn scaled_duty() {
+        let output = ChannelOutput {
+            direction: Direction::Forward,
+            duty: 500,
+        };
+        assert_eq!(output.scaled_duty(2400), 1200);
+    }
+
+    #[test]
+    fn signed_outputs() {
+        for speed in [-1000, -1, 0, 1, 1000] {
+            assert_eq!(ChannelOutput::from_signed(speed).signed(), speed);
+        }
+        assert_eq!(
+            ChannelOutput::from_signed(-400),
+            ChannelOutput {
+                direction: Direction::Reverse,
+                duty: 400
+            }
+        );
+    }
+}
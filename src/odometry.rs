@@ -0,0 +1,279 @@
+//! Dead reckoning from the wheel encoders.
+//!
+//! [`WheelOdometry`] takes the raw counts of the two quadrature encoder timers, and integrates
+//! how far each wheel moved into a pose, with x pointing forward and y to the left of where the
+//! rover started. Counts are taken as 16 bit and may wrap around, so the 32 bit timers can be
+//! truncated, as long as no wheel moves more than 32767 counts between updates.
+
+use core::f32::consts::PI;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OdometryConfig {
+    /// Encoder counts per wheel revolution, counting every edge of both channels.
+    pub counts_per_rev: u16,
+    /// In metres.
+    pub wheel_diameter: f32,
+    /// Distance between the wheels, in metres.
+    pub track: f32,
+    /// Set for an encoder that counts down while its wheel drives forward.
+    pub invert_right: bool,
+    pub invert_left: bool,
+}
+
+impl Default for OdometryConfig {
+    fn default() -> Self {
+        OdometryConfig {
+            // 360 line encoders, counted on both edges of both channels
+            counts_per_rev: 1440,
+            wheel_diameter: 0.065,
+            track: 0.1,
+            invert_right: false,
+            invert_left: false,
+        }
+    }
+}
+
+impl OdometryConfig {
+    /// How far a wheel moves per count, in metres.
+    pub fn metres_per_count(&self) -> f32 {
+        PI * self.wheel_diameter / f32::from(self.counts_per_rev)
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Pose {
+    /// In metres.
+    pub x: f32,
+    pub y: f32,
+    /// Heading in radians anticlockwise from the x axis, within `(-PI, PI]`.
+    pub theta: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Sample {
+    right: u16,
+    left: u16,
+    at_ms: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WheelOdometry {
+    config: OdometryConfig,
+    last: Option<Sample>,
+    pose: Pose,
+    /// Signed distance each wheel travelled, in metres.
+    right_distance: f32,
+    left_distance: f32,
+    /// Over the last update, in metres per second.
+    right_speed: f32,
+    left_speed: f32,
+}
+
+impl WheelOdometry {
+    /// At the origin, facing along the x axis. The first update only records the counts.
+    pub fn new(config: OdometryConfig) -> Self {
+        WheelOdometry {
+            config,
+            last: None,
+            pose: Pose::default(),
+            right_distance: 0.0,
+            left_distance: 0.0,
+            right_speed: 0.0,
+            left_speed: 0.0,
+        }
+    }
+
+    pub fn config(&self) -> OdometryConfig {
+        self.config
+    }
+
+    /// Integrates the movement since the last update from the raw counts of the right and left
+    /// wheel encoders, read at `now_ms` from a free running clock.
+    pub fn update(&mut self, right_count: u16, left_count: u16, now_ms: u32) {
+        let sample = Sample {
+            right: right_count,
+            left: left_count,
+            at_ms: now_ms,
+        };
+        let last = match self.last.replace(sample) {
+            Some(last) => last,
+            None => return,
+        };
+
+        let scale = self.config.metres_per_count();
+        let counts = |now: u16, last: u16, invert: bool| {
+            // Widened first, as there is no i16 for minus `i16::MIN`
+            let counts = i32::from(now.wrapping_sub(last) as i16);
+            (if invert { -counts } else { counts }) as f32
+        };
+        let right = counts(right_count, last.right, self.config.invert_right) * scale;
+        let left = counts(left_count, last.left, self.config.invert_left) * scale;
+        self.right_distance += right;
+        self.left_distance += left;
+
+        let elapsed_ms = now_ms.wrapping_sub(last.at_ms);
+        if elapsed_ms > 0 {
+            let elapsed = elapsed_ms as f32 / 1000.0;
+            self.right_speed = right / elapsed;
+            self.left_speed = left / elapsed;
+        }
+
+        // Midpoint integration: move along the average of the old and new heading
+        let distance = (right + left) / 2.0;
+        let turned = (right - left) / self.config.track;
+        let midpoint = self.pose.theta + turned / 2.0;
+        self.pose.x += distance * libm::cosf(midpoint);
+        self.pose.y += distance * libm::sinf(midpoint);
+        self.pose.theta = wrap_angle(self.pose.theta + turned);
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    /// Moves the origin to the current pose, without losing track of the counts.
+    pub fn reset_pose(&mut self) {
+        self.pose = Pose::default();
+    }
+
+    /// Signed distance travelled by the middle of the axle, in metres.
+    pub fn distance(&self) -> f32 {
+        (self.right_distance + self.left_distance) / 2.0
+    }
+
+    /// Signed distances travelled by the right and left wheels, in metres.
+    pub fn wheel_distances(&self) -> (f32, f32) {
+        (self.right_distance, self.left_distance)
+    }
+
+    /// Speeds of the right and left wheels over the last update, in metres per second.
+    pub fn wheel_speeds(&self) -> (f32, f32) {
+        (self.right_speed, self.left_speed)
+    }
+}
+
+impl Default for WheelOdometry {
+    fn default() -> Self {
+        WheelOdometry::new(OdometryConfig::default())
+    }
+}
+
+/// Brings an angle into `(-PI, PI]`.
+pub fn wrap_angle(angle: f32) -> f32 {
+    let mut angle = angle % (2.0 * PI);
+    if angle > PI {
+        angle -= 2.0 * PI;
+    } else if angle <= -PI {
+        angle += 2.0 * PI;
+    }
+    angle
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: OdometryConfig = OdometryConfig {
+        counts_per_rev: 1000,
+        // 1 mm per count
+        wheel_diameter: 1.0 / PI,
+        track: 0.1,
+        invert_right: false,
+        invert_left: false,
+    };
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    /// Feeds the odometry `steps` updates 10 ms apart, moving the wheels by the given counts each
+    /// time, starting from counts of `start`.
+    fn drive(odometry: &mut WheelOdometry, start: u16, right: i16, left: i16, steps: u16) {
+        let (mut r, mut l) = (start, start);
+        odometry.update(r, l, 0);
+        for i in 1..=steps {
+            r = r.wrapping_add(right as u16);
+            l = l.wrapping_add(left as u16);
+            odometry.update(r, l, u32::from(i) * 10);
+        }
+    }
+
+    #[test]
+    fn straight_line() {
+        let mut odometry = WheelOdometry::new(CONFIG);
+        drive(&mut odometry, 0, 5, 5, 100);
+        let pose = odometry.pose();
+        assert!(close(pose.x, 0.5) && close(pose.y, 0.0) && close(pose.theta, 0.0));
+        assert!(close(odometry.distance(), 0.5));
+        let (right, left) = odometry.wheel_speeds();
+        assert!(close(right, 0.5) && close(left, 0.5));
+    }
+
+    #[test]
+    fn counter_wrap_around() {
+        let mut odometry = WheelOdometry::new(CONFIG);
+        drive(&mut odometry, u16::MAX - 200, -5, -5, 100);
+        assert!(close(odometry.pose().x, -0.5));
+        let mut odometry = WheelOdometry::new(CONFIG);
+        drive(&mut odometry, u16::MAX - 200, 5, 5, 100);
+        assert!(close(odometry.pose().x, 0.5));
+    }
+
+    #[test]
+    fn inverted_half_counter_jump() {
+        let mut odometry = WheelOdometry::new(OdometryConfig {
+            invert_right: true,
+            ..CONFIG
+        });
+        odometry.update(0, 0, 0);
+        odometry.update(0x8000, 0, 10);
+        let (right, left) = odometry.wheel_distances();
+        assert!(close(right, 32.768), "{}", right);
+        assert_eq!(left, 0.0);
+    }
+
+    #[test]
+    fn spin_on_the_spot() {
+        let mut odometry = WheelOdometry::new(CONFIG);
+        // An eighth of a turn left moves each wheel by PI * track / 8
+        let counts = (PI * 0.1 / 8.0 * 1000.0) as i16;
+        drive(&mut odometry, 0, 1, -1, counts as u16);
+        let pose = odometry.pose();
+        assert!(close(pose.x, 0.0) && close(pose.y, 0.0));
+        assert!((pose.theta - PI / 4.0).abs() < 0.01, "{:?}", pose);
+        assert!(close(odometry.distance(), 0.0));
+    }
+
+    #[test]
+    fn arc() {
+        let mut odometry = WheelOdometry::new(CONFIG);
+        // Radius 0.1 m to the left: 2 mm along the arc and 0.02 rad per step
+        drive(&mut odometry, 0, 3, 1, 100);
+        let pose = odometry.pose();
+        assert!((pose.theta - 2.0).abs() < 1e-3, "{:?}", pose);
+        assert!((pose.x - 0.1 * libm::sinf(2.0)).abs() < 1e-3, "{:?}", pose);
+        assert!(
+            (pose.y - 0.1 * (1.0 - libm::cosf(2.0))).abs() < 1e-3,
+            "{:?}",
+            pose
+        );
+    }
+
+    #[test]
+    fn inverted_encoder() {
+        let mut odometry = WheelOdometry::new(OdometryConfig {
+            invert_left: true,
+            ..CONFIG
+        });
+        drive(&mut odometry, 0, 5, -5, 10);
+        assert!(close(odometry.pose().x, 0.05));
+    }
+
+    #[test]
+    fn wrap_angles() {
+        assert!(close(wrap_angle(3.0 * PI / 2.0), -PI / 2.0));
+        assert!(close(wrap_angle(-3.0 * PI / 2.0), PI / 2.0));
+        assert!(close(wrap_angle(PI), PI));
+        assert!(close(wrap_angle(-PI), PI));
+    }
+}
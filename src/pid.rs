@@ -0,0 +1,392 @@
+//! Closed loop wheel speed control.
+//!
+//! Open loop, the same duty runs the two motors at different speeds and the rover curves.
+//! [`Pid`] is a generic PID controller with feedforward, a low pass filtered derivative on the
+//! measurement, output clamping and anti-windup. [`WheelSpeedController`] runs one per wheel: it
+//! takes the ramped speed from the [`MotorRamp`](crate::motor::MotorRamp), the wheel speed
+//! measured by the [`WheelOdometry`](crate::odometry::WheelOdometry), and returns the channel
+//! output that closes the gap. It is meant to be updated at a fixed rate from a timer task.
+
+use crate::motor::{ChannelOutput, Direction, FULL_SPEED};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PidConfig {
+    /// Proportional gain, output per unit of error.
+    pub kp: f32,
+    /// Integral gain, output per unit of error and second.
+    pub ki: f32,
+    /// Derivative gain, output per unit of error per second.
+    pub kd: f32,
+    /// Feedforward gain, output per unit of setpoint.
+    pub kf: f32,
+    /// Time constant of the derivative's low pass filter, in seconds. Zero disables the filter.
+    pub derivative_tau: f32,
+    pub output_min: f32,
+    pub output_max: f32,
+}
+
+impl PidConfig {
+    /// Gains for [`WheelSpeedController`], with speeds in metres per second and the output a
+    /// fraction of full duty. Tuned against a motor reaching 0.25 m/s at full duty with a 0.1 s
+    /// time constant, the rover's nominal motors.
+    pub const WHEEL_SPEED: PidConfig = PidConfig {
+        kp: 4.0,
+        ki: 30.0,
+        kd: 0.01,
+        kf: 4.0,
+        derivative_tau: 0.02,
+        output_min: -1.0,
+        output_max: 1.0,
+    };
+}
+
+impl Default for PidConfig {
+    fn default() -> Self {
+        PidConfig::WHEEL_SPEED
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Pid {
+    config: PidConfig,
+    /// Accumulated integral term, in units of output.
+    integral: f32,
+    last_measurement: Option<f32>,
+    /// Filtered rate of change of the measurement, per second.
+    derivative: f32,
+}
+
+impl Pid {
+    pub fn new(config: PidConfig) -> Self {
+        Pid {
+            config,
+            integral: 0.0,
+            last_measurement: None,
+            derivative: 0.0,
+        }
+    }
+
+    pub fn config(&self) -> PidConfig {
+        self.config
+    }
+
+    /// Takes effect with the next update, keeping the accumulated integral.
+    pub fn set_config(&mut self, config: PidConfig) {
+        self.config = config;
+    }
+
+    /// Limits the output to `min..=max` from the next update on.
+    pub fn set_limits(&mut self, min: f32, max: f32) {
+        self.config.output_min = min;
+        self.config.output_max = max;
+    }
+
+    /// Forgets the integral and the last measurement, e.g. after the output was cut.
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.last_measurement = None;
+        self.derivative = 0.0;
+    }
+
+    /// The output that drives `measurement` towards `setpoint`, `dt` seconds after the last
+    /// update.
+    ///
+    /// The derivative acts on the measurement rather than the error, so a step in the setpoint
+    /// does not kick the output. While the output is saturated, the integral only moves in the
+    /// direction that brings it back within its limits.
+    pub fn update(&mut self, setpoint: f32, measurement: f32, dt: f32) -> f32 {
+        let config = &self.config;
+        let error = setpoint - measurement;
+
+        if let Some(last) = self.last_measurement.replace(measurement) {
+            if dt > 0.0 {
+                let rate = (measurement - last) / dt;
+                let alpha = dt / (config.derivative_tau + dt);
+                self.derivative += alpha * (rate - self.derivative);
+            }
+        }
+
+        let unintegrated = config.kf * setpoint + config.kp * error - config.kd * self.derivative;
+        let integral = self.integral + config.ki * error * dt;
+        let output = unintegrated + integral;
+        let clamped = output.clamp(config.output_min, config.output_max);
+
+        // Conditional integration: keep the new integral unless it winds further into the limit
+        let winding_up = (output > config.output_max && error > 0.0)
+            || (output < config.output_min && error < 0.0);
+        if !winding_up {
+            self.integral = integral;
+        }
+        // The limits may have moved since the integral was accumulated
+        let span = config.output_max - config.output_min;
+        self.integral = self.integral.clamp(-span, span);
+
+        clamped
+    }
+}
+
+impl Default for Pid {
+    fn default() -> Self {
+        Pid::new(PidConfig::default())
+    }
+}
+
+/// Speed control of one wheel.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WheelSpeedController {
+    pid: Pid,
+    /// Wheel speed at full speed, in metres per second.
+    max_wheel_speed: f32,
+    /// Factor applied to the feedforward.
+    trim: f32,
+}
+
+impl WheelSpeedController {
+    /// `max_wheel_speed` is what a target of [`FULL_SPEED`] stands for, in metres per second.
+    pub fn new(config: PidConfig, max_wheel_speed: f32) -> Self {
+        WheelSpeedController {
+            pid: Pid::new(config),
+            max_wheel_speed,
+            trim: 1.0,
+        }
+    }
+
+    /// Scales the feedforward, e.g. below 1 for a motor stronger than the one the gains were
+    /// tuned for. The wheel still settles at its target, the trim only leaves less of the gap to
+    /// the rest of the PID.
+    pub fn set_trim(&mut self, trim: f32) {
+        self.trim = trim;
+    }
+
+    pub fn pid(&self) -> &Pid {
+        &self.pid
+    }
+
+    pub fn pid_mut(&mut self) -> &mut Pid {
+        &mut self.pid
+    }
+
+    /// The output that brings the wheel from `measured` metres per second to `target`, a signed
+    /// speed in thousandths of full speed, `dt` seconds after the last update.
+    ///
+    /// A target of zero cuts the channel, so the wheel coasts where the ramp expects it to. The
+    /// output never runs against the target's direction, braking is left to the ramp.
+    pub fn update(&mut self, target: i16, measured: f32, dt: f32) -> ChannelOutput {
+        if target == 0 {
+            self.pid.reset();
+            return ChannelOutput {
+                direction: Direction::Stop,
+                duty: 0,
+            };
+        }
+
+        let config = self.pid.config();
+        let (output_min, output_max) = if target > 0 {
+            (0.0, config.output_max.max(0.0))
+        } else {
+            (config.output_min.min(0.0), 0.0)
+        };
+        self.pid.set_config(PidConfig {
+            kf: config.kf * self.trim,
+            output_min,
+            output_max,
+            ..config
+        });
+        let setpoint = f32::from(target) / f32::from(FULL_SPEED) * self.max_wheel_speed;
+        let duty = self.pid.update(setpoint, measured, dt);
+        self.pid.set_config(config);
+
+        ChannelOutput::from_signed((duty * f32::from(FULL_SPEED) + 0.5 * duty.signum()) as i16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Update period of the controller, in seconds, as on the rover.
+    const DT: f32 = 0.01;
+
+    /// First order model of a DC motor and wheel: the speed settles exponentially towards
+    /// `gain` times the duty, with time constant `tau` seconds.
+    struct Motor {
+        gain: f32,
+        tau: f32,
+        speed: f32,
+    }
+
+    impl Motor {
+        /// The rover's nominal motor, 0.25 m/s at full duty.
+        fn nominal() -> Self {
+            Motor {
+                gain: 0.25,
+                tau: 0.1,
+                speed: 0.0,
+            }
+        }
+
+        /// Advances the model by `dt` at signed `duty`, returning the new speed.
+        fn step(&mut self, duty: f32, dt: f32) -> f32 {
+            // Integrated in small steps, so the model is accurate whatever the controller period
+            let steps = 10;
+            let h = dt / steps as f32;
+            for _ in 0..steps {
+                self.speed += h * (self.gain * duty - self.speed) / self.tau;
+            }
+            self.speed
+        }
+    }
+
+    fn signed(output: ChannelOutput) -> f32 {
+        f32::from(output.signed()) / f32::from(FULL_SPEED)
+    }
+
+    /// Runs `controller` on `motor` towards `target` for `seconds`, returning the speeds seen.
+    fn run(
+        controller: &mut WheelSpeedController,
+        motor: &mut Motor,
+        target: i16,
+        seconds: f32,
+    ) -> Vec<f32> {
+        let steps = (seconds / DT) as usize;
+        (0..steps)
+            .map(|_| {
+                let output = controller.update(target, motor.speed, DT);
+                motor.step(signed(output), DT)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn step_response() {
+        let mut controller = WheelSpeedController::new(PidConfig::WHEEL_SPEED, 0.25);
+        let mut motor = Motor::nominal();
+        let speeds = run(&mut controller, &mut motor, 600, 1.0);
+
+        let setpoint = 0.15;
+        let peak = speeds.iter().cloned().fold(0.0, f32::max);
+        assert!(peak < setpoint * 1.1, "overshoot to {}", peak);
+        // Within 5 % after 0.5 s, and stays there
+        let settled = (0.5 / DT) as usize;
+        for speed in &speeds[settled..] {
+            assert!((speed - setpoint).abs() < setpoint * 0.05, "{}", speed);
+        }
+        assert!((speeds.last().unwrap() - setpoint).abs() < setpoint * 0.01);
+    }
+
+    #[test]
+    fn mismatched_motors_run_at_the_same_speed() {
+        let mut right = WheelSpeedController::new(PidConfig::WHEEL_SPEED, 0.25);
+        let mut left = WheelSpeedController::new(PidConfig::WHEEL_SPEED, 0.25);
+        let mut strong = Motor {
+            gain: 0.3,
+            ..Motor::nominal()
+        };
+        let mut weak = Motor {
+            gain: 0.2,
+            tau: 0.15,
+            ..Motor::nominal()
+        };
+        let right_speeds = run(&mut right, &mut strong, 500, 1.0);
+        let left_speeds = run(&mut left, &mut weak, 500, 1.0);
+        let difference = right_speeds.last().unwrap() - left_speeds.last().unwrap();
+        assert!(difference.abs() < 0.001, "{}", difference);
+        // The right wheel's distance travelled stays close to the left's
+        let distance = |speeds: &[f32]| speeds.iter().sum::<f32>() * DT;
+        let drift = distance(&right_speeds) - distance(&left_speeds);
+        assert!(drift.abs() < 0.01, "{} m", drift);
+    }
+
+    #[test]
+    fn trim_keeps_the_speeds_equal() {
+        let mut right = WheelSpeedController::new(PidConfig::WHEEL_SPEED, 0.25);
+        let mut left = WheelSpeedController::new(PidConfig::WHEEL_SPEED, 0.25);
+        right.set_trim(0.8);
+        let speeds = |controller: &mut WheelSpeedController| {
+            run(controller, &mut Motor::nominal(), 500, 1.0)
+        };
+        let right_speeds = speeds(&mut right);
+        let left_speeds = speeds(&mut left);
+        // Less duty at first, but the same speed once settled
+        assert!(right_speeds[0] < left_speeds[0]);
+        let difference = right_speeds.last().unwrap() - left_speeds.last().unwrap();
+        assert!(difference.abs() < 0.001, "{}", difference);
+        assert!((left_speeds.last().unwrap() - 0.125).abs() < 0.001);
+    }
+
+    #[test]
+    fn anti_windup() {
+        // Asks for more than the motor can give, then for a speed it can reach
+        let mut controller = WheelSpeedController::new(PidConfig::WHEEL_SPEED, 0.25);
+        let mut motor = Motor {
+            gain: 0.2,
+            ..Motor::nominal()
+        };
+        let speeds = run(&mut controller, &mut motor, FULL_SPEED, 2.0);
+        assert!((speeds.last().unwrap() - 0.2).abs() < 0.001);
+
+        // Backs off at once, instead of first unwinding two seconds' worth of integral
+        let output = controller.update(400, motor.speed, DT);
+        assert!(output.duty < 100, "{:?}", output);
+        let speeds = run(&mut controller, &mut motor, 400, 1.0);
+        assert!((speeds.last().unwrap() - 0.1).abs() < 0.002);
+    }
+
+    #[test]
+    fn output_stays_within_limits() {
+        let mut pid = Pid::new(PidConfig {
+            output_min: -0.5,
+            output_max: 0.5,
+            ..PidConfig::WHEEL_SPEED
+        });
+        assert_eq!(pid.update(10.0, 0.0, DT), 0.5);
+        assert_eq!(pid.update(-10.0, 0.0, DT), -0.5);
+    }
+
+    #[test]
+    fn filtered_derivative() {
+        let config = PidConfig {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 1.0,
+            kf: 0.0,
+            derivative_tau: 0.02,
+            output_min: -100.0,
+            output_max: 100.0,
+        };
+        let mut unfiltered = Pid::new(PidConfig {
+            derivative_tau: 0.0,
+            ..config
+        });
+        let mut filtered = Pid::new(config);
+        unfiltered.update(0.0, 0.0, DT);
+        filtered.update(0.0, 0.0, DT);
+
+        // A single noisy sample of 0.01 m/s, on a 10 ms period
+        let spike = unfiltered.update(0.0, 0.01, DT);
+        assert!((spike + 1.0).abs() < 1e-4, "{}", spike);
+        let damped = filtered.update(0.0, 0.01, DT);
+        assert!((damped + 1.0 / 3.0).abs() < 1e-4, "{}", damped);
+    }
+
+    #[test]
+    fn zero_target_coasts() {
+        let mut controller = WheelSpeedController::new(PidConfig::WHEEL_SPEED, 0.25);
+        let mut motor = Motor::nominal();
+        run(&mut controller, &mut motor, -800, 0.5);
+        assert!(motor.speed < -0.19);
+        let output = controller.update(0, motor.speed, DT);
+        assert_eq!(output.direction, Direction::Stop);
+        assert_eq!(output.duty, 0);
+    }
+
+    #[test]
+    fn never_drives_against_the_target() {
+        let mut controller = WheelSpeedController::new(PidConfig::WHEEL_SPEED, 0.25);
+        // Still rolling forward fast when the target drops to a crawl
+        let output = controller.update(100, 0.25, DT);
+        assert_ne!(output.direction, Direction::Reverse);
+        let output = controller.update(-100, 0.25, DT);
+        assert_ne!(output.direction, Direction::Forward);
+    }
+}
@@ -0,0 +1,105 @@
+//! Transmit side of USART1 driven by DMA2.
+//!
+//! [`DmaSerialTx::send`] copies a message into a static buffer and hands it to DMA2 stream 7 on
+//! channel 4, so sending telemetry costs the CPU a copy instead of a byte per interrupt, and
+//! nothing waits on a debugger the way semihosting does. Sending while the previous message is
+//! still going out fails with [`Busy`], the caller decides whether to drop the message or retry.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use stm32f4xx_hal::pac;
+use stm32f4xx_hal::serial::Tx;
+
+/// DMA stream and channel of the USART1 transmit requests.
+const STREAM: usize = 7;
+const DMA_CHANNEL: u8 = 4;
+
+/// The previous message is still being sent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Busy;
+
+pub struct DmaSerialTx {
+    _tx: Tx<pac::USART1>,
+    dma: pac::DMA2,
+    buffer: &'static mut [u8],
+}
+
+impl DmaSerialTx {
+    /// Takes over a USART1 transmitter set up by the HAL, e.g. on PA9, and DMA2. Messages are
+    /// limited to the length of `buffer`.
+    pub fn new(tx: Tx<pac::USART1>, dma: pac::DMA2, buffer: &'static mut [u8]) -> Self {
+        // Safe, enabling a clock does not touch anything another driver relies on, and the
+        // transmitter owns CR3's DMA bit
+        unsafe {
+            (*pac::RCC::ptr())
+                .ahb1enr
+                .modify(|_, w| w.dma2en().enabled());
+            (*pac::USART1::ptr()).cr3.modify(|_, w| w.dmat().enabled());
+        }
+
+        let st = &dma.st[STREAM];
+        st.cr.write(|w| w.en().disabled());
+        while st.cr.read().en().is_enabled() {}
+        let data_register = unsafe { &(*pac::USART1::ptr()).dr as *const _ as u32 };
+        st.par.write(|w| unsafe { w.pa().bits(data_register) });
+
+        DmaSerialTx {
+            _tx: tx,
+            dma,
+            buffer,
+        }
+    }
+
+    /// Whether a message is still being handed to the USART.
+    pub fn is_busy(&self) -> bool {
+        self.dma.st[STREAM].cr.read().en().is_enabled()
+    }
+
+    /// Starts sending `bytes` and returns straight away.
+    ///
+    /// # Panics
+    ///
+    /// If `bytes` does not fit the buffer.
+    pub fn send(&mut self, bytes: &[u8]) -> Result<(), Busy> {
+        if self.is_busy() {
+            return Err(Busy);
+        }
+        self.buffer[..bytes.len()].copy_from_slice(bytes);
+        self.clear_flags();
+
+        let st = &self.dma.st[STREAM];
+        st.m0ar
+            .write(|w| unsafe { w.m0a().bits(self.buffer.as_ptr() as u32) });
+        st.ndtr.write(|w| w.ndt().bits(bytes.len() as u16));
+        st.cr.write(|w| {
+            unsafe { w.chsel().bits(DMA_CHANNEL) }
+                .minc()
+                .incremented()
+                .psize()
+                .bits8()
+                .msize()
+                .bits8()
+                .dir()
+                .memory_to_peripheral()
+        });
+        // The buffer has to be written before the DMA reads it
+        compiler_fence(Ordering::Release);
+        st.cr.modify(|_, w| w.en().enabled());
+        Ok(())
+    }
+
+    fn clear_flags(&mut self) {
+        self.dma.hifcr.write(|w| {
+            w.ctcif7()
+                .set_bit()
+                .chtif7()
+                .set_bit()
+                .cteif7()
+                .set_bit()
+                .cdmeif7()
+                .set_bit()
+                .cfeif7()
+                .set_bit()
+        });
+    }
+}
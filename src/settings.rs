@@ -0,0 +1,392 @@
+//! Values that can be tuned while the rover runs.
+//!
+//! [`Settings`] gathers the thresholds and timings that used to be compile time constants, in
+//! whole units so they can be typed in at the console. Every [`Param`] has a name, a unit and a
+//! range its value is checked against. Some only work together, e.g. the sensors cannot sample
+//! faster than they range, so [`Settings::set`] narrows the range to what fits the others. The
+//! firmware hands the settings on to the parts they configure, e.g. [`Settings::drive_config`].
+
+use core::fmt;
+
+use crate::cliff_filter::{FilterConfig, HYSTERESIS_MM, MAX_WINDOW, WINDOW};
+use crate::cliff_monitor::{CliffMonitor, STALE_MS};
+use crate::diff_drive::DiffDriveConfig;
+use crate::drive::{DriveConfig, CLIFF_THRESHOLD, PRE_TURN_MS, TURN_MS};
+use crate::tof::{INTER_MEASUREMENT_MS, MAX_CONVERGENCE_MS, READOUT_MS, SENSOR_DEADLINE_MS};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Param {
+    CliffThreshold,
+    CliffHysteresis,
+    FilterWindow,
+    StaleMs,
+    PreTurnMs,
+    TurnMs,
+    TurnAngle,
+    ConvergenceMs,
+    MeasurementPeriodMs,
+    TrimA,
+    TrimB,
+}
+
+/// A value outside of the parameter's range, or between its steps.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OutOfRange {
+    pub min: i32,
+    pub max: i32,
+    pub step: i32,
+}
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "out of range {} to {}", self.min, self.max)?;
+        if self.step > 1 {
+            write!(f, " in steps of {}", self.step)?;
+        }
+        Ok(())
+    }
+}
+
+impl Param {
+    pub const ALL: [Param; 11] = [
+        Param::CliffThreshold,
+        Param::CliffHysteresis,
+        Param::FilterWindow,
+        Param::StaleMs,
+        Param::PreTurnMs,
+        Param::TurnMs,
+        Param::TurnAngle,
+        Param::ConvergenceMs,
+        Param::MeasurementPeriodMs,
+        Param::TrimA,
+        Param::TrimB,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Param::CliffThreshold => "cliff_threshold",
+            Param::CliffHysteresis => "cliff_hysteresis",
+            Param::FilterWindow => "filter_window",
+            Param::StaleMs => "stale_ms",
+            Param::PreTurnMs => "pre_turn_ms",
+            Param::TurnMs => "turn_ms",
+            Param::TurnAngle => "turn_angle",
+            Param::ConvergenceMs => "convergence_ms",
+            Param::MeasurementPeriodMs => "measurement_period_ms",
+            Param::TrimA => "trim_a",
+            Param::TrimB => "trim_b",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Param> {
+        Param::ALL
+            .iter()
+            .copied()
+            .find(|param| param.name() == name)
+    }
+
+    pub fn unit(self) -> &'static str {
+        match self {
+            Param::CliffThreshold | Param::CliffHysteresis => "mm",
+            Param::FilterWindow => "samples",
+            Param::StaleMs
+            | Param::PreTurnMs
+            | Param::TurnMs
+            | Param::ConvergenceMs
+            | Param::MeasurementPeriodMs => "ms",
+            Param::TurnAngle => "deg",
+            Param::TrimA | Param::TrimB => "%",
+        }
+    }
+
+    /// Smallest and largest value, inclusive.
+    pub fn range(self) -> (i32, i32) {
+        match self {
+            // The VL6180X reads 255 mm for anything out of range
+            Param::CliffThreshold => (1, 254),
+            Param::CliffHysteresis => (0, 50),
+            Param::FilterWindow => (1, MAX_WINDOW as i32),
+            Param::StaleMs => (10, 1000),
+            Param::PreTurnMs | Param::TurnMs => (0, 5000),
+            // The angle turned wraps round at half a turn, so it never reaches 180
+            Param::TurnAngle => (0, 179),
+            // Limits of the VL6180X's range registers, and a sample well within the deadline
+            Param::ConvergenceMs => (1, 63),
+            Param::MeasurementPeriodMs => (10, SENSOR_DEADLINE_MS as i32 / 2),
+            Param::TrimA | Param::TrimB => (0, 100),
+        }
+    }
+
+    /// Values are whole multiples of this.
+    pub fn step(self) -> i32 {
+        match self {
+            // The VL6180X counts the period in 10 ms
+            Param::MeasurementPeriodMs => 10,
+            _ => 1,
+        }
+    }
+
+    pub fn check(self, value: i32) -> Result<i32, OutOfRange> {
+        check(self.range(), self.step(), value)
+    }
+
+    /// Whether the sensors have to be restarted for a new value to take effect.
+    pub fn restarts_sensors(self) -> bool {
+        matches!(self, Param::ConvergenceMs | Param::MeasurementPeriodMs)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Settings {
+    /// Ranges beyond this are cliffs, in millimetres.
+    pub cliff_threshold_mm: u16,
+    /// How far below the threshold a cliff ends, in millimetres.
+    pub cliff_hysteresis_mm: u16,
+    /// Ranges the median is taken of, see `FilterConfig::window`.
+    pub filter_window: u8,
+    /// How long a reading stays fresh, in milliseconds.
+    pub stale_ms: u32,
+    pub pre_turn_ms: u32,
+    /// Longest a turn may take, in milliseconds.
+    pub turn_ms: u32,
+    /// How far to turn away from a cliff, in degrees, less than half a turn. 0 turns for
+    /// `turn_ms` instead.
+    pub turn_angle_deg: u16,
+    /// The VL6180Xs' limit on the range convergence time, in milliseconds.
+    pub max_convergence_ms: u8,
+    /// Time between samples of the VL6180Xs, in milliseconds, in steps of 10. Longer than a
+    /// range takes, see `tof::READOUT_MS`, and shorter than `stale_ms`.
+    pub inter_measurement_ms: u16,
+    /// Feedforward of channel `a`, in percent, see `DiffDriveConfig::trim_a`.
+    pub trim_a_percent: u8,
+    pub trim_b_percent: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            cliff_threshold_mm: CLIFF_THRESHOLD,
+            cliff_hysteresis_mm: HYSTERESIS_MM,
+            filter_window: WINDOW,
+            stale_ms: STALE_MS,
+            pre_turn_ms: PRE_TURN_MS,
+            turn_ms: TURN_MS,
+            turn_angle_deg: 0,
+            max_convergence_ms: MAX_CONVERGENCE_MS,
+            inter_measurement_ms: INTER_MEASUREMENT_MS,
+            trim_a_percent: 100,
+            trim_b_percent: 100,
+        }
+    }
+}
+
+impl Settings {
+    pub fn get(&self, param: Param) -> i32 {
+        match param {
+            Param::CliffThreshold => i32::from(self.cliff_threshold_mm),
+            Param::CliffHysteresis => i32::from(self.cliff_hysteresis_mm),
+            Param::FilterWindow => i32::from(self.filter_window),
+            Param::StaleMs => self.stale_ms as i32,
+            Param::PreTurnMs => self.pre_turn_ms as i32,
+            Param::TurnMs => self.turn_ms as i32,
+            Param::TurnAngle => i32::from(self.turn_angle_deg),
+            Param::ConvergenceMs => i32::from(self.max_convergence_ms),
+            Param::MeasurementPeriodMs => i32::from(self.inter_measurement_ms),
+            Param::TrimA => i32::from(self.trim_a_percent),
+            Param::TrimB => i32::from(self.trim_b_percent),
+        }
+    }
+
+    /// Smallest and largest value of `param` that goes with the other settings, inclusive.
+    pub fn range(&self, param: Param) -> (i32, i32) {
+        let (min, max) = param.range();
+        let step = param.step();
+        let period = i32::from(self.inter_measurement_ms);
+        match param {
+            // A sample takes the convergence time and the readout, and has to come in before the
+            // last one goes stale
+            Param::MeasurementPeriodMs => {
+                let fastest = i32::from(self.max_convergence_ms) + i32::from(READOUT_MS) + 1;
+                let slowest = self.stale_ms as i32 - 1;
+                (
+                    min.max((fastest + step - 1) / step * step),
+                    max.min(slowest / step * step),
+                )
+            }
+            Param::ConvergenceMs => (min, max.min(period - i32::from(READOUT_MS) - 1)),
+            Param::StaleMs => (min.max(period + 1), max),
+            _ => (min, max),
+        }
+    }
+
+    /// Changes a setting, leaving it as it was if `value` is out of its range or does not go with
+    /// the other settings, see [`Settings::range`].
+    pub fn set(&mut self, param: Param, value: i32) -> Result<(), OutOfRange> {
+        let value = check(self.range(param), param.step(), value)?;
+        self.put(param, value);
+        Ok(())
+    }
+
+    /// Whether every setting is in range, with the others as they are.
+    pub fn is_valid(&self) -> bool {
+        Param::ALL.iter().all(|param| {
+            let value = self.get(*param);
+            check(self.range(*param), param.step(), value).is_ok()
+        })
+    }
+
+    /// Changes a setting to a `value` in `param`'s own range, without looking at the others.
+    pub(crate) fn put(&mut self, param: Param, value: i32) {
+        // Every range fits the field it is checked for
+        match param {
+            Param::CliffThreshold => self.cliff_threshold_mm = value as u16,
+            Param::CliffHysteresis => self.cliff_hysteresis_mm = value as u16,
+            Param::FilterWindow => self.filter_window = value as u8,
+            Param::StaleMs => self.stale_ms = value as u32,
+            Param::PreTurnMs => self.pre_turn_ms = value as u32,
+            Param::TurnMs => self.turn_ms = value as u32,
+            Param::TurnAngle => self.turn_angle_deg = value as u16,
+            Param::ConvergenceMs => self.max_convergence_ms = value as u8,
+            Param::MeasurementPeriodMs => self.inter_measurement_ms = value as u16,
+            Param::TrimA => self.trim_a_percent = value as u8,
+            Param::TrimB => self.trim_b_percent = value as u8,
+        }
+    }
+
+    /// Sets the monitor's cliff filter and staleness timeout.
+    pub fn configure_monitor(&self, monitor: &mut CliffMonitor) {
+        monitor.set_filter_config(self.filter_config());
+        monitor.set_stale_ms(self.stale_ms);
+    }
+
+    pub fn filter_config(&self) -> FilterConfig {
+        FilterConfig {
+            window: self.filter_window,
+            threshold_mm: self.cliff_threshold_mm,
+            hysteresis_mm: self.cliff_hysteresis_mm,
+        }
+    }
+
+    pub fn drive_config(&self) -> DriveConfig {
+        DriveConfig {
+            pre_turn_ms: self.pre_turn_ms,
+            turn_ms: self.turn_ms,
+            turn_angle: match self.turn_angle_deg {
+                0 => None,
+                deg => Some(f32::from(deg).to_radians()),
+            },
+        }
+    }
+
+    /// `config` with the trims replaced.
+    pub fn diff_drive_config(&self, config: DiffDriveConfig) -> DiffDriveConfig {
+        DiffDriveConfig {
+            trim_a: f32::from(self.trim_a_percent) / 100.0,
+            trim_b: f32::from(self.trim_b_percent) / 100.0,
+            ..config
+        }
+    }
+}
+
+fn check((min, max): (i32, i32), step: i32, value: i32) -> Result<i32, OutOfRange> {
+    if (min..=max).contains(&value) && value % step == 0 {
+        Ok(value)
+    } else {
+        Err(OutOfRange { min, max, step })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for param in Param::ALL {
+            assert_eq!(Param::from_name(param.name()), Some(param));
+        }
+        assert_eq!(Param::from_name("cliff"), None);
+    }
+
+    #[test]
+    fn every_default_is_in_range() {
+        let settings = Settings::default();
+        for param in Param::ALL {
+            let value = settings.get(param);
+            assert_eq!(param.check(value), Ok(value), "{:?}", param);
+        }
+        assert!(settings.is_valid());
+    }
+
+    #[test]
+    fn set_checks_the_range() {
+        let mut settings = Settings::default();
+        for param in Param::ALL {
+            let (min, max) = settings.range(param);
+            let step = param.step();
+            settings.set(param, max).unwrap();
+            assert_eq!(settings.get(param), max);
+            settings.set(param, min).unwrap();
+            assert_eq!(settings.get(param), min);
+            let refused = Err(OutOfRange { min, max, step });
+            assert_eq!(settings.set(param, max + step), refused);
+            assert_eq!(settings.get(param), min);
+            assert!(settings.is_valid());
+        }
+    }
+
+    #[test]
+    fn checks_the_sensor_timing_against_the_other_settings() {
+        let mut settings = Settings::default();
+        // Whole steps of 10, shorter than `stale_ms` and longer than a range takes
+        assert_eq!(settings.range(Param::MeasurementPeriodMs), (20, 90));
+        assert!(settings.set(Param::MeasurementPeriodMs, 25).is_err());
+        assert!(settings.set(Param::MeasurementPeriodMs, 100).is_err());
+        assert!(settings.set(Param::ConvergenceMs, 15).is_err());
+
+        settings.set(Param::StaleMs, 300).unwrap();
+        settings.set(Param::MeasurementPeriodMs, 250).unwrap();
+        settings.set(Param::ConvergenceMs, 63).unwrap();
+        assert_eq!(settings.range(Param::MeasurementPeriodMs), (80, 290));
+        assert_eq!(settings.range(Param::StaleMs), (251, 1000));
+
+        // Never near the sensor task's deadline
+        settings.set(Param::StaleMs, 1000).unwrap();
+        assert_eq!(settings.range(Param::MeasurementPeriodMs).1, 500);
+        assert!(settings.is_valid());
+        settings.inter_measurement_ms = 1000;
+        assert!(!settings.is_valid());
+    }
+
+    #[test]
+    fn shows_the_step() {
+        let range = OutOfRange {
+            min: 20,
+            max: 90,
+            step: 10,
+        };
+        assert_eq!(range.to_string(), "out of range 20 to 90 in steps of 10");
+    }
+
+    #[test]
+    fn configures_the_rover() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.drive_config(), DriveConfig::default());
+
+        settings.set(Param::TurnAngle, 90).unwrap();
+        assert!(settings.set(Param::TurnAngle, 180).is_err());
+        settings.set(Param::TrimB, 80).unwrap();
+        settings.set(Param::CliffThreshold, 30).unwrap();
+        settings.set(Param::FilterWindow, 5).unwrap();
+        let angle = settings.drive_config().turn_angle.unwrap();
+        assert!((angle - core::f32::consts::FRAC_PI_2).abs() < 1e-6);
+        let config = settings.diff_drive_config(DiffDriveConfig::default());
+        assert_eq!((config.trim_a, config.trim_b), (1.0, 0.8));
+
+        let mut monitor = CliffMonitor::default();
+        settings.configure_monitor(&mut monitor);
+        let config = monitor.filter_config();
+        assert_eq!((config.threshold_mm, config.window), (30, 5));
+        assert_eq!(Settings::default().filter_config(), FilterConfig::default());
+    }
+}
@@ -0,0 +1,164 @@
+//! Watchdog supervision of the firmware's tasks.
+//!
+//! Every critical task checks in with the [`Supervisor`] at least once per its deadline. The
+//! watchdog is only fed while every task is on time, so a hung task, e.g. one stuck on the I2C
+//! bus, resets the rover. Once a task misses its deadline the supervisor stays tripped, so the
+//! reset cannot be called off by the task catching up.
+//!
+//! [`ResetCause`] decodes the reset flags in `RCC_CSR`, so the next boot can report that it
+//! recovered from a watchdog reset.
+
+/// What the supervisor wants done with the watchdog.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Every task is on time.
+    Feed,
+    /// The task with this index missed its deadline. Make the outputs safe and let the watchdog
+    /// reset the rover.
+    Starve { task: usize },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Supervisor<const N: usize> {
+    /// Longest each task may go without checking in, in milliseconds.
+    deadlines_ms: [u32; N],
+    last_check_in_ms: [u32; N],
+    tripped: Option<usize>,
+}
+
+impl<const N: usize> Supervisor<N> {
+    /// Supervises `N` tasks, indexed like `deadlines_ms`, counting them all as checked in at
+    /// `now_ms`.
+    pub fn new(deadlines_ms: [u32; N], now_ms: u32) -> Self {
+        Supervisor {
+            deadlines_ms,
+            last_check_in_ms: [now_ms; N],
+            tripped: None,
+        }
+    }
+
+    pub fn check_in(&mut self, task: usize, now_ms: u32) {
+        self.last_check_in_ms[task] = now_ms;
+    }
+
+    /// Whether the watchdog may be fed at `now_ms`.
+    ///
+    /// `now_ms` is from the same free running clock as the check ins and may wrap around. A
+    /// check in that raced ahead of `now_ms` counts as on time.
+    pub fn poll(&mut self, now_ms: u32) -> Verdict {
+        if self.tripped.is_none() {
+            self.tripped = (0..N).find(|&task| {
+                let age_ms = now_ms.wrapping_sub(self.last_check_in_ms[task]) as i32;
+                age_ms > self.deadlines_ms[task] as i32
+            });
+        }
+        match self.tripped {
+            Some(task) => Verdict::Starve { task },
+            None => Verdict::Feed,
+        }
+    }
+}
+
+/// Why the microcontroller last reset, from the flags in `RCC_CSR`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResetCause {
+    IndependentWatchdog,
+    WindowWatchdog,
+    LowPower,
+    Software,
+    Brownout,
+    PowerOn,
+    /// The reset pin, e.g. the reset button or a debugger.
+    Pin,
+    Unknown,
+}
+
+impl ResetCause {
+    const LPWRRSTF: u32 = 1 << 31;
+    const WWDGRSTF: u32 = 1 << 30;
+    const IWDGRSTF: u32 = 1 << 29;
+    const SFTRSTF: u32 = 1 << 28;
+    const PORRSTF: u32 = 1 << 27;
+    const PINRSTF: u32 = 1 << 26;
+    const BORRSTF: u32 = 1 << 25;
+
+    /// Decodes the value of `RCC_CSR`. Every reset also pulls the reset pin low, and a power on
+    /// also counts as a brownout, so the more specific flags win.
+    pub fn from_csr(csr: u32) -> Self {
+        [
+            (Self::IWDGRSTF, ResetCause::IndependentWatchdog),
+            (Self::WWDGRSTF, ResetCause::WindowWatchdog),
+            (Self::LPWRRSTF, ResetCause::LowPower),
+            (Self::SFTRSTF, ResetCause::Software),
+            (Self::PORRSTF, ResetCause::PowerOn),
+            (Self::BORRSTF, ResetCause::Brownout),
+            (Self::PINRSTF, ResetCause::Pin),
+        ]
+        .iter()
+        .find(|(flag, _)| csr & flag != 0)
+        .map_or(ResetCause::Unknown, |(_, cause)| *cause)
+    }
+
+    pub fn is_watchdog(self) -> bool {
+        matches!(
+            self,
+            ResetCause::IndependentWatchdog | ResetCause::WindowWatchdog
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feeds_while_tasks_check_in() {
+        let mut supervisor = Supervisor::new([10, 100], 0);
+        for now in (0..1000).step_by(5) {
+            supervisor.check_in(0, now);
+            if now % 50 == 0 {
+                supervisor.check_in(1, now);
+            }
+            assert_eq!(supervisor.poll(now), Verdict::Feed, "{}", now);
+        }
+    }
+
+    #[test]
+    fn starves_once_a_task_is_late() {
+        let mut supervisor = Supervisor::new([10, 100], 0);
+        supervisor.check_in(1, 5);
+        assert_eq!(supervisor.poll(10), Verdict::Feed);
+        assert_eq!(supervisor.poll(11), Verdict::Starve { task: 0 });
+
+        // Checking in late does not call off the reset
+        supervisor.check_in(0, 12);
+        assert_eq!(supervisor.poll(12), Verdict::Starve { task: 0 });
+    }
+
+    #[test]
+    fn check_in_ahead_of_now() {
+        let mut supervisor = Supervisor::new([10], 0);
+        supervisor.check_in(0, 20);
+        assert_eq!(supervisor.poll(19), Verdict::Feed);
+    }
+
+    #[test]
+    fn deadlines_across_clock_wrap_around() {
+        let mut supervisor = Supervisor::new([10], u32::MAX - 5);
+        assert_eq!(supervisor.poll(4), Verdict::Feed);
+        assert_eq!(supervisor.poll(5), Verdict::Starve { task: 0 });
+    }
+
+    #[test]
+    fn reset_causes() {
+        // Pin and brownout flags as they come out of a power on
+        assert_eq!(ResetCause::from_csr(0x0E00_0000), ResetCause::PowerOn);
+        assert_eq!(
+            ResetCause::from_csr(0x2400_0000),
+            ResetCause::IndependentWatchdog
+        );
+        assert!(ResetCause::from_csr(0x2400_0000).is_watchdog());
+        assert_eq!(ResetCause::from_csr(0x0400_0000), ResetCause::Pin);
+        assert_eq!(ResetCause::from_csr(0), ResetCause::Unknown);
+    }
+}
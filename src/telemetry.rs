@@ -0,0 +1,550 @@
+//! Framed binary telemetry.
+//!
+//! The rover streams a [`Packet`] over the serial port every few tens of milliseconds: the four
+//! cliff sensors' ranges and health, the cliff flags, the drive state and the motor outputs.
+//! Each frame is the packet's fixed size, little endian payload followed by its CRC-16/CCITT,
+//! COBS encoded so the only zero byte is the delimiter that ends the frame. A receiver that
+//! joins mid stream, or loses bytes, picks up again at the next zero.
+//!
+//! The same code decodes the stream on the host, see [`Decoder`].
+
+use heapless::Vec;
+
+use crate::cliff_monitor::{CliffMonitor, SensorStatus};
+use crate::drive::{Cliffs, Command, Corner, DriveState, Heading, MotorCommand, TurnDirection};
+use crate::motor::ChannelOutput;
+use crate::supervisor::ResetCause;
+
+/// Baud rate of the serial port the packets go out on.
+pub const BAUD_RATE: u32 = 115_200;
+
+/// Bumped whenever the payload layout changes.
+pub const VERSION: u8 = 1;
+/// Bytes in the payload, before the CRC.
+pub const PAYLOAD_LEN: usize = 34;
+/// Longest frame on the wire: payload, CRC, one byte of COBS overhead and the delimiter.
+pub const MAX_FRAME_LEN: usize = PAYLOAD_LEN + 2 + 2;
+
+/// Age of a sensor that has never produced a reading.
+pub const NO_READING: u16 = u16::MAX;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SensorTelemetry {
+    pub status: SensorStatus,
+    /// Latest range, 0 before the first reading.
+    pub range_mm: u16,
+    /// How old the latest range was when the packet was sent, in milliseconds, saturating at
+    /// `NO_READING - 1`. [`NO_READING`] if there is none.
+    pub age_ms: u16,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Packet {
+    /// Counts up with every packet sent, so gaps show packets lost on the way.
+    pub sequence: u16,
+    /// When the packet was put together, in milliseconds since start up.
+    pub at_ms: u32,
+    /// Indexed by `Corner`.
+    pub sensors: [SensorTelemetry; 4],
+    pub cliffs: Cliffs,
+    pub command: Command,
+    pub heading: Heading,
+    pub turn_direction: TurnDirection,
+    pub motor_command: MotorCommand,
+    /// Signed outputs of channels `a` and `b`, in thousandths of the maximum duty.
+    pub duties: [i16; 2],
+    /// Why the rover last reset.
+    pub reset_cause: ResetCause,
+}
+
+impl Packet {
+    /// A snapshot of the rover at `now_ms`, with the channel outputs last applied.
+    pub fn snapshot(
+        sequence: u16,
+        now_ms: u32,
+        monitor: &CliffMonitor,
+        drive_state: &DriveState,
+        outputs: (ChannelOutput, ChannelOutput),
+        reset_cause: ResetCause,
+    ) -> Self {
+        let sensors = Corner::ALL.map(|corner| {
+            let health = monitor.health(corner, now_ms);
+            match health.last {
+                Some(reading) => SensorTelemetry {
+                    status: health.status,
+                    range_mm: reading.range_mm,
+                    age_ms: (now_ms.wrapping_sub(reading.at_ms) as i32)
+                        .clamp(0, i32::from(NO_READING - 1)) as u16,
+                },
+                None => SensorTelemetry {
+                    status: health.status,
+                    range_mm: 0,
+                    age_ms: NO_READING,
+                },
+            }
+        });
+        Packet {
+            sequence,
+            at_ms: now_ms,
+            sensors,
+            cliffs: monitor.cliffs(now_ms),
+            command: drive_state.command(),
+            heading: drive_state.heading(),
+            turn_direction: drive_state.turn_direction(),
+            motor_command: drive_state.motor_command(),
+            duties: [outputs.0.signed(), outputs.1.signed()],
+            reset_cause,
+        }
+    }
+
+    /// The payload, see the module documentation.
+    pub fn to_payload(&self) -> [u8; PAYLOAD_LEN] {
+        let mut payload = [0; PAYLOAD_LEN];
+        payload[0] = VERSION;
+        payload[1..3].copy_from_slice(&self.sequence.to_le_bytes());
+        payload[3..7].copy_from_slice(&self.at_ms.to_le_bytes());
+        for (sensor, bytes) in self.sensors.iter().zip(payload[7..27].chunks_exact_mut(5)) {
+            bytes[0..2].copy_from_slice(&sensor.range_mm.to_le_bytes());
+            bytes[2..4].copy_from_slice(&sensor.age_ms.to_le_bytes());
+            bytes[4] = status_code(sensor.status);
+        }
+        payload[27] = Corner::ALL.iter().fold(0, |bits, &corner| {
+            bits | (self.cliffs.get(corner) as u8) << corner.index()
+        });
+        let mut drive = command_code(self.command) | motor_command_code(self.motor_command) << 4;
+        if self.heading == Heading::Reverse {
+            drive |= DRIVE_REVERSE;
+        }
+        if self.turn_direction == TurnDirection::Right {
+            drive |= DRIVE_TURN_RIGHT;
+        }
+        payload[28] = drive;
+        payload[29..31].copy_from_slice(&self.duties[0].to_le_bytes());
+        payload[31..33].copy_from_slice(&self.duties[1].to_le_bytes());
+        payload[33] = reset_cause_code(self.reset_cause);
+        payload
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self, DecodeError> {
+        if payload.len() != PAYLOAD_LEN {
+            return Err(DecodeError::Length);
+        }
+        if payload[0] != VERSION {
+            return Err(DecodeError::Version(payload[0]));
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([payload[i], payload[i + 1]]);
+
+        let mut sensors = [SensorTelemetry {
+            status: SensorStatus::NoData,
+            range_mm: 0,
+            age_ms: NO_READING,
+        }; 4];
+        for (sensor, bytes) in sensors.iter_mut().zip(payload[7..27].chunks_exact(5)) {
+            sensor.range_mm = u16::from_le_bytes([bytes[0], bytes[1]]);
+            sensor.age_ms = u16::from_le_bytes([bytes[2], bytes[3]]);
+            sensor.status = status_from_code(bytes[4])?;
+        }
+
+        let mut cliffs = Cliffs::NONE;
+        for corner in Corner::ALL {
+            cliffs.set(corner, payload[27] & 1 << corner.index() != 0);
+        }
+
+        let drive = payload[28];
+        Ok(Packet {
+            sequence: u16_at(1),
+            at_ms: u32::from_le_bytes([payload[3], payload[4], payload[5], payload[6]]),
+            sensors,
+            cliffs,
+            command: command_from_code(drive & 0x03)?,
+            heading: if drive & DRIVE_REVERSE != 0 {
+                Heading::Reverse
+            } else {
+                Heading::Forward
+            },
+            turn_direction: if drive & DRIVE_TURN_RIGHT != 0 {
+                TurnDirection::Right
+            } else {
+                TurnDirection::Left
+            },
+            motor_command: motor_command_from_code(drive >> 4)?,
+            duties: [u16_at(29) as i16, u16_at(31) as i16],
+            reset_cause: reset_cause_from_code(payload[33])?,
+        })
+    }
+
+    /// Writes the whole frame, delimiter included, to the start of `frame` and returns its
+    /// length.
+    pub fn encode(&self, frame: &mut [u8; MAX_FRAME_LEN]) -> usize {
+        let mut data = [0; PAYLOAD_LEN + 2];
+        data[..PAYLOAD_LEN].copy_from_slice(&self.to_payload());
+        let crc = crc16(&data[..PAYLOAD_LEN]);
+        data[PAYLOAD_LEN..].copy_from_slice(&crc.to_le_bytes());
+        let len = cobs_encode(&data, frame);
+        frame[len] = 0;
+        len + 1
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// More bytes than any frame has before a delimiter, e.g. after joining mid stream.
+    TooLong,
+    /// Not valid COBS.
+    Framing,
+    /// The frame is too short or too long for a packet.
+    Length,
+    /// The CRC does not match, the frame was corrupted.
+    Crc,
+    /// Sent by firmware with a different payload layout.
+    Version(u8),
+    /// A field holds a value no packet has.
+    Value,
+}
+
+/// Splits a byte stream into packets.
+#[derive(Debug, Default, Clone)]
+pub struct Decoder {
+    frame: Vec<u8, MAX_FRAME_LEN>,
+    overflowed: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder::default()
+    }
+
+    /// Takes the next byte of the stream. Returns the packet, or why it could not be decoded,
+    /// when the byte ends a frame.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Packet, DecodeError>> {
+        if byte != 0 {
+            if self.frame.push(byte).is_err() {
+                self.overflowed = true;
+            }
+            return None;
+        }
+
+        let result = if self.overflowed {
+            Err(DecodeError::TooLong)
+        } else if self.frame.is_empty() {
+            // Back to back delimiters, e.g. sent to flush a receiver
+            self.overflowed = false;
+            return None;
+        } else {
+            decode_frame(&self.frame)
+        };
+        self.frame.clear();
+        self.overflowed = false;
+        Some(result)
+    }
+}
+
+/// Decodes one frame, without its delimiter.
+fn decode_frame(frame: &[u8]) -> Result<Packet, DecodeError> {
+    let mut data = [0; MAX_FRAME_LEN];
+    let len = cobs_decode(frame, &mut data)?;
+    if len != PAYLOAD_LEN + 2 {
+        return Err(DecodeError::Length);
+    }
+    let (payload, crc) = data[..len].split_at(PAYLOAD_LEN);
+    if crc16(payload).to_le_bytes() != crc {
+        return Err(DecodeError::Crc);
+    }
+    Packet::from_payload(payload)
+}
+
+const DRIVE_REVERSE: u8 = 1 << 2;
+const DRIVE_TURN_RIGHT: u8 = 1 << 3;
+
+fn status_code(status: SensorStatus) -> u8 {
+    match status {
+        SensorStatus::NoData => 0,
+        SensorStatus::Ok => 1,
+        SensorStatus::Stale => 2,
+        SensorStatus::Error => 3,
+    }
+}
+
+fn status_from_code(code: u8) -> Result<SensorStatus, DecodeError> {
+    Ok(match code {
+        0 => SensorStatus::NoData,
+        1 => SensorStatus::Ok,
+        2 => SensorStatus::Stale,
+        3 => SensorStatus::Error,
+        _ => return Err(DecodeError::Value),
+    })
+}
+
+fn command_code(command: Command) -> u8 {
+    match command {
+        Command::Advance => 0,
+        Command::PreTurn => 1,
+        Command::Turn => 2,
+        Command::Standby => 3,
+    }
+}
+
+fn command_from_code(code: u8) -> Result<Command, DecodeError> {
+    Ok(match code {
+        0 => Command::Advance,
+        1 => Command::PreTurn,
+        2 => Command::Turn,
+        3 => Command::Standby,
+        _ => return Err(DecodeError::Value),
+    })
+}
+
+fn motor_command_code(command: MotorCommand) -> u8 {
+    match command {
+        MotorCommand::Stop => 0,
+        MotorCommand::Forward => 1,
+        MotorCommand::Reverse => 2,
+        MotorCommand::SpinLeft => 3,
+        MotorCommand::SpinRight => 4,
+    }
+}
+
+fn motor_command_from_code(code: u8) -> Result<MotorCommand, DecodeError> {
+    Ok(match code {
+        0 => MotorCommand::Stop,
+        1 => MotorCommand::Forward,
+        2 => MotorCommand::Reverse,
+        3 => MotorCommand::SpinLeft,
+        4 => MotorCommand::SpinRight,
+        _ => return Err(DecodeError::Value),
+    })
+}
+
+const RESET_CAUSES: [ResetCause; 8] = [
+    ResetCause::Unknown,
+    ResetCause::PowerOn,
+    ResetCause::Pin,
+    ResetCause::Brownout,
+    ResetCause::Software,
+    ResetCause::IndependentWatchdog,
+    ResetCause::WindowWatchdog,
+    ResetCause::LowPower,
+];
+
+fn reset_cause_code(cause: ResetCause) -> u8 {
+    RESET_CAUSES.iter().position(|&c| c == cause).unwrap_or(0) as u8
+}
+
+fn reset_cause_from_code(code: u8) -> Result<ResetCause, DecodeError> {
+    RESET_CAUSES
+        .get(usize::from(code))
+        .copied()
+        .ok_or(DecodeError::Value)
+}
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, starting from 0xFFFF.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ u16::from(byte) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// COBS encodes `data`, of less than 254 bytes, into `out` and returns the encoded length,
+/// without a delimiter.
+fn cobs_encode(data: &[u8], out: &mut [u8]) -> usize {
+    let mut code_at = 0;
+    let mut len = 1;
+    for &byte in data {
+        if byte == 0 {
+            out[code_at] = (len - code_at) as u8;
+            code_at = len;
+        } else {
+            out[len] = byte;
+        }
+        len += 1;
+    }
+    out[code_at] = (len - code_at) as u8;
+    len
+}
+
+/// Undoes [`cobs_encode`], returning the decoded length.
+fn cobs_decode(frame: &[u8], out: &mut [u8]) -> Result<usize, DecodeError> {
+    let mut len = 0;
+    let mut i = 0;
+    while i < frame.len() {
+        let code = usize::from(frame[i]);
+        if code == 0 || i + code > frame.len() {
+            return Err(DecodeError::Framing);
+        }
+        for &byte in &frame[i + 1..i + code] {
+            *out.get_mut(len).ok_or(DecodeError::Length)? = byte;
+            len += 1;
+        }
+        i += code;
+        // Every group but the last ends in a zero, groups of 254 bytes excepted
+        if i < frame.len() && code < 0xFF {
+            *out.get_mut(len).ok_or(DecodeError::Length)? = 0;
+            len += 1;
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> Packet {
+        Packet {
+            sequence: 0x1234,
+            at_ms: 0x0001_0000,
+            sensors: [
+                SensorTelemetry {
+                    status: SensorStatus::Ok,
+                    range_mm: 12,
+                    age_ms: 5,
+                },
+                SensorTelemetry {
+                    status: SensorStatus::Stale,
+                    range_mm: 255,
+                    age_ms: 400,
+                },
+                SensorTelemetry {
+                    status: SensorStatus::Error,
+                    range_mm: 0,
+                    age_ms: 0,
+                },
+                SensorTelemetry {
+                    status: SensorStatus::NoData,
+                    range_mm: 0,
+                    age_ms: NO_READING,
+                },
+            ],
+            cliffs: Cliffs {
+                br: false,
+                fr: true,
+                fl: true,
+                bl: true,
+            },
+            command: Command::Turn,
+            heading: Heading::Reverse,
+            turn_direction: TurnDirection::Right,
+            motor_command: MotorCommand::SpinRight,
+            duties: [-1000, 1000],
+            reset_cause: ResetCause::IndependentWatchdog,
+        }
+    }
+
+    fn decode_all(
+        decoder: &mut Decoder,
+        bytes: &[u8],
+    ) -> std::vec::Vec<Result<Packet, DecodeError>> {
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.push(byte))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = packet().encode(&mut frame);
+        assert_eq!(len, MAX_FRAME_LEN);
+        // The delimiter is the only zero
+        assert_eq!(frame[..len].iter().filter(|&&b| b == 0).count(), 1);
+        assert_eq!(frame[len - 1], 0);
+
+        let mut decoder = Decoder::new();
+        assert_eq!(decode_all(&mut decoder, &frame[..len]), [Ok(packet())]);
+    }
+
+    #[test]
+    fn resynchronises_mid_stream() {
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = packet().encode(&mut frame);
+        let mut stream = std::vec::Vec::new();
+        // Joins halfway through a frame, then a run of noise longer than any frame
+        stream.extend_from_slice(&frame[len / 2..len]);
+        stream.extend_from_slice(&frame[..len]);
+        stream.extend_from_slice(&[0x55; 2 * MAX_FRAME_LEN]);
+        stream.push(0);
+        stream.extend_from_slice(&frame[..len]);
+
+        let mut decoder = Decoder::new();
+        let results = decode_all(&mut decoder, &stream);
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_err());
+        assert_eq!(results[1], Ok(packet()));
+        assert_eq!(results[2], Err(DecodeError::TooLong));
+        assert_eq!(results[3], Ok(packet()));
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = packet().encode(&mut frame);
+        // Flip a bit in the sequence number, without making it zero
+        frame[2] ^= 0x01;
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            decode_all(&mut decoder, &frame[..len]),
+            [Err(DecodeError::Crc)]
+        );
+    }
+
+    #[test]
+    fn zeros_in_the_payload() {
+        let mut zeros = packet();
+        zeros.sequence = 0;
+        zeros.at_ms = 0;
+        zeros.duties = [0, 0];
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = zeros.encode(&mut frame);
+        let mut decoder = Decoder::new();
+        assert_eq!(decode_all(&mut decoder, &frame[..len]), [Ok(zeros)]);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut payload = packet().to_payload();
+        payload[0] = VERSION + 1;
+        assert_eq!(
+            Packet::from_payload(&payload),
+            Err(DecodeError::Version(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn snapshot_of_the_rover() {
+        let mut monitor = CliffMonitor::new(50);
+        monitor.record(Corner::FrontLeft, 10, 90);
+        monitor.record_error(Corner::BackLeft);
+        let packet = Packet::snapshot(
+            7,
+            100,
+            &monitor,
+            &DriveState::default(),
+            (
+                ChannelOutput::from_signed(-250),
+                ChannelOutput::from_signed(0),
+            ),
+            ResetCause::PowerOn,
+        );
+        let fl = packet.sensors[Corner::FrontLeft.index()];
+        assert_eq!(
+            (fl.status, fl.range_mm, fl.age_ms),
+            (SensorStatus::Ok, 10, 10)
+        );
+        let bl = packet.sensors[Corner::BackLeft.index()];
+        assert_eq!((bl.status, bl.age_ms), (SensorStatus::Error, NO_READING));
+        assert!(packet.cliffs.bl && !packet.cliffs.fl);
+        assert_eq!(packet.command, Command::Standby);
+        assert_eq!(packet.duties, [-250, 0]);
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+}
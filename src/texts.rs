@@ -0,0 +1,58 @@
+//! What the test programs show, their titles, menus and readings.
+//!
+//! The screen catalogue on the host draws the same screens, so it takes them from here rather
+//! than from a copy that could drift apart from the programs.
+
+use core::fmt::{self, Write};
+
+/// Title of `main`, the rover testbed.
+pub const TESTBED_TITLE: &str = "Testbed";
+/// `main`'s menu, in the order of its screens.
+pub const TESTBED_MENU: [&str; 4] = ["Ferris", "Hello", "GYUL53L0X range", "Goodbye"];
+pub const WELCOME_TEXT: &str = "Hello\nShao Yuan";
+pub const GOODBYE_TEXT: &str = "Goodbye\nSee you soon!";
+
+/// Title of `vl6180x_dynamic`.
+pub const VL6180X_TITLE: &str = "VL6180X";
+/// `vl6180x_dynamic`'s menu, in the order of its tests.
+pub const VL6180X_MENU: [&str; 5] = [
+    "Ferris",
+    "Range continuous",
+    "Range single",
+    "Ambient continuous",
+    "Ambient single",
+];
+
+/// A range reading, e.g. `42 mm`.
+pub fn write_range(w: &mut impl Write, range_mm: u16) -> fmt::Result {
+    write!(w, "{} mm", range_mm)
+}
+
+/// An ambient light reading, e.g. `012.8000 lux`.
+pub fn write_ambient(w: &mut impl Write, lux: f32) -> fmt::Result {
+    write!(w, "{:08.4} lux", lux)
+}
+
+/// A reading of `vl6180x_dynamic`, under the name of its test. The names are too long to share
+/// the status bar with the hint.
+pub fn write_test_reading(w: &mut impl Write, test: &str, reading: &str) -> fmt::Result {
+    write!(w, "{}\n{}", test, reading)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_the_readings() {
+        let mut text = String::new();
+        write_range(&mut text, 42).unwrap();
+        assert_eq!(text, "42 mm");
+        text.clear();
+        write_ambient(&mut text, 12.8).unwrap();
+        assert_eq!(text, "012.8000 lux");
+        text.clear();
+        write_test_reading(&mut text, "Range single", "42 mm").unwrap();
+        assert_eq!(text, "Range single\n42 mm");
+    }
+}
@@ -0,0 +1,655 @@
+//! Several VL6180Xs sharing one I2C bus.
+//!
+//! Every VL6180X comes out of reset at the same address, so they are booted one at a time by
+//! releasing their x_shut pins in turn and moving each to its own address before the next one
+//! wakes up. Sensors that fail to come up are held in reset, so they cannot answer for the next
+//! sensor at the default address.
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use embedded_hal::digital::v2::OutputPin;
+use vl6180x::{Config, RangeContinuousMode, VL6180X};
+
+/// Address a VL6180X answers at after leaving reset.
+pub const DEFAULT_ADDRESS: u8 = 0x29;
+/// Value of the `IDENTIFICATION__MODEL_ID` register.
+const MODEL_ID: u8 = 0xB4;
+/// Index of the `IDENTIFICATION__MODEL_ID` register.
+const MODEL_ID_REGISTER: [u8; 2] = [0x00, 0x00];
+/// Index of the `SYSRANGE__PART_TO_PART_RANGE_OFFSET` register, in millimetres.
+const RANGE_OFFSET_REGISTER: [u8; 2] = [0x00, 0x24];
+/// Index of the `SYSRANGE__CROSSTALK_COMPENSATION_RATE` register, see [`Correction`].
+const CROSSTALK_REGISTER: [u8; 2] = [0x00, 0x1E];
+/// Index of the `RESULT__RANGE_RETURN_RATE` register, see [`TofArray::return_rate`].
+const RETURN_RATE_REGISTER: [u8; 2] = [0x00, 0x66];
+/// Index of the `RESULT__RANGE_STATUS` register, see [`RangeStatus`].
+const RANGE_STATUS_REGISTER: [u8; 2] = [0x00, 0x4D];
+/// How long a sensor takes to boot after x_shut is released.
+pub const BOOT_MS: u32 = 50;
+/// How long x_shut is held low to reset a sensor.
+pub const RESET_MS: u32 = 1;
+/// The rover's limit on the range convergence time, in milliseconds.
+pub const MAX_CONVERGENCE_MS: u8 = 10;
+/// Time between samples when ranging continuously, in milliseconds.
+pub const INTER_MEASUREMENT_MS: u16 = 20;
+/// What a range takes on top of its convergence time, the 4.3 ms of readout averaging and the
+/// set up, rounded up. The time between samples has to allow for both.
+pub const READOUT_MS: u8 = 8;
+/// The rover's supervisor resets it if no sample comes in for this long, in milliseconds.
+pub const SENSOR_DEADLINE_MS: u32 = 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TofError {
+    /// The sensor could not be configured at the default address after leaving reset.
+    Boot,
+    /// Moving the sensor to its own address failed.
+    Address,
+    /// The sensor did not identify as a VL6180X at its new address.
+    Identification,
+    /// Starting continuous ranging failed.
+    Start,
+    /// Writing the [`Correction`] failed.
+    Calibration,
+    /// Reading a result register failed.
+    Read,
+}
+
+/// What the error code of a range says about it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RangeStatus {
+    /// A good range.
+    Valid,
+    /// Too little light came back to range anything, e.g. over a cliff. The range reads the
+    /// maximum.
+    NoTarget,
+    /// Something is closer than the sensor can range.
+    Underflow,
+    /// The sensor failed, or ambient light drowned out the signal. The range means nothing.
+    Unreliable,
+}
+
+impl RangeStatus {
+    /// From the error code in the top four bits of `RESULT__RANGE_STATUS`.
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => RangeStatus::Valid,
+            // Early convergence estimate, max convergence, ignore threshold, range overflow
+            6..=8 | 13 | 15 => RangeStatus::NoTarget,
+            // Raw range underflow, range underflow
+            12 | 14 => RangeStatus::Underflow,
+            // VCSEL and PLL failures, max signal to noise ratio
+            _ => RangeStatus::Unreliable,
+        }
+    }
+}
+
+/// Makes up for where a sensor is mounted, see `calibration`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Correction {
+    /// Added to the factory range offset, in millimetres.
+    pub offset_mm: i8,
+    /// Light the cover window reflects back into the sensor, in Mcps with 7 fractional bits.
+    /// The sensor discounts it from every range.
+    pub crosstalk: u16,
+}
+
+/// Which sensors came up, by position.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InitReport<const N: usize> {
+    pub results: [Result<(), TofError>; N],
+}
+
+impl<const N: usize> InitReport<N> {
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(Result::is_ok)
+    }
+
+    /// Positions that failed to come up and why.
+    pub fn failed(&self) -> impl Iterator<Item = (usize, TofError)> + '_ {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, result)| result.err().map(|e| (i, e)))
+    }
+}
+
+pub struct TofArray<I2C, XShut, Int, const N: usize> {
+    /// Handle for raw transactions, e.g. reading back identification registers.
+    bus: I2C,
+    sensors: [Option<VL6180X<RangeContinuousMode, I2C>>; N],
+    /// `(x_shut, interrupt)` pins of each sensor.
+    pins: [(XShut, Int); N],
+    config: Config,
+    base_address: u8,
+    corrections: [Correction; N],
+}
+
+impl<I2C, E, XShut, Int, const N: usize> TofArray<I2C, XShut, Int, N>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    XShut: OutputPin,
+{
+    /// Takes the `(x_shut, interrupt)` pins of each sensor and holds every sensor in reset.
+    /// Sensor `i` will be moved to address `base_address + i`.
+    ///
+    /// # Panics
+    ///
+    /// If the addresses do not fit in the 7 bit I2C address space or include the default
+    /// address.
+    pub fn new(bus: I2C, mut pins: [(XShut, Int); N], config: Config, base_address: u8) -> Self {
+        let last_address = base_address as usize + N;
+        assert!(last_address <= 0x7F, "addresses past 0x7F");
+        assert!(
+            !(base_address as usize..last_address).contains(&(DEFAULT_ADDRESS as usize)),
+            "addresses overlap the default address"
+        );
+
+        for (x_shut, _) in pins.iter_mut() {
+            x_shut.set_low().ok();
+        }
+
+        TofArray {
+            bus,
+            sensors: core::array::from_fn(|_| None),
+            pins,
+            config,
+            base_address,
+            corrections: [Correction::default(); N],
+        }
+    }
+
+    /// Boots every sensor in turn, moves it to its address and starts continuous ranging.
+    /// `acquire` hands out a bus handle for each sensor.
+    pub fn init(
+        &mut self,
+        mut acquire: impl FnMut() -> I2C,
+        delay: &mut impl DelayMs<u32>,
+    ) -> InitReport<N> {
+        let mut results = [Ok(()); N];
+        for (i, result) in results.iter_mut().enumerate() {
+            *result = self.boot(i, acquire(), delay);
+        }
+        InitReport { results }
+    }
+
+    /// Resets and boots a single sensor again, e.g. after it dropped off the bus.
+    pub fn reinit(
+        &mut self,
+        index: usize,
+        i2c: I2C,
+        delay: &mut impl DelayMs<u32>,
+    ) -> Result<(), TofError> {
+        self.sensors[index] = None;
+        self.pins[index].0.set_low().ok();
+        delay.delay_ms(RESET_MS);
+        self.boot(index, i2c, delay)
+    }
+
+    /// Holds a sensor in reset, e.g. to stop it interfering with the bus.
+    pub fn shut_down(&mut self, index: usize) {
+        self.sensors[index] = None;
+        self.pins[index].0.set_low().ok();
+    }
+
+    /// Lets a sensor held in reset boot. It answers at the default address after [`BOOT_MS`],
+    /// so no other sensor may be released until it has been [`start`](Self::start)ed.
+    ///
+    /// Together with `shut_down` this resets a sensor without blocking for the boot time, e.g.
+    /// when the waits are scheduled on a timer instead.
+    pub fn release(&mut self, index: usize) {
+        self.pins[index].0.set_high().ok();
+    }
+
+    /// Moves a sensor that has booted after [`release`](Self::release) to its address and
+    /// starts continuous ranging. On failure it is held in reset again.
+    pub fn start(&mut self, index: usize, i2c: I2C) -> Result<(), TofError> {
+        let address = self.address(index);
+        let sensor = VL6180X::with_config(i2c, &self.config)
+            .map_err(|_| TofError::Boot)
+            .and_then(|mut sensor| {
+                sensor
+                    .change_i2c_address(address)
+                    .map_err(|_| TofError::Address)?;
+                self.identify(address)?;
+                self.correct(index, address)?;
+                sensor
+                    .start_range_continuous_mode()
+                    .map_err(|_| TofError::Start)
+            });
+
+        match sensor {
+            Ok(sensor) => {
+                self.sensors[index] = Some(sensor);
+                Ok(())
+            }
+            Err(e) => {
+                self.pins[index].0.set_low().ok();
+                Err(e)
+            }
+        }
+    }
+
+    fn boot(
+        &mut self,
+        index: usize,
+        i2c: I2C,
+        delay: &mut impl DelayMs<u32>,
+    ) -> Result<(), TofError> {
+        self.release(index);
+        delay.delay_ms(BOOT_MS);
+        self.start(index, i2c)
+    }
+
+    /// Checks that a VL6180X answers at `address`.
+    fn identify(&mut self, address: u8) -> Result<(), TofError> {
+        let mut model_id = [0];
+        match self
+            .bus
+            .write_read(address, &MODEL_ID_REGISTER, &mut model_id)
+        {
+            Ok(()) if model_id[0] == MODEL_ID => Ok(()),
+            _ => Err(TofError::Identification),
+        }
+    }
+
+    /// Applies the sensor's correction, adding the offset to the one the sensor read from its
+    /// NVM when it booted.
+    fn correct(&mut self, index: usize, address: u8) -> Result<(), TofError> {
+        let correction = self.corrections[index];
+        if correction.offset_mm != 0 {
+            let mut factory = [0];
+            self.bus
+                .write_read(address, &RANGE_OFFSET_REGISTER, &mut factory)
+                .map_err(|_| TofError::Calibration)?;
+            let offset = (factory[0] as i8).saturating_add(correction.offset_mm);
+            let [high, low] = RANGE_OFFSET_REGISTER;
+            self.bus
+                .write(address, &[high, low, offset as u8])
+                .map_err(|_| TofError::Calibration)?;
+        }
+        let [high, low] = CROSSTALK_REGISTER;
+        let [rate_high, rate_low] = correction.crosstalk.to_be_bytes();
+        self.bus
+            .write(address, &[high, low, rate_high, rate_low])
+            .map_err(|_| TofError::Calibration)
+    }
+
+    /// Status of the latest range of the sensor at `index`.
+    pub fn range_status(&mut self, index: usize) -> Result<RangeStatus, TofError> {
+        let mut status = [0];
+        self.bus
+            .write_read(self.address(index), &RANGE_STATUS_REGISTER, &mut status)
+            .map_err(|_| TofError::Read)?;
+        Ok(RangeStatus::from_code(status[0] >> 4))
+    }
+
+    /// Signal rate of the latest range of the sensor at `index`, in Mcps with 7 fractional
+    /// bits, e.g. for working out the crosstalk.
+    pub fn return_rate(&mut self, index: usize) -> Result<u16, TofError> {
+        let mut rate = [0; 2];
+        self.bus
+            .write_read(self.address(index), &RETURN_RATE_REGISTER, &mut rate)
+            .map_err(|_| TofError::Read)?;
+        Ok(u16::from_be_bytes(rate))
+    }
+
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    /// I2C address of the sensor at `index` once it is booted.
+    pub fn address(&self, index: usize) -> u8 {
+        self.base_address + index as u8
+    }
+
+    /// Whether the sensor at `index` is booted and ranging.
+    pub fn is_active(&self, index: usize) -> bool {
+        self.sensors[index].is_some()
+    }
+
+    /// The sensor at `index`, if it is booted and ranging.
+    pub fn sensor_mut(&mut self, index: usize) -> Option<&mut VL6180X<RangeContinuousMode, I2C>> {
+        self.sensors[index].as_mut()
+    }
+
+    pub fn interrupt_pin_mut(&mut self, index: usize) -> &mut Int {
+        &mut self.pins[index].1
+    }
+
+    /// Splits off the interrupt pins, so they can be serviced without locking the sensors.
+    pub fn split_interrupt_pins(self) -> (TofArray<I2C, XShut, (), N>, [Int; N]) {
+        let mut pins = self.pins.map(|(x_shut, int)| (Some(x_shut), Some(int)));
+        let x_shut_pins = core::array::from_fn(|i| (pins[i].0.take().unwrap(), ()));
+        let interrupt_pins = core::array::from_fn(|i| pins[i].1.take().unwrap());
+        (
+            TofArray {
+                bus: self.bus,
+                sensors: self.sensors,
+                pins: x_shut_pins,
+                config: self.config,
+                base_address: self.base_address,
+                corrections: self.corrections,
+            },
+            interrupt_pins,
+        )
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Used by sensors booted from now on.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    pub fn correction(&self, index: usize) -> Correction {
+        self.corrections[index]
+    }
+
+    /// Takes effect the next time the sensor at `index` boots.
+    pub fn set_correction(&mut self, index: usize, correction: Correction) {
+        self.corrections[index] = correction;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Index of the `I2C_SLAVE__DEVICE_ADDRESS` register.
+    const DEVICE_ADDRESS_REGISTER: usize = 0x212;
+    /// Index of the `SYSTEM__FRESH_OUT_OF_RESET` register.
+    const FRESH_OUT_OF_RESET_REGISTER: usize = 0x016;
+    const BASE_ADDRESS: u8 = 0x30;
+
+    /// A VL6180X as far as its registers go, which it loses in reset.
+    struct Chip {
+        /// Whether x_shut is high.
+        powered: bool,
+        address: u8,
+        registers: [u8; 0x300],
+        /// Range offset the chip loads from its NVM.
+        factory_offset_mm: i8,
+        /// Like a chip that keeps answering at the default address.
+        ignores_address: bool,
+    }
+
+    impl Chip {
+        fn new(factory_offset_mm: i8) -> Self {
+            Chip {
+                powered: false,
+                address: DEFAULT_ADDRESS,
+                registers: [0; 0x300],
+                factory_offset_mm,
+                ignores_address: false,
+            }
+        }
+
+        fn boot(&mut self) {
+            self.address = DEFAULT_ADDRESS;
+            self.registers = [0; 0x300];
+            self.registers[usize::from(MODEL_ID_REGISTER[1])] = MODEL_ID;
+            self.registers[FRESH_OUT_OF_RESET_REGISTER] = 1;
+            self.registers[usize::from(RANGE_OFFSET_REGISTER[1])] = self.factory_offset_mm as u8;
+        }
+
+        fn register(&self, index: [u8; 2]) -> u8 {
+            self.registers[usize::from(u16::from_be_bytes(index))]
+        }
+
+        fn offset_mm(&self) -> i8 {
+            self.register(RANGE_OFFSET_REGISTER) as i8
+        }
+
+        fn crosstalk(&self) -> u16 {
+            let [high, low] = CROSSTALK_REGISTER;
+            u16::from_be_bytes([self.register([high, low]), self.register([high, low + 1])])
+        }
+    }
+
+    /// Chips on a shared bus, a clone for every handle. Nothing answering is a NACK.
+    #[derive(Clone)]
+    struct Bus(Rc<RefCell<Vec<Chip>>>);
+
+    impl Bus {
+        fn new(chips: Vec<Chip>) -> Self {
+            Bus(Rc::new(RefCell::new(chips)))
+        }
+
+        fn x_shut(&self, chip: usize) -> (XShut, ()) {
+            (
+                XShut {
+                    bus: self.clone(),
+                    chip,
+                },
+                (),
+            )
+        }
+
+        /// Runs `f` on the chip answering at `address`, panicking if more than one does.
+        fn with_chip<T>(&self, address: u8, f: impl FnOnce(&mut Chip) -> T) -> Result<T, ()> {
+            let mut chips = self.0.borrow_mut();
+            let mut answering = chips
+                .iter_mut()
+                .filter(|chip| chip.powered && chip.address == address);
+            let chip = answering.next().ok_or(())?;
+            assert!(answering.next().is_none(), "chips clash at {:#x}", address);
+            Ok(f(chip))
+        }
+
+        fn chip<T>(&self, chip: usize, f: impl FnOnce(&Chip) -> T) -> T {
+            f(&self.0.borrow()[chip])
+        }
+    }
+
+    impl Write for Bus {
+        type Error = ();
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), ()> {
+            self.with_chip(address, |chip| {
+                let index = usize::from(u16::from_be_bytes([bytes[0], bytes[1]]));
+                for (i, &byte) in bytes[2..].iter().enumerate() {
+                    chip.registers[index + i] = byte;
+                }
+                if index == DEVICE_ADDRESS_REGISTER && !chip.ignores_address {
+                    chip.address = bytes[2] & 0x7F;
+                }
+            })
+        }
+    }
+
+    impl WriteRead for Bus {
+        type Error = ();
+
+        fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), ()> {
+            self.with_chip(address, |chip| {
+                let index = usize::from(u16::from_be_bytes([bytes[0], bytes[1]]));
+                buffer.copy_from_slice(&chip.registers[index..index + buffer.len()]);
+            })
+        }
+    }
+
+    /// Powers a chip on the bus while high.
+    struct XShut {
+        bus: Bus,
+        chip: usize,
+    }
+
+    impl OutputPin for XShut {
+        type Error = ();
+
+        fn set_low(&mut self) -> Result<(), ()> {
+            self.bus.0.borrow_mut()[self.chip].powered = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), ()> {
+            let mut chips = self.bus.0.borrow_mut();
+            let chip = &mut chips[self.chip];
+            if !chip.powered {
+                chip.powered = true;
+                chip.boot();
+            }
+            Ok(())
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayMs<u32> for NoDelay {
+        fn delay_ms(&mut self, _ms: u32) {}
+    }
+
+    type Tofs = TofArray<Bus, XShut, (), 3>;
+
+    fn array(chips: Vec<Chip>) -> (Bus, Tofs) {
+        let bus = Bus::new(chips);
+        let pins = core::array::from_fn(|i| bus.x_shut(i));
+        let tofs = TofArray::new(bus.clone(), pins, Config::new(), BASE_ADDRESS);
+        (bus, tofs)
+    }
+
+    fn powered(bus: &Bus) -> Vec<bool> {
+        bus.0.borrow().iter().map(|chip| chip.powered).collect()
+    }
+
+    #[test]
+    fn new_holds_every_sensor_in_reset() {
+        let chips = (0..3).map(|_| Chip::new(0)).collect();
+        let bus = Bus::new(chips);
+        for pin in 0..3 {
+            bus.x_shut(pin).0.set_high().unwrap();
+        }
+
+        let pins = core::array::from_fn(|i| bus.x_shut(i));
+        let tofs: Tofs = TofArray::new(bus.clone(), pins, Config::new(), BASE_ADDRESS);
+        assert_eq!(powered(&bus), [false; 3]);
+        assert_eq!(
+            (0..3).map(|i| tofs.address(i)).collect::<Vec<_>>(),
+            [0x30, 0x31, 0x32]
+        );
+        assert!((0..3).all(|i| !tofs.is_active(i)));
+    }
+
+    #[test]
+    #[should_panic(expected = "default address")]
+    fn addresses_must_not_overlap_the_default() {
+        let bus = Bus::new((0..3).map(|_| Chip::new(0)).collect());
+        let pins = core::array::from_fn(|i| bus.x_shut(i));
+        let _: Tofs = TofArray::new(bus, pins, Config::new(), DEFAULT_ADDRESS - 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "past 0x7F")]
+    fn addresses_must_fit_in_7_bits() {
+        let bus = Bus::new((0..3).map(|_| Chip::new(0)).collect());
+        let pins = core::array::from_fn(|i| bus.x_shut(i));
+        let _: Tofs = TofArray::new(bus, pins, Config::new(), 0x7E);
+    }
+
+    #[test]
+    fn init_moves_every_sensor_to_its_address() {
+        let (bus, mut tofs) = array((0..3).map(|_| Chip::new(0)).collect());
+        let report = tofs.init(|| bus.clone(), &mut NoDelay);
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(powered(&bus), [true; 3]);
+        for i in 0..3 {
+            assert_eq!(bus.chip(i, |chip| chip.address), tofs.address(i));
+            assert!(tofs.is_active(i));
+        }
+    }
+
+    #[test]
+    fn sensor_not_found_at_its_address_is_held_in_reset() {
+        let mut stuck = Chip::new(0);
+        stuck.ignores_address = true;
+        let (bus, mut tofs) = array(vec![Chip::new(0), stuck, Chip::new(0)]);
+
+        // The next sensor boots at the default address without a clash
+        let report = tofs.init(|| bus.clone(), &mut NoDelay);
+        assert_eq!(
+            report.results,
+            [Ok(()), Err(TofError::Identification), Ok(())]
+        );
+        assert_eq!(
+            report.failed().collect::<Vec<_>>(),
+            [(1, TofError::Identification)]
+        );
+        assert_eq!(powered(&bus), [true, false, true]);
+        assert!(!tofs.is_active(1));
+        assert_eq!(bus.chip(2, |chip| chip.address), tofs.address(2));
+    }
+
+    #[test]
+    fn start_fails_without_a_sensor_booted() {
+        let (bus, mut tofs) = array((0..3).map(|_| Chip::new(0)).collect());
+
+        // Still in reset, nothing answers at the default address
+        assert_eq!(tofs.start(0, bus.clone()), Err(TofError::Boot));
+        assert_eq!(powered(&bus), [false; 3]);
+
+        tofs.release(0);
+        assert_eq!(powered(&bus), [true, false, false]);
+        bus.0.borrow_mut()[0].registers[usize::from(MODEL_ID_REGISTER[1])] = 0;
+        assert!(tofs.start(0, bus.clone()).is_err());
+        assert_eq!(powered(&bus), [false; 3]);
+        assert!(!tofs.is_active(0));
+    }
+
+    #[test]
+    fn corrections_add_to_the_factory_offset() {
+        let (bus, mut tofs) = array(vec![Chip::new(5), Chip::new(-100), Chip::new(100)]);
+        let corrections = [(-3, 0x1234), (-100, 0), (100, 7)];
+        for (i, &(offset_mm, crosstalk)) in corrections.iter().enumerate() {
+            tofs.set_correction(
+                i,
+                Correction {
+                    offset_mm,
+                    crosstalk,
+                },
+            );
+        }
+
+        assert!(tofs.init(|| bus.clone(), &mut NoDelay).is_ok());
+        // Saturating rather than wrapping round
+        assert_eq!(
+            (0..3)
+                .map(|i| bus.chip(i, Chip::offset_mm))
+                .collect::<Vec<_>>(),
+            [2, i8::MIN, i8::MAX]
+        );
+        assert_eq!(
+            (0..3)
+                .map(|i| bus.chip(i, Chip::crosstalk))
+                .collect::<Vec<_>>(),
+            [0x1234, 0, 7]
+        );
+
+        // Applied afresh on every boot, not on top of the last one
+        assert!(tofs.reinit(0, bus.clone(), &mut NoDelay).is_ok());
+        assert_eq!(bus.chip(0, Chip::offset_mm), 2);
+    }
+
+    #[test]
+    fn no_offset_correction_keeps_the_factory_offset() {
+        let (bus, mut tofs) = array(vec![Chip::new(-7), Chip::new(0), Chip::new(12)]);
+        tofs.set_correction(
+            2,
+            Correction {
+                offset_mm: 0,
+                crosstalk: 0x80,
+            },
+        );
+        assert!(tofs.init(|| bus.clone(), &mut NoDelay).is_ok());
+        assert_eq!(bus.chip(0, Chip::offset_mm), -7);
+        assert_eq!(bus.chip(2, Chip::offset_mm), 12);
+        assert_eq!(bus.chip(2, Chip::crosstalk), 0x80);
+    }
+}
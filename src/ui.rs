@@ -0,0 +1,649 @@
+//! One button user interface on the SSD1306.
+//!
+//! Every test program shows the same things the same way: a [`StatusBar`] along the top and a
+//! [`Screen`] below it, e.g. a scrolling [`Menu`], a [`ValueEditor`] or a [`Message`], drawn by
+//! [`draw`]. The drawing goes through embedded-graphics to any `DrawTarget<Color = BinaryColor>`,
+//! the display in `BufferedGraphicsMode` on the rover, which is flushed afterwards.
+//!
+//! The PA0 button is the only input, its [`Gesture`]s go to the screen on show: a click moves on,
+//! e.g. to the next menu item, a double click moves back and a long press picks, e.g. the item.
+
+use core::fmt::Write;
+
+use embedded_graphics::{
+    mono_font::{
+        ascii::{FONT_10X20, FONT_6X10},
+        MonoTextStyle, MonoTextStyleBuilder,
+    },
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use heapless::String;
+
+use crate::button::Gesture;
+
+pub const WIDTH: u32 = 128;
+pub const HEIGHT: u32 = 64;
+/// Characters on a line of the 6x10 font.
+pub const COLUMNS: usize = (WIDTH / CHAR_WIDTH) as usize;
+/// Lines below the status bar.
+pub const BODY_LINES: usize = 5;
+/// Where the screens are drawn, under the status bar and a blank line of pixels.
+pub const BODY: Rectangle = Rectangle::new(
+    Point::new(0, LINE_HEIGHT as i32 + 2),
+    Size::new(WIDTH, BODY_LINES as u32 * LINE_HEIGHT),
+);
+
+const CHAR_WIDTH: u32 = 6;
+const LINE_HEIGHT: u32 = 10;
+/// The menu's scroll bar, along the right edge.
+const SCROLL_BAR_WIDTH: u32 = 2;
+/// Characters of the value editor's 10x20 font on a line, large enough to read at arm's length.
+const VALUE_COLUMNS: usize = 12;
+
+/// Milliseconds from a free running cycle counter, e.g. the DWT's, for the test programs that
+/// have no monotonic timer.
+#[derive(Debug, Copy, Clone)]
+pub struct CycleClock {
+    cycles_per_ms: u32,
+    last: u32,
+    now_ms: u32,
+}
+
+impl CycleClock {
+    pub fn new(cycles_per_ms: u32, cycles: u32) -> Self {
+        CycleClock {
+            cycles_per_ms,
+            last: cycles,
+            now_ms: 0,
+        }
+    }
+
+    /// Milliseconds since [`new`](Self::new), with the counter at `cycles`. Has to be called
+    /// before the counter wraps around twice, every 89 s at 48 MHz.
+    pub fn update(&mut self, cycles: u32) -> u32 {
+        let ms = cycles.wrapping_sub(self.last) / self.cycles_per_ms;
+        self.last = self.last.wrapping_add(ms * self.cycles_per_ms);
+        self.now_ms = self.now_ms.wrapping_add(ms);
+        self.now_ms
+    }
+}
+
+/// What goes below the status bar.
+pub trait Screen {
+    /// What the screen is done with, e.g. the menu item picked.
+    type Output;
+
+    fn press(&mut self, gesture: Gesture) -> Option<Self::Output>;
+
+    /// Draws onto a cleared [`WIDTH`] by [`BODY_LINES`] lines area.
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>;
+
+    /// Characters of its text that do not fit and are left out of the drawing.
+    fn cut_off(&self) -> usize {
+        0
+    }
+}
+
+/// A line along the top, e.g. the program on the left and how it is doing on the right.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StatusBar<'a> {
+    pub title: &'a str,
+    pub status: &'a str,
+}
+
+impl<'a> StatusBar<'a> {
+    pub fn new(title: &'a str, status: &'a str) -> Self {
+        StatusBar { title, status }
+    }
+
+    pub fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        Rectangle::new(Point::zero(), Size::new(WIDTH, LINE_HEIGHT))
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(target)?;
+        let status = fit(self.status, COLUMNS);
+        let style = inverted();
+        Text::with_baseline(
+            fit(self.title, self.title_columns()),
+            Point::new(1, 0),
+            style,
+            Baseline::Top,
+        )
+        .draw(target)?;
+        Text::with_text_style(
+            status,
+            Point::new(WIDTH as i32 - 1, 0),
+            style,
+            TextStyleBuilder::new()
+                .alignment(Alignment::Right)
+                .baseline(Baseline::Top)
+                .build(),
+        )
+        .draw(target)?;
+        Ok(())
+    }
+
+    /// Characters of the title and the status that do not fit on the bar.
+    pub fn cut_off(&self) -> usize {
+        cut(self.title, self.title_columns()) + cut(self.status, COLUMNS)
+    }
+
+    /// The status wins over the title, with a space between them.
+    fn title_columns(&self) -> usize {
+        (COLUMNS - self.status.chars().count().min(COLUMNS)).saturating_sub(1)
+    }
+}
+
+/// Clears `target` and draws the status bar and the screen.
+pub fn draw<D, S>(target: &mut D, status: &StatusBar, screen: &S) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+    S: Screen,
+{
+    target.clear(BinaryColor::Off)?;
+    status.draw(target)?;
+    screen.draw(&mut target.cropped(&BODY))
+}
+
+/// A list to pick from, scrolling to keep the selected item in view. A click selects the next
+/// item, round to the first after the last, a double click the one before, and a long press
+/// picks the selected one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Menu<'a> {
+    items: &'a [&'a str],
+    selected: usize,
+    /// First item in view.
+    top: usize,
+}
+
+impl<'a> Menu<'a> {
+    pub fn new(items: &'a [&'a str]) -> Self {
+        Menu {
+            items,
+            selected: 0,
+            top: 0,
+        }
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Selects the item at `index`, e.g. to come back to the one picked last.
+    pub fn select(&mut self, index: usize) {
+        if index < self.items.len() {
+            self.selected = index;
+            if self.selected < self.top {
+                self.top = self.selected;
+            } else if self.selected >= self.top + BODY_LINES {
+                self.top = self.selected + 1 - BODY_LINES;
+            }
+        }
+    }
+
+    fn scrolls(&self) -> bool {
+        self.items.len() > BODY_LINES
+    }
+
+    /// Highlighted across, up to the scroll bar if there is one.
+    fn width(&self) -> u32 {
+        if self.scrolls() {
+            WIDTH - SCROLL_BAR_WIDTH - 1
+        } else {
+            WIDTH
+        }
+    }
+
+    /// Characters of an item that fit, within a margin either side.
+    fn columns(&self) -> usize {
+        ((self.width() - 2) / CHAR_WIDTH) as usize
+    }
+}
+
+impl<'a> Screen for Menu<'a> {
+    /// Index of the item picked.
+    type Output = usize;
+
+    fn press(&mut self, gesture: Gesture) -> Option<usize> {
+        let len = self.items.len();
+        if len == 0 {
+            return None;
+        }
+        match gesture {
+            Gesture::Click => self.select((self.selected + 1) % len),
+            Gesture::DoubleClick => self.select((self.selected + len - 1) % len),
+            Gesture::LongPress => return Some(self.selected),
+            Gesture::Hold(_) => (),
+        }
+        None
+    }
+
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let width = self.width();
+        for (line, index) in (self.top..self.items.len()).take(BODY_LINES).enumerate() {
+            let y = (line as u32 * LINE_HEIGHT) as i32;
+            let style = if index == self.selected {
+                Rectangle::new(Point::new(0, y), Size::new(width, LINE_HEIGHT))
+                    .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                    .draw(target)?;
+                inverted()
+            } else {
+                normal()
+            };
+            Text::with_baseline(
+                fit(self.items[index], self.columns()),
+                Point::new(2, y),
+                style,
+                Baseline::Top,
+            )
+            .draw(target)?;
+        }
+
+        if self.scrolls() {
+            // The thumb is as long and as far down as the items in view
+            let track = BODY_LINES as u32 * LINE_HEIGHT;
+            let len = self.items.len() as u32;
+            let thumb = Rectangle::new(
+                Point::new(
+                    (WIDTH - SCROLL_BAR_WIDTH) as i32,
+                    (track * self.top as u32 / len) as i32,
+                ),
+                Size::new(SCROLL_BAR_WIDTH, track * BODY_LINES as u32 / len),
+            );
+            thumb
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                .draw(target)?;
+        }
+        Ok(())
+    }
+
+    /// Of all the items, whether in view or not.
+    fn cut_off(&self) -> usize {
+        let columns = self.columns();
+        self.items.iter().map(|item| cut(item, columns)).sum()
+    }
+}
+
+/// Changes a number. A click adds a step, round to the smallest value past the largest, a double
+/// click takes one away, round to the largest, and a long press is done with the value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ValueEditor<'a> {
+    label: &'a str,
+    unit: &'a str,
+    value: i32,
+    /// Smallest and largest value, inclusive, like `settings::Param::range`.
+    range: (i32, i32),
+    step: i32,
+}
+
+impl<'a> ValueEditor<'a> {
+    /// Starts from `value`, brought into `range`.
+    pub fn new(label: &'a str, unit: &'a str, value: i32, range: (i32, i32), step: i32) -> Self {
+        ValueEditor {
+            label,
+            unit,
+            value: value.clamp(range.0, range.1),
+            range,
+            step: step.max(1),
+        }
+    }
+
+    pub fn value(&self) -> i32 {
+        self.value
+    }
+
+    fn value_text(&self) -> String<24> {
+        let mut value = String::new();
+        write!(value, "{} {}", self.value, self.unit).ok();
+        value
+    }
+
+    fn hint(&self) -> String<32> {
+        let mut hint = String::new();
+        write!(hint, "{}..{} hold: done", self.range.0, self.range.1).ok();
+        hint
+    }
+}
+
+impl<'a> Screen for ValueEditor<'a> {
+    /// The value, once done.
+    type Output = i32;
+
+    fn press(&mut self, gesture: Gesture) -> Option<i32> {
+        let (min, max) = self.range;
+        match gesture {
+            Gesture::Click => {
+                self.value = match self.value.checked_add(self.step) {
+                    Some(value) if value <= max => value,
+                    _ => min,
+                };
+            }
+            Gesture::DoubleClick => {
+                self.value = match self.value.checked_sub(self.step) {
+                    Some(value) if value >= min => value,
+                    _ => max,
+                };
+            }
+            Gesture::LongPress => return Some(self.value),
+            Gesture::Hold(_) => (),
+        }
+        None
+    }
+
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        Text::with_baseline(
+            fit(self.label, COLUMNS),
+            Point::zero(),
+            normal(),
+            Baseline::Top,
+        )
+        .draw(target)?;
+
+        let value = self.value_text();
+        Text::with_text_style(
+            fit(&value, VALUE_COLUMNS),
+            Point::new(WIDTH as i32 / 2, LINE_HEIGHT as i32 + 4),
+            MonoTextStyle::new(&FONT_10X20, BinaryColor::On),
+            TextStyleBuilder::new()
+                .alignment(Alignment::Center)
+                .baseline(Baseline::Top)
+                .build(),
+        )
+        .draw(target)?;
+
+        Text::with_baseline(
+            fit(&self.hint(), COLUMNS),
+            Point::new(0, ((BODY_LINES - 1) as u32 * LINE_HEIGHT) as i32),
+            normal(),
+            Baseline::Top,
+        )
+        .draw(target)
+        .map(|_| ())
+    }
+
+    /// Of the label, the value as it is now and the hint.
+    fn cut_off(&self) -> usize {
+        cut(self.label, COLUMNS)
+            + cut(&self.value_text(), VALUE_COLUMNS)
+            + cut(&self.hint(), COLUMNS)
+    }
+}
+
+/// Lines of text in the middle of the screen, e.g. a reading. Done with on a long press.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Message<'a> {
+    text: &'a str,
+}
+
+impl<'a> Message<'a> {
+    /// Lines past [`BODY_LINES`] and characters past [`COLUMNS`] are cut off, see
+    /// [`Screen::cut_off`].
+    pub fn new(text: &'a str) -> Self {
+        Message { text }
+    }
+}
+
+impl<'a> Screen for Message<'a> {
+    type Output = ();
+
+    fn press(&mut self, gesture: Gesture) -> Option<()> {
+        (gesture == Gesture::LongPress).then_some(())
+    }
+
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let lines = self.text.lines().take(BODY_LINES);
+        let top = (BODY_LINES - lines.clone().count()) as u32 * LINE_HEIGHT / 2;
+        let style = TextStyleBuilder::new()
+            .alignment(Alignment::Center)
+            .baseline(Baseline::Top)
+            .build();
+        for (index, line) in lines.enumerate() {
+            let y = top + index as u32 * LINE_HEIGHT;
+            Text::with_text_style(
+                fit(line, COLUMNS),
+                Point::new(WIDTH as i32 / 2, y as i32),
+                normal(),
+                style,
+            )
+            .draw(target)?;
+        }
+        Ok(())
+    }
+
+    /// Of the lines in view, and all of those past them.
+    fn cut_off(&self) -> usize {
+        let mut lines = self.text.lines();
+        let in_view: usize = lines
+            .by_ref()
+            .take(BODY_LINES)
+            .map(|line| cut(line, COLUMNS))
+            .sum();
+        in_view + lines.map(|line| line.chars().count()).sum::<usize>()
+    }
+}
+
+fn normal() -> MonoTextStyle<'static, BinaryColor> {
+    MonoTextStyle::new(&FONT_6X10, BinaryColor::On)
+}
+
+/// Dark text for the light bars.
+fn inverted() -> MonoTextStyle<'static, BinaryColor> {
+    MonoTextStyleBuilder::new()
+        .font(&FONT_6X10)
+        .text_color(BinaryColor::Off)
+        .build()
+}
+
+/// The first `columns` characters of `text`.
+fn fit(text: &str, columns: usize) -> &str {
+    match text.char_indices().nth(columns) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// Characters of `text` that [`fit`] leaves out.
+fn cut(text: &str, columns: usize) -> usize {
+    text.chars().count().saturating_sub(columns)
+}
+
+/// A display in memory for the tests, that counts the pixels drawn off it.
+#[cfg(test)]
+pub(crate) struct Frame {
+    pixels: [[bool; WIDTH as usize]; HEIGHT as usize],
+    pub outside: usize,
+}
+
+#[cfg(test)]
+impl Frame {
+    pub fn new() -> Self {
+        Frame {
+            pixels: [[false; WIDTH as usize]; HEIGHT as usize],
+            outside: 0,
+        }
+    }
+
+    pub fn lit(&self, x: usize, y: usize) -> bool {
+        self.pixels[y][x]
+    }
+
+    /// Lit pixels in a row, from `x` on.
+    pub fn lit_in_row(&self, x: usize, y: usize) -> usize {
+        self.pixels[y][x..].iter().filter(|lit| **lit).count()
+    }
+}
+
+#[cfg(test)]
+impl OriginDimensions for Frame {
+    fn size(&self) -> Size {
+        Size::new(WIDTH, HEIGHT)
+    }
+}
+
+#[cfg(test)]
+impl DrawTarget for Frame {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<BinaryColor>>,
+    {
+        for Pixel(point, color) in pixels {
+            match self.pixels.get_mut(point.y as usize) {
+                Some(row) if point.x >= 0 && point.y >= 0 && (point.x as u32) < WIDTH => {
+                    row[point.x as usize] = color.is_on();
+                }
+                _ => self.outside += 1,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ITEMS: [&str; 7] = ["One", "Two", "Three", "Four", "Five", "Six", "Seven"];
+
+    #[test]
+    fn cycle_clock() {
+        let mut clock = CycleClock::new(48_000, u32::MAX - 47_999);
+        assert_eq!(clock.update(u32::MAX), 0);
+        // A millisecond on, across the counter wrapping around
+        assert_eq!(clock.update(0), 1);
+        assert_eq!(clock.update(96_000 + 47_999), 3);
+        assert_eq!(clock.update(96_000 + 48_000), 4);
+    }
+
+    #[test]
+    fn menu_scrolls_to_the_selection() {
+        let mut menu = Menu::new(&ITEMS);
+        for _ in 0..5 {
+            assert_eq!(menu.press(Gesture::Click), None);
+        }
+        assert_eq!((menu.selected(), menu.top), (5, 1));
+        assert_eq!(menu.press(Gesture::Hold(1)), None);
+        assert_eq!(menu.press(Gesture::LongPress), Some(5));
+
+        // Round to the top again, and back
+        menu.press(Gesture::Click);
+        menu.press(Gesture::Click);
+        assert_eq!((menu.selected(), menu.top), (0, 0));
+        menu.press(Gesture::DoubleClick);
+        assert_eq!((menu.selected(), menu.top), (6, 2));
+        menu.select(0);
+        menu.select(6);
+        assert_eq!((menu.selected(), menu.top), (6, 2));
+        menu.select(7);
+        assert_eq!(menu.selected(), 6);
+
+        assert_eq!(Menu::new(&[]).press(Gesture::LongPress), None);
+    }
+
+    #[test]
+    fn value_editor_steps_round() {
+        let mut editor = ValueEditor::new("Trim a", "%", 120, (0, 100), 25);
+        assert_eq!(editor.value(), 100);
+        assert_eq!(editor.press(Gesture::Click), None);
+        assert_eq!(editor.value(), 0);
+        editor.press(Gesture::DoubleClick);
+        assert_eq!(editor.value(), 100);
+        editor.press(Gesture::DoubleClick);
+        editor.press(Gesture::DoubleClick);
+        assert_eq!(editor.press(Gesture::LongPress), Some(50));
+
+        let mut editor = ValueEditor::new("Big", "", i32::MAX, (i32::MIN, i32::MAX), 1);
+        editor.press(Gesture::Click);
+        assert_eq!(editor.value(), i32::MIN);
+        editor.press(Gesture::DoubleClick);
+        assert_eq!(editor.value(), i32::MAX);
+    }
+
+    #[test]
+    fn draws_inside_the_display() {
+        let status = StatusBar::new("A title much too long for the bar", "Running");
+        let mut frame = Frame::new();
+        let mut menu = Menu::new(&ITEMS);
+        menu.select(6);
+        draw(&mut frame, &status, &menu).unwrap();
+        assert_eq!(frame.outside, 0);
+        // Light bar with dark text, the status right aligned
+        assert!(frame.lit(0, 0) && frame.lit(WIDTH as usize - 1, 9));
+        assert!(frame.lit_in_row(0, 5) < WIDTH as usize);
+        // Blank line between the bar and the menu
+        assert_eq!(frame.lit_in_row(0, 10), 0);
+        // The last five items in view, the last one selected
+        let body = BODY.top_left.y as usize;
+        assert!(!frame.lit(0, body) && frame.lit(0, body + 4 * 10));
+        let thumb = WIDTH as usize - 1;
+        assert!(!frame.lit(thumb, body + 10) && frame.lit(thumb, body + 22));
+
+        let mut frame = Frame::new();
+        let editor = ValueEditor::new("Stale", "ms", 1000, (10, 1000), 10);
+        draw(&mut frame, &status, &editor).unwrap();
+        assert_eq!(frame.outside, 0);
+
+        let mut frame = Frame::new();
+        let text = "One\nTwo\nThree\nFour\nFive\nSix, never drawn\nSeven";
+        draw(&mut frame, &status, &Message::new(text)).unwrap();
+        assert_eq!(frame.outside, 0);
+        assert_eq!(frame.lit_in_row(0, HEIGHT as usize - 1), 0);
+    }
+
+    #[test]
+    fn fits() {
+        assert_eq!(fit("Range", 3), "Ran");
+        assert_eq!(fit("Range", 21), "Range");
+        assert_eq!(fit("°C", 1), "°");
+        assert_eq!((cut("Range", 3), cut("Range", 21), cut("°C", 1)), (2, 0, 1));
+    }
+
+    #[test]
+    fn counts_what_is_cut_off() {
+        let status = StatusBar::new("A title much too long for the bar", "Running");
+        // 13 columns left of the 21 for the title
+        assert_eq!(status.cut_off(), 33 - 13);
+        assert_eq!(StatusBar::new("Settings", "hold: done").cut_off(), 0);
+
+        // 20 columns beside the scroll bar
+        let items = [
+            "An item just too long",
+            "Two",
+            "Three",
+            "Four",
+            "Five",
+            "Six",
+        ];
+        assert_eq!(Menu::new(&items).cut_off(), 1);
+        assert_eq!(Menu::new(&ITEMS).cut_off(), 0);
+
+        let editor = ValueEditor::new("Stale", "ms", 1000, (10, 1000), 10);
+        assert_eq!(editor.cut_off(), 0);
+        let editor = ValueEditor::new("Big", "units", i32::MIN, (i32::MIN, 0), 1);
+        // `-2147483648 units`, and `-2147483648..0 hold: done`
+        assert_eq!(editor.cut_off(), 5 + 4);
+
+        assert_eq!(Message::new("Hello\nShao Yuan").cut_off(), 0);
+        let text = "A line\nmuch too long to fit on the display";
+        assert_eq!(Message::new(text).cut_off(), 35 - 21);
+        assert_eq!(Message::new("1\n2\n3\n4\n5\nSix\nSeven").cut_off(), 8);
+    }
+}
@@ -0,0 +1,149 @@
+//! Decodes the rover's serial telemetry on the host.
+//!
+//! The frame format lives in the firmware's `telemetry` module, so the decoder always matches
+//! the rover it was built with. [`Packets`] reads packets out of any byte stream, e.g. a capture
+//! of the serial port, and [`write_csv`] turns them into CSV rows.
+
+use std::io::{self, Read, Write};
+
+use stm32f401_rover_testbed::telemetry::{DecodeError, Decoder, Packet};
+
+pub use stm32f401_rover_testbed::telemetry;
+
+pub const CSV_HEADER: &str = "sequence,t_ms,\
+    range_br,age_br,status_br,range_fr,age_fr,status_fr,\
+    range_fl,age_fl,status_fl,range_bl,age_bl,status_bl,\
+    cliff_br,cliff_fr,cliff_fl,cliff_bl,\
+    command,heading,turn,motor,duty_a,duty_b,reset_cause";
+
+pub fn write_csv(packet: &Packet, mut w: impl Write) -> io::Result<()> {
+    write!(w, "{},{}", packet.sequence, packet.at_ms)?;
+    for sensor in &packet.sensors {
+        write!(
+            w,
+            ",{},{},{:?}",
+            sensor.range_mm, sensor.age_ms, sensor.status
+        )?;
+    }
+    writeln!(
+        w,
+        ",{},{},{},{},{:?},{:?},{:?},{:?},{},{},{:?}",
+        packet.cliffs.br as u8,
+        packet.cliffs.fr as u8,
+        packet.cliffs.fl as u8,
+        packet.cliffs.bl as u8,
+        packet.command,
+        packet.heading,
+        packet.turn_direction,
+        packet.motor_command,
+        packet.duties[0],
+        packet.duties[1],
+        packet.reset_cause,
+    )
+}
+
+/// Iterates over the packets in a byte stream, and the frames that failed to decode.
+pub struct Packets<R> {
+    reader: R,
+    decoder: Decoder,
+    buffer: [u8; 256],
+    pending: std::ops::Range<usize>,
+}
+
+impl<R: Read> Packets<R> {
+    pub fn new(reader: R) -> Self {
+        Packets {
+            reader,
+            decoder: Decoder::new(),
+            buffer: [0; 256],
+            pending: 0..0,
+        }
+    }
+}
+
+/// A packet, or a frame that could not be decoded.
+pub type Frame = Result<Packet, DecodeError>;
+
+impl<R: Read> Iterator for Packets<R> {
+    type Item = io::Result<Frame>;
+
+    /// Ends with the stream. A frame cut off at the end of the stream is dropped.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            for i in self.pending.by_ref() {
+                if let Some(frame) = self.decoder.push(self.buffer[i]) {
+                    return Some(Ok(frame));
+                }
+            }
+            match self.reader.read(&mut self.buffer) {
+                Ok(0) => return None,
+                Ok(len) => self.pending = 0..len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stm32f401_rover_testbed::cliff_monitor::SensorStatus;
+    use stm32f401_rover_testbed::drive::{Cliffs, Command, Heading, MotorCommand, TurnDirection};
+    use stm32f401_rover_testbed::supervisor::ResetCause;
+    use telemetry::{SensorTelemetry, MAX_FRAME_LEN};
+
+    fn packet(sequence: u16) -> Packet {
+        Packet {
+            sequence,
+            at_ms: 1500,
+            sensors: [SensorTelemetry {
+                status: SensorStatus::Ok,
+                range_mm: 14,
+                age_ms: 3,
+            }; 4],
+            cliffs: Cliffs {
+                fl: true,
+                ..Cliffs::NONE
+            },
+            command: Command::PreTurn,
+            heading: Heading::Reverse,
+            turn_direction: TurnDirection::Left,
+            motor_command: MotorCommand::Reverse,
+            duties: [-600, -600],
+            reset_cause: ResetCause::PowerOn,
+        }
+    }
+
+    fn stream(packets: &[Packet]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for packet in packets {
+            let mut frame = [0; MAX_FRAME_LEN];
+            let len = packet.encode(&mut frame);
+            bytes.extend_from_slice(&frame[..len]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn reads_packets_across_buffer_boundaries() {
+        let packets: Vec<Packet> = (0..20).map(packet).collect();
+        let decoded: Vec<Frame> = Packets::new(&stream(&packets)[..])
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(decoded, packets.into_iter().map(Ok).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn csv_row() {
+        let mut row = Vec::new();
+        write_csv(&packet(3), &mut row).unwrap();
+        let row = String::from_utf8(row).unwrap();
+        assert_eq!(
+            row,
+            "3,1500,14,3,Ok,14,3,Ok,14,3,Ok,14,3,Ok,0,0,1,0,\
+             PreTurn,Reverse,Left,Reverse,-600,-600,PowerOn\n"
+        );
+        assert_eq!(row.split(',').count(), CSV_HEADER.split(',').count());
+    }
+}
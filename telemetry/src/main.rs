@@ -0,0 +1,86 @@
+//! Converts a capture of the rover's serial telemetry to CSV.
+//!
+//! cargo run -p rover-telemetry --target x86_64-unknown-linux-gnu -- [OPTIONS] [INPUT]
+//!
+//! INPUT            raw bytes from the serial port, e.g. a file written by
+//!                  `cat /dev/ttyUSB0 > capture.bin` with the port at 115200 baud raw, or a
+//!                  serial device read directly (default stdin)
+//! --output PATH    write the CSV to PATH (default stdout)
+//!
+//! Frames that fail to decode are skipped and counted on stderr.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::process;
+
+use rover_telemetry::{write_csv, Packets, CSV_HEADER};
+
+struct Args {
+    input: Option<String>,
+    output: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        input: None,
+        output: None,
+    };
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--output" => {
+                args.output = Some(iter.next().ok_or(format!("missing value for {}", arg))?)
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown argument {}", arg)),
+            _ if args.input.is_none() => args.input = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    Ok(args)
+}
+
+fn open_or_exit(path: &str, open: impl FnOnce(&str) -> io::Result<File>) -> File {
+    open(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(2);
+    })
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let input: Box<dyn Read> = match &args.input {
+        Some(path) => Box::new(open_or_exit(path, |p| File::open(p))),
+        None => Box::new(io::stdin()),
+    };
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(open_or_exit(path, |p| File::create(p))),
+        None => Box::new(io::stdout()),
+    };
+    let mut output = BufWriter::new(output);
+
+    writeln!(output, "{}", CSV_HEADER).expect("write CSV");
+    let (mut packets, mut bad) = (0u64, 0u64);
+    for frame in Packets::new(input) {
+        match frame {
+            Ok(Ok(packet)) => {
+                packets += 1;
+                write_csv(&packet, &mut output).expect("write CSV");
+            }
+            Ok(Err(e)) => {
+                bad += 1;
+                eprintln!("bad frame after {} packets: {:?}", packets, e);
+            }
+            Err(e) => {
+                eprintln!("read failed: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    output.flush().expect("write CSV");
+    eprintln!("{} packets, {} bad frames", packets, bad);
+}
@@ -0,0 +1,243 @@
+//! Live view of the rover's serial telemetry in the terminal.
+//!
+//! Packets come from a serial port or a recorded session, see [`source`], and are collected
+//! into a [`History`] that [`ui`] draws: a range sparkline per cliff sensor, the cliff flags
+//! and sensor health, and the drive state transitions. Recordings are the raw bytes off the
+//! serial port, so a replay goes through exactly the same decoder as a live session.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use rover_telemetry::Frame;
+use stm32f401_rover_testbed::drive::{Command, Heading, MotorCommand};
+use stm32f401_rover_testbed::telemetry::Packet;
+
+pub mod source;
+pub mod ui;
+
+/// Samples kept per sensor, more than a wide terminal shows.
+pub const RANGE_HISTORY: usize = 512;
+/// Drive state transitions kept.
+pub const TRANSITION_HISTORY: usize = 64;
+
+/// A change of drive state.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transition {
+    pub at_ms: u32,
+    pub command: Command,
+    pub heading: Heading,
+    pub motor_command: MotorCommand,
+}
+
+impl Transition {
+    fn of(packet: &Packet) -> Self {
+        Transition {
+            at_ms: packet.at_ms,
+            command: packet.command,
+            heading: packet.heading,
+            motor_command: packet.motor_command,
+        }
+    }
+
+    fn same_state(&self, other: &Transition) -> bool {
+        (self.command, self.heading, self.motor_command)
+            == (other.command, other.heading, other.motor_command)
+    }
+}
+
+/// Everything the viewer has seen so far.
+#[derive(Debug, Default, Clone)]
+pub struct History {
+    /// Ranges of every sensor, oldest first, indexed by `Corner`.
+    ranges: [VecDeque<u16>; 4],
+    latest: Option<Packet>,
+    /// Oldest first.
+    transitions: VecDeque<Transition>,
+    packets: u64,
+    bad_frames: u64,
+    /// Packets the sequence numbers say went missing.
+    lost: u64,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History::default()
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        let packet = match frame {
+            Ok(packet) => packet,
+            Err(_) => {
+                self.bad_frames += 1;
+                return;
+            }
+        };
+        self.packets += 1;
+
+        if let Some(latest) = &self.latest {
+            // A sequence number going backwards is the rover restarting, not a loss
+            let gap = packet.sequence.wrapping_sub(latest.sequence);
+            if packet.at_ms >= latest.at_ms && gap > 1 {
+                self.lost += u64::from(gap - 1);
+            }
+        }
+
+        for (ranges, sensor) in self.ranges.iter_mut().zip(packet.sensors.iter()) {
+            if ranges.len() == RANGE_HISTORY {
+                ranges.pop_front();
+            }
+            ranges.push_back(sensor.range_mm);
+        }
+
+        let transition = Transition::of(&packet);
+        if !matches!(self.transitions.back(), Some(last) if last.same_state(&transition)) {
+            if self.transitions.len() == TRANSITION_HISTORY {
+                self.transitions.pop_front();
+            }
+            self.transitions.push_back(transition);
+        }
+        self.latest = Some(packet);
+    }
+
+    /// Ranges of the sensor at `index` in `Corner` order, oldest first.
+    pub fn ranges(&self, index: usize) -> impl Iterator<Item = u16> + '_ {
+        self.ranges[index].iter().copied()
+    }
+
+    pub fn latest(&self) -> Option<&Packet> {
+        self.latest.as_ref()
+    }
+
+    /// Drive state transitions, oldest first.
+    pub fn transitions(&self) -> impl DoubleEndedIterator<Item = &Transition> + '_ {
+        self.transitions.iter()
+    }
+
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    pub fn bad_frames(&self) -> u64 {
+        self.bad_frames
+    }
+
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+}
+
+/// Paces a replay to the timestamps in the packets, `speed` times faster than they were sent.
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    speed: f64,
+    /// Packet time and wall clock time the replay is lined up on.
+    base: Option<(u32, Duration)>,
+}
+
+impl Pacer {
+    pub fn new(speed: f64) -> Self {
+        Pacer { speed, base: None }
+    }
+
+    /// How long to wait before showing a packet sent at `at_ms`, with `now` the time since the
+    /// replay started. Starts over when the timestamps go backwards, e.g. at a reset of the
+    /// rover.
+    pub fn delay(&mut self, at_ms: u32, now: Duration) -> Duration {
+        let (base_ms, base) = match self.base {
+            Some((base_ms, base)) if at_ms >= base_ms => (base_ms, base),
+            _ => {
+                self.base = Some((at_ms, now));
+                return Duration::ZERO;
+            }
+        };
+        let due = base + Duration::from_secs_f64(f64::from(at_ms - base_ms) / 1000.0 / self.speed);
+        due.saturating_sub(now)
+    }
+
+    /// Lines the replay up again after a pause, so it carries on from where it stopped.
+    pub fn resume(&mut self) {
+        self.base = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stm32f401_rover_testbed::cliff_monitor::SensorStatus;
+    use stm32f401_rover_testbed::drive::{Cliffs, TurnDirection};
+    use stm32f401_rover_testbed::supervisor::ResetCause;
+    use stm32f401_rover_testbed::telemetry::{DecodeError, SensorTelemetry};
+
+    pub fn packet(sequence: u16, at_ms: u32, command: Command, range_mm: u16) -> Packet {
+        Packet {
+            sequence,
+            at_ms,
+            sensors: [SensorTelemetry {
+                status: SensorStatus::Ok,
+                range_mm,
+                age_ms: 4,
+            }; 4],
+            cliffs: Cliffs::NONE,
+            command,
+            heading: Heading::Forward,
+            turn_direction: TurnDirection::Left,
+            motor_command: MotorCommand::Forward,
+            duties: [1000, 1000],
+            reset_cause: ResetCause::PowerOn,
+        }
+    }
+
+    #[test]
+    fn keeps_transitions_only() {
+        let mut history = History::new();
+        history.push(Ok(packet(0, 0, Command::Advance, 12)));
+        history.push(Ok(packet(1, 50, Command::Advance, 13)));
+        history.push(Ok(packet(2, 100, Command::PreTurn, 255)));
+        history.push(Ok(packet(3, 150, Command::PreTurn, 14)));
+
+        let at: Vec<u32> = history.transitions().map(|t| t.at_ms).collect();
+        assert_eq!(at, [0, 100]);
+        assert_eq!(history.ranges(0).collect::<Vec<_>>(), [12, 13, 255, 14]);
+        assert_eq!(history.latest().unwrap().sequence, 3);
+    }
+
+    #[test]
+    fn counts_lost_and_bad_frames() {
+        let mut history = History::new();
+        history.push(Ok(packet(u16::MAX - 1, 0, Command::Advance, 12)));
+        history.push(Err(DecodeError::Crc));
+        // Wraps around, with one packet lost to the CRC error and another one missing
+        history.push(Ok(packet(1, 150, Command::Advance, 12)));
+        assert_eq!(
+            (history.packets(), history.bad_frames(), history.lost()),
+            (2, 1, 2)
+        );
+
+        // The rover restarting is no loss
+        history.push(Ok(packet(0, 0, Command::Standby, 12)));
+        assert_eq!(history.lost(), 2);
+    }
+
+    #[test]
+    fn range_history_is_bounded() {
+        let mut history = History::new();
+        for i in 0..RANGE_HISTORY as u16 + 10 {
+            history.push(Ok(packet(i, u32::from(i), Command::Advance, i)));
+        }
+        assert_eq!(history.ranges(2).count(), RANGE_HISTORY);
+        assert_eq!(history.ranges(2).next(), Some(10));
+    }
+
+    #[test]
+    fn paces_replay() {
+        let ms = Duration::from_millis;
+        let mut pacer = Pacer::new(2.0);
+        assert_eq!(pacer.delay(1000, ms(5)), ms(0));
+        assert_eq!(pacer.delay(1100, ms(5)), ms(50));
+        // Late packets go out straight away
+        assert_eq!(pacer.delay(1200, ms(500)), ms(0));
+        // A restart of the rover lines the replay up again
+        assert_eq!(pacer.delay(10, ms(600)), ms(0));
+        assert_eq!(pacer.delay(50, ms(600)), ms(20));
+    }
+}
@@ -0,0 +1,112 @@
+//! Shows the rover's telemetry live in the terminal.
+//!
+//! cargo run -p rover-viewer --target x86_64-unknown-linux-gnu -- [OPTIONS]
+//!
+//! --port PATH      read from the serial port at PATH, e.g. /dev/ttyUSB0
+//! --baud N         baud rate of the serial port (default 115200)
+//! --record PATH    with --port, also write everything received to PATH for a later replay
+//! --replay PATH    replay a recording, or any raw capture of the serial port, instead
+//! --speed X        replay X times faster than recorded (default 1)
+//!
+//! Keys: q or Esc quits, space pauses and resumes a replay.
+
+use std::env;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind};
+use rover_viewer::source::{Event, Source};
+use rover_viewer::ui::{self, Status};
+use rover_viewer::History;
+use stm32f401_rover_testbed::telemetry::BAUD_RATE;
+
+/// How often the screen is redrawn, in milliseconds.
+const FRAME_MS: u64 = 50;
+
+fn parse_args() -> Result<Source, String> {
+    let (mut port, mut replay, mut record) = (None, None, None);
+    let mut baud = BAUD_RATE;
+    let mut speed: f64 = 1.0;
+    let mut iter = env::args().skip(1);
+    while let Some(flag) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("missing value for {}", flag));
+        match flag.as_str() {
+            "--port" => port = Some(value()?),
+            "--baud" => baud = value()?.parse().map_err(|e| format!("--baud: {}", e))?,
+            "--record" => record = Some(value()?),
+            "--replay" => replay = Some(value()?),
+            "--speed" => speed = value()?.parse().map_err(|e| format!("--speed: {}", e))?,
+            _ => return Err(format!("unknown argument {}", flag)),
+        }
+    }
+    if speed <= 0.0 || speed.is_nan() {
+        return Err("--speed has to be positive".into());
+    }
+    match (port, replay) {
+        (Some(path), None) => Ok(Source::Serial { path, baud, record }),
+        (None, Some(path)) if record.is_none() => Ok(Source::Replay { path, speed }),
+        (None, Some(_)) => Err("--record only works with --port".into()),
+        _ => Err("give either --port or --replay".into()),
+    }
+}
+
+fn main() {
+    let source = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let replaying = matches!(source, Source::Replay { .. });
+    let paused = Arc::new(AtomicBool::new(false));
+    let events = source.spawn(paused.clone()).unwrap_or_else(|e| {
+        eprintln!("{}: {}", source.describe(), e);
+        process::exit(2);
+    });
+
+    let description = source.describe();
+    let mut history = History::new();
+    let mut ended: Option<String> = None;
+    let mut terminal = ratatui::init();
+    let result = (|| -> std::io::Result<()> {
+        loop {
+            loop {
+                match events.try_recv() {
+                    Ok(Event::Frame(frame)) => history.push(frame),
+                    Ok(Event::Failed(e)) => ended = Some(format!("read failed: {}", e)),
+                    Ok(Event::Ended) => ended = Some("end of recording".into()),
+                    Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+                }
+            }
+
+            let status = Status {
+                source: &description,
+                paused: paused.load(Ordering::Relaxed),
+                ended: ended.as_deref(),
+            };
+            terminal.draw(|frame| ui::draw(frame, &history, &status))?;
+
+            if event::poll(Duration::from_millis(FRAME_MS))? {
+                if let TermEvent::Key(key) = event::read()? {
+                    if key.kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    match key.code {
+                        KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                        KeyCode::Char(' ') if replaying => {
+                            paused.fetch_xor(true, Ordering::Relaxed);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    })();
+    ratatui::restore();
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
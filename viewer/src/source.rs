@@ -0,0 +1,136 @@
+//! Where the packets come from.
+//!
+//! A [`Source`] runs on its own thread and sends every decoded frame down a channel, so the
+//! terminal stays responsive while it waits on the serial port or on the replay's clock.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rover_telemetry::{Frame, Packets};
+
+use crate::Pacer;
+
+pub enum Source {
+    /// A serial port, e.g. `/dev/ttyUSB0`, optionally recording everything received to a file.
+    Serial {
+        path: String,
+        baud: u32,
+        record: Option<String>,
+    },
+    /// A recording, replayed `speed` times faster than it was recorded.
+    Replay { path: String, speed: f64 },
+}
+
+/// What the thread reading a source sends.
+pub enum Event {
+    Frame(Frame),
+    /// The source failed, nothing more will come.
+    Failed(String),
+    /// A replay reached the end of its recording.
+    Ended,
+}
+
+impl Source {
+    /// A short description for the title bar.
+    pub fn describe(&self) -> String {
+        match self {
+            Source::Serial {
+                path,
+                baud,
+                record: Some(record),
+            } => format!("{} @ {} baud, recording to {}", path, baud, record),
+            Source::Serial { path, baud, .. } => format!("{} @ {} baud", path, baud),
+            Source::Replay { path, speed } => format!("replay of {} at {}x", path, speed),
+        }
+    }
+
+    /// Opens the source and starts reading it on a new thread. A replay holds back while
+    /// `paused` is set.
+    pub fn spawn(&self, paused: Arc<AtomicBool>) -> io::Result<Receiver<Event>> {
+        let (sender, receiver) = mpsc::channel();
+        match self {
+            Source::Serial { path, baud, record } => {
+                let port = serialport::new(path, *baud)
+                    .timeout(Duration::from_millis(100))
+                    .open()
+                    .map_err(io::Error::other)?;
+                let record = record.as_ref().map(File::create).transpose()?;
+                let reader = Recording {
+                    inner: port,
+                    record,
+                };
+                thread::spawn(move || {
+                    for frame in Packets::new(reader) {
+                        let event = match frame {
+                            Ok(frame) => Event::Frame(frame),
+                            Err(e) => Event::Failed(e.to_string()),
+                        };
+                        let failed = matches!(event, Event::Failed(_));
+                        if sender.send(event).is_err() || failed {
+                            return;
+                        }
+                    }
+                });
+            }
+            Source::Replay { path, speed } => {
+                let file = File::open(path)?;
+                let mut pacer = Pacer::new(*speed);
+                thread::spawn(move || {
+                    let start = Instant::now();
+                    for frame in Packets::new(io::BufReader::new(file)) {
+                        let frame = match frame {
+                            Ok(frame) => frame,
+                            Err(e) => {
+                                sender.send(Event::Failed(e.to_string())).ok();
+                                return;
+                            }
+                        };
+                        if paused.load(Ordering::Relaxed) {
+                            while paused.load(Ordering::Relaxed) {
+                                thread::sleep(Duration::from_millis(20));
+                            }
+                            pacer.resume();
+                        }
+                        if let Ok(packet) = &frame {
+                            thread::sleep(pacer.delay(packet.at_ms, start.elapsed()));
+                        }
+                        if sender.send(Event::Frame(frame)).is_err() {
+                            return;
+                        }
+                    }
+                    sender.send(Event::Ended).ok();
+                });
+            }
+        }
+        Ok(receiver)
+    }
+}
+
+/// Reads from a serial port, copying everything read to the recording. The port's read
+/// timeouts only mean nothing arrived yet.
+struct Recording<R> {
+    inner: R,
+    record: Option<File>,
+}
+
+impl<R: Read> Read for Recording<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.inner.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+                Ok(len) => {
+                    if let Some(record) = &mut self.record {
+                        record.write_all(&buf[..len])?;
+                    }
+                    return Ok(len);
+                }
+            }
+        }
+    }
+}
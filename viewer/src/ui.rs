@@ -0,0 +1,227 @@
+//! Draws a [`History`].
+
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph, RenderDirection, Sparkline};
+use ratatui::Frame;
+
+use stm32f401_rover_testbed::cliff_monitor::SensorStatus;
+use stm32f401_rover_testbed::drive::Corner;
+use stm32f401_rover_testbed::telemetry::NO_READING;
+
+use crate::History;
+
+/// Tallest range the sparklines show, the VL6180X reads out of range above this.
+const MAX_RANGE_MM: u64 = 255;
+
+const NAMES: [&str; 4] = ["Back right", "Front right", "Front left", "Back left"];
+
+/// What the viewer is doing, for the title bar.
+pub struct Status<'a> {
+    pub source: &'a str,
+    pub paused: bool,
+    /// Why no more packets will come, if so.
+    pub ended: Option<&'a str>,
+}
+
+pub fn draw(frame: &mut Frame, history: &History, status: &Status) {
+    let [title, body, help] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [sensors, side] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(body);
+    let [cliffs, transitions] =
+        Layout::vertical([Constraint::Length(9), Constraint::Min(0)]).areas(side);
+
+    frame.render_widget(title_bar(history, status), title);
+    draw_sparklines(frame, history, sensors);
+    frame.render_widget(cliff_panel(history), cliffs);
+    frame.render_widget(transition_list(history, transitions.height), transitions);
+    frame.render_widget(
+        Paragraph::new("q quit   space pause replay").style(Style::new().fg(Color::DarkGray)),
+        help,
+    );
+}
+
+fn title_bar<'a>(history: &History, status: &Status<'a>) -> Paragraph<'a> {
+    let mut spans = vec![
+        Span::styled(" rover ", Style::new().add_modifier(Modifier::REVERSED)),
+        Span::raw(format!(" {}  ", status.source)),
+        Span::raw(format!(
+            "{} packets, {} lost, {} bad",
+            history.packets(),
+            history.lost(),
+            history.bad_frames()
+        )),
+    ];
+    if let Some(packet) = history.latest() {
+        spans.push(Span::raw(format!(
+            "  t {:.1} s  reset: {:?}",
+            f64::from(packet.at_ms) / 1000.0,
+            packet.reset_cause
+        )));
+    }
+    if status.paused {
+        spans.push(Span::styled("  PAUSED", Style::new().fg(Color::Yellow)));
+    }
+    if let Some(ended) = status.ended {
+        spans.push(Span::styled(
+            format!("  {}", ended),
+            Style::new().fg(Color::Yellow),
+        ));
+    }
+    Paragraph::new(Line::from(spans))
+}
+
+fn draw_sparklines(frame: &mut Frame, history: &History, area: Rect) {
+    let areas = Layout::vertical([Constraint::Ratio(1, 4); 4]).split(area);
+    for corner in Corner::ALL {
+        let index = corner.index();
+        let area = areas[index];
+        let title = match history.latest().map(|packet| packet.sensors[index]) {
+            Some(sensor) if sensor.age_ms != NO_READING => format!(
+                " {} {} mm, {:?}, {} ms old ",
+                NAMES[index], sensor.range_mm, sensor.status, sensor.age_ms
+            ),
+            Some(sensor) => format!(" {} {:?} ", NAMES[index], sensor.status),
+            None => format!(" {} ", NAMES[index]),
+        };
+
+        // The newest samples on the right, as many as fit
+        let width = usize::from(area.width.saturating_sub(2));
+        let ranges: Vec<u64> = history.ranges(index).map(u64::from).collect();
+        let shown = &ranges[ranges.len().saturating_sub(width)..];
+        let sparkline = Sparkline::default()
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .data(shown.iter().rev().copied())
+            .direction(RenderDirection::RightToLeft)
+            .max(MAX_RANGE_MM)
+            .style(Style::new().fg(Color::Cyan));
+        frame.render_widget(sparkline, area);
+    }
+}
+
+fn cliff_panel(history: &History) -> Paragraph<'static> {
+    let block = Block::default().borders(Borders::ALL).title(" Cliffs ");
+    let packet = match history.latest() {
+        Some(packet) => packet,
+        None => return Paragraph::new("waiting for telemetry").block(block),
+    };
+
+    let mut lines: Vec<Line> = Corner::ALL
+        .iter()
+        .map(|&corner| {
+            let sensor = packet.sensors[corner.index()];
+            let (flag, colour) = if packet.cliffs.get(corner) {
+                ("CLIFF", Color::Red)
+            } else {
+                ("floor", Color::Green)
+            };
+            let health = match sensor.status {
+                SensorStatus::Ok => Color::Green,
+                SensorStatus::NoData | SensorStatus::Stale => Color::Yellow,
+                SensorStatus::Error => Color::Red,
+            };
+            Line::from(vec![
+                Span::raw(format!("{:<12}", NAMES[corner.index()])),
+                Span::styled(format!("{:<6}", flag), Style::new().fg(colour)),
+                Span::styled(format!("{:?}", sensor.status), Style::new().fg(health)),
+            ])
+        })
+        .collect();
+    lines.push(Line::raw(""));
+    lines.push(Line::raw(format!(
+        "{:?} heading {:?}, turning {:?}",
+        packet.command, packet.heading, packet.turn_direction
+    )));
+    lines.push(Line::raw(format!(
+        "motors {:?}  a {:+5}  b {:+5}",
+        packet.motor_command, packet.duties[0], packet.duties[1]
+    )));
+    Paragraph::new(lines).block(block)
+}
+
+fn transition_list(history: &History, height: u16) -> List<'static> {
+    let items: Vec<ListItem> = history
+        .transitions()
+        .rev()
+        .take(usize::from(height))
+        .map(|transition| {
+            ListItem::new(format!(
+                "{:>8.2} s  {:<8} {:<8} {:?}",
+                f64::from(transition.at_ms) / 1000.0,
+                format!("{:?}", transition.command),
+                format!("{:?}", transition.heading),
+                transition.motor_command
+            ))
+        })
+        .collect();
+    List::new(items).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(" Drive state, latest first "),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::packet;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use stm32f401_rover_testbed::drive::Command;
+
+    fn render(history: &History) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(120, 30)).unwrap();
+        let status = Status {
+            source: "test",
+            paused: true,
+            ended: None,
+        };
+        terminal
+            .draw(|frame| draw(frame, history, &status))
+            .unwrap();
+        let buffer = terminal.backend().buffer();
+        (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn waits_for_telemetry() {
+        let screen = render(&History::new());
+        assert!(screen.contains("waiting for telemetry"), "{}", screen);
+        assert!(screen.contains("0 packets"));
+    }
+
+    #[test]
+    fn shows_the_latest_packet() {
+        let mut history = History::new();
+        history.push(Ok(packet(0, 1000, Command::Advance, 12)));
+        let mut last = packet(1, 1050, Command::PreTurn, 14);
+        last.cliffs.fl = true;
+        history.push(Ok(last));
+
+        let screen = render(&history);
+        assert!(
+            screen.contains("Front left 14 mm, Ok, 4 ms old"),
+            "{}",
+            screen
+        );
+        assert!(screen.contains("CLIFF"));
+        assert!(screen.contains("PAUSED"));
+        // Latest transition first
+        let pre_turn = screen.find("1.05 s  PreTurn").expect("PreTurn transition");
+        let advance = screen.find("1.00 s  Advance").expect("Advance transition");
+        assert!(pre_turn < advance);
+    }
+}